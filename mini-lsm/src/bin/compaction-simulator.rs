@@ -0,0 +1,326 @@
+//! Simulates a compaction strategy on SSTs holding random keys, without any
+//! I/O, and reports the shape of the LSM tree and the write amplification.
+
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+use clap::{Args, Parser, Subcommand};
+use mini_lsm::{
+    compact::{
+        CompactionController, CompactionOptions, CompactionTask,
+        LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    },
+    key::KeyBytes,
+    lsm_storage::{LsmStorageOptions, LsmStorageState},
+    table::SsTable,
+};
+use rand::Rng;
+
+/// Every simulated key stands for a 1 KiB entry.
+const ENTRY_SIZE: u64 = 1 << 10;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    strategy: Strategy,
+}
+
+#[derive(Subcommand, Debug)]
+enum Strategy {
+    Leveled {
+        #[arg(long, default_value_t = 2)]
+        level0_file_num_compaction_trigger: usize,
+        #[arg(long, default_value_t = 4)]
+        level_size_multiplier: usize,
+        #[arg(long, default_value_t = 4)]
+        max_levels: usize,
+        #[arg(long, default_value_t = 4)]
+        base_level_size_mb: usize,
+        #[command(flatten)]
+        sim: SimulationArgs,
+    },
+    Tiered {
+        #[arg(long, default_value_t = 8)]
+        num_tiers: usize,
+        #[arg(long, default_value_t = 200)]
+        max_size_amplification_percent: usize,
+        #[arg(long, default_value_t = 1)]
+        size_ratio: usize,
+        #[arg(long, default_value_t = 2)]
+        min_merge_width: usize,
+        #[arg(long)]
+        max_merge_width: Option<usize>,
+        #[command(flatten)]
+        sim: SimulationArgs,
+    },
+    Simple {
+        #[arg(long, default_value_t = 200)]
+        size_ratio_percent: usize,
+        #[arg(long, default_value_t = 2)]
+        level0_file_num_compaction_trigger: usize,
+        #[arg(long, default_value_t = 3)]
+        max_levels: usize,
+        #[command(flatten)]
+        sim: SimulationArgs,
+    },
+}
+
+#[derive(Args, Debug)]
+struct SimulationArgs {
+    /// Number of SSTs to flush
+    #[arg(long, default_value_t = 200)]
+    iterations: usize,
+    /// Keys written by each flush, also the size of compacted SSTs
+    #[arg(long, default_value_t = 1024)]
+    sst_keys: usize,
+    /// Keys are drawn uniformly from `0..key_space`
+    #[arg(long, default_value_t = 1 << 16)]
+    key_space: u32,
+    /// Print the LSM tree after every flush
+    #[arg(long)]
+    dump: bool,
+}
+
+struct MockStorage {
+    snapshot: LsmStorageState,
+    controller: CompactionController,
+    /// The sorted keys held by each SST
+    keys: HashMap<usize, Vec<u32>>,
+    next_sst_id: usize,
+    sst_keys: usize,
+    flushed_bytes: u64,
+    written_bytes: u64,
+    num_compactions: usize,
+}
+
+impl MockStorage {
+    fn new(compaction_options: CompactionOptions, sst_keys: usize) -> Self {
+        let controller = CompactionController::new(&compaction_options);
+        let snapshot = LsmStorageState::create(&LsmStorageOptions {
+            compaction_options,
+            ..LsmStorageOptions::default()
+        });
+        Self {
+            snapshot,
+            controller,
+            keys: HashMap::new(),
+            next_sst_id: 1,
+            sst_keys,
+            flushed_bytes: 0,
+            written_bytes: 0,
+            num_compactions: 0,
+        }
+    }
+
+    fn add_sst(&mut self, keys: Vec<u32>) -> usize {
+        let id = self.next_sst_id;
+        self.next_sst_id += 1;
+        let key = |k: u32| {
+            KeyBytes::from_bytes_with_ts(
+                Bytes::copy_from_slice(&k.to_be_bytes()),
+                0,
+            )
+        };
+        let size = keys.len() as u64 * ENTRY_SIZE;
+        let sst = SsTable::create_meta_only(
+            id,
+            size,
+            key(keys[0]),
+            key(*keys.last().unwrap()),
+        );
+        self.snapshot.sstables.insert(id, Arc::new(sst));
+        self.keys.insert(id, keys);
+        self.written_bytes += size;
+        id
+    }
+
+    fn flush(&mut self, mut keys: Vec<u32>) {
+        keys.sort_unstable();
+        keys.dedup();
+        self.flushed_bytes += keys.len() as u64 * ENTRY_SIZE;
+        let id = self.add_sst(keys);
+        if self.controller.flush_to_l0() {
+            self.snapshot.l0_sstable.insert(0, id);
+        } else {
+            self.snapshot.levels.insert(0, (id, vec![id]));
+        }
+    }
+
+    /// Run compaction tasks until the controller has none left.
+    fn compact(&mut self) {
+        while let Some(task) =
+            self.controller.generate_compaction_task(&self.snapshot)
+        {
+            let input = match &task {
+                CompactionTask::Leveled(task) => task
+                    .upper_level_sst_ids
+                    .iter()
+                    .chain(&task.lower_level_sst_ids)
+                    .copied()
+                    .collect::<Vec<_>>(),
+                CompactionTask::Simple(task) => task
+                    .upper_level_sst_ids
+                    .iter()
+                    .chain(&task.lower_level_sst_ids)
+                    .copied()
+                    .collect(),
+                CompactionTask::Tiered(task) => task
+                    .tiers
+                    .iter()
+                    .flat_map(|(_, ids)| ids)
+                    .copied()
+                    .collect(),
+            };
+            let mut merged = input
+                .iter()
+                .flat_map(|id| &self.keys[id])
+                .copied()
+                .collect::<Vec<_>>();
+            merged.sort_unstable();
+            merged.dedup();
+            let output = merged
+                .chunks(self.sst_keys)
+                .map(|chunk| self.add_sst(chunk.to_vec()))
+                .collect::<Vec<_>>();
+            let (snapshot, removed) = self.controller.apply_compaction_result(
+                &self.snapshot,
+                &task,
+                &output,
+                false,
+            );
+            self.snapshot = snapshot;
+            for id in removed {
+                self.snapshot.sstables.remove(&id);
+                self.keys.remove(&id);
+            }
+            self.num_compactions += 1;
+        }
+    }
+
+    fn level_size(&self, ids: &[usize]) -> u64 {
+        ids.iter()
+            .map(|id| self.snapshot.sstables[id].table_size())
+            .sum()
+    }
+
+    fn dump(&self) {
+        let l0 = &self.snapshot.l0_sstable;
+        if !l0.is_empty() {
+            println!(
+                "L0  ({:>3} SSTs, {:>6} KiB): {:?}",
+                l0.len(),
+                self.level_size(l0) >> 10,
+                l0
+            );
+        }
+        let prefix = if self.controller.flush_to_l0() {
+            "L"
+        } else {
+            "T"
+        };
+        for (id, ids) in &self.snapshot.levels {
+            println!(
+                "{}{:<2} ({:>3} SSTs, {:>6} KiB): {:?}",
+                prefix,
+                id,
+                ids.len(),
+                self.level_size(ids) >> 10,
+                ids
+            );
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let (compaction_options, sim) = match cli.strategy {
+        Strategy::Leveled {
+            level0_file_num_compaction_trigger,
+            level_size_multiplier,
+            max_levels,
+            base_level_size_mb,
+            sim,
+        } => (
+            CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier,
+                level0_file_num_compaction_trigger,
+                max_levels,
+                base_level_size_mb,
+            }),
+            sim,
+        ),
+        Strategy::Tiered {
+            num_tiers,
+            max_size_amplification_percent,
+            size_ratio,
+            min_merge_width,
+            max_merge_width,
+            sim,
+        } => (
+            CompactionOptions::Tiered(TieredCompactionOptions {
+                num_tiers,
+                max_size_amplification_percent,
+                size_ratio,
+                min_merge_width,
+                max_merge_width,
+            }),
+            sim,
+        ),
+        Strategy::Simple {
+            size_ratio_percent,
+            level0_file_num_compaction_trigger,
+            max_levels,
+            sim,
+        } => (
+            CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent,
+                level0_file_num_compaction_trigger,
+                max_levels,
+            }),
+            sim,
+        ),
+    };
+
+    let mut storage = MockStorage::new(compaction_options, sim.sst_keys);
+    let mut rng = rand::thread_rng();
+    let mut max_sorted_runs = 0;
+    for i in 0..sim.iterations {
+        let keys = (0..sim.sst_keys)
+            .map(|_| rng.gen_range(0..sim.key_space))
+            .collect();
+        storage.flush(keys);
+        storage.compact();
+        let sorted_runs = storage.snapshot.l0_sstable.len()
+            + storage
+                .snapshot
+                .levels
+                .iter()
+                .filter(|(_, ids)| !ids.is_empty())
+                .count();
+        max_sorted_runs = max_sorted_runs.max(sorted_runs);
+        if sim.dump {
+            println!("=== after flush {} ===", i + 1);
+            storage.dump();
+        }
+    }
+
+    println!("=== final state ===");
+    storage.dump();
+    let live_bytes = storage
+        .snapshot
+        .sstables
+        .values()
+        .map(|sst| sst.table_size())
+        .sum::<u64>();
+    println!("flushed: {} KiB", storage.flushed_bytes >> 10);
+    println!("written: {} KiB", storage.written_bytes >> 10);
+    println!("live: {} KiB", live_bytes >> 10);
+    println!("compactions: {}", storage.num_compactions);
+    println!("max sorted runs: {}", max_sorted_runs);
+    println!(
+        "write amplification: {:.2}",
+        storage.written_bytes as f64 / storage.flushed_bytes as f64
+    );
+}
@@ -0,0 +1,155 @@
+//! Compares random-get throughput of the `FileBackend`s SSTs are read with.
+//! The tree is loaded and compacted once, then reopened with each backend.
+//! Buffered and mmap reads hit the page cache once the data was read, while
+//! direct reads only have the block cache to rely on.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Parser;
+use mini_lsm::{
+    compact::{CompactionOptions, LeveledCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm, SyncMode},
+    table::FileBackend,
+};
+use rand::Rng;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Number of keys loaded before reading
+    #[arg(long, default_value_t = 200_000)]
+    num_keys: usize,
+    #[arg(long, default_value_t = 100)]
+    value_size: usize,
+    /// Number of gets run with each backend, split between the threads
+    #[arg(long, default_value_t = 200_000)]
+    gets: usize,
+    #[arg(long, default_value_t = 4)]
+    threads: usize,
+    /// Maximum number of blocks held in the block cache
+    #[arg(long, default_value_t = 1 << 10)]
+    block_cache_capacity: u64,
+    /// Directory to create the benchmark database in
+    #[arg(long)]
+    dir: Option<PathBuf>,
+}
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{idx:010}").into_bytes()
+}
+
+fn options(args: &Args, file_backend: FileBackend) -> LsmStorageOptions {
+    LsmStorageOptions {
+        target_sst_size: 1 << 20,
+        block_cache_capacity: args.block_cache_capacity,
+        file_backend,
+        compaction_options: CompactionOptions::Leveled(
+            LeveledCompactionOptions {
+                level_size_multiplier: 4,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 4,
+                base_level_size_mb: 4,
+            },
+        ),
+        sync_mode: SyncMode::None,
+        // memtables are flushed on this thread once loaded
+        write_stall: None,
+        ..LsmStorageOptions::default()
+    }
+}
+
+/// Load the keys, then compact until no task is left.
+fn load(args: &Args, dir: &PathBuf) -> Result<()> {
+    let storage = MiniLsm::open(dir, &options(args, FileBackend::Buffered))?;
+    // compaction runs on this thread below
+    storage.close()?;
+    let mut rng = rand::thread_rng();
+    let mut value = vec![0; args.value_size];
+    for idx in 0..args.num_keys {
+        rng.fill(&mut value[..]);
+        storage.put(&key_of(idx), &value)?;
+    }
+    loop {
+        storage.force_flush()?;
+        let stats = storage.stats();
+        if stats.imm_memtables == 0 && stats.memtable_bytes == 0 {
+            break;
+        }
+    }
+    loop {
+        let compactions = storage.stats().compactions;
+        storage.force_compaction()?;
+        if storage.stats().compactions == compactions {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Run the gets of random keys on `threads` threads, returning the time
+/// they took.
+fn run(args: &Args, storage: &MiniLsm) -> Result<Duration> {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        let threads = (0..args.threads)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    let mut rng = rand::thread_rng();
+                    for _ in 0..args.gets / args.threads {
+                        let key = key_of(rng.gen_range(0..args.num_keys));
+                        assert!(storage.get(&key)?.is_some());
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .try_for_each(|thread| thread.join().unwrap())
+    })?;
+    Ok(start.elapsed())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let dir = args
+        .dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("mini-lsm-get-bench-{}", std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    load(&args, &dir)?;
+
+    println!(
+        "{} gets on {} threads over {} keys, block cache of {} blocks",
+        args.gets, args.threads, args.num_keys, args.block_cache_capacity
+    );
+    for backend in [
+        FileBackend::Buffered,
+        FileBackend::Mmap,
+        FileBackend::Direct,
+    ] {
+        let storage = MiniLsm::open(&dir, &options(&args, backend))?;
+        // fill the block cache before timing
+        run(&args, &storage)?;
+        let elapsed = run(&args, &storage)?;
+        let stats = storage.stats();
+        println!(
+            "{:<10} {:>10.0} gets/s {:>8.1} us/get {:>6.1}% block cache hits",
+            format!("{backend:?}"),
+            args.gets as f64 / elapsed.as_secs_f64(),
+            elapsed.as_micros() as f64 * args.threads as f64 / args.gets as f64,
+            stats.block_cache_hits as f64 * 100.0
+                / (stats.block_cache_hits + stats.block_cache_misses) as f64
+        );
+        storage.close()?;
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
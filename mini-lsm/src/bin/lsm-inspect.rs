@@ -0,0 +1,117 @@
+//! Dumps the files of a mini-lsm data directory without opening the engine,
+//! so that nothing on disk is modified: SSTs, WALs, the manifest, and the
+//! level layout the manifest replays to.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand, ValueEnum};
+use mini_lsm::{
+    compact::{
+        CompactionOptions, LeveledCompactionOptions,
+        SimpleLeveledCompactionOptions, TieredCompactionOptions,
+    },
+    debug::{dump_layout, dump_manifest, dump_value_log, dump_wal},
+    lsm_storage::LsmStorageOptions,
+    table::{FileObject, SsTable},
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the meta, filters and blocks of an SST
+    Sst {
+        path: PathBuf,
+        /// Also print every entry of every block
+        #[arg(long)]
+        entries: bool,
+    },
+    /// Print the write batches of a WAL
+    Wal { path: PathBuf },
+    /// Print the records of a manifest
+    Manifest { path: PathBuf },
+    /// Print the values of a value log segment
+    Vlog { path: PathBuf },
+    /// Print the SSTs of each level and the live WALs of a data directory
+    Layout {
+        dir: PathBuf,
+        /// The compaction strategy the directory was written with
+        #[arg(long, value_enum, default_value_t = Strategy::Leveled)]
+        compaction: Strategy,
+        /// The number of levels of leveled and simple compaction
+        #[arg(long, default_value_t = 6)]
+        max_levels: usize,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Strategy {
+    Leveled,
+    Tiered,
+    Simple,
+    None,
+}
+
+/// Options that replay the manifest like the engine does. Replaying only
+/// depends on the strategy and the number of levels.
+fn replay_options(strategy: Strategy, max_levels: usize) -> LsmStorageOptions {
+    let compaction_options = match strategy {
+        Strategy::Leveled => {
+            CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: 10,
+                level0_file_num_compaction_trigger: 4,
+                max_levels,
+                base_level_size_mb: 256,
+            })
+        }
+        Strategy::Tiered => {
+            CompactionOptions::Tiered(TieredCompactionOptions {
+                num_tiers: 8,
+                max_size_amplification_percent: 200,
+                size_ratio: 1,
+                min_merge_width: 2,
+                max_merge_width: None,
+            })
+        }
+        Strategy::Simple => {
+            CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels,
+            })
+        }
+        Strategy::None => CompactionOptions::NoCompaction,
+    };
+    LsmStorageOptions {
+        compaction_options,
+        ..LsmStorageOptions::default()
+    }
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Sst { path, entries } => {
+            // SSTs are named after their id
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse().ok())
+                .unwrap_or_default();
+            let sst = SsTable::open(id, None, FileObject::open(&path)?)?;
+            sst.dump(entries)
+        }
+        Command::Wal { path } => dump_wal(path),
+        Command::Manifest { path } => dump_manifest(path),
+        Command::Vlog { path } => dump_value_log(path),
+        Command::Layout {
+            dir,
+            compaction,
+            max_levels,
+        } => dump_layout(dir, &replay_options(compaction, max_levels)),
+    }
+}
@@ -0,0 +1,191 @@
+//! Compares short range scans of a leveled tree, which read each level one
+//! SST after another, with merging an iterator over every SST, as if all of
+//! them could overlap.
+
+use std::{
+    ops::Bound,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Parser;
+use mini_lsm::{
+    compact::{CompactionOptions, LeveledCompactionOptions},
+    iterators::{StorageIterator, merge_iterator::MergeIter},
+    key::{KeySlice, TS_RANGE_BEGIN},
+    lsm_storage::{BlockCache, LsmStorageOptions, MiniLsm, SyncMode},
+    table::{FileObject, SsTable, SsTableIterator},
+};
+use rand::Rng;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Number of keys loaded before scanning
+    #[arg(long, default_value_t = 200_000)]
+    num_keys: usize,
+    #[arg(long, default_value_t = 100)]
+    value_size: usize,
+    /// Size of the SSTs, small to get a deep tree from little data
+    #[arg(long, default_value_t = 256 << 10)]
+    target_sst_size: usize,
+    /// Number of scans run by each method
+    #[arg(long, default_value_t = 2000)]
+    scans: usize,
+    /// Number of entries read by each scan
+    #[arg(long, default_value_t = 100)]
+    scan_len: usize,
+    /// Directory to create the benchmark database in
+    #[arg(long)]
+    dir: Option<PathBuf>,
+}
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{idx:010}").into_bytes()
+}
+
+/// Load the keys in random order, then compact until no task is left.
+fn load(args: &Args, dir: &PathBuf) -> Result<Arc<MiniLsm>> {
+    let options = LsmStorageOptions {
+        target_sst_size: args.target_sst_size,
+        compaction_options: CompactionOptions::Leveled(
+            LeveledCompactionOptions {
+                level_size_multiplier: 4,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 4,
+                base_level_size_mb: 1,
+            },
+        ),
+        sync_mode: SyncMode::None,
+        // memtables are flushed on this thread once loaded
+        write_stall: None,
+        ..LsmStorageOptions::default()
+    };
+    let storage = MiniLsm::open(dir, &options)?;
+    // compaction runs on this thread below
+    storage.close()?;
+    let mut rng = rand::thread_rng();
+    let mut value = vec![0; args.value_size];
+    for _ in 0..args.num_keys {
+        // incompressible, so that SSTs are as many as their size suggests
+        rng.fill(&mut value[..]);
+        storage.put(&key_of(rng.gen_range(0..args.num_keys)), &value)?;
+    }
+    loop {
+        storage.force_flush()?;
+        let stats = storage.stats();
+        if stats.imm_memtables == 0 && stats.memtable_bytes == 0 {
+            break;
+        }
+    }
+    loop {
+        let compactions = storage.stats().compactions;
+        storage.force_compaction()?;
+        if storage.stats().compactions == compactions {
+            break;
+        }
+    }
+    Ok(storage)
+}
+
+/// Run `scans` scans from random keys, returning the time they took.
+fn run(
+    args: &Args,
+    mut scan: impl FnMut(&[u8]) -> Result<usize>,
+) -> Result<Duration> {
+    let mut rng = rand::thread_rng();
+    let start = Instant::now();
+    for _ in 0..args.scans {
+        let key = key_of(rng.gen_range(0..args.num_keys));
+        let read = scan(&key)?;
+        assert!(read <= args.scan_len);
+    }
+    Ok(start.elapsed())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let dir = args
+        .dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("mini-lsm-scan-bench-{}", std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    let storage = load(&args, &dir)?;
+    let stats = storage.stats();
+    for level in stats.levels.iter().filter(|level| level.num_ssts > 0) {
+        println!(
+            "L{}: {} SSTs, {} KiB",
+            level.level,
+            level.num_ssts,
+            level.bytes >> 10
+        );
+    }
+
+    let concat = run(&args, |key| {
+        let mut iter = storage.scan(Bound::Included(key), Bound::Unbounded)?;
+        let mut read = 0;
+        while iter.is_valid() && read < args.scan_len {
+            iter.next()?;
+            read += 1;
+        }
+        Ok(read)
+    })?;
+
+    // every live SST, each seeked on its own
+    let block_cache = Arc::new(BlockCache::new(1 << 16));
+    let mut tables = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "sst") {
+            let id = path.file_stem().unwrap().to_str().unwrap().parse()?;
+            tables.push(Arc::new(SsTable::open(
+                id,
+                Some(block_cache.clone()),
+                FileObject::open(&path)?,
+            )?));
+        }
+    }
+    let merge = run(&args, |key| {
+        let mut iters = Vec::with_capacity(tables.len());
+        for table in &tables {
+            iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
+                table.clone(),
+                KeySlice::from_slice(key, TS_RANGE_BEGIN),
+            )?));
+        }
+        let mut iter = MergeIter::create(iters);
+        let mut read = 0;
+        while iter.is_valid() && read < args.scan_len {
+            iter.next()?;
+            read += 1;
+        }
+        Ok(read)
+    })?;
+
+    println!(
+        "{} scans of {} entries over {} SSTs",
+        args.scans,
+        args.scan_len,
+        tables.len()
+    );
+    for (name, elapsed) in [("concat", concat), ("merge-all", merge)] {
+        println!(
+            "{:<10} {:>10.0} scans/s {:>10.1} us/scan",
+            name,
+            args.scans as f64 / elapsed.as_secs_f64(),
+            elapsed.as_micros() as f64 / args.scans as f64
+        );
+    }
+    println!(
+        "speedup    {:>10.2}x",
+        merge.as_secs_f64() / concat.as_secs_f64()
+    );
+    drop(storage);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Compares the write throughput of the WAL sync modes, with concurrent
+//! writers sharing fsyncs through group commit.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use mini_lsm::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, SyncMode},
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Number of concurrent writer threads
+    #[arg(long, default_value_t = 8)]
+    threads: usize,
+    /// Number of puts issued by each thread
+    #[arg(long, default_value_t = 2000)]
+    writes_per_thread: usize,
+    #[arg(long, default_value_t = 128)]
+    value_size: usize,
+    /// Interval of the background sync in the interval mode
+    #[arg(long, default_value_t = 10)]
+    interval_ms: u64,
+    /// Directory to create the benchmark databases in
+    #[arg(long)]
+    dir: Option<PathBuf>,
+}
+
+fn run(args: &Args, sync_mode: SyncMode) -> anyhow::Result<Duration> {
+    let dir = args
+        .dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("mini-lsm-wal-bench-{}", std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        sync_mode,
+        ..LsmStorageOptions::default()
+    };
+    let storage = MiniLsm::open(&dir, &options)?;
+    let value = vec![b'x'; args.value_size];
+    let start = Instant::now();
+    let writers = (0..args.threads)
+        .map(|thread| {
+            let storage = Arc::clone(&storage);
+            let value = value.clone();
+            let writes = args.writes_per_thread;
+            std::thread::spawn(move || -> anyhow::Result<()> {
+                for idx in 0..writes {
+                    let key = format!("key_{thread:03}_{idx:010}");
+                    storage.put(key.as_bytes(), &value)?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().expect("writer panicked")?;
+    }
+    let elapsed = start.elapsed();
+    storage.close()?;
+    drop(storage);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(elapsed)
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let total_writes = (args.threads * args.writes_per_thread) as f64;
+    let modes = [
+        ("none", SyncMode::None),
+        (
+            "interval",
+            SyncMode::Interval(Duration::from_millis(args.interval_ms)),
+        ),
+        ("every-write", SyncMode::EveryWrite),
+    ];
+    println!(
+        "{} threads x {} puts of {} bytes",
+        args.threads, args.writes_per_thread, args.value_size
+    );
+    for (name, sync_mode) in modes {
+        let elapsed = run(&args, sync_mode)?;
+        println!(
+            "{:<12} {:>10.0} ops/s {:>10.1} us/op",
+            name,
+            total_writes / elapsed.as_secs_f64(),
+            elapsed.as_micros() as f64 * args.threads as f64 / total_writes
+        );
+    }
+    Ok(())
+}
@@ -2,49 +2,67 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-//! keys
-//! (overlap, key_len, key, ts, value_len, value)
+//! entries
+//! (overlap, rest_len, rest, ts, value_len, value)
+//! `overlap` is the length of the prefix shared with the previous key, and
+//! always 0 at a restart point.
+//!
+//! block
+//! (entries, restart offsets, num_restarts)
 
 use bytes::BufMut;
 
 use crate::key::{KeySlice, KeyVec};
 
-use super::{Block, SIZEOF_U16};
+use super::{Block, RESTART_INTERVAL, SIZEOF_U16};
 
 pub struct BlockBuilder {
-    offsets: Vec<u16>,
+    restarts: Vec<u16>,
     data: Vec<u8>,
     block_size: usize,
-    first_key: KeyVec,
+    num_entries: usize,
+    last_key: KeyVec,
 }
 
 impl BlockBuilder {
     pub fn new(block_size: usize) -> Self {
         Self {
-            offsets: Vec::new(),
+            restarts: Vec::new(),
             data: Vec::new(),
             block_size,
-            first_key: KeyVec::new(),
+            num_entries: 0,
+            last_key: KeyVec::new(),
         }
     }
     #[must_use]
     /// `false` means no space
     pub fn add(&mut self, key: KeySlice, value: &[u8]) -> bool {
         assert!(!key.is_empty(), "key must not be empty");
+        let is_restart = self.num_entries.is_multiple_of(RESTART_INTERVAL);
+        let overlap = if is_restart {
+            0
+        } else {
+            compute_overlap(self.last_key.as_key_slice(), key)
+        };
+        let entry_size = SIZEOF_U16 * 3 /* overlap, rest_len and value_len */
+            + key.raw_len()
+            - overlap
+            + value.len()
+            + if is_restart { SIZEOF_U16 } else { 0 };
         // full
-        if self.estimated_size() + key.raw_len() + value.len() + SIZEOF_U16 * 3 /* key_len, value_len and offset */ > self.block_size
+        if self.estimated_size() + entry_size > self.block_size
             && !self.is_empty()
         {
             return false;
         }
-        // Add the offset of the data into the offset array.
-        self.offsets.push(self.data.len() as u16);
-        let overlap = compute_overlap(self.first_key.as_key_slice(), key);
+        if is_restart {
+            self.restarts.push(self.data.len() as u16);
+        }
         // Encode key overlap.
         self.data.put_u16(overlap as u16);
-        // Encode key length.
+        // Encode the length of the rest of the key.
         self.data.put_u16((key.key_len() - overlap) as u16);
-        // Encode key content.
+        // Encode the rest of the key.
         self.data.put(&key.key_ref()[overlap..]);
         // Encode key ts
         self.data.put_u64(key.ts());
@@ -53,42 +71,35 @@ impl BlockBuilder {
         // Encode value content.
         self.data.put(value);
 
-        if self.first_key.is_empty() {
-            self.first_key = key.to_key_vec();
-        }
-
+        self.last_key.set_from_slice(key);
+        self.num_entries += 1;
         true
     }
     fn estimated_size(&self) -> usize {
-        /* number of key-value pairs in the block */
-        SIZEOF_U16  +
-          self.offsets.len() * SIZEOF_U16 /* offsets */
-           + self.data.len() // key-value pairs
+        /* number of restart points in the block */
+        SIZEOF_U16
+            + self.restarts.len() * SIZEOF_U16 /* restart offsets */
+            + self.data.len() // key-value pairs
     }
     pub fn is_empty(&self) -> bool {
-        self.offsets.is_empty()
+        self.num_entries == 0
     }
     pub fn build(self) -> Block {
         if self.is_empty() {
             panic!("block should not be empty");
         }
         Block {
-            data: self.data,
-            offsets: self.offsets,
+            data: self.data.into(),
+            restarts: self.restarts,
         }
     }
 }
 
-fn compute_overlap(first_key: KeySlice, key: KeySlice) -> usize {
-    let mut i = 0;
-    loop {
-        if i >= first_key.key_len() || i >= key.key_len() {
-            break;
-        }
-        if first_key.key_ref()[i] != key.key_ref()[i] {
-            break;
-        }
-        i += 1;
-    }
-    i
+fn compute_overlap(prev_key: KeySlice, key: KeySlice) -> usize {
+    prev_key
+        .key_ref()
+        .iter()
+        .zip(key.key_ref())
+        .take_while(|(a, b)| a == b)
+        .count()
 }
@@ -16,20 +16,20 @@ pub struct BlockIterator {
     key: KeyVec,
     /// the current value range in the block.data, corresponds to the current key
     value_range: (usize, usize),
-    /// the current index at the iterator position
-    idx: usize,
-    /// the first key in the block
-    first_key: KeyVec,
+    /// the offset of the current entry
+    offset: usize,
+    /// the offset of the entry after the current one
+    next_offset: usize,
 }
 
 impl BlockIterator {
     fn new(block: Arc<Block>) -> Self {
         Self {
-            first_key: block.get_first_key(),
             block,
             key: KeyVec::new(),
             value_range: (0, 0),
-            idx: 0,
+            offset: 0,
+            next_offset: 0,
         }
     }
     /// Creates a block iterator and seek to the first entry.
@@ -45,8 +45,22 @@ impl BlockIterator {
         iter.seek_to_key(key);
         iter
     }
+
+    /// Creates a block iterator and seek to the last key that <= `key`.
+    pub fn create_and_seek_for_prev(block: Arc<Block>, key: KeySlice) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_for_prev(key);
+        iter
+    }
+
+    /// Creates a block iterator and seek to the last entry.
+    pub fn create_and_seek_to_last(block: Arc<Block>) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_last();
+        iter
+    }
     /// Returns the key of the current entry.
-    pub fn key(&self) -> KeySlice {
+    pub fn key(&self) -> KeySlice<'_> {
         debug_assert!(!self.key.is_empty(), "invalid iterator");
         self.key.as_key_slice()
     }
@@ -61,38 +75,72 @@ impl BlockIterator {
     }
     /// Seeks to the first key in the block.
     pub fn seek_to_first(&mut self) {
-        self.seek_to(0);
-    }
-    /// Seeks to the idx-th key in the block.
-    fn seek_to(&mut self, idx: usize) {
-        // None
-        if idx >= self.block.offsets.len() {
-            self.key.clear();
-            self.value_range = (0, 0);
+        self.seek_to_restart(0);
+    }
+    /// Seeks to the last key in the block.
+    pub fn seek_to_last(&mut self) {
+        let Some(restart) = self.block.restarts.len().checked_sub(1) else {
+            self.invalidate();
             return;
+        };
+        self.seek_to_restart(restart);
+        while self.next_offset < self.block.data.len() {
+            self.next();
+        }
+    }
+    /// Seeks to the idx-th restart point in the block.
+    fn seek_to_restart(&mut self, idx: usize) {
+        match self.block.restarts.get(idx) {
+            Some(&offset) => {
+                self.key.clear();
+                self.seek_to_offset(offset as usize);
+            }
+            None => self.invalidate(),
         }
-        let offset = self.block.offsets[idx] as usize;
-        self.seek_to_offset(offset);
-        self.idx = idx;
+    }
+    fn invalidate(&mut self) {
+        self.key.clear();
+        self.value_range = (0, 0);
+        self.offset = self.block.data.len();
+        self.next_offset = self.block.data.len();
     }
     /// Move to the next key in the block.
     pub fn next(&mut self) {
-        self.idx += 1;
-        self.seek_to(self.idx);
+        if self.next_offset >= self.block.data.len() {
+            self.invalidate();
+        } else {
+            self.seek_to_offset(self.next_offset);
+        }
     }
-    /// Seek to the specified position and update the current `key` and `value`
-    /// Index update will be handled by caller
+    /// Move to the previous key in the block. Keys are prefix-compressed, so
+    /// it is decoded from the restart point before it.
+    pub fn prev(&mut self) {
+        let offset = self.offset;
+        if offset == 0 {
+            self.invalidate();
+            return;
+        }
+        let restart = self
+            .block
+            .restarts
+            .partition_point(|&restart| (restart as usize) < offset)
+            - 1;
+        self.seek_to_restart(restart);
+        while self.next_offset < offset {
+            self.next();
+        }
+    }
+    /// Decode the entry at `offset` and update the current `key` and `value`.
+    /// `key` must hold the previous key unless `offset` is a restart point.
     fn seek_to_offset(&mut self, offset: usize) {
         let mut entry = &self.block.data[offset..];
         // Since `get_u16()` will automatically move the ptr 2 bytes ahead here,
         // we don't need to manually advance it
         let overlap_len = entry.get_u16() as usize;
-        let key_len = entry.get_u16() as usize;
-        let key = &entry[..key_len];
-        self.key.clear();
-        self.key.append(&self.first_key.key_ref()[..overlap_len]);
-        self.key.append(key);
-        entry.advance(key_len);
+        let rest_len = entry.get_u16() as usize;
+        self.key.truncate(overlap_len);
+        self.key.append(&entry[..rest_len]);
+        entry.advance(rest_len);
         let ts = entry.get_u64();
         self.key.set_ts(ts);
         let value_len = entry.get_u16() as usize;
@@ -100,41 +148,48 @@ impl BlockIterator {
         let value_offset_begin = offset
             + SIZEOF_U16
             + SIZEOF_U16
+            + rest_len
             + std::mem::size_of::<u64>()
-            + key_len
             + SIZEOF_U16;
         let value_offset_end = value_offset_begin + value_len;
         self.value_range = (value_offset_begin, value_offset_end);
-        entry.advance(value_len);
+        self.offset = offset;
+        self.next_offset = value_offset_end;
     }
     /// Seek to the first key that is >= 'key'.
     pub fn seek_to_key(&mut self, key: KeySlice) {
-        let mut left = 0;
-        let mut right = self.block.offsets.len();
-        while left < right {
-            let mid = left + (right - left) / 2;
-            self.seek_to(mid);
-            assert!(self.is_valid());
-            match self.key().cmp(&key) {
-                std::cmp::Ordering::Less => {
-                    left = mid + 1;
-                }
-                std::cmp::Ordering::Equal => return,
-                std::cmp::Ordering::Greater => right = mid,
-            }
+        // the restart points store full keys, so binary search over them for
+        // the last one not after `key`, then scan forward from there
+        let restart = self
+            .block
+            .restarts
+            .partition_point(|&offset| self.block.key_at(offset) <= key)
+            .saturating_sub(1);
+        self.seek_to_restart(restart);
+        while self.is_valid() && self.key() < key {
+            self.next();
+        }
+    }
+    /// Seek to the last key that is <= `key`.
+    pub fn seek_for_prev(&mut self, key: KeySlice) {
+        self.seek_to_key(key);
+        if !self.is_valid() {
+            self.seek_to_last();
+        } else if self.key() > key {
+            self.prev();
         }
-        self.seek_to(left);
     }
 }
 
 impl Block {
-    fn get_first_key(&self) -> KeyVec {
-        let mut buf = &self.data[..];
-        // first key overlap is always 0
+    /// The key of the restart entry at `offset`, which is stored in full.
+    fn key_at(&self, offset: u16) -> KeySlice<'_> {
+        let mut buf = &self.data[offset as usize..];
+        // the overlap of a restart entry is always 0
         buf.get_u16();
         let key_len = buf.get_u16() as usize;
         let key = &buf[..key_len];
         buf.advance(key_len);
-        KeyVec::from_vec_with_ts(key.to_vec(), buf.get_u64())
+        KeySlice::from_slice(key, buf.get_u64())
     }
 }
@@ -6,40 +6,47 @@ use bytes::{Buf, BufMut, Bytes};
 
 pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
 
+/// Every `RESTART_INTERVAL`-th entry of a block stores its full key, the
+/// others only the suffix that differs from the previous key.
+pub(crate) const RESTART_INTERVAL: usize = 16;
+
 mod builder;
 pub use builder::BlockBuilder;
 mod iterator;
+pub use iterator::BlockIterator;
 
 /// A block is the smallest unit of read and caching in LSM tree.
 /// It is a collection of sorted key-value pairs.
 pub struct Block {
-    pub(crate) data: Vec<u8>,
-    pub(crate) offsets: Vec<u16>,
+    pub(crate) data: Bytes,
+    /// Offsets of the entries that store a full key
+    pub(crate) restarts: Vec<u16>,
 }
 
 impl Block {
     pub fn encode(&self) -> Bytes {
-        let mut buf = self.data.clone();
-        let offsets_len = self.offsets.len();
-        for offset in &self.offsets {
-            buf.put_u16(*offset);
+        let mut buf = self.data.to_vec();
+        let restarts_len = self.restarts.len();
+        for restart in &self.restarts {
+            buf.put_u16(*restart);
         }
-        buf.put_u16(offsets_len as u16);
+        buf.put_u16(restarts_len as u16);
         buf.into()
     }
-    pub fn decode(data: &[u8]) -> Self {
-        // get number of elements in the block
-        let entry_offsets_len =
+    /// Decode a block, sharing the memory of `data`.
+    pub fn decode(data: &Bytes) -> Self {
+        // get number of restart points in the block
+        let restarts_len =
             (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
-        let data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
-        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16];
-        // get offset array
-        let offsets = offsets_raw
+        let data_end = data.len() - SIZEOF_U16 - restarts_len * SIZEOF_U16;
+        let restarts_raw = &data[data_end..data.len() - SIZEOF_U16];
+        // get restart array
+        let restarts = restarts_raw
             .chunks(SIZEOF_U16)
             .map(|mut x| x.get_u16())
             .collect();
         // retrieve data
-        let data = data[0..data_end].to_vec();
-        Self { data, offsets }
+        let data = data.slice(0..data_end);
+        Self { data, restarts }
     }
 }
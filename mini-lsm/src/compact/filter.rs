@@ -0,0 +1,42 @@
+use std::{fmt, sync::Arc};
+
+use bytes::Bytes;
+
+type FilterFn = dyn Fn(&[u8], &[u8]) -> bool + Send + Sync;
+
+/// Removes entries during compaction. Only the newest version every reader
+/// can see is checked, and a match deletes the key as a tombstone would.
+#[derive(Clone)]
+pub enum CompactionFilter {
+    /// Removes the keys starting with the prefix
+    Prefix(Bytes),
+    /// Removes the entries for which the closure, given the key and the
+    /// value, returns true
+    Custom(Arc<FilterFn>),
+}
+
+impl CompactionFilter {
+    pub fn custom(
+        filter: impl Fn(&[u8], &[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        CompactionFilter::Custom(Arc::new(filter))
+    }
+
+    pub(crate) fn matches(&self, key: &[u8], value: &[u8]) -> bool {
+        match self {
+            CompactionFilter::Prefix(prefix) => key.starts_with(prefix),
+            CompactionFilter::Custom(filter) => filter(key, value),
+        }
+    }
+}
+
+impl fmt::Debug for CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactionFilter::Prefix(prefix) => {
+                f.debug_tuple("Prefix").field(prefix).finish()
+            }
+            CompactionFilter::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
@@ -1 +1,216 @@
-pub struct LeveledCompactionTask {}
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeveledCompactionTask {
+    /// `None` means an L0 compaction
+    pub upper_level: Option<usize>,
+    pub upper_level_sst_ids: Vec<usize>,
+    pub lower_level: usize,
+    pub lower_level_sst_ids: Vec<usize>,
+    pub is_lower_level_bottom_level: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeveledCompactionOptions {
+    /// Each level is this many times larger than the level above it
+    pub level_size_multiplier: usize,
+    /// Compact L0 into the base level once it holds this many SSTs
+    pub level0_file_num_compaction_trigger: usize,
+    pub max_levels: usize,
+    /// Target size of the first non-empty level in MB
+    pub base_level_size_mb: usize,
+}
+
+pub struct LeveledCompactionController {
+    options: LeveledCompactionOptions,
+}
+
+impl LeveledCompactionController {
+    pub fn new(options: LeveledCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    /// Find the SSTs of `in_level` overlapping with the key range of `sst_ids`.
+    fn find_overlapping_ssts(
+        &self,
+        snapshot: &LsmStorageState,
+        sst_ids: &[usize],
+        in_level: usize,
+    ) -> Vec<usize> {
+        let begin_key = sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].first_key())
+            .min()
+            .cloned()
+            .unwrap();
+        let end_key = sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].last_key())
+            .max()
+            .cloned()
+            .unwrap();
+        snapshot.levels[in_level - 1]
+            .1
+            .iter()
+            .filter(|id| {
+                let sst = &snapshot.sstables[*id];
+                !(sst.last_key().key_ref() < begin_key.key_ref()
+                    || sst.first_key().key_ref() > end_key.key_ref())
+            })
+            .copied()
+            .collect()
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<LeveledCompactionTask> {
+        let max_levels = self.options.max_levels;
+        let mut target_level_size = vec![0; max_levels];
+        let real_level_size = snapshot
+            .levels
+            .iter()
+            .map(|(_, ids)| {
+                ids.iter()
+                    .map(|id| snapshot.sstables[id].table_size() as usize)
+                    .sum::<usize>()
+            })
+            .collect::<Vec<_>>();
+        let base_level_size_bytes = self.options.base_level_size_mb << 20;
+
+        // The bottom level sets the targets of the levels above it, and
+        // L0 goes to the first level with a non-zero target.
+        target_level_size[max_levels - 1] =
+            real_level_size[max_levels - 1].max(base_level_size_bytes);
+        let mut base_level = max_levels;
+        for i in (0..max_levels - 1).rev() {
+            let next_level_size = target_level_size[i + 1];
+            let this_level_size =
+                next_level_size / self.options.level_size_multiplier;
+            if next_level_size > base_level_size_bytes {
+                target_level_size[i] = this_level_size;
+            }
+            if target_level_size[i] > 0 {
+                base_level = i + 1;
+            }
+        }
+
+        if snapshot.l0_sstable.len()
+            >= self.options.level0_file_num_compaction_trigger
+        {
+            tracing::debug!("flush L0 SST to base level {}", base_level);
+            return Some(LeveledCompactionTask {
+                upper_level: None,
+                upper_level_sst_ids: snapshot.l0_sstable.clone(),
+                lower_level: base_level,
+                lower_level_sst_ids: self.find_overlapping_ssts(
+                    snapshot,
+                    &snapshot.l0_sstable,
+                    base_level,
+                ),
+                is_lower_level_bottom_level: base_level == max_levels,
+            });
+        }
+
+        let mut priorities = Vec::with_capacity(max_levels);
+        for level in 0..max_levels {
+            let prio =
+                real_level_size[level] as f64 / target_level_size[level] as f64;
+            if prio > 1.0 {
+                priorities.push((prio, level + 1));
+            }
+        }
+        priorities.sort_by(|a, b| a.partial_cmp(b).unwrap().reverse());
+        let (_, level) = priorities.first()?;
+        let level = *level;
+        // the oldest SST of the level goes first
+        let selected_sst =
+            snapshot.levels[level - 1].1.iter().min().copied().unwrap();
+        tracing::debug!(
+            "compaction triggered by priority: L{} SST {} to L{}",
+            level,
+            selected_sst,
+            level + 1
+        );
+        Some(LeveledCompactionTask {
+            upper_level: Some(level),
+            upper_level_sst_ids: vec![selected_sst],
+            lower_level: level + 1,
+            lower_level_sst_ids: self.find_overlapping_ssts(
+                snapshot,
+                &[selected_sst],
+                level + 1,
+            ),
+            is_lower_level_bottom_level: level + 1 == max_levels,
+        })
+    }
+
+    /// Apply the compaction result to a snapshot, returning the new state
+    /// and the SSTs to remove. During recovery the SST objects are not
+    /// loaded yet, so the lower level is not sorted here.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &LeveledCompactionTask,
+        output: &[usize],
+        in_recovery: bool,
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let mut files_to_remove = Vec::new();
+        let mut upper_level_sst_ids_set = task
+            .upper_level_sst_ids
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let mut lower_level_sst_ids_set = task
+            .lower_level_sst_ids
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        if let Some(upper_level) = task.upper_level {
+            let new_upper_level_ssts = snapshot.levels[upper_level - 1]
+                .1
+                .iter()
+                .filter(|x| !upper_level_sst_ids_set.remove(x))
+                .copied()
+                .collect::<Vec<_>>();
+            assert!(upper_level_sst_ids_set.is_empty());
+            snapshot.levels[upper_level - 1].1 = new_upper_level_ssts;
+        } else {
+            // SSTs flushed since the task was generated stay in L0
+            let new_l0_ssts = snapshot
+                .l0_sstable
+                .iter()
+                .filter(|x| !upper_level_sst_ids_set.remove(x))
+                .copied()
+                .collect::<Vec<_>>();
+            assert!(upper_level_sst_ids_set.is_empty());
+            snapshot.l0_sstable = new_l0_ssts;
+        }
+
+        files_to_remove.extend(&task.upper_level_sst_ids);
+        files_to_remove.extend(&task.lower_level_sst_ids);
+
+        let mut new_lower_level_ssts = snapshot.levels[task.lower_level - 1]
+            .1
+            .iter()
+            .filter(|x| !lower_level_sst_ids_set.remove(x))
+            .copied()
+            .collect::<Vec<_>>();
+        assert!(lower_level_sst_ids_set.is_empty());
+        new_lower_level_ssts.extend(output);
+        if !in_recovery {
+            new_lower_level_ssts.sort_by(|x, y| {
+                snapshot.sstables[x]
+                    .first_key()
+                    .cmp(snapshot.sstables[y].first_key())
+            });
+        }
+        snapshot.levels[task.lower_level - 1].1 = new_lower_level_ssts;
+        (snapshot, files_to_remove)
+    }
+}
@@ -2,7 +2,552 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-
+mod filter;
 mod leveled;
+mod simple_leveled;
+mod tiered;
+
+use std::{
+    sync::{Arc, atomic::Ordering},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+pub use filter::CompactionFilter;
+pub use leveled::{
+    LeveledCompactionController, LeveledCompactionOptions,
+    LeveledCompactionTask,
+};
+use serde::{Deserialize, Serialize};
+pub use simple_leveled::{
+    SimpleLeveledCompactionController, SimpleLeveledCompactionOptions,
+    SimpleLeveledCompactionTask,
+};
+pub use tiered::{
+    TieredCompactionController, TieredCompactionOptions, TieredCompactionTask,
+};
+
+use crate::{
+    iterators::{
+        StorageIterator, concat_iterator::SstConcatIterator,
+        merge_iterator::MergeIter, two_merge_iterator::TwoMergeIterator,
+    },
+    key::KeySlice,
+    lsm_storage::{ColumnFamily, LsmStorageInner, LsmStorageState},
+    manifest::ManifestRecord,
+    range_tombstone::{RangeTombstone, RangeTombstoneFragments},
+    table::{SsTable, SsTableBuilder, SsTableIterator},
+    value::{StoredValue, now_ms},
+    vlog::{ValueLogSnapshot, ValuePointer},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CompactionTask {
+    Leveled(LeveledCompactionTask),
+    Tiered(TieredCompactionTask),
+    Simple(SimpleLeveledCompactionTask),
+}
+
+impl CompactionTask {
+    fn compact_to_bottom_level(&self) -> bool {
+        match self {
+            CompactionTask::Leveled(task) => task.is_lower_level_bottom_level,
+            CompactionTask::Tiered(task) => task.bottom_tier_included,
+            CompactionTask::Simple(task) => task.is_lower_level_bottom_level,
+        }
+    }
+
+    fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            })
+            | CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            }) => {
+                [upper_level_sst_ids.as_slice(), lower_level_sst_ids].concat()
+            }
+            CompactionTask::Tiered(task) => task
+                .tiers
+                .iter()
+                .flat_map(|(_, sst_ids)| sst_ids.iter().copied())
+                .collect(),
+        }
+    }
+
+    /// The level whose options the output SSTs are built with. Tiers are not
+    /// levels: a merge into the bottom tier counts as the last level, any
+    /// other merge as L1.
+    fn output_level(&self) -> usize {
+        match self {
+            CompactionTask::Leveled(task) => task.lower_level,
+            CompactionTask::Simple(task) => task.lower_level,
+            CompactionTask::Tiered(task) if task.bottom_tier_included => {
+                usize::MAX
+            }
+            CompactionTask::Tiered(_) => 1,
+        }
+    }
+}
+
+pub enum CompactionController {
+    Leveled(LeveledCompactionController),
+    Tiered(TieredCompactionController),
+    Simple(SimpleLeveledCompactionController),
+    NoCompaction,
+}
+
+impl CompactionController {
+    pub fn new(options: &CompactionOptions) -> Self {
+        match options {
+            CompactionOptions::Leveled(options) => {
+                Self::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                Self::Tiered(TieredCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Simple(options) => Self::Simple(
+                SimpleLeveledCompactionController::new(options.clone()),
+            ),
+            CompactionOptions::NoCompaction => Self::NoCompaction,
+        }
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<CompactionTask> {
+        match self {
+            Self::Leveled(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Leveled),
+            Self::Tiered(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Tiered),
+            Self::Simple(ctrl) => ctrl
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Simple),
+            Self::NoCompaction => None,
+        }
+    }
+
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+        output: &[usize],
+        in_recovery: bool,
+    ) -> (LsmStorageState, Vec<usize>) {
+        match (self, task) {
+            (Self::Leveled(ctrl), CompactionTask::Leveled(task)) => ctrl
+                .apply_compaction_result(snapshot, task, output, in_recovery),
+            (Self::Tiered(ctrl), CompactionTask::Tiered(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
+            (Self::Simple(ctrl), CompactionTask::Simple(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
+            _ => unreachable!("compaction task does not match the controller"),
+        }
+    }
+
+    /// Whether flushed memtables are placed in L0. Tiered compaction puts
+    /// each of them in a new tier instead.
+    pub fn flush_to_l0(&self) -> bool {
+        !matches!(self, Self::Tiered(_))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CompactionOptions {
+    /// Leveled compaction with partial compaction and dynamic level sizes
+    Leveled(LeveledCompactionOptions),
+    /// Tiered compaction (= RocksDB's Universal Compaction)
+    Tiered(TieredCompactionOptions),
+    /// Simple leveled compaction, merging whole levels at a time
+    Simple(SimpleLeveledCompactionOptions),
+    /// All flushed SSTs stay in L0
+    NoCompaction,
+}
+
+impl CompactionOptions {
+    /// The number of levels below L0. Tiers are created as SSTs are flushed.
+    pub(crate) fn num_levels(&self) -> usize {
+        match self {
+            CompactionOptions::Leveled(options) => options.max_levels,
+            CompactionOptions::Simple(options) => options.max_levels,
+            CompactionOptions::Tiered(_) | CompactionOptions::NoCompaction => 0,
+        }
+    }
+}
+
+impl LsmStorageInner {
+    /// Write the merged output of `iter` into SSTs of about
+    /// `target_sst_size`, dropping the versions no reader can see. Expired
+    /// and filtered values every reader can see become tombstones.
+    /// `range_tombstones` are those of the input SSTs: the versions every
+    /// reader sees deleted by them are dropped, and they are split between
+    /// the output SSTs by key, except at the bottom level once every reader
+    /// sees them.
+    fn compact_generate_sst_from_iter(
+        &self,
+        family: &ColumnFamily,
+        mut iter: impl 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
+        compact_to_bottom_level: bool,
+        output_level: usize,
+        range_tombstones: Vec<RangeTombstone>,
+        value_log: &ValueLogSnapshot,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let watermark = self.mvcc().gc_watermark();
+        let settled_range_tombstones = RangeTombstoneFragments::new(
+            range_tombstones
+                .iter()
+                .filter(|tombstone| tombstone.ts <= watermark),
+        );
+        let range_tombstones = range_tombstones
+            .into_iter()
+            .filter(|tombstone| {
+                !compact_to_bottom_level || tombstone.ts > watermark
+            })
+            .collect::<Vec<_>>();
+        // the output SST being built holds the keys from this one on
+        let mut sst_lower_key: Option<Bytes> = None;
+        let now = now_ms();
+        let filters = self.compaction_filters.lock().clone();
+        let mut builder: Option<SsTableBuilder> = None;
+        let mut new_ssts = Vec::new();
+        let mut last_key = Vec::<u8>::new();
+        let mut first_key_below_watermark = false;
+        while iter.is_valid() {
+            let same_as_last_key = iter.key().key_ref() == last_key;
+            // a removed value must still shadow the older versions below
+            let mut tombstone = false;
+            if !same_as_last_key {
+                first_key_below_watermark = true;
+            }
+            if iter.key().ts() <= watermark {
+                if same_as_last_key && !first_key_below_watermark {
+                    // shadowed by a newer version every reader can see
+                    iter.next()?;
+                    continue;
+                }
+                first_key_below_watermark = false;
+                // the range tombstone, kept or not, hides the older versions
+                if settled_range_tombstones
+                    .covers(iter.key().key_ref(), iter.key().ts())
+                {
+                    if !same_as_last_key {
+                        last_key.clear();
+                        last_key.extend(iter.key().key_ref());
+                    }
+                    iter.next()?;
+                    continue;
+                }
+                let removed = match StoredValue::decode(iter.value())? {
+                    None => true,
+                    Some(value) if value.is_expired(now) => true,
+                    Some(value) if filters.is_empty() => false,
+                    Some(value) if value.separated => {
+                        let pointer = ValuePointer::decode(value.value)?;
+                        // without the segment, garbage collection already
+                        // copied this version above, where it is filtered
+                        value_log.contains(pointer.segment) && {
+                            let user_value = value_log.read(&pointer)?;
+                            filters.iter().any(|filter| {
+                                filter
+                                    .matches(iter.key().key_ref(), &user_value)
+                            })
+                        }
+                    }
+                    Some(value) => filters.iter().any(|filter| {
+                        filter.matches(iter.key().key_ref(), value.value)
+                    }),
+                };
+                // nothing older lies below the bottom level
+                if removed && compact_to_bottom_level {
+                    if !same_as_last_key {
+                        last_key.clear();
+                        last_key.extend(iter.key().key_ref());
+                    }
+                    iter.next()?;
+                    continue;
+                }
+                tombstone = removed;
+            }
+
+            // all versions of a key go to the same SST
+            if !same_as_last_key
+                && let Some(mut full) = builder.take_if(|builder| {
+                    builder.estimated_size() >= family.options.target_sst_size
+                })
+            {
+                let sst_upper_key =
+                    Bytes::copy_from_slice(iter.key().key_ref());
+                add_range_tombstones_within(
+                    &mut full,
+                    &range_tombstones,
+                    sst_lower_key.as_deref(),
+                    Some(&sst_upper_key),
+                );
+                sst_lower_key = Some(sst_upper_key);
+                new_ssts.push(self.build_compacted_sst(full)?);
+            }
+            builder
+                .get_or_insert_with(|| family.options.sst_builder(output_level))
+                .add(iter.key(), if tombstone { &[] } else { iter.value() });
+            if !same_as_last_key {
+                last_key.clear();
+                last_key.extend(iter.key().key_ref());
+            }
+            iter.next()?;
+        }
+        if builder.is_none() && !range_tombstones.is_empty() {
+            builder = Some(family.options.sst_builder(output_level));
+        }
+        if let Some(mut builder) = builder {
+            add_range_tombstones_within(
+                &mut builder,
+                &range_tombstones,
+                sst_lower_key.as_deref(),
+                None,
+            );
+            new_ssts.push(self.build_compacted_sst(builder)?);
+        }
+        Ok(new_ssts)
+    }
+
+    fn build_compacted_sst(
+        &self,
+        builder: SsTableBuilder,
+    ) -> Result<Arc<SsTable>> {
+        let id = self.next_sst_id();
+        Ok(Arc::new(builder.build(
+            id,
+            Some(self.block_cache.clone()),
+            self.path_of_sst(id),
+        )?))
+    }
+
+    fn compact(
+        &self,
+        family: &ColumnFamily,
+        task: &CompactionTask,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        // taken first, so that compaction filters can read the separated
+        // values of the input SSTs
+        let value_log = self.value_log.snapshot();
+        let snapshot = {
+            let guard = family.state.read();
+            Arc::clone(&guard)
+        };
+        let range_tombstones = task
+            .input_sst_ids()
+            .iter()
+            .flat_map(|id| snapshot.sstables[id].range_tombstones().to_vec())
+            .collect::<Vec<_>>();
+        let tables = |ids: &[usize]| {
+            ids.iter()
+                .map(|id| snapshot.sstables[id].clone())
+                .collect::<Vec<_>>()
+        };
+        // the SSTs of a level or tier are sorted and do not overlap, so they
+        // are read one after another instead of all at once
+        let concat_iter = |ids: &[usize]| {
+            SstConcatIterator::create_and_seek_to_first(tables(ids))
+        };
+        match task {
+            CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level,
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            })
+            | CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level,
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            }) => {
+                let lower_iter = concat_iter(lower_level_sst_ids)?;
+                if upper_level.is_some() {
+                    self.compact_generate_sst_from_iter(
+                        family,
+                        TwoMergeIterator::create(
+                            concat_iter(upper_level_sst_ids)?,
+                            lower_iter,
+                        )?,
+                        task.compact_to_bottom_level(),
+                        task.output_level(),
+                        range_tombstones,
+                        &value_log,
+                    )
+                } else {
+                    // L0 SSTs overlap each other
+                    let mut iters =
+                        Vec::with_capacity(upper_level_sst_ids.len());
+                    for table in tables(upper_level_sst_ids) {
+                        iters.push(Box::new(
+                            SsTableIterator::create_and_seek_to_first(table)?,
+                        ));
+                    }
+                    self.compact_generate_sst_from_iter(
+                        family,
+                        TwoMergeIterator::create(
+                            MergeIter::create(iters),
+                            lower_iter,
+                        )?,
+                        task.compact_to_bottom_level(),
+                        task.output_level(),
+                        range_tombstones,
+                        &value_log,
+                    )
+                }
+            }
+            CompactionTask::Tiered(TieredCompactionTask { tiers, .. }) => {
+                // newer tiers come first, so they win on equal keys
+                let mut iters = Vec::with_capacity(tiers.len());
+                for (_, tier_sst_ids) in tiers {
+                    iters.push(Box::new(concat_iter(tier_sst_ids)?));
+                }
+                self.compact_generate_sst_from_iter(
+                    family,
+                    MergeIter::create(iters),
+                    task.compact_to_bottom_level(),
+                    task.output_level(),
+                    range_tombstones,
+                    &value_log,
+                )
+            }
+        }
+    }
+
+    /// Run one compaction task of `family` if its controller asks for one.
+    pub(crate) fn trigger_compaction(
+        &self,
+        family: &ColumnFamily,
+    ) -> Result<()> {
+        let _compaction_lock = family.compaction_lock.lock();
+        let snapshot = {
+            let guard = family.state.read();
+            Arc::clone(&guard)
+        };
+        let Some(task) = family
+            .compaction_controller
+            .generate_compaction_task(&snapshot)
+        else {
+            return Ok(());
+        };
+        tracing::debug!("running compaction task: {:?}", task);
+        let new_ssts = self.compact(family, &task)?;
+        let output =
+            new_ssts.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+        let read_bytes = task
+            .input_sst_ids()
+            .iter()
+            .map(|id| snapshot.sstables[id].table_size())
+            .sum();
+        let written_bytes = new_ssts.iter().map(|sst| sst.table_size()).sum();
+        self.metrics.compactions.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .compaction_read_bytes
+            .fetch_add(read_bytes, Ordering::Relaxed);
+        self.metrics
+            .compaction_written_bytes
+            .fetch_add(written_bytes, Ordering::Relaxed);
+        let ssts_to_remove = {
+            let state_lock = self.state_lock.lock();
+            if family.is_dropped() {
+                // the family was dropped while compacting
+                drop(state_lock);
+                for sst in new_ssts {
+                    std::fs::remove_file(self.path_of_sst(sst.sst_id()))?;
+                }
+                return Ok(());
+            }
+            let mut snapshot = family.state.read().as_ref().clone();
+            for sst in new_ssts {
+                let prev = snapshot.sstables.insert(sst.sst_id(), sst);
+                assert!(prev.is_none());
+            }
+            let (mut snapshot, files_to_remove) = family
+                .compaction_controller
+                .apply_compaction_result(&snapshot, &task, &output, false);
+            let mut ssts_to_remove = Vec::with_capacity(files_to_remove.len());
+            for id in &files_to_remove {
+                let sst = snapshot.sstables.remove(id);
+                assert!(sst.is_some(), "cannot remove {id}.sst");
+                ssts_to_remove.push(sst.unwrap());
+            }
+            *family.state.write() = Arc::new(snapshot);
+            // the new SSTs must be durable before the manifest refers to them
+            self.sync_dir()?;
+            self.manifest().add_record(
+                &state_lock,
+                family.manifest_record(ManifestRecord::Compaction(
+                    task,
+                    output.clone(),
+                )),
+            )?;
+            ssts_to_remove
+        };
+        self.notify_stalled_writers();
+        tracing::debug!(
+            "compaction finished: {} files removed, output={:?}",
+            ssts_to_remove.len(),
+            output
+        );
+        // readers still holding an old snapshot keep their file handles open
+        for sst in ssts_to_remove {
+            std::fs::remove_file(self.path_of_sst(sst.sst_id()))?;
+        }
+        self.sync_dir()?;
+        Ok(())
+    }
+
+    /// Compact every column family in the background every 50ms until `rx`
+    /// is notified. The thread runs even if the default family does not
+    /// compact, as families created later may.
+    pub(crate) fn spawn_compaction_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<JoinHandle<()>>> {
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => {
+                        for family in this.column_families() {
+                            if let Err(e) = this.trigger_compaction(&family) {
+                                tracing::error!("compaction failed: {}", e);
+                            }
+                        }
+                    }
+                    recv(rx) -> _ => return,
+                }
+            }
+        });
+        Ok(Some(handle))
+    }
+}
 
-pub enum CompactionTask {}
+/// Add the parts of `range_tombstones` within `[lower, upper)`, so that the
+/// output SSTs of a compaction do not overlap.
+fn add_range_tombstones_within(
+    builder: &mut SsTableBuilder,
+    range_tombstones: &[RangeTombstone],
+    lower: Option<&[u8]>,
+    upper: Option<&[u8]>,
+) {
+    for tombstone in range_tombstones {
+        if let Some(clipped) = tombstone.clip(lower, upper) {
+            builder.add_range_tombstone(clipped);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimpleLeveledCompactionOptions {
+    /// Compact a level into the next one once the lower level holds fewer
+    /// than this percentage of the upper level's SSTs
+    pub size_ratio_percent: usize,
+    /// Compact L0 into L1 once it holds this many SSTs
+    pub level0_file_num_compaction_trigger: usize,
+    pub max_levels: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SimpleLeveledCompactionTask {
+    /// `None` means an L0 compaction
+    pub upper_level: Option<usize>,
+    pub upper_level_sst_ids: Vec<usize>,
+    pub lower_level: usize,
+    pub lower_level_sst_ids: Vec<usize>,
+    pub is_lower_level_bottom_level: bool,
+}
+
+/// Merges a whole level into the next one at a time.
+pub struct SimpleLeveledCompactionController {
+    options: SimpleLeveledCompactionOptions,
+}
+
+impl SimpleLeveledCompactionController {
+    pub fn new(options: SimpleLeveledCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<SimpleLeveledCompactionTask> {
+        let max_levels = self.options.max_levels;
+        let level_sizes = std::iter::once(snapshot.l0_sstable.len())
+            .chain(snapshot.levels.iter().map(|(_, ids)| ids.len()))
+            .collect::<Vec<_>>();
+
+        for level in 0..max_levels {
+            if level == 0
+                && snapshot.l0_sstable.len()
+                    < self.options.level0_file_num_compaction_trigger
+            {
+                continue;
+            }
+            let lower_level = level + 1;
+            if level_sizes[level] == 0 {
+                continue;
+            }
+            let size_ratio =
+                level_sizes[lower_level] as f64 / level_sizes[level] as f64;
+            if size_ratio < self.options.size_ratio_percent as f64 / 100.0 {
+                tracing::debug!(
+                    "compaction triggered at L{} and L{} with size ratio {}",
+                    level,
+                    lower_level,
+                    size_ratio
+                );
+                let upper_level_sst_ids = if level == 0 {
+                    snapshot.l0_sstable.clone()
+                } else {
+                    snapshot.levels[level - 1].1.clone()
+                };
+                return Some(SimpleLeveledCompactionTask {
+                    upper_level: (level > 0).then_some(level),
+                    upper_level_sst_ids,
+                    lower_level,
+                    lower_level_sst_ids: snapshot.levels[lower_level - 1]
+                        .1
+                        .clone(),
+                    is_lower_level_bottom_level: lower_level == max_levels,
+                });
+            }
+        }
+        None
+    }
+
+    /// Apply the compaction result to a snapshot, returning the new state
+    /// and the SSTs to remove.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &SimpleLeveledCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let mut files_to_remove = Vec::new();
+        if let Some(upper_level) = task.upper_level {
+            assert_eq!(
+                task.upper_level_sst_ids,
+                snapshot.levels[upper_level - 1].1,
+                "upper level changed during compaction"
+            );
+            snapshot.levels[upper_level - 1].1.clear();
+        } else {
+            // SSTs flushed since the task was generated stay in L0
+            let mut l0_ssts_compacted = task
+                .upper_level_sst_ids
+                .iter()
+                .copied()
+                .collect::<HashSet<_>>();
+            snapshot
+                .l0_sstable
+                .retain(|id| !l0_ssts_compacted.remove(id));
+            assert!(l0_ssts_compacted.is_empty());
+        }
+        files_to_remove.extend(&task.upper_level_sst_ids);
+
+        assert_eq!(
+            task.lower_level_sst_ids,
+            snapshot.levels[task.lower_level - 1].1,
+            "lower level changed during compaction"
+        );
+        files_to_remove.extend(&task.lower_level_sst_ids);
+        snapshot.levels[task.lower_level - 1].1 = output.to_vec();
+        (snapshot, files_to_remove)
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TieredCompactionOptions {
+    /// Do not compact until there are this many tiers
+    pub num_tiers: usize,
+    /// Merge every tier once the tiers above the last one hold this
+    /// percentage of the last tier's SSTs
+    pub max_size_amplification_percent: usize,
+    /// Merge the tiers above a tier that is this percentage larger than
+    /// all of them together
+    pub size_ratio: usize,
+    /// The fewest tiers a size-ratio compaction merges
+    pub min_merge_width: usize,
+    /// The most tiers merged to reduce the number of sorted runs
+    pub max_merge_width: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TieredCompactionTask {
+    /// The tiers to merge, from latest to earliest
+    pub tiers: Vec<(usize, Vec<usize>)>,
+    pub bottom_tier_included: bool,
+}
+
+/// Universal compaction: every flush adds a tier, and adjacent tiers are
+/// merged into one. L0 is not used.
+pub struct TieredCompactionController {
+    options: TieredCompactionOptions,
+}
+
+impl TieredCompactionController {
+    pub fn new(options: TieredCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<TieredCompactionTask> {
+        assert!(
+            snapshot.l0_sstable.is_empty(),
+            "tiered compaction does not use L0"
+        );
+        let num_tiers = snapshot.levels.len();
+        if num_tiers < self.options.num_tiers.max(2) {
+            return None;
+        }
+
+        // space amplification: everything above the last tier against it
+        let size = snapshot.levels[..num_tiers - 1]
+            .iter()
+            .map(|(_, ids)| ids.len())
+            .sum::<usize>();
+        let last_tier_size = snapshot.levels[num_tiers - 1].1.len();
+        let space_amp_ratio =
+            size as f64 / last_tier_size.max(1) as f64 * 100.0;
+        if space_amp_ratio >= self.options.max_size_amplification_percent as f64
+        {
+            tracing::debug!(
+                "compaction triggered by space amplification ratio {}",
+                space_amp_ratio
+            );
+            return Some(TieredCompactionTask {
+                tiers: snapshot.levels.clone(),
+                bottom_tier_included: true,
+            });
+        }
+
+        // size ratio: the first tier much larger than all the tiers above it
+        let size_ratio_trigger =
+            (100.0 + self.options.size_ratio as f64) / 100.0;
+        let mut size = 0;
+        for id in 0..num_tiers - 1 {
+            size += snapshot.levels[id].1.len();
+            let next_tier_size = snapshot.levels[id + 1].1.len();
+            let size_ratio = next_tier_size as f64 / size as f64;
+            if size_ratio > size_ratio_trigger
+                && id + 1 >= self.options.min_merge_width
+            {
+                tracing::debug!(
+                    "compaction triggered by size ratio {} on the top {} tiers",
+                    size_ratio,
+                    id + 1
+                );
+                return Some(TieredCompactionTask {
+                    tiers: snapshot.levels[..=id].to_vec(),
+                    bottom_tier_included: false,
+                });
+            }
+        }
+
+        // too many sorted runs: merge the top tiers
+        let num_tiers_to_take =
+            num_tiers.min(self.options.max_merge_width.unwrap_or(usize::MAX));
+        tracing::debug!(
+            "compaction triggered to reduce sorted runs: top {} tiers",
+            num_tiers_to_take
+        );
+        Some(TieredCompactionTask {
+            tiers: snapshot.levels[..num_tiers_to_take].to_vec(),
+            bottom_tier_included: num_tiers_to_take == num_tiers,
+        })
+    }
+
+    /// Replace the compacted tiers with one tier holding `output`, returning
+    /// the new state and the SSTs to remove. Tiers flushed since the task
+    /// was generated stay on top.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &TieredCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        assert!(
+            snapshot.l0_sstable.is_empty(),
+            "tiered compaction does not use L0"
+        );
+        let mut snapshot = snapshot.clone();
+        let mut tiers_to_remove = task
+            .tiers
+            .iter()
+            .map(|(tier_id, ids)| (*tier_id, ids))
+            .collect::<HashMap<_, _>>();
+        let mut levels = Vec::with_capacity(snapshot.levels.len());
+        let mut files_to_remove = Vec::new();
+        let mut new_tier_added = false;
+        for (tier_id, ids) in &snapshot.levels {
+            if let Some(task_ids) = tiers_to_remove.remove(tier_id) {
+                assert_eq!(task_ids, ids, "tier {tier_id} changed");
+                files_to_remove.extend(ids);
+            } else {
+                levels.push((*tier_id, ids.clone()));
+            }
+            if tiers_to_remove.is_empty() && !new_tier_added {
+                new_tier_added = true;
+                // every version may have been dropped
+                if let Some(&tier_id) = output.first() {
+                    levels.push((tier_id, output.to_vec()));
+                }
+            }
+        }
+        assert!(tiers_to_remove.is_empty(), "some tiers are missing");
+        snapshot.levels = levels;
+        (snapshot, files_to_remove)
+    }
+}
@@ -1 +1,340 @@
+//! Human-readable dumps of the engine state and of the files on disk, used
+//! by the `lsm-inspect` binary.
 
+use std::{collections::BTreeSet, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use crate::{
+    block::BlockIterator,
+    key::KeySlice,
+    lsm_storage::{
+        DEFAULT_COLUMN_FAMILY_ID, LsmStorageInner, LsmStorageOptions,
+        LsmStorageState, ManifestReplay, MiniLsm,
+    },
+    manifest::Manifest,
+    range_tombstone::RangeTombstone,
+    table::{FileObject, SsTable, filter::Filter},
+    value::StoredValue,
+    vlog::{ValueLog, ValuePointer},
+    wal::Wal,
+};
+
+/// Values longer than this are cut short in dumps.
+const VALUE_PREVIEW_LEN: usize = 32;
+
+impl SsTable {
+    /// Uncompressed size of the data blocks over their size on disk, 1.0
+    /// when no block is compressed.
+    pub fn compression_ratio(&self) -> f64 {
+        match self.compressed_size() {
+            0 => 1.0,
+            compressed => self.uncompressed_size() as f64 / compressed as f64,
+        }
+    }
+
+    /// Print the meta, filters, range tombstones and block layout of
+    /// this SST, and every entry if `with_entries` is set.
+    pub fn dump(&self, with_entries: bool) -> Result<()> {
+        println!(
+            "SST {:05}: {} bytes, {} blocks, max ts {}",
+            self.sst_id(),
+            self.table_size(),
+            self.num_of_blocks(),
+            self.max_ts()
+        );
+        println!(
+            "  keys {} ..= {}",
+            fmt_key(self.first_key().as_key_slice()),
+            fmt_key(self.last_key().as_key_slice())
+        );
+        println!(
+            "  block meta at offset {}, compression ratio {:.2}",
+            self.block_meta_offset,
+            self.compression_ratio()
+        );
+        if let Some(filter) = &self.filter {
+            println!("  filter: {}", fmt_filter(filter));
+        }
+        if let Some(prefix_filter) = &self.prefix_filter {
+            println!(
+                "  prefix filter: {:?}, {}",
+                prefix_filter.extractor,
+                fmt_filter(&prefix_filter.filter)
+            );
+        }
+        println!("  range tombstones: {}", self.range_tombstones.len());
+        for tombstone in &self.range_tombstones {
+            println!("    {}", fmt_range_tombstone(tombstone));
+        }
+        for (idx, meta) in self.block_meta.iter().enumerate() {
+            let end = self
+                .block_meta
+                .get(idx + 1)
+                .map_or(self.block_meta_offset, |next| next.offset);
+            let block = self.read_block(idx)?;
+            println!(
+                "  block {idx} at offset {}: {:?}, {} -> {} bytes, \
+                 {} restart points, {} ..= {}",
+                meta.offset,
+                meta.compression,
+                meta.uncompressed_len,
+                end - meta.offset - 4,
+                block.restarts.len(),
+                fmt_key(meta.first_key.as_key_slice()),
+                fmt_key(meta.last_key.as_key_slice())
+            );
+            if with_entries {
+                let mut iter = BlockIterator::create_and_seek_to_first(block);
+                while iter.is_valid() {
+                    println!(
+                        "    {} => {}",
+                        fmt_key(iter.key()),
+                        fmt_value(iter.value())
+                    );
+                    iter.next();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LsmStorageInner {
+    /// Print the SSTs of each level with their size and compression ratio,
+    /// for every column family.
+    pub fn dump_structure(&self) {
+        for family in self.column_families() {
+            if !family.is_default() {
+                println!("column family {}", family.name);
+            }
+            let snapshot = family.state.read().clone();
+            dump_levels(&snapshot, family.compaction_controller.flush_to_l0());
+        }
+    }
+}
+
+impl MiniLsm {
+    pub fn dump_structure(&self) {
+        self.inner.dump_structure()
+    }
+}
+
+/// Print the SSTs of each level of `snapshot`, tiers unless `flush_to_l0`.
+fn dump_levels(snapshot: &LsmStorageState, flush_to_l0: bool) {
+    let dump_level = |name: String, sst_ids: &[usize]| {
+        println!("{name} ({} SSTs)", sst_ids.len());
+        for id in sst_ids {
+            let sst = &snapshot.sstables[id];
+            println!(
+                "  {id:05}.sst {:>10} bytes, {:>3} blocks, \
+                 compression ratio {:.2}, {} ..= {}",
+                sst.table_size(),
+                sst.num_of_blocks(),
+                sst.compression_ratio(),
+                fmt_key(sst.first_key().as_key_slice()),
+                fmt_key(sst.last_key().as_key_slice())
+            );
+        }
+    };
+    if !snapshot.l0_sstable.is_empty() {
+        dump_level("L0".to_string(), &snapshot.l0_sstable);
+    }
+    let prefix = if flush_to_l0 { "L" } else { "T" };
+    for (id, sst_ids) in &snapshot.levels {
+        dump_level(format!("{prefix}{id}"), sst_ids);
+    }
+}
+
+/// Print every write batch of the WAL at `path`.
+pub fn dump_wal(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let (records, valid_len) = Wal::read_records(path)?;
+    let file_len = std::fs::metadata(path)?.len();
+    println!("WAL {}: {} records", path.display(), records.len());
+    for record in records {
+        println!(
+            "  record at offset {}: {} entries",
+            record.offset,
+            record.entries.len()
+        );
+        for (family, key, value) in record.entries {
+            let family = if family == DEFAULT_COLUMN_FAMILY_ID {
+                String::new()
+            } else {
+                format!("[cf {family}] ")
+            };
+            match RangeTombstone::from_wal_entry(
+                key.key_ref(),
+                key.ts(),
+                &value,
+            ) {
+                Some(tombstone) => {
+                    println!("    {family}{}", fmt_range_tombstone(&tombstone))
+                }
+                None => println!(
+                    "    {family}{} => {}",
+                    fmt_key(key.as_key_slice()),
+                    fmt_value(&value)
+                ),
+            }
+        }
+    }
+    if valid_len < file_len {
+        println!("  torn tail of {} bytes", file_len - valid_len);
+    }
+    Ok(())
+}
+
+/// Print the values of the value log segment at `path`.
+pub fn dump_value_log(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let records = ValueLog::read_segment(path)?;
+    println!("value log {}: {} values", path.display(), records.len());
+    for record in records {
+        let family = if record.family == DEFAULT_COLUMN_FAMILY_ID {
+            String::new()
+        } else {
+            format!("[cf {}] ", record.family)
+        };
+        let len = record.value.len();
+        println!(
+            "  offset {:>10}: {family}{} => {len} bytes {:?}{}",
+            record.pointer.offset,
+            fmt_key(record.key.as_key_slice()),
+            record.value.slice(..len.min(VALUE_PREVIEW_LEN)),
+            if len > VALUE_PREVIEW_LEN { "..." } else { "" }
+        );
+    }
+    Ok(())
+}
+
+/// Print the records of the manifest at `path`, in the order they replay.
+pub fn dump_manifest(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let records = Manifest::read_records(path)?;
+    println!("manifest {}: {} records", path.display(), records.len());
+    for (idx, record) in records.iter().enumerate() {
+        println!("  {idx:>5}: {record:?}");
+    }
+    Ok(())
+}
+
+/// Print the levels and live WALs of the data directory `path`, as the
+/// manifest describes them, without opening the engine. `options` must
+/// match the compaction options the directory was written with.
+pub fn dump_layout(
+    path: impl AsRef<Path>,
+    options: &LsmStorageOptions,
+) -> Result<()> {
+    let path = path.as_ref();
+    let records = Manifest::read_records(path.join("MANIFEST"))?;
+    let mut replay = ManifestReplay::new(options);
+    for record in records {
+        replay.apply(record);
+    }
+    println!("{}: largest id {}", path.display(), replay.max_id);
+    let mut wals = BTreeSet::new();
+    for (id, family) in &mut replay.families {
+        if *id != DEFAULT_COLUMN_FAMILY_ID {
+            println!("column family {} ({id})", family.name);
+        }
+        let state = &mut family.state;
+        let sst_ids = state
+            .l0_sstable
+            .iter()
+            .chain(state.levels.iter().flat_map(|(_, ssts)| ssts))
+            .copied()
+            .collect::<Vec<_>>();
+        for sst_id in sst_ids {
+            let sst_path = LsmStorageInner::path_of_sst_static(path, sst_id);
+            let file = FileObject::open(&sst_path)
+                .with_context(|| format!("failed to open SST {sst_id}"))?;
+            state
+                .sstables
+                .insert(sst_id, Arc::new(SsTable::open(sst_id, None, file)?));
+        }
+        dump_levels(state, family.compaction_controller.flush_to_l0());
+        wals.extend(family.memtables.iter().copied());
+    }
+    println!("WALs ({})", wals.len());
+    for id in wals {
+        let wal_path = LsmStorageInner::path_of_wal_static(path, id);
+        if !wal_path.exists() {
+            println!("  {id:05}.wal not on disk");
+            continue;
+        }
+        let (records, _) = Wal::read_records(&wal_path)?;
+        println!(
+            "  {id:05}.wal {:>10} bytes, {:>5} records",
+            std::fs::metadata(&wal_path)?.len(),
+            records.len()
+        );
+    }
+    Ok(())
+}
+
+fn fmt_key(key: KeySlice) -> String {
+    format!("{:?}@{}", Bytes::copy_from_slice(key.key_ref()), key.ts())
+}
+
+fn fmt_range_tombstone(tombstone: &RangeTombstone) -> String {
+    format!(
+        "delete range [{:?}, {:?})@{}",
+        tombstone.start, tombstone.end, tombstone.ts
+    )
+}
+
+fn fmt_filter(filter: &Filter) -> String {
+    match filter {
+        Filter::Bloom(bloom) => format!(
+            "bloom, {} bits, {} hash functions",
+            bloom.filter.len() * 8,
+            bloom.k
+        ),
+        Filter::Ribbon(ribbon) => format!(
+            "ribbon, {} slots, {} bits per slot",
+            ribbon.num_slots, ribbon.r
+        ),
+    }
+}
+
+/// Describe a stored value, showing at most `VALUE_PREVIEW_LEN` bytes of
+/// the user value.
+fn fmt_value(raw: &[u8]) -> String {
+    match StoredValue::decode(raw) {
+        Ok(None) => "tombstone".to_string(),
+        Ok(Some(value)) if value.separated => {
+            let pointer = match ValuePointer::decode(value.value) {
+                Ok(pointer) => format!(
+                    "segment {} at offset {}, {} bytes",
+                    pointer.segment, pointer.offset, pointer.len
+                ),
+                Err(e) => format!("undecodable pointer: {e}"),
+            };
+            match value.expire_at {
+                Some(expire_at) => format!(
+                    "value log pointer to {pointer}, expires at {expire_at}"
+                ),
+                None => format!("value log pointer to {pointer}"),
+            }
+        }
+        Ok(Some(value)) => {
+            let len = value.value.len();
+            let preview = Bytes::copy_from_slice(
+                &value.value[..len.min(VALUE_PREVIEW_LEN)],
+            );
+            let ellipsis = if len > VALUE_PREVIEW_LEN { "..." } else { "" };
+            match value.expire_at {
+                Some(expire_at) => format!(
+                    "{len} bytes {preview:?}{ellipsis}, expires at {expire_at}"
+                ),
+                None => format!("{len} bytes {preview:?}{ellipsis}"),
+            }
+        }
+        Err(e) => {
+            format!("undecodable value {:?}: {e}", Bytes::copy_from_slice(raw))
+        }
+    }
+}
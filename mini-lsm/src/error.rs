@@ -0,0 +1,68 @@
+use std::{fmt, path::PathBuf};
+
+/// Errors callers may want to handle, carried inside `anyhow::Error`. Use
+/// `err.downcast_ref::<LsmError>()` to tell them apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LsmError {
+    /// A serializable transaction read a key written by another transaction
+    /// that committed after `read_ts`.
+    TxnConflict {
+        read_ts: u64,
+        conflict_commit_ts: u64,
+    },
+    /// A record of a file failed its integrity check somewhere other than
+    /// at the end of the file, where a torn write is expected after a crash.
+    Corruption {
+        path: PathBuf,
+        offset: u64,
+        reason: String,
+    },
+    /// A write waited longer than `WriteStallOptions::stop_timeout` for
+    /// flushes or compaction to catch up.
+    WriteStall {
+        imm_memtables: usize,
+        l0_sstables: usize,
+    },
+    /// A read at `ts` may miss versions compaction collapsed, as only those
+    /// from `oldest_ts` on are kept.
+    HistoryUnavailable { ts: u64, oldest_ts: u64 },
+}
+
+impl fmt::Display for LsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LsmError::TxnConflict {
+                read_ts,
+                conflict_commit_ts,
+            } => write!(
+                f,
+                "transaction conflict: read at ts {read_ts}, but a key it \
+                 read was written at ts {conflict_commit_ts}"
+            ),
+            LsmError::Corruption {
+                path,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "{} is corrupted at offset {offset}: {reason}",
+                path.display()
+            ),
+            LsmError::WriteStall {
+                imm_memtables,
+                l0_sstables,
+            } => write!(
+                f,
+                "writes stalled: {imm_memtables} memtables waiting to be \
+                 flushed and {l0_sstables} SSTs in L0"
+            ),
+            LsmError::HistoryUnavailable { ts, oldest_ts } => write!(
+                f,
+                "cannot read at ts {ts}: versions are only kept from ts \
+                 {oldest_ts} on"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LsmError {}
@@ -0,0 +1,164 @@
+// SPDX-FileCopyrightText: LakeSoul Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{
+    key::KeySlice,
+    table::{SsTable, SsTableIterator},
+};
+
+use super::{SeekIterator, StorageIterator};
+
+/// Concatenates the iterators of SSTs sorted by key that do not overlap,
+/// such as the SSTs of one level. Only one SST is open at a time, and the
+/// next or previous one is opened once the current one is exhausted.
+pub struct SstConcatIterator {
+    current: Option<SsTableIterator>,
+    /// The index of the SST `current` iterates over
+    sst_idx: usize,
+    sstables: Vec<Arc<SsTable>>,
+}
+
+impl SstConcatIterator {
+    fn new(sstables: Vec<Arc<SsTable>>) -> Self {
+        debug_assert!(
+            sstables
+                .windows(2)
+                .all(|pair| pair[0].first_key() <= pair[1].first_key()),
+            "SSTs of a concat iterator must be sorted"
+        );
+        Self {
+            current: None,
+            sst_idx: 0,
+            sstables,
+        }
+    }
+
+    pub fn create_and_seek_to_first(
+        sstables: Vec<Arc<SsTable>>,
+    ) -> Result<Self> {
+        let mut iter = Self::new(sstables);
+        iter.seek_to_first()?;
+        Ok(iter)
+    }
+
+    /// Create an iterator positioned at the first entry >= `key`. SSTs
+    /// ending before `key` are never opened.
+    pub fn create_and_seek_to_key(
+        sstables: Vec<Arc<SsTable>>,
+        key: KeySlice,
+    ) -> Result<Self> {
+        let mut iter = Self::new(sstables);
+        iter.seek_to_key(key)?;
+        Ok(iter)
+    }
+
+    /// Open the following SSTs until one has an entry left. An SST may hold
+    /// range tombstones only, or no entry past the seek key.
+    fn move_until_valid(&mut self) -> Result<()> {
+        while !self.is_valid() {
+            self.sst_idx += 1;
+            let Some(table) = self.sstables.get(self.sst_idx) else {
+                self.current = None;
+                return Ok(());
+            };
+            self.current =
+                Some(SsTableIterator::create_and_seek_to_first(table.clone())?);
+        }
+        Ok(())
+    }
+
+    /// Open the preceding SSTs until one has an entry left.
+    fn move_back_until_valid(&mut self) -> Result<()> {
+        while !self.is_valid() {
+            let Some(idx) = self.sst_idx.checked_sub(1) else {
+                self.current = None;
+                return Ok(());
+            };
+            self.sst_idx = idx;
+            self.current = Some(SsTableIterator::create_and_seek_to_last(
+                self.sstables[idx].clone(),
+            )?);
+        }
+        Ok(())
+    }
+}
+
+impl StorageIterator for SstConcatIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn key(&self) -> KeySlice<'_> {
+        self.current.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.as_ref().is_some_and(|iter| iter.is_valid())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.current.as_mut().unwrap().next()?;
+        self.move_until_valid()
+    }
+}
+
+impl SeekIterator for SstConcatIterator {
+    fn prev(&mut self) -> Result<()> {
+        self.current.as_mut().unwrap().prev()?;
+        self.move_back_until_valid()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.sst_idx = 0;
+        self.current = match self.sstables.first() {
+            Some(table) => {
+                Some(SsTableIterator::create_and_seek_to_first(table.clone())?)
+            }
+            None => None,
+        };
+        self.move_until_valid()
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.sst_idx = self.sstables.len();
+        self.current = None;
+        self.move_back_until_valid()
+    }
+
+    fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        self.sst_idx = self
+            .sstables
+            .partition_point(|table| table.last_key().as_key_slice() < key);
+        self.current = match self.sstables.get(self.sst_idx) {
+            Some(table) => Some(SsTableIterator::create_and_seek_to_key(
+                table.clone(),
+                key,
+            )?),
+            None => None,
+        };
+        self.move_until_valid()
+    }
+
+    fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        // SSTs starting after `key` are never opened
+        self.sst_idx = self
+            .sstables
+            .partition_point(|table| table.first_key().as_key_slice() <= key);
+        self.current = None;
+        if let Some(idx) = self.sst_idx.checked_sub(1) {
+            self.sst_idx = idx;
+            self.current = Some(SsTableIterator::create_and_seek_for_prev(
+                self.sstables[idx].clone(),
+                key,
+            )?);
+        }
+        self.move_back_until_valid()
+    }
+}
@@ -3,17 +3,24 @@ use std::{
     collections::{BinaryHeap, binary_heap::PeekMut},
 };
 
-use crate::{iterators::StorageIterator, key::KeySlice};
+use anyhow::Result;
 
-struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>);
+use crate::{
+    iterators::{SeekIterator, StorageIterator},
+    key::KeySlice,
+};
+
+/// A child iterator with its index, and whether the merge walks backward.
+/// The heap yields the smallest key first when walking forward, and the
+/// largest first when walking backward. Equal keys yield the lowest index
+/// first either way.
+struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>, pub bool);
 
 impl<I: StorageIterator> Ord for HeapWrapper<I> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.1
-            .key()
-            .cmp(&other.1.key())
-            .then(self.0.cmp(&other.0))
-            .reverse()
+        let by_key = self.1.key().cmp(&other.1.key());
+        let by_key = if self.2 { by_key } else { by_key.reverse() };
+        by_key.then(other.0.cmp(&self.0))
     }
 }
 
@@ -34,42 +41,124 @@ impl<I: StorageIterator> Eq for HeapWrapper<I> {}
 pub struct MergeIter<I: StorageIterator> {
     iters: BinaryHeap<HeapWrapper<I>>,
     current: Option<HeapWrapper<I>>,
+    /// The invalid iterators, kept to be positioned again by a seek or a
+    /// change of direction
+    exhausted: Vec<HeapWrapper<I>>,
+    backward: bool,
 }
 
 impl<I: StorageIterator> MergeIter<I> {
     pub fn create(iters: Vec<Box<I>>) -> Self {
-        if iters.is_empty() {
-            return Self {
-                iters: BinaryHeap::new(),
-                current: None,
-            };
-        }
-        let mut heap = BinaryHeap::new();
-        if iters.iter().all(|x| x.is_valid()) {
-            // all invalid, last one is current
-            let mut iters = iters;
-            return Self {
-                iters: heap,
-                current: Some(HeapWrapper(0, iters.pop().unwrap())),
-            };
-        }
+        let mut iter = Self {
+            iters: BinaryHeap::new(),
+            current: None,
+            exhausted: Vec::new(),
+            backward: false,
+        };
+        iter.rebuild(
+            iters
+                .into_iter()
+                .enumerate()
+                .map(|(idx, iter)| HeapWrapper(idx, iter, false))
+                .collect(),
+        );
+        iter
+    }
 
-        for (idx, iter) in iters.into_iter().enumerate() {
-            if iter.is_valid() {
-                heap.push(HeapWrapper(idx, iter))
+    /// Rebuild the heap from `iters` once they are positioned.
+    fn rebuild(&mut self, iters: Vec<HeapWrapper<I>>) {
+        for mut iter in iters {
+            iter.2 = self.backward;
+            if iter.1.is_valid() {
+                self.iters.push(iter);
+            } else {
+                self.exhausted.push(iter);
             }
         }
+        self.current = self.iters.pop();
+    }
+}
 
-        let current = heap.pop();
+impl<I: 'static + for<'a> SeekIterator<KeyType<'a> = KeySlice<'a>>>
+    MergeIter<I>
+{
+    /// Position every iterator with `seek`, walking in the given direction.
+    fn seek_all(
+        &mut self,
+        backward: bool,
+        mut seek: impl FnMut(&mut I) -> Result<()>,
+    ) -> Result<()> {
+        let mut iters: Vec<_> = std::mem::take(&mut self.iters).into_vec();
+        iters.extend(self.current.take());
+        iters.append(&mut self.exhausted);
+        self.backward = backward;
+        let result = iters.iter_mut().try_for_each(|iter| seek(&mut iter.1));
+        self.rebuild(iters);
+        result
+    }
+
+    /// Turn around at the current key, positioning every iterator at the
+    /// entry after it, or before it when turning backward.
+    fn turn(&mut self, backward: bool) -> Result<()> {
+        let key = self.key().to_key_vec();
+        let key = key.as_key_slice();
+        self.seek_all(backward, |iter| {
+            if backward {
+                iter.seek_for_prev(key)?;
+                if iter.is_valid() && iter.key() == key {
+                    iter.prev()?;
+                }
+            } else {
+                iter.seek_to_key(key)?;
+                if iter.is_valid() && iter.key() == key {
+                    iter.next()?;
+                }
+            }
+            Ok(())
+        })
+    }
 
-        Self {
-            iters: heap,
-            current,
+    /// Move to the following entry in the current direction.
+    fn step(&mut self) -> Result<()> {
+        let backward = self.backward;
+        let advance = |iter: &mut I| {
+            if backward { iter.prev() } else { iter.next() }
+        };
+        let current = self.current.as_mut().unwrap();
+        // while for inner iter
+        while let Some(mut inner_iter) = self.iters.peek_mut() {
+            if inner_iter.1.key() == current.1.key() {
+                // no access
+                if let e @ Err(_) = advance(&mut inner_iter.1) {
+                    self.exhausted.push(PeekMut::pop(inner_iter));
+                    return e;
+                }
+                if !inner_iter.1.is_valid() {
+                    self.exhausted.push(PeekMut::pop(inner_iter));
+                }
+            } else {
+                break;
+            }
+        }
+        advance(&mut current.1)?;
+        if !current.1.is_valid() {
+            let current = self.current.take().unwrap();
+            self.exhausted.push(current);
+            self.current = self.iters.pop();
+            return Ok(());
         }
+        if let Some(mut inner_iter) = self.iters.peek_mut()
+            && *current < *inner_iter
+        {
+            // inner_iter > current
+            // current still valid
+            std::mem::swap(&mut *inner_iter, current);
+        }
+        Ok(())
     }
 }
 
-impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>>
+impl<I: 'static + for<'a> SeekIterator<KeyType<'a> = KeySlice<'a>>>
     StorageIterator for MergeIter<I>
 {
     type KeyType<'a> = KeySlice<'a>;
@@ -89,37 +178,49 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>>
             .unwrap_or(false)
     }
 
-    fn next(&mut self) -> anyhow::Result<()> {
-        let current = self.current.as_mut().unwrap();
-        // while for inner iter
-        while let Some(mut inner_iter) = self.iters.peek_mut() {
-            if inner_iter.1.key() == current.1.key() {
-                // no access
-                if let e @ Err(_) = inner_iter.1.next() {
-                    PeekMut::pop(inner_iter);
-                    return e;
-                }
-                if !inner_iter.1.is_valid() {
-                    PeekMut::pop(inner_iter);
-                }
-            } else {
-                break;
-            }
-        }
-        current.1.next()?;
-        if !current.1.is_valid() {
-            if let Some(iter) = self.iters.pop() {
-                *current = iter;
-            }
-            return Ok(());
+    fn next(&mut self) -> Result<()> {
+        if self.backward {
+            return self.turn(false);
         }
-        if let Some(mut inner_iter) = self.iters.peek_mut() {
-            if *current < *inner_iter {
-                // inner_iter > current
-                // current still valid
-                std::mem::swap(&mut *inner_iter, current);
-            }
+        self.step()
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iters
+            .iter()
+            .map(|x| x.1.num_active_iterators())
+            .sum::<usize>()
+            + self
+                .current
+                .as_ref()
+                .map(|x| x.1.num_active_iterators())
+                .unwrap_or(0)
+    }
+}
+
+impl<I: 'static + for<'a> SeekIterator<KeyType<'a> = KeySlice<'a>>> SeekIterator
+    for MergeIter<I>
+{
+    fn prev(&mut self) -> Result<()> {
+        if !self.backward {
+            return self.turn(true);
         }
-        Ok(())
+        self.step()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.seek_all(false, |iter| iter.seek_to_first())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.seek_all(true, |iter| iter.seek_to_last())
+    }
+
+    fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        self.seek_all(false, |iter| iter.seek_to_key(key))
+    }
+
+    fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        self.seek_all(true, |iter| iter.seek_for_prev(key))
     }
 }
@@ -9,7 +9,7 @@ pub mod two_merge_iterator;
 use anyhow::Result;
 
 pub trait StorageIterator {
-    type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord
+    type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord + Copy
     where
         Self: 'a;
 
@@ -21,3 +21,17 @@ pub trait StorageIterator {
         1
     }
 }
+
+/// An iterator that can also walk backward, and be positioned at either
+/// end or around a key. `next` and `prev` may be mixed, each moving to the
+/// entry after or before the current one.
+pub trait SeekIterator: StorageIterator {
+    /// Move to the previous entry, becoming invalid past the first one.
+    fn prev(&mut self) -> Result<()>;
+    fn seek_to_first(&mut self) -> Result<()>;
+    fn seek_to_last(&mut self) -> Result<()>;
+    /// Seek to the first entry >= `key`.
+    fn seek_to_key(&mut self, key: Self::KeyType<'_>) -> Result<()>;
+    /// Seek to the last entry <= `key`.
+    fn seek_for_prev(&mut self, key: Self::KeyType<'_>) -> Result<()>;
+}
@@ -2,7 +2,9 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use super::StorageIterator;
+use anyhow::Result;
+
+use super::{SeekIterator, StorageIterator};
 
 /// Merges two iterators of different types into one.
 /// If the two iterators have the same key, only
@@ -11,4 +13,172 @@ pub struct TwoMergeIterator<A: StorageIterator, B: StorageIterator> {
     a: A,
     b: B,
     choose_a: bool,
+    backward: bool,
+}
+
+impl<
+    A: 'static + SeekIterator,
+    B: 'static + for<'a> SeekIterator<KeyType<'a> = A::KeyType<'a>>,
+> TwoMergeIterator<A, B>
+{
+    fn choose_a(a: &A, b: &B, backward: bool) -> bool {
+        if !a.is_valid() {
+            return false;
+        }
+        if !b.is_valid() {
+            return true;
+        }
+        if backward {
+            a.key() > b.key()
+        } else {
+            a.key() < b.key()
+        }
+    }
+
+    /// Skip the entry of B if A has the same key.
+    fn skip_b(&mut self) -> Result<()> {
+        if self.a.is_valid()
+            && self.b.is_valid()
+            && self.b.key() == self.a.key()
+        {
+            if self.backward {
+                self.b.prev()?;
+            } else {
+                self.b.next()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Skip the entry of B if needed, and choose the iterator to read from.
+    fn settle(&mut self) -> Result<()> {
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&self.a, &self.b, self.backward);
+        Ok(())
+    }
+
+    pub fn create(a: A, b: B) -> Result<Self> {
+        let mut iter = Self {
+            choose_a: false,
+            backward: false,
+            a,
+            b,
+        };
+        iter.settle()?;
+        Ok(iter)
+    }
+
+    /// Turn around at the current key, positioning the iterator not chosen
+    /// past it in the new direction, as the chosen one is once moved.
+    fn turn(&mut self, backward: bool) -> Result<()> {
+        self.backward = backward;
+        if self.choose_a {
+            if backward {
+                self.b.seek_for_prev(self.a.key())?;
+            } else {
+                self.b.seek_to_key(self.a.key())?;
+            }
+            // B may hold the key too
+            self.skip_b()
+        } else if backward {
+            // A does not hold the key, or it would have been chosen
+            self.a.seek_for_prev(self.b.key())
+        } else {
+            self.a.seek_to_key(self.b.key())
+        }
+    }
+}
+
+impl<
+    A: 'static + SeekIterator,
+    B: 'static + for<'a> SeekIterator<KeyType<'a> = A::KeyType<'a>>,
+> StorageIterator for TwoMergeIterator<A, B>
+{
+    type KeyType<'a> = A::KeyType<'a>;
+
+    fn key(&self) -> Self::KeyType<'_> {
+        if self.choose_a {
+            self.a.key()
+        } else {
+            self.b.key()
+        }
+    }
+
+    fn value(&self) -> &[u8] {
+        if self.choose_a {
+            self.a.value()
+        } else {
+            self.b.value()
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        if self.choose_a {
+            self.a.is_valid()
+        } else {
+            self.b.is_valid()
+        }
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.backward {
+            self.turn(false)?;
+        }
+        if self.choose_a {
+            self.a.next()?;
+        } else {
+            self.b.next()?;
+        }
+        self.settle()
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.a.num_active_iterators() + self.b.num_active_iterators()
+    }
+}
+
+impl<
+    A: 'static + SeekIterator,
+    B: 'static + for<'a> SeekIterator<KeyType<'a> = A::KeyType<'a>>,
+> SeekIterator for TwoMergeIterator<A, B>
+{
+    fn prev(&mut self) -> Result<()> {
+        if !self.backward {
+            self.turn(true)?;
+        }
+        if self.choose_a {
+            self.a.prev()?;
+        } else {
+            self.b.prev()?;
+        }
+        self.settle()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.a.seek_to_first()?;
+        self.b.seek_to_first()?;
+        self.backward = false;
+        self.settle()
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.a.seek_to_last()?;
+        self.b.seek_to_last()?;
+        self.backward = true;
+        self.settle()
+    }
+
+    fn seek_to_key(&mut self, key: Self::KeyType<'_>) -> Result<()> {
+        self.a.seek_to_key(key)?;
+        self.b.seek_to_key(key)?;
+        self.backward = false;
+        self.settle()
+    }
+
+    fn seek_for_prev(&mut self, key: Self::KeyType<'_>) -> Result<()> {
+        self.a.seek_for_prev(key)?;
+        self.b.seek_for_prev(key)?;
+        self.backward = true;
+        self.settle()
+    }
 }
@@ -70,6 +70,11 @@ impl Key<Vec<u8>> {
         self.0.clear()
     }
 
+    /// Shorten the key to its first `len` bytes, keeping the ts.
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len)
+    }
+
     /// Append a slice to the end of the key
     pub fn append(&mut self, data: &[u8]) {
         self.0.extend(data)
@@ -86,7 +91,7 @@ impl Key<Vec<u8>> {
         self.1 = key_slice.1;
     }
 
-    pub fn as_key_slice(&self) -> KeySlice {
+    pub fn as_key_slice(&self) -> KeySlice<'_> {
         Key(self.0.as_slice(), self.1)
     }
 
@@ -116,7 +121,7 @@ impl Key<Bytes> {
         Self(Bytes::new(), TS_DEFAULT)
     }
 
-    pub fn as_key_slice(&self) -> KeySlice {
+    pub fn as_key_slice(&self) -> KeySlice<'_> {
         Key(&self.0, self.1)
     }
 
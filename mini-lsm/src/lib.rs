@@ -3,6 +3,7 @@
 pub mod block;
 pub mod compact;
 pub mod debug;
+pub mod error;
 pub mod iterators;
 pub mod key;
 pub mod lsm_iterator;
@@ -10,7 +11,11 @@ pub mod lsm_storage;
 pub mod manifest;
 pub mod mem_table;
 pub mod mvcc;
+pub mod range_tombstone;
+pub mod stats;
 pub mod table;
+pub mod value;
+pub mod vlog;
 pub mod wal;
 
 #[cfg(test)]
@@ -1,42 +1,336 @@
 use std::ops::Bound;
 
+use anyhow::{Result, bail};
 use bytes::Bytes;
 
-use crate::iterators::StorageIterator;
-use anyhow::Result;
+use crate::{
+    iterators::{
+        SeekIterator, StorageIterator, concat_iterator::SstConcatIterator,
+        merge_iterator::MergeIter, two_merge_iterator::TwoMergeIterator,
+    },
+    key::{KeySlice, TS_RANGE_BEGIN, TS_RANGE_END},
+    mem_table::MemTableIter,
+    range_tombstone::RangeTombstoneFragments,
+    table::SsTableIterator,
+    value::{StoredValue, now_ms},
+    vlog::{ValueLogSnapshot, ValuePointer},
+};
+
+/// Represents the internal type for an LSM iterator: memtables, then L0,
+/// then the other levels, each read one SST after another.
+type LsmIteratorInner = TwoMergeIterator<
+    TwoMergeIterator<MergeIter<MemTableIter>, MergeIter<SsTableIterator>>,
+    MergeIter<SstConcatIterator>,
+>;
 
 pub struct LsmIterator {
-    // inner: LsmIteratorgccInner,
+    inner: LsmIteratorInner,
+    start_bound: Bound<Bytes>,
     end_bound: Bound<Bytes>,
     is_valid: bool,
     read_ts: u64,
+    /// Values that expire by this time are hidden, so that the scan sees
+    /// the same values from start to end
+    now: u64,
+    /// The range tombstones visible at `read_ts`
+    range_tombstones: RangeTombstoneFragments,
     prev_key: Vec<u8>,
+    /// Whether the iterator walks backward. `inner` is then positioned
+    /// before the current key, which is `prev_key`.
+    backward: bool,
+    /// The stored value of the current key when walking backward
+    backward_value: Bytes,
+    value_log: ValueLogSnapshot,
+    /// The current value if it is read from the value log
+    separated_value: Option<Bytes>,
+}
+
+impl LsmIterator {
+    pub(crate) fn new(
+        iter: LsmIteratorInner,
+        start_bound: Bound<Bytes>,
+        end_bound: Bound<Bytes>,
+        read_ts: u64,
+        range_tombstones: RangeTombstoneFragments,
+        value_log: ValueLogSnapshot,
+    ) -> Result<Self> {
+        let mut iter = Self {
+            is_valid: false,
+            inner: iter,
+            start_bound,
+            end_bound,
+            read_ts,
+            now: now_ms(),
+            range_tombstones,
+            prev_key: Vec::new(),
+            backward: false,
+            backward_value: Bytes::new(),
+            value_log,
+            separated_value: None,
+        };
+        iter.is_valid = iter.inner_in_bound();
+        iter.move_to_key()?;
+        Ok(iter)
+    }
+
+    /// Whether `key` is after the start bound.
+    fn after_start(&self, key: &[u8]) -> bool {
+        match self.start_bound.as_ref() {
+            Bound::Unbounded => true,
+            Bound::Included(start) => key >= start.as_ref(),
+            Bound::Excluded(start) => key > start.as_ref(),
+        }
+    }
+
+    /// Whether `key` is before the end bound.
+    fn before_end(&self, key: &[u8]) -> bool {
+        match self.end_bound.as_ref() {
+            Bound::Unbounded => true,
+            Bound::Included(end) => key <= end.as_ref(),
+            Bound::Excluded(end) => key < end.as_ref(),
+        }
+    }
+
+    /// Whether the inner iterator is valid and still within the bounds.
+    fn inner_in_bound(&self) -> bool {
+        if !self.inner.is_valid() {
+            return false;
+        }
+        let key = self.inner.key().key_ref();
+        self.after_start(key) && self.before_end(key)
+    }
+
+    fn next_inner(&mut self) -> Result<()> {
+        self.inner.next()?;
+        self.is_valid = self.inner_in_bound();
+        Ok(())
+    }
+
+    /// Read the value `value` points to if it is separated.
+    fn read_separated(
+        value_log: &ValueLogSnapshot,
+        value: &StoredValue,
+    ) -> Result<Option<Bytes>> {
+        if !value.separated {
+            return Ok(None);
+        }
+        let pointer = ValuePointer::decode(value.value)?;
+        Ok(Some(value_log.read(&pointer)?))
+    }
+
+    /// Skip to the newest visible version of the next user key, hiding
+    /// versions newer than `read_ts`, older versions, tombstones, expired
+    /// values and versions deleted by a range tombstone.
+    fn move_to_key(&mut self) -> Result<()> {
+        loop {
+            while self.is_valid && self.inner.key().key_ref() == self.prev_key {
+                self.next_inner()?;
+            }
+            if !self.is_valid {
+                break;
+            }
+            self.prev_key.clear();
+            self.prev_key.extend(self.inner.key().key_ref());
+            while self.is_valid
+                && self.inner.key().key_ref() == self.prev_key
+                && self.inner.key().ts() > self.read_ts
+            {
+                self.next_inner()?;
+            }
+            if !self.is_valid {
+                break;
+            }
+            if self.inner.key().key_ref() != self.prev_key {
+                // every version of this key is newer than `read_ts`
+                continue;
+            }
+            let key = self.inner.key();
+            if let Some(value) = StoredValue::decode(self.inner.value())?
+                && !value.is_expired(self.now)
+                && !self.range_tombstones.covers(key.key_ref(), key.ts())
+            {
+                self.separated_value =
+                    Self::read_separated(&self.value_log, &value)?;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk back to the newest visible version of the previous user key,
+    /// hiding the same versions as `move_to_key`. Its versions come oldest
+    /// first, so the value is copied, and `inner` is left before them.
+    fn move_to_prev_key(&mut self) -> Result<()> {
+        loop {
+            if !self.inner_in_bound() {
+                self.is_valid = false;
+                return Ok(());
+            }
+            self.prev_key.clear();
+            self.prev_key.extend(self.inner.key().key_ref());
+            let mut visible = None;
+            while self.inner_in_bound()
+                && self.inner.key().key_ref() == self.prev_key
+            {
+                let ts = self.inner.key().ts();
+                if ts <= self.read_ts {
+                    visible =
+                        Some((ts, Bytes::copy_from_slice(self.inner.value())));
+                }
+                self.inner.prev()?;
+            }
+            // none when every version of this key is newer than `read_ts`
+            let Some((ts, value)) = visible else {
+                continue;
+            };
+            let separated_value = match StoredValue::decode(&value)? {
+                Some(value)
+                    if !value.is_expired(self.now)
+                        && !self
+                            .range_tombstones
+                            .covers(&self.prev_key, ts) =>
+                {
+                    Self::read_separated(&self.value_log, &value)?
+                }
+                _ => continue,
+            };
+            self.separated_value = separated_value;
+            self.backward_value = value;
+            self.is_valid = true;
+            return Ok(());
+        }
+    }
+}
+
+/// Position `inner` at the first version of the first user key after `key`.
+fn seek_inner_after(inner: &mut LsmIteratorInner, key: &[u8]) -> Result<()> {
+    // the oldest possible version sorts last among those of `key`
+    let last_version = KeySlice::from_slice(key, TS_RANGE_END);
+    inner.seek_to_key(last_version)?;
+    if inner.is_valid() && inner.key() == last_version {
+        inner.next()?;
+    }
+    Ok(())
+}
+
+/// Position `inner` at the last version of the last user key before `key`.
+fn seek_inner_before(inner: &mut LsmIteratorInner, key: &[u8]) -> Result<()> {
+    // the newest possible version sorts first among those of `key`
+    let first_version = KeySlice::from_slice(key, TS_RANGE_BEGIN);
+    inner.seek_for_prev(first_version)?;
+    if inner.is_valid() && inner.key() == first_version {
+        inner.prev()?;
+    }
+    Ok(())
 }
 
 impl StorageIterator for LsmIterator {
     type KeyType<'a> = &'a [u8];
 
     fn is_valid(&self) -> bool {
-        todo!()
+        self.is_valid
     }
 
     fn key(&self) -> &[u8] {
-        todo!()
+        if self.backward {
+            return &self.prev_key;
+        }
+        self.inner.key().key_ref()
     }
 
     fn value(&self) -> &[u8] {
-        todo!()
+        if let Some(value) = &self.separated_value {
+            return value;
+        }
+        let value = if self.backward {
+            &self.backward_value
+        } else {
+            self.inner.value()
+        };
+        StoredValue::decode(value)
+            .ok()
+            .flatten()
+            .expect("checked by move_to_key")
+            .value
     }
 
     fn next(&mut self) -> Result<()> {
-        // self.next_inner()?;
-        // self.move_to_key()?;
-        // Ok(())
-        todo!()
+        if self.backward {
+            // back to the versions of the current key, which `move_to_key`
+            // skips as it is still `prev_key`
+            self.backward = false;
+            self.inner.seek_to_key(KeySlice::from_slice(
+                &self.prev_key,
+                TS_RANGE_BEGIN,
+            ))?;
+            self.is_valid = self.inner_in_bound();
+        } else {
+            self.next_inner()?;
+        }
+        self.move_to_key()?;
+        Ok(())
     }
 
     fn num_active_iterators(&self) -> usize {
-        todo!()
+        self.inner.num_active_iterators()
+    }
+}
+
+impl SeekIterator for LsmIterator {
+    fn prev(&mut self) -> Result<()> {
+        if !self.backward {
+            self.backward = true;
+            seek_inner_before(&mut self.inner, &self.prev_key)?;
+        }
+        self.move_to_prev_key()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.backward = false;
+        self.prev_key.clear();
+        match self.start_bound.as_ref() {
+            Bound::Unbounded => self.inner.seek_to_first()?,
+            Bound::Included(start) => self
+                .inner
+                .seek_to_key(KeySlice::from_slice(start, TS_RANGE_BEGIN))?,
+            Bound::Excluded(start) => seek_inner_after(&mut self.inner, start)?,
+        }
+        self.is_valid = self.inner_in_bound();
+        self.move_to_key()
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.backward = true;
+        match self.end_bound.as_ref() {
+            Bound::Unbounded => self.inner.seek_to_last()?,
+            Bound::Included(end) => self
+                .inner
+                .seek_for_prev(KeySlice::from_slice(end, TS_RANGE_END))?,
+            Bound::Excluded(end) => seek_inner_before(&mut self.inner, end)?,
+        }
+        self.move_to_prev_key()
+    }
+
+    fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
+        if !self.after_start(key) {
+            return self.seek_to_first();
+        }
+        self.backward = false;
+        self.prev_key.clear();
+        self.inner
+            .seek_to_key(KeySlice::from_slice(key, TS_RANGE_BEGIN))?;
+        self.is_valid = self.inner_in_bound();
+        self.move_to_key()
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        if !self.before_end(key) {
+            return self.seek_to_last();
+        }
+        self.backward = true;
+        self.inner
+            .seek_for_prev(KeySlice::from_slice(key, TS_RANGE_END))?;
+        self.move_to_prev_key()
     }
 }
 
@@ -50,6 +344,15 @@ pub struct FusedIterator<I: StorageIterator> {
     has_errored: bool,
 }
 
+impl<I: StorageIterator> FusedIterator<I> {
+    pub fn new(iter: I) -> Self {
+        Self {
+            iter,
+            has_errored: false,
+        }
+    }
+}
+
 impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
     type KeyType<'a>
         = I::KeyType<'a>
@@ -57,18 +360,80 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
         Self: 'a;
 
     fn value(&self) -> &[u8] {
-        todo!()
+        if !self.is_valid() {
+            panic!("invalid access to the underlying iterator");
+        }
+        self.iter.value()
     }
 
     fn key(&self) -> Self::KeyType<'_> {
-        todo!()
+        if !self.is_valid() {
+            panic!("invalid access to the underlying iterator");
+        }
+        self.iter.key()
     }
 
     fn is_valid(&self) -> bool {
-        todo!()
+        !self.has_errored && self.iter.is_valid()
     }
 
     fn next(&mut self) -> anyhow::Result<()> {
-        todo!()
+        if self.has_errored {
+            bail!("the iterator is tainted");
+        }
+        if self.iter.is_valid()
+            && let Err(e) = self.iter.next()
+        {
+            self.has_errored = true;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iter.num_active_iterators()
+    }
+}
+
+impl<I: SeekIterator> FusedIterator<I> {
+    /// Run `f` unless the iterator is tainted, tainting it if `f` fails.
+    fn guard(
+        &mut self,
+        f: impl FnOnce(&mut I) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if self.has_errored {
+            bail!("the iterator is tainted");
+        }
+        if let Err(e) = f(&mut self.iter) {
+            self.has_errored = true;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl<I: SeekIterator> SeekIterator for FusedIterator<I> {
+    /// Like `next`, does nothing if the iterator is already invalid.
+    fn prev(&mut self) -> anyhow::Result<()> {
+        self.guard(|iter| match iter.is_valid() {
+            true => iter.prev(),
+            false => Ok(()),
+        })
+    }
+
+    fn seek_to_first(&mut self) -> anyhow::Result<()> {
+        self.guard(|iter| iter.seek_to_first())
+    }
+
+    fn seek_to_last(&mut self) -> anyhow::Result<()> {
+        self.guard(|iter| iter.seek_to_last())
+    }
+
+    fn seek_to_key(&mut self, key: Self::KeyType<'_>) -> anyhow::Result<()> {
+        self.guard(|iter| iter.seek_to_key(key))
+    }
+
+    fn seek_for_prev(&mut self, key: Self::KeyType<'_>) -> anyhow::Result<()> {
+        self.guard(|iter| iter.seek_for_prev(key))
     }
 }
@@ -0,0 +1,69 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::block::Block;
+
+/// Caches decoded blocks by `(sst_id, block_idx)` and counts how often a
+/// lookup is served from memory.
+pub struct BlockCache {
+    cache: moka::sync::Cache<(usize, usize), Arc<Block>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// create a cache holding at most `max_capacity` blocks
+    pub fn new(max_capacity: u64) -> Self {
+        Self {
+            cache: moka::sync::Cache::new(max_capacity),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the block at `key`, loading it with `init` on a miss.
+    pub fn try_get_with(
+        &self,
+        key: (usize, usize),
+        init: impl FnOnce() -> Result<Arc<Block>>,
+    ) -> Result<Arc<Block>> {
+        let mut loaded = false;
+        let block = self
+            .cache
+            .try_get_with(key, || {
+                loaded = true;
+                init()
+            })
+            .map_err(|e| anyhow!("{}", e))?;
+        if loaded {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(block)
+    }
+
+    /// number of lookups served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// number of lookups that had to read the block from disk
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// fraction of lookups served from the cache, 0 if there was none
+    pub fn hit_rate(&self) -> f64 {
+        let (hits, misses) = (self.hits(), self.misses());
+        if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::{fs::File, path::Path};
+
+use anyhow::{Context, Result, bail};
+
+use super::LsmStorageInner;
+use crate::{
+    manifest::{Manifest, ManifestRecord},
+    vlog::ValueLog,
+};
+
+impl LsmStorageInner {
+    /// Flush everything written so far and hard-link the resulting SSTs and
+    /// the value log into `dir`, next to a manifest holding a snapshot
+    /// record for each column family.
+    pub(crate) fn checkpoint(&self, dir: &Path) -> Result<()> {
+        if dir.exists() && dir.read_dir()?.next().is_some() {
+            bail!("checkpoint dir {} is not empty", dir.display());
+        }
+        std::fs::create_dir_all(dir)
+            .context("failed to create checkpoint dir")?;
+
+        // garbage collection cannot remove segments while they are linked
+        let _gc_lock = self.value_log.gc_lock.lock();
+        // compaction cannot swap out SSTs while they are linked
+        let state_lock = self.state_lock.lock();
+        if !self.memtables_empty() {
+            self.force_freeze_memtable(&state_lock)?;
+        }
+        let families = self.column_families();
+        for family in &families {
+            while !family.state.read().imm_memtables.is_empty() {
+                self.flush_next_imm_memtable(family, &state_lock)?;
+            }
+        }
+
+        // the linked segments are not appended to anymore
+        self.value_log.seal()?;
+        for segment in self.value_log.sealed_segments() {
+            link_or_copy(
+                &self.value_log.path_of_segment(segment),
+                &ValueLog::path_of_segment_static(dir, segment),
+            )?;
+        }
+
+        let manifest = Manifest::create(dir.join("MANIFEST"))?;
+        for family in &families {
+            let snapshot = family.state.read().clone();
+            let sst_ids = snapshot
+                .l0_sstable
+                .iter()
+                .chain(snapshot.levels.iter().flat_map(|(_, ssts)| ssts));
+            for &sst_id in sst_ids {
+                link_or_copy(
+                    &self.path_of_sst(sst_id),
+                    &Self::path_of_sst_static(dir, sst_id),
+                )?;
+            }
+            if !family.is_default() {
+                manifest.add_record_when_init(
+                    ManifestRecord::CreateColumnFamily(
+                        family.id,
+                        family.name.clone(),
+                        family.options.column_family_options(),
+                    ),
+                )?;
+            }
+            manifest.add_record_when_init(family.manifest_record(
+                ManifestRecord::Snapshot(
+                    snapshot.l0_sstable.clone(),
+                    snapshot.levels.clone(),
+                ),
+            ))?;
+        }
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    // links cannot cross file systems
+    if std::fs::hard_link(src, dst).is_err() {
+        std::fs::copy(src, dst).with_context(|| {
+            format!("failed to copy {} to checkpoint", src.display())
+        })?;
+        File::open(dst)?.sync_all()?;
+    }
+    Ok(())
+}
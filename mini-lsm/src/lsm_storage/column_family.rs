@@ -0,0 +1,298 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use anyhow::{Result, bail};
+use parking_lot::{Mutex, RwLock};
+
+use super::{ColumnFamilyOptions, LsmStorageInner, LsmStorageOptions};
+use crate::{
+    compact::CompactionController, lsm_storage::LsmStorageState,
+    manifest::ManifestRecord, mem_table::MemTable,
+};
+
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+pub(crate) const DEFAULT_COLUMN_FAMILY_ID: u32 = 0;
+
+/// A keyspace with its own memtables, levels and options. The memtables of
+/// all families are frozen together and log to one WAL, so that a write
+/// batch spanning several families is atomic.
+pub(crate) struct ColumnFamily {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
+    /// The engine options, with those of the family in place
+    pub(crate) options: Arc<LsmStorageOptions>,
+    pub(crate) compaction_controller: CompactionController,
+    /// Held while a compaction runs or SSTs are ingested, so that the
+    /// output of a compaction never overlaps an SST ingested meanwhile
+    pub(crate) compaction_lock: Mutex<()>,
+    /// Set under the state lock and the write lock once the family is
+    /// dropped
+    dropped: AtomicBool,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(
+        id: u32,
+        name: String,
+        state: LsmStorageState,
+        options: LsmStorageOptions,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            state: Arc::new(RwLock::new(Arc::new(state))),
+            compaction_controller: CompactionController::new(
+                &options.compaction_options,
+            ),
+            options: Arc::new(options),
+            compaction_lock: Mutex::new(()),
+            dropped: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn is_default(&self) -> bool {
+        self.id == DEFAULT_COLUMN_FAMILY_ID
+    }
+
+    pub(crate) fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Fail if the family was dropped, as its SSTs are gone.
+    pub(crate) fn check_not_dropped(&self) -> Result<()> {
+        if self.is_dropped() {
+            bail!("column family {} was dropped", self.name);
+        }
+        Ok(())
+    }
+
+    /// Wrap a record about the SSTs of this family, unless it is the
+    /// default one.
+    pub(crate) fn manifest_record(
+        &self,
+        record: ManifestRecord,
+    ) -> ManifestRecord {
+        if self.is_default() {
+            record
+        } else {
+            ManifestRecord::ColumnFamily(self.id, Box::new(record))
+        }
+    }
+}
+
+/// A handle to a column family, to read and write it through `MiniLsm`.
+/// It stays valid as long as the family is not dropped.
+#[derive(Clone)]
+pub struct ColumnFamilyHandle(pub(crate) Arc<ColumnFamily>);
+
+impl ColumnFamilyHandle {
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+}
+
+/// A column family as the manifest describes it, before its SSTs are
+/// opened and its WALs replayed.
+pub(crate) struct ReplayedFamily {
+    pub(crate) name: String,
+    pub(crate) options: LsmStorageOptions,
+    pub(crate) compaction_controller: CompactionController,
+    pub(crate) state: LsmStorageState,
+    /// The memtables whose WAL may hold writes of the family that are not
+    /// flushed yet
+    pub(crate) memtables: BTreeSet<usize>,
+}
+
+impl ReplayedFamily {
+    fn new(name: String, options: LsmStorageOptions) -> Self {
+        Self {
+            name,
+            compaction_controller: CompactionController::new(
+                &options.compaction_options,
+            ),
+            state: LsmStorageState::create(&options),
+            options,
+            memtables: BTreeSet::new(),
+        }
+    }
+}
+
+/// Replays the records of a manifest on the column families.
+pub(crate) struct ManifestReplay {
+    pub(crate) families: BTreeMap<u32, ReplayedFamily>,
+    /// The largest SST or memtable id the records mention
+    pub(crate) max_id: usize,
+    /// The largest column family id ever created, even if dropped since
+    pub(crate) max_family_id: u32,
+    options: LsmStorageOptions,
+    memtable: Option<usize>,
+}
+
+impl ManifestReplay {
+    /// Start with the default column family only, using `options`.
+    pub(crate) fn new(options: &LsmStorageOptions) -> Self {
+        let default =
+            ReplayedFamily::new(DEFAULT_COLUMN_FAMILY.into(), options.clone());
+        Self {
+            families: BTreeMap::from([(DEFAULT_COLUMN_FAMILY_ID, default)]),
+            max_id: 0,
+            max_family_id: DEFAULT_COLUMN_FAMILY_ID,
+            options: options.clone(),
+            memtable: None,
+        }
+    }
+
+    pub(crate) fn apply(&mut self, record: ManifestRecord) {
+        match record {
+            ManifestRecord::CreateColumnFamily(id, name, options) => {
+                let mut family = ReplayedFamily::new(
+                    name,
+                    self.options.with_column_family(&options),
+                );
+                // the family starts with a memtable logging to the
+                // current WAL
+                family.memtables.extend(self.memtable);
+                self.families.insert(id, family);
+                self.max_family_id = self.max_family_id.max(id);
+            }
+            ManifestRecord::DropColumnFamily(id) => {
+                self.families.remove(&id);
+            }
+            ManifestRecord::ColumnFamily(id, record) => {
+                let family = self
+                    .families
+                    .get_mut(&id)
+                    .unwrap_or_else(|| panic!("column family {id} not exist"));
+                let max_id = family.state.apply_manifest_record(
+                    *record,
+                    &family.compaction_controller,
+                    &mut family.memtables,
+                );
+                self.max_id = self.max_id.max(max_id);
+            }
+            ManifestRecord::NewMemtable(id) => {
+                for family in self.families.values_mut() {
+                    family.memtables.insert(id);
+                }
+                self.memtable = Some(id);
+                self.max_id = self.max_id.max(id);
+            }
+            record => {
+                let family =
+                    self.families.get_mut(&DEFAULT_COLUMN_FAMILY_ID).unwrap();
+                let max_id = family.state.apply_manifest_record(
+                    record,
+                    &family.compaction_controller,
+                    &mut family.memtables,
+                );
+                self.max_id = self.max_id.max(max_id);
+            }
+        }
+    }
+}
+
+impl LsmStorageInner {
+    pub(crate) fn column_family(
+        &self,
+        name: &str,
+    ) -> Option<Arc<ColumnFamily>> {
+        self.column_families
+            .read()
+            .values()
+            .find(|family| family.name == name)
+            .cloned()
+    }
+
+    /// The column families, starting with the default one.
+    pub(crate) fn column_families(&self) -> Vec<Arc<ColumnFamily>> {
+        self.column_families.read().values().cloned().collect()
+    }
+
+    /// Create an empty column family, whose memtable logs to the current
+    /// WAL like those of the others.
+    pub(crate) fn create_column_family(
+        &self,
+        name: &str,
+        options: &ColumnFamilyOptions,
+    ) -> Result<Arc<ColumnFamily>> {
+        // memtables are frozen under the state lock
+        let state_lock = self.state_lock.lock();
+        if self.column_family(name).is_some() {
+            bail!("column family {name} already exists");
+        }
+        let id = self.next_column_family_id.fetch_add(1, Ordering::SeqCst);
+        let options = self.options.with_column_family(options);
+        options.validate()?;
+        let mut state = LsmStorageState::create(&options);
+        state.memtable = Arc::new(MemTable::create_sharing_wal(
+            &self.default_family.state.read().memtable,
+        ));
+        self.manifest().add_record(
+            &state_lock,
+            ManifestRecord::CreateColumnFamily(
+                id,
+                name.to_string(),
+                options.column_family_options(),
+            ),
+        )?;
+        let family =
+            Arc::new(ColumnFamily::new(id, name.to_string(), state, options));
+        self.column_families.write().insert(id, family.clone());
+        Ok(family)
+    }
+
+    /// Drop a column family with its data. The WALs only it still needed
+    /// and its SSTs are removed.
+    pub(crate) fn drop_column_family(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_COLUMN_FAMILY {
+            bail!("the default column family cannot be dropped");
+        }
+        let state_lock = self.state_lock.lock();
+        let Some(family) = self.column_family(name) else {
+            bail!("column family {name} does not exist");
+        };
+        {
+            // no write batch is half applied to the family
+            let _write_lock = self.mvcc().write_lock.lock();
+            family.dropped.store(true, Ordering::SeqCst);
+            self.column_families.write().remove(&family.id);
+        }
+        self.manifest().add_record(
+            &state_lock,
+            ManifestRecord::DropColumnFamily(family.id),
+        )?;
+        let snapshot = family.state.read().clone();
+        if self.options.enable_wal {
+            for memtable in &snapshot.imm_memtables {
+                if !self.wal_in_use(memtable.id()) {
+                    std::fs::remove_file(self.path_of_wal(memtable.id()))?;
+                }
+            }
+        }
+        // readers still holding the family keep their file handles open
+        for sst_id in snapshot.sstables.keys() {
+            std::fs::remove_file(self.path_of_sst(*sst_id))?;
+        }
+        self.sync_dir()?;
+        Ok(())
+    }
+
+    /// Whether a column family still has to flush the memtable logging to
+    /// the WAL `id`. Call with the state lock held.
+    pub(crate) fn wal_in_use(&self, id: usize) -> bool {
+        self.column_families.read().values().any(|family| {
+            family
+                .state
+                .read()
+                .imm_memtables
+                .iter()
+                .any(|memtable| memtable.id() == id)
+        })
+    }
+}
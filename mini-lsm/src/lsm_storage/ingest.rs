@@ -0,0 +1,265 @@
+use std::{ops::Bound, path::Path, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+
+use super::{ColumnFamily, LsmStorageInner, LsmStorageState};
+use crate::{
+    compact::CompactionController,
+    iterators::StorageIterator,
+    key::{KeySlice, TS_RANGE_BEGIN, TS_RANGE_END},
+    manifest::ManifestRecord,
+    mvcc::CommittedTxnData,
+    table::{FileObject, SsTable, SsTableIterator},
+    value::StoredValue,
+};
+
+impl LsmStorageInner {
+    /// Ingest the SSTs written by `SstFileWriter` at `paths` into `family`,
+    /// as if their keys were written by one write batch. Each file is first
+    /// rewritten into a staged SST of the engine, without holding any lock.
+    /// Then the staged SSTs get a new commit ts, and each is added to the
+    /// lowest level no SST above overlaps. The files must not overlap each
+    /// other, and are left in place. Memtables overlapping them are flushed
+    /// first.
+    pub(crate) fn ingest_external_files<P: AsRef<Path>>(
+        &self,
+        family: &ColumnFamily,
+        paths: &[P],
+    ) -> Result<()> {
+        let mut files = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let table = SsTable::open(0, None, FileObject::open(path)?)
+                    .with_context(|| {
+                        format!("failed to open {}", path.display())
+                    })?;
+                if !table.range_tombstones().is_empty() {
+                    bail!("{} holds range tombstones", path.display());
+                }
+                if table.global_ts.is_some() {
+                    bail!("{} is an SST of an engine", path.display());
+                }
+                Ok(Arc::new(table))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if files.is_empty() {
+            return Ok(());
+        }
+        files.sort_by(|x, y| x.first_key().cmp(y.first_key()));
+        for pair in files.windows(2) {
+            if pair[0].last_key().key_ref() >= pair[1].first_key().key_ref() {
+                bail!("external files overlap");
+            }
+        }
+
+        let mut staged = Vec::with_capacity(files.len());
+        let result = self.stage_external_files(family, files, &mut staged);
+        let staged_ids = staged.iter().map(SsTable::sst_id).collect::<Vec<_>>();
+        let result =
+            result.and_then(|()| self.install_ingested_ssts(family, staged));
+        if result.is_err() {
+            for sst_id in staged_ids {
+                std::fs::remove_file(self.path_of_sst(sst_id))?;
+            }
+        }
+        result
+    }
+
+    /// Rewrite the files into SSTs of the engine, pushed to `staged`. They
+    /// are built with the options of the level they would be added to now,
+    /// which may change before they are.
+    fn stage_external_files(
+        &self,
+        family: &ColumnFamily,
+        files: Vec<Arc<SsTable>>,
+        staged: &mut Vec<SsTable>,
+    ) -> Result<()> {
+        let snapshot = family.state.read().clone();
+        for file in files {
+            let sst_id = self.next_sst_id();
+            let (_, options_level) = ingest_level(
+                &snapshot,
+                &family.compaction_controller,
+                &file,
+                sst_id,
+            );
+            staged.push(self.rewrite_external_file(
+                family,
+                file,
+                sst_id,
+                options_level,
+            )?);
+        }
+        // the staged SSTs must be durable before the manifest refers to them
+        self.sync_dir()
+    }
+
+    /// Give the staged SSTs a new commit ts and add them to the tree. Writes
+    /// only wait while the memtables overlapping them are flushed.
+    fn install_ingested_ssts(
+        &self,
+        family: &ColumnFamily,
+        staged: Vec<SsTable>,
+    ) -> Result<()> {
+        let _compaction_lock = family.compaction_lock.lock();
+        let state_lock = self.state_lock.lock();
+        family.check_not_dropped()?;
+        // the memtables are read before the SSTs, so they must not hold
+        // older versions of the keys ingested
+        let mut write_lock = loop {
+            let write_lock = self.mvcc().write_lock.lock();
+            if !memtables_overlap(&family.state.read(), &staged) {
+                break write_lock;
+            }
+            drop(write_lock);
+            self.force_freeze_memtable(&state_lock)?;
+            while !family.state.read().imm_memtables.is_empty() {
+                self.flush_next_imm_memtable(family, &state_lock)?;
+            }
+        };
+        *write_lock += 1;
+        let ts = *write_lock;
+
+        let mut snapshot = family.state.read().as_ref().clone();
+        let mut placements = Vec::with_capacity(staged.len());
+        for sst in staged {
+            let sst_id = sst.sst_id();
+            let sst = sst.set_global_ts(&self.path_of_sst(sst_id), ts)?;
+            let (level, _) = ingest_level(
+                &snapshot,
+                &family.compaction_controller,
+                &sst,
+                sst_id,
+            );
+            snapshot.sstables.insert(sst_id, Arc::new(sst));
+            snapshot.add_ingested_sst(sst_id, level);
+            if let Some((_, ssts)) = snapshot
+                .levels
+                .iter_mut()
+                .find(|(id, _)| Some(*id) == level)
+            {
+                ssts.sort_by(|x, y| {
+                    snapshot.sstables[x]
+                        .first_key()
+                        .cmp(snapshot.sstables[y].first_key())
+                });
+            }
+            placements.push((sst_id, level));
+        }
+
+        self.manifest().add_record(
+            &state_lock,
+            family.manifest_record(ManifestRecord::Ingest(placements)),
+        )?;
+        *family.state.write() = Arc::new(snapshot);
+        if self.options.serializable && family.is_default() {
+            self.mvcc().record_write(
+                ts,
+                CommittedTxnData {
+                    any_key: true,
+                    ..Default::default()
+                },
+            );
+        }
+        self.mvcc().update_commit_ts(ts);
+        Ok(())
+    }
+
+    /// Copy the keys of an external file into the SST `sst_id`, at ts 0
+    /// until it is given the ts of the ingestion.
+    fn rewrite_external_file(
+        &self,
+        family: &ColumnFamily,
+        file: Arc<SsTable>,
+        sst_id: usize,
+        level: usize,
+    ) -> Result<SsTable> {
+        let mut builder = family.options.sst_builder(level);
+        let mut iter = SsTableIterator::create_and_seek_to_first(file)?;
+        let mut last_key = Vec::new();
+        while iter.is_valid() {
+            let key = iter.key().key_ref();
+            if !last_key.is_empty() && key <= last_key.as_slice() {
+                bail!("keys of external file are not strictly increasing");
+            }
+            // pointers into another value log cannot be read here
+            if StoredValue::decode(iter.value())?
+                .is_some_and(|value| value.separated)
+            {
+                bail!("external file holds separated values");
+            }
+            builder.add(KeySlice::from_slice(key, 0), iter.value());
+            last_key.clear();
+            last_key.extend_from_slice(key);
+            iter.next()?;
+        }
+        builder.build(
+            sst_id,
+            Some(self.block_cache.clone()),
+            self.path_of_sst(sst_id),
+        )
+    }
+}
+
+/// Whether a memtable holds a key within the range of one of the files.
+fn memtables_overlap(snapshot: &LsmStorageState, files: &[SsTable]) -> bool {
+    std::iter::once(&snapshot.memtable)
+        .chain(snapshot.imm_memtables.iter())
+        .any(|memtable| {
+            files.iter().any(|file| {
+                memtable
+                    .scan(
+                        Bound::Included(KeySlice::from_slice(
+                            file.first_key().key_ref(),
+                            TS_RANGE_BEGIN,
+                        )),
+                        Bound::Included(KeySlice::from_slice(
+                            file.last_key().key_ref(),
+                            TS_RANGE_END,
+                        )),
+                    )
+                    .is_valid()
+            })
+        })
+}
+
+/// Where `file` is ingested: the last level or tier before the first one
+/// holding an overlapping SST, as `(id, level)`. The id is `None` for L0
+/// and `new_tier` for a new tier on top; `level` is the one whose options the
+/// SST is built with, chosen for tiers as for the output of compaction.
+fn ingest_level(
+    snapshot: &LsmStorageState,
+    compaction_controller: &CompactionController,
+    file: &SsTable,
+    new_tier: usize,
+) -> (Option<usize>, usize) {
+    let (first, last) = (file.first_key().key_ref(), file.last_key().key_ref());
+    let overlaps = |ssts: &[usize]| {
+        ssts.iter().any(|id| {
+            let sst = &snapshot.sstables[id];
+            sst.first_key().key_ref() <= last
+                && first <= sst.last_key().key_ref()
+        })
+    };
+    let tiered = !compaction_controller.flush_to_l0();
+    if !tiered && overlaps(&snapshot.l0_sstable) {
+        return (None, 0);
+    }
+    let num_levels = snapshot
+        .levels
+        .iter()
+        .take_while(|(_, ssts)| !overlaps(ssts))
+        .count();
+    if num_levels == 0 {
+        return (tiered.then_some(new_tier), 0);
+    }
+    let level = snapshot.levels[num_levels - 1].0;
+    match tiered {
+        false => (Some(level), level),
+        true if num_levels == snapshot.levels.len() => {
+            (Some(level), usize::MAX)
+        }
+        true => (Some(level), 1),
+    }
+}
@@ -1,24 +1,92 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    ops::Bound,
     path::{Path, PathBuf},
-    sync::{Arc, atomic::AtomicUsize},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use bytes::Bytes;
-use options::LsmStorageOptions;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
 
 use crate::{
-    block::Block, key, mem_table::MemTable, mvcc::LsmMvccInner, table::SsTable,
+    compact::{CompactionController, CompactionFilter},
+    iterators::{
+        StorageIterator, concat_iterator::SstConcatIterator,
+        merge_iterator::MergeIter, two_merge_iterator::TwoMergeIterator,
+    },
+    key::{KeySlice, TS_RANGE_BEGIN, TS_RANGE_END},
+    lsm_iterator::{FusedIterator, LsmIterator},
+    manifest::{Manifest, ManifestRecord},
+    mem_table::MemTable,
+    mvcc::{CommittedTxnData, LsmMvccInner, Transaction},
+    range_tombstone::{
+        RangeTombstone, RangeTombstoneFragments, max_covering_ts,
+    },
+    stats::{Metrics, MetricsServer},
+    table::{FileObject, SsTable, SsTableIterator},
+    value::{StoredValue, now_ms},
+    vlog::{ValueLog, ValueLogSnapshot, ValuePointer},
+    wal::Wal,
 };
 
+mod block_cache;
+mod checkpoint;
+mod column_family;
+mod ingest;
 mod options;
+mod vlog_gc;
+mod write_stall;
+pub use block_cache::BlockCache;
+pub(crate) use column_family::{
+    ColumnFamily, DEFAULT_COLUMN_FAMILY_ID, ManifestReplay,
+};
+pub use column_family::{ColumnFamilyHandle, DEFAULT_COLUMN_FAMILY};
+pub use options::{
+    ColumnFamilyOptions, LsmStorageOptions, SyncMode, ValueLogOptions,
+    WriteStallOptions,
+};
 
-pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 pub struct MiniLsm {
     pub(crate) inner: Arc<LsmStorageInner>,
+    /// Notifies the compaction thread to stop
+    compaction_notifier: crossbeam_channel::Sender<()>,
+    compaction_thread: Mutex<Option<JoinHandle<()>>>,
+    /// Notifies the WAL sync thread to stop
+    wal_sync_notifier: crossbeam_channel::Sender<()>,
+    wal_sync_thread: Mutex<Option<JoinHandle<()>>>,
+    /// Notifies the flush thread to stop
+    flush_notifier: crossbeam_channel::Sender<()>,
+    flush_thread: Mutex<Option<JoinHandle<()>>>,
+    /// Started by `serve_metrics`
+    pub(crate) metrics_servers: Mutex<Vec<MetricsServer>>,
+}
+
+impl Drop for MiniLsm {
+    fn drop(&mut self) {
+        self.stop_metrics_servers();
+        self.compaction_notifier.send(()).ok();
+        self.flush_notifier.send(()).ok();
+        self.wal_sync_notifier.send(()).ok();
+        // a flush or compaction left running would race with the next open
+        for thread in [
+            &self.compaction_thread,
+            &self.flush_thread,
+            &self.wal_sync_thread,
+        ] {
+            if let Some(thread) = thread.lock().take() {
+                thread.join().ok();
+            }
+        }
+    }
 }
+
 impl MiniLsm {
     /// Start the storage engine by either loading an existing directory
     /// or creating a new one if the directory does
@@ -27,43 +95,355 @@ impl MiniLsm {
         path: impl AsRef<Path>,
         options: &LsmStorageOptions,
     ) -> anyhow::Result<Arc<Self>> {
-        todo!()
+        let inner = Arc::new(LsmStorageInner::open(path, options)?);
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let compaction_thread = inner.spawn_compaction_thread(rx)?;
+        let (wal_sync_tx, wal_sync_rx) = crossbeam_channel::unbounded();
+        let wal_sync_thread = inner.spawn_wal_sync_thread(wal_sync_rx)?;
+        let (flush_tx, flush_rx) = crossbeam_channel::unbounded();
+        let flush_thread = inner.spawn_flush_thread(flush_rx)?;
+        Ok(Arc::new(Self {
+            inner,
+            compaction_notifier: tx,
+            compaction_thread: Mutex::new(compaction_thread),
+            wal_sync_notifier: wal_sync_tx,
+            wal_sync_thread: Mutex::new(wal_sync_thread),
+            flush_notifier: flush_tx,
+            flush_thread: Mutex::new(flush_thread),
+            metrics_servers: Mutex::new(Vec::new()),
+        }))
+    }
+
+    fn stop_metrics_servers(&self) {
+        for server in std::mem::take(&mut *self.metrics_servers.lock()) {
+            server.stop();
+        }
     }
 
+    /// Stop the background compaction, flushes and metrics servers, and make
+    /// everything acknowledged so far durable. Without a WAL, the memtables
+    /// are flushed to L0.
     pub fn close(&self) -> anyhow::Result<()> {
-        todo!()
+        self.stop_metrics_servers();
+        self.compaction_notifier.send(()).ok();
+        if let Some(compaction_thread) = self.compaction_thread.lock().take() {
+            compaction_thread
+                .join()
+                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        }
+        self.flush_notifier.send(()).ok();
+        if let Some(flush_thread) = self.flush_thread.lock().take() {
+            flush_thread
+                .join()
+                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        }
+        self.wal_sync_notifier.send(()).ok();
+        if let Some(wal_sync_thread) = self.wal_sync_thread.lock().take() {
+            wal_sync_thread
+                .join()
+                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        }
+
+        if self.inner.options.enable_wal {
+            self.inner.sync()?;
+            self.inner.sync_dir()?;
+            return Ok(());
+        }
+        if !self.inner.memtables_empty() {
+            let state_lock = self.inner.state_lock.lock();
+            self.inner.force_freeze_memtable(&state_lock)?;
+        }
+        for family in self.inner.column_families() {
+            while !family.state.read().imm_memtables.is_empty() {
+                self.inner.force_flush_next_imm_memtable(&family)?;
+            }
+        }
+        self.inner.sync_dir()?;
+        Ok(())
     }
 
     pub fn get(&self, key: &[u8]) -> anyhow::Result<Option<Bytes>> {
-        todo!()
+        self.inner.get(key)
     }
     pub fn put(&self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
-        todo!()
+        self.inner.put(key, value)
+    }
+    /// Put a value that `get` and `scan` hide once `ttl` has passed, and
+    /// that compaction then removes.
+    pub fn put_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> anyhow::Result<()> {
+        self.inner.put_with_ttl(key, value, ttl)
     }
     pub fn delete(&self, key: &[u8]) -> anyhow::Result<()> {
-        todo!()
+        self.inner.delete(key)
+    }
+    /// Delete every key in `[start, end)` with a single range tombstone.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> anyhow::Result<()> {
+        self.inner.delete_range(start, end)
+    }
+    /// Apply several puts and deletes atomically, with one commit ts.
+    pub fn write_batch<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+    ) -> anyhow::Result<()> {
+        self.inner.write_batch(batch)
+    }
+    /// Start a transaction reading from a snapshot of the current data.
+    pub fn new_txn(&self) -> anyhow::Result<Arc<Transaction>> {
+        self.inner.new_txn()
+    }
+    /// Iterate over the live keys in `[lower, upper]`, as of the moment the
+    /// iterator is created.
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> anyhow::Result<FusedIterator<LsmIterator>> {
+        self.inner.scan(lower, upper)
+    }
+
+    /// The commit ts of the latest write, to read the data as of now later
+    /// with `get_at` and `scan_at`.
+    pub fn latest_commit_ts(&self) -> u64 {
+        self.inner.mvcc().latest_commit_ts()
+    }
+    /// Read `key` as of commit ts `ts`, as returned by `latest_commit_ts`
+    /// at the time to read. Compaction only keeps the versions of the last
+    /// `LsmStorageOptions::retention_commits` commits, and reads of older
+    /// ones may fail with `LsmError::HistoryUnavailable`.
+    pub fn get_at(&self, key: &[u8], ts: u64) -> anyhow::Result<Option<Bytes>> {
+        self.inner.get_at(&self.inner.default_family, key, ts)
+    }
+    /// Iterate over the keys in `[lower, upper]` live as of commit ts `ts`,
+    /// which `get_at` restricts in the same way.
+    pub fn scan_at(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        ts: u64,
+    ) -> anyhow::Result<FusedIterator<LsmIterator>> {
+        self.inner
+            .scan_at(&self.inner.default_family, lower, upper, ts)
+    }
+
+    pub fn sync(&self) -> anyhow::Result<()> {
+        self.inner.sync()
+    }
+
+    /// Fraction of block reads served by the block cache.
+    pub fn block_cache_hit_rate(&self) -> f64 {
+        self.inner.block_cache.hit_rate()
+    }
+
+    pub fn force_freeze_memtable(&self) -> anyhow::Result<()> {
+        let state_lock = self.inner.state_lock.lock();
+        self.inner.force_freeze_memtable(&state_lock)
+    }
+
+    /// Freeze the memtables, and flush the earliest immutable memtable of
+    /// each column family.
+    pub fn force_flush(&self) -> anyhow::Result<()> {
+        if !self.inner.memtables_empty() {
+            self.force_freeze_memtable()?;
+        }
+        for family in self.inner.column_families() {
+            if !family.state.read().imm_memtables.is_empty() {
+                self.inner.force_flush_next_imm_memtable(&family)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Have compaction also remove the entries `filter` matches.
+    pub fn add_compaction_filter(&self, filter: CompactionFilter) {
+        self.inner.add_compaction_filter(filter)
+    }
+
+    /// Run one round of compaction of each column family on the calling
+    /// thread, where one is due.
+    pub fn force_compaction(&self) -> anyhow::Result<()> {
+        for family in self.inner.column_families() {
+            self.inner.trigger_compaction(&family)?;
+        }
+        Ok(())
+    }
+
+    /// Write a copy of the data acknowledged so far to the new directory
+    /// `dir`, which opens as an independent engine. SSTs are hard-linked,
+    /// so a checkpoint costs little space until compaction rewrites them.
+    pub fn checkpoint(&self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.inner.checkpoint(dir.as_ref())
+    }
+
+    /// Bulk-load the SSTs written by `SstFileWriter` at `paths`. Their keys
+    /// become visible at once, with one new commit ts, each file being
+    /// rewritten into the lowest level that no SST above overlaps. The
+    /// files must not overlap each other, and are left in place.
+    pub fn ingest_external_files<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+    ) -> anyhow::Result<()> {
+        self.inner
+            .ingest_external_files(&self.inner.default_family, paths)
+    }
+
+    /// Garbage-collect the oldest sealed segment of the value log holding
+    /// garbage, copying its live values to the active segment. Returns
+    /// whether a segment was removed; call it until it returns false to
+    /// collect all of them.
+    pub fn gc_value_log(&self) -> anyhow::Result<bool> {
+        self.inner.gc_value_log()
+    }
+
+    /// Create an empty column family. It is kept across restarts, along
+    /// with `options`, until dropped.
+    pub fn create_column_family(
+        &self,
+        name: &str,
+        options: &ColumnFamilyOptions,
+    ) -> anyhow::Result<ColumnFamilyHandle> {
+        let family = self.inner.create_column_family(name, options)?;
+        Ok(ColumnFamilyHandle(family))
+    }
+
+    /// Drop a column family and delete its data. Handles to it fail from
+    /// then on.
+    pub fn drop_column_family(&self, name: &str) -> anyhow::Result<()> {
+        self.inner.drop_column_family(name)
+    }
+
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamilyHandle> {
+        self.inner.column_family(name).map(ColumnFamilyHandle)
+    }
+
+    /// The names of the column families, starting with the default one.
+    pub fn list_column_families(&self) -> Vec<String> {
+        self.inner
+            .column_families()
+            .iter()
+            .map(|family| family.name.clone())
+            .collect()
+    }
+
+    pub fn get_cf(
+        &self,
+        cf: &ColumnFamilyHandle,
+        key: &[u8],
+    ) -> anyhow::Result<Option<Bytes>> {
+        self.inner.get_cf(&cf.0, key)
+    }
+    pub fn put_cf(
+        &self,
+        cf: &ColumnFamilyHandle,
+        key: &[u8],
+        value: &[u8],
+    ) -> anyhow::Result<()> {
+        self.write_batch_cf(&[(cf, WriteBatchRecord::Put(key, value))])
+    }
+    pub fn delete_cf(
+        &self,
+        cf: &ColumnFamilyHandle,
+        key: &[u8],
+    ) -> anyhow::Result<()> {
+        self.write_batch_cf(&[(cf, WriteBatchRecord::Del(key))])
+    }
+    /// Apply records to several column families atomically, with one
+    /// commit ts.
+    pub fn write_batch_cf<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&ColumnFamilyHandle, WriteBatchRecord<T>)],
+    ) -> anyhow::Result<()> {
+        let batch = batch
+            .iter()
+            .map(|(cf, record)| (cf.0.as_ref(), record))
+            .collect::<Vec<_>>();
+        self.inner.write_batch_cf(&batch)
+    }
+    pub fn ingest_external_files_cf<P: AsRef<Path>>(
+        &self,
+        cf: &ColumnFamilyHandle,
+        paths: &[P],
+    ) -> anyhow::Result<()> {
+        self.inner.ingest_external_files(&cf.0, paths)
+    }
+    pub fn scan_cf(
+        &self,
+        cf: &ColumnFamilyHandle,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> anyhow::Result<FusedIterator<LsmIterator>> {
+        self.inner.scan_cf(&cf.0, lower, upper)
+    }
+    pub fn get_at_cf(
+        &self,
+        cf: &ColumnFamilyHandle,
+        key: &[u8],
+        ts: u64,
+    ) -> anyhow::Result<Option<Bytes>> {
+        self.inner.get_at(&cf.0, key, ts)
+    }
+    pub fn scan_at_cf(
+        &self,
+        cf: &ColumnFamilyHandle,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        ts: u64,
+    ) -> anyhow::Result<FusedIterator<LsmIterator>> {
+        self.inner.scan_at(&cf.0, lower, upper, ts)
     }
-    // pub fn scan(&self,lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> anyhow::Result<()> {
-    //     todo!()
-    // }
 }
 
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
     Put(T, T),
+    /// A put that is no longer readable once the duration has passed
+    PutWithTtl(T, T, Duration),
     Del(T),
+    /// Deletes the keys in `[start, end)`
+    DelRange(T, T),
+}
+
+impl<T: AsRef<[u8]>> WriteBatchRecord<T> {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            WriteBatchRecord::Put(key, _)
+            | WriteBatchRecord::PutWithTtl(key, _, _)
+            | WriteBatchRecord::Del(key)
+            | WriteBatchRecord::DelRange(key, _) => key.as_ref(),
+        }
+    }
 }
 
 pub(crate) struct LsmStorageInner {
-    pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
+    /// Also found in `column_families`
+    pub(crate) default_family: Arc<ColumnFamily>,
+    /// The live column families by id
+    column_families: RwLock<BTreeMap<u32, Arc<ColumnFamily>>>,
+    next_column_family_id: AtomicU32,
+    /// Held to change the state of any column family
     pub(crate) state_lock: Mutex<()>,
     path: PathBuf,
     pub(crate) block_cache: Arc<BlockCache>,
     next_sst_id: AtomicUsize,
     pub(crate) options: Arc<LsmStorageOptions>,
-    // pub(crate) compaction_controller: CompactionController,
-    // pub(crate) manifest: Option<Manifest>,
+    pub(crate) manifest: Option<Manifest>,
+    /// Holds the values of at least `ValueLogOptions::min_value_size`
+    pub(crate) value_log: ValueLog,
     pub(crate) mvcc: Option<LsmMvccInner>,
-    // pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    /// Decide what compaction removes besides shadowed versions and expired
+    /// values. They are not persisted, so add them again after a restart.
+    pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    pub(crate) metrics: Metrics,
+    /// Writers stalled by `WriteStallOptions` wait on `stall_cond` for a
+    /// flush or a compaction
+    stall_lock: Mutex<()>,
+    stall_cond: Condvar,
+    /// Wakes up the flush thread when a memtable is frozen
+    flush_notifier: crossbeam_channel::Sender<()>,
+    flush_requests: crossbeam_channel::Receiver<()>,
 }
 
 impl LsmStorageInner {
@@ -71,46 +451,1100 @@ impl LsmStorageInner {
         path: impl AsRef<Path>,
         options: &LsmStorageOptions,
     ) -> anyhow::Result<Self> {
-        todo!()
+        options.validate()?;
+        let path = path.as_ref();
+        let mut replay = ManifestReplay::new(options);
+        let block_cache =
+            Arc::new(BlockCache::new(options.block_cache_capacity));
+        let mut next_sst_id = 1;
+        let mut last_commit_ts = 0;
+        if !path.exists() {
+            std::fs::create_dir_all(path).context("failed to create DB dir")?;
+        }
+        let manifest_path = path.join("MANIFEST");
+        let manifest = if !manifest_path.exists() {
+            let state = &mut replay
+                .families
+                .get_mut(&DEFAULT_COLUMN_FAMILY_ID)
+                .unwrap()
+                .state;
+            if options.enable_wal {
+                state.memtable = Arc::new(MemTable::create_with_wal(
+                    state.memtable.id(),
+                    Self::path_of_wal_static(path, state.memtable.id()),
+                )?);
+            }
+            let manifest = Manifest::create(&manifest_path)?;
+            manifest.add_record_when_init(ManifestRecord::NewMemtable(
+                state.memtable.id(),
+            ))?;
+            manifest
+        } else {
+            let (manifest, records) = Manifest::recover(&manifest_path)?;
+            for record in records {
+                replay.apply(record);
+            }
+            next_sst_id = next_sst_id.max(replay.max_id);
+
+            for family in replay.families.values_mut() {
+                let state = &mut family.state;
+                let sst_ids = state
+                    .l0_sstable
+                    .iter()
+                    .chain(state.levels.iter().flat_map(|(_, files)| files))
+                    .copied()
+                    .collect::<Vec<_>>();
+                for sst_id in sst_ids {
+                    let sst = SsTable::open(
+                        sst_id,
+                        Some(block_cache.clone()),
+                        FileObject::open_with_backend(
+                            Self::path_of_sst_static(path, sst_id),
+                            options.file_backend,
+                        )
+                        .with_context(|| {
+                            format!("failed to open SST {sst_id}")
+                        })?,
+                    )?;
+                    last_commit_ts = last_commit_ts.max(sst.max_ts());
+                    state.sstables.insert(sst_id, Arc::new(sst));
+                }
+                // levels replayed from compaction and ingestion records are
+                // not sorted yet
+                for (_, ssts) in &mut state.levels {
+                    ssts.sort_by(|x, y| {
+                        state.sstables[x]
+                            .first_key()
+                            .cmp(state.sstables[y].first_key())
+                    });
+                }
+            }
+
+            next_sst_id += 1;
+            if options.enable_wal {
+                last_commit_ts =
+                    last_commit_ts.max(Self::replay_wals(path, &mut replay)?);
+            }
+            let memtable = if options.enable_wal {
+                MemTable::create_with_wal(
+                    next_sst_id,
+                    Self::path_of_wal_static(path, next_sst_id),
+                )?
+            } else {
+                MemTable::create(next_sst_id)
+            };
+            for family in replay.families.values_mut() {
+                family.state.memtable =
+                    Arc::new(MemTable::create_sharing_wal(&memtable));
+            }
+            manifest.add_record_when_init(ManifestRecord::NewMemtable(
+                memtable.id(),
+            ))?;
+            next_sst_id += 1;
+            manifest
+        };
+
+        let column_families = replay
+            .families
+            .into_iter()
+            .map(|(id, family)| {
+                let family = ColumnFamily::new(
+                    id,
+                    family.name,
+                    family.state,
+                    family.options,
+                );
+                (id, Arc::new(family))
+            })
+            .collect::<BTreeMap<_, _>>();
+        // opened even without `value_log`, as values may have been kept there
+        // by a previous run
+        let value_log = ValueLog::open(
+            path,
+            options.value_log.clone().unwrap_or_default().segment_size,
+        )?;
+        let (flush_notifier, flush_requests) = crossbeam_channel::bounded(1);
+        let storage = Self {
+            default_family: column_families[&DEFAULT_COLUMN_FAMILY_ID].clone(),
+            column_families: RwLock::new(column_families),
+            next_column_family_id: AtomicU32::new(replay.max_family_id + 1),
+            state_lock: Mutex::new(()),
+            path: path.to_path_buf(),
+            block_cache,
+            next_sst_id: AtomicUsize::new(next_sst_id),
+            options: options.clone().into(),
+            manifest: Some(manifest),
+            value_log,
+            mvcc: Some(LsmMvccInner::new(
+                last_commit_ts,
+                options.retention_commits,
+            )),
+            compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            metrics: Metrics::new(),
+            stall_lock: Mutex::new(()),
+            stall_cond: Condvar::new(),
+            flush_notifier,
+            flush_requests,
+        };
+        storage.sync_dir()?;
+        Ok(storage)
     }
+
+    /// Replay the WALs holding writes that some column family has not
+    /// flushed into immutable memtables, returning the largest ts found.
+    fn replay_wals(path: &Path, replay: &mut ManifestReplay) -> Result<u64> {
+        let ids = replay
+            .families
+            .values()
+            .flat_map(|family| family.memtables.iter().copied())
+            .collect::<BTreeSet<_>>();
+        let mut max_ts = 0;
+        for id in ids {
+            let wal_path = Self::path_of_wal_static(path, id);
+            // only the default family is sure to have a memtable logging to
+            // every WAL, and the WAL is removed once all families flushed it
+            let default = &replay.families[&DEFAULT_COLUMN_FAMILY_ID];
+            if !default.memtables.contains(&id) && !wal_path.exists() {
+                continue;
+            }
+            let (wal, records) = Wal::recover(&wal_path)?;
+            let wal = Arc::new(wal);
+            let mut memtables = BTreeMap::new();
+            for record in records {
+                for (family_id, key, value) in record.entries {
+                    max_ts = max_ts.max(key.ts());
+                    // dropped, or flushed since
+                    if !replay
+                        .families
+                        .get(&family_id)
+                        .is_some_and(|family| family.memtables.contains(&id))
+                    {
+                        continue;
+                    }
+                    memtables
+                        .entry(family_id)
+                        .or_insert_with(|| {
+                            MemTable::create_with_recovered_wal(id, wal.clone())
+                        })
+                        .apply_batch(&[(key.as_key_slice(), &value)]);
+                }
+            }
+            // as at runtime, the default family keeps a memtable for every
+            // WAL until flushed, so that the WAL is removed with it
+            if replay.families[&DEFAULT_COLUMN_FAMILY_ID]
+                .memtables
+                .contains(&id)
+            {
+                memtables.entry(DEFAULT_COLUMN_FAMILY_ID).or_insert_with(
+                    || MemTable::create_with_recovered_wal(id, wal.clone()),
+                );
+            }
+            for (family_id, memtable) in memtables {
+                let family = replay.families.get_mut(&family_id).unwrap();
+                family.state.imm_memtables.insert(0, Arc::new(memtable));
+            }
+        }
+        Ok(max_ts)
+    }
+
     pub(crate) fn next_sst_id(&self) -> usize {
-        self.next_sst_id
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        self.next_sst_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub(crate) fn mvcc(&self) -> &LsmMvccInner {
+        self.mvcc.as_ref().unwrap()
+    }
+
+    pub(crate) fn manifest(&self) -> &Manifest {
+        self.manifest.as_ref().unwrap()
+    }
+
+    pub(crate) fn path_of_sst_static(
+        path: impl AsRef<Path>,
+        id: usize,
+    ) -> PathBuf {
+        path.as_ref().join(format!("{id:05}.sst"))
+    }
+
+    pub(crate) fn path_of_sst(&self, id: usize) -> PathBuf {
+        Self::path_of_sst_static(&self.path, id)
+    }
+
+    pub(crate) fn path_of_wal_static(
+        path: impl AsRef<Path>,
+        id: usize,
+    ) -> PathBuf {
+        path.as_ref().join(format!("{id:05}.wal"))
+    }
+
+    pub(crate) fn path_of_wal(&self, id: usize) -> PathBuf {
+        Self::path_of_wal_static(&self.path, id)
+    }
+
+    pub(crate) fn sync_dir(&self) -> Result<()> {
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Sync the WAL the memtables of all column families log to.
+    pub(crate) fn sync(&self) -> Result<()> {
+        // the WAL must not point to values that are not durable
+        self.value_log.sync()?;
+        self.default_family.state.read().memtable.sync_wal()
+    }
+
+    /// Whether the memtables of every column family are empty.
+    pub(crate) fn memtables_empty(&self) -> bool {
+        self.column_families()
+            .iter()
+            .all(|family| family.state.read().memtable.is_empty())
+    }
+
+    /// With `SyncMode::Interval`, sync the WAL in the background until `rx`
+    /// is notified.
+    pub(crate) fn spawn_wal_sync_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<JoinHandle<()>>> {
+        let SyncMode::Interval(interval) = self.options.sync_mode else {
+            return Ok(None);
+        };
+        if !self.options.enable_wal {
+            return Ok(None);
+        }
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(interval);
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => {
+                        if let Err(e) = this.sync() {
+                            tracing::error!("failed to sync WAL: {}", e);
+                        }
+                    }
+                    recv(rx) -> _ => return,
+                }
+            }
+        });
+        Ok(Some(handle))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get_cf(&self.default_family, key)
+    }
+
+    pub(crate) fn get_cf(
+        &self,
+        family: &ColumnFamily,
+        key: &[u8],
+    ) -> Result<Option<Bytes>> {
+        let start = Instant::now();
+        let value =
+            self.get_with_ts(family, key, self.mvcc().latest_commit_ts());
+        self.metrics.get_latency.record(start.elapsed());
+        value
+    }
+
+    /// Read `key` as of a past `ts`, which compaction may have collapsed
+    /// the versions of.
+    pub(crate) fn get_at(
+        &self,
+        family: &ColumnFamily,
+        key: &[u8],
+        ts: u64,
+    ) -> Result<Option<Bytes>> {
+        self.mvcc()
+            .with_read_ts(ts, || self.get_with_ts(family, key, ts))
     }
+
     pub(crate) fn get_with_ts(
         &self,
+        family: &ColumnFamily,
         key: &[u8],
         read_ts: u64,
     ) -> Result<Option<Bytes>> {
+        family.check_not_dropped()?;
+        // taken first, so that the values of the versions read below are
+        // not garbage-collected in between
+        let value_log = self.value_log.snapshot();
+        let snapshot = {
+            let guard = family.state.read();
+            Arc::clone(&guard)
+        }; // drop global lock here
+
+        let now = now_ms();
+        match self.get_stored(&snapshot, key, read_ts)? {
+            (Some((ts, value)), range_deleted_at)
+                if range_deleted_at
+                    .is_none_or(|deleted_at| ts >= deleted_at) =>
+            {
+                user_value(value, now, &value_log)
+            }
+            // versions older than a range tombstone covering the key are
+            // deleted
+            _ => Ok(None),
+        }
+    }
+
+    /// The newest version of `key` at `read_ts` as stored, with its ts, and
+    /// the ts of the newest range tombstone covering the key at `read_ts`.
+    pub(crate) fn get_stored(
+        &self,
+        snapshot: &LsmStorageState,
+        key: &[u8],
+        read_ts: u64,
+    ) -> Result<(Option<StoredVersion>, Option<u64>)> {
+        // Newer sources always hold newer versions, so the first version
+        // found from top to bottom is the newest one.
+        let memtables = std::iter::once(&snapshot.memtable)
+            .chain(snapshot.imm_memtables.iter());
+        let sst_ids = snapshot
+            .l0_sstable
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, files)| files))
+            .filter(|sst_id| key_within(key, &snapshot.sstables[sst_id]));
+
+        let range_deleted_at = memtables
+            .clone()
+            .filter_map(|memtable| memtable.range_tombstone_ts(key, read_ts))
+            .chain(sst_ids.clone().filter_map(|sst_id| {
+                let table = &snapshot.sstables[sst_id];
+                max_covering_ts(table.range_tombstones(), key, read_ts)
+            }))
+            .max();
+
+        for memtable in memtables {
+            if let Some(version) = memtable.get_visible(key, read_ts) {
+                return Ok((Some(version), range_deleted_at));
+            }
+        }
+
+        for sst_id in sst_ids {
+            let table = snapshot.sstables[sst_id].clone();
+            if !table.may_contain(key) {
+                self.metrics.bloom_useful.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let iter = SsTableIterator::create_and_seek_to_key(
+                table,
+                KeySlice::from_slice(key, read_ts),
+            )?;
+            if iter.is_valid() && iter.key().key_ref() == key {
+                let version =
+                    (iter.key().ts(), Bytes::copy_from_slice(iter.value()));
+                return Ok((Some(version), range_deleted_at));
+            }
+            // also counts SSTs holding only versions newer than `read_ts`,
+            // which only transactions read past
+            self.metrics
+                .bloom_false_positives
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        Ok((None, range_deleted_at))
+    }
+
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.scan_cf(&self.default_family, lower, upper)
+    }
+
+    pub(crate) fn scan_cf(
+        &self,
+        family: &ColumnFamily,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.scan_with_ts(family, lower, upper, self.mvcc().latest_commit_ts())
+    }
+
+    /// Scan as of a past `ts`. The iterator keeps the SSTs and the values
+    /// it reads once created, so `ts` only needs to be held until then.
+    pub(crate) fn scan_at(
+        &self,
+        family: &ColumnFamily,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        ts: u64,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.mvcc()
+            .with_read_ts(ts, || self.scan_with_ts(family, lower, upper, ts))
+    }
+
+    pub(crate) fn scan_with_ts(
+        &self,
+        family: &ColumnFamily,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_ts: u64,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        family.check_not_dropped()?;
+        // taken first, so that the values the iterator reads are not
+        // garbage-collected while it is open
+        let value_log = self.value_log.snapshot();
         let snapshot = {
-            let guard = self.state.read();
+            let guard = family.state.read();
             Arc::clone(&guard)
         }; // drop global lock here
-        todo!()
+
+        let mut range_tombstones = Vec::new();
+        let memtable_iters = std::iter::once(&snapshot.memtable)
+            .chain(snapshot.imm_memtables.iter())
+            .map(|memtable| {
+                range_tombstones.extend(memtable.range_tombstones());
+                Box::new(
+                    memtable
+                        .scan(map_lower_bound(lower), map_upper_bound(upper)),
+                )
+            })
+            .collect();
+        let memtable_iter = MergeIter::create(memtable_iters);
+
+        // SSTs outside of the bounds, or without the prefix of a prefix
+        // scan, are skipped without reading them
+        let mut overlapping_tables = |sst_ids: &[usize]| {
+            let mut tables = Vec::new();
+            for sst_id in sst_ids {
+                let table = &snapshot.sstables[sst_id];
+                if !range_overlap(lower, upper, table) {
+                    continue;
+                }
+                // they may delete keys of the prefix in lower levels
+                range_tombstones.extend_from_slice(table.range_tombstones());
+                if table.may_contain_range(lower, upper) {
+                    tables.push(table.clone());
+                } else {
+                    self.metrics
+                        .prefix_filter_useful
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            tables
+        };
+        let mut l0_iters = Vec::with_capacity(snapshot.l0_sstable.len());
+        for table in overlapping_tables(&snapshot.l0_sstable) {
+            l0_iters.push(Box::new(seek_table(table, lower)?));
+        }
+        let l0_iter = MergeIter::create(l0_iters);
+        let mut level_iters = Vec::with_capacity(snapshot.levels.len());
+        for (_, level_sst_ids) in &snapshot.levels {
+            let tables = overlapping_tables(level_sst_ids);
+            if !tables.is_empty() {
+                level_iters.push(Box::new(seek_level(tables, lower)?));
+            }
+        }
+        let levels_iter = MergeIter::create(level_iters);
+
+        let iter = TwoMergeIterator::create(
+            TwoMergeIterator::create(memtable_iter, l0_iter)?,
+            levels_iter,
+        )?;
+        let range_tombstones = RangeTombstoneFragments::new(
+            range_tombstones
+                .iter()
+                .filter(|tombstone| tombstone.ts <= read_ts),
+        );
+        Ok(FusedIterator::new(LsmIterator::new(
+            iter,
+            lower.map(Bytes::copy_from_slice),
+            upper.map(Bytes::copy_from_slice),
+            read_ts,
+            range_tombstones,
+            value_log,
+        )?))
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::Put(key, value)])
+    }
+
+    pub fn put_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::PutWithTtl(key, value, ttl)])
+    }
+
+    pub fn add_compaction_filter(&self, filter: CompactionFilter) {
+        self.compaction_filters.lock().push(filter);
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::Del(key)])
+    }
+
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::DelRange(start, end)])
+    }
+
+    /// Apply the records atomically, in order: a key written twice keeps
+    /// the last value.
+    pub fn write_batch<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+    ) -> Result<()> {
+        let batch = batch
+            .iter()
+            .map(|record| (self.default_family.as_ref(), record))
+            .collect::<Vec<_>>();
+        self.write_batch_cf(&batch)
+    }
+
+    /// Apply records to several column families atomically, in order.
+    pub(crate) fn write_batch_cf<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&ColumnFamily, &WriteBatchRecord<T>)],
+    ) -> Result<()> {
+        for (_, record) in batch {
+            match record {
+                WriteBatchRecord::Put(key, value)
+                | WriteBatchRecord::PutWithTtl(key, value, _) => {
+                    if key.as_ref().is_empty() {
+                        bail!("key cannot be empty");
+                    }
+                    if value.as_ref().is_empty() {
+                        bail!("value cannot be empty");
+                    }
+                }
+                WriteBatchRecord::Del(key) => {
+                    if key.as_ref().is_empty() {
+                        bail!("key cannot be empty");
+                    }
+                }
+                WriteBatchRecord::DelRange(start, end) => {
+                    if start.as_ref() >= end.as_ref() {
+                        bail!("range cannot be empty");
+                    }
+                }
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        self.write_batch_inner(batch, None)?;
+        self.metrics.put_latency.record(start.elapsed());
+        Ok(())
+    }
+
+    /// Write all the records with one new commit ts, returning it. They go
+    /// to the WAL as a single record, and readers see either all of them or
+    /// none. With `SyncMode::EveryWrite` they only become visible, and this
+    /// only returns, once they are durable. A serializable transaction
+    /// passes its read ts and read set, validated under the write lock.
+    pub(crate) fn write_batch_inner<T: AsRef<[u8]>>(
+        &self,
+        batch: &[(&ColumnFamily, &WriteBatchRecord<T>)],
+        reads: Option<(u64, &HashSet<u32>)>,
+    ) -> Result<u64> {
+        self.wait_for_write_stall()?;
+        let batch = &drop_range_deleted(batch);
+        let now = now_ms();
+        let min_separated_size = self
+            .options
+            .value_log
+            .as_ref()
+            .map(|options| options.min_value_size);
+        // the values going to the value log, with their expiry time
+        let mut separated = Vec::new();
+        let mut values = batch
+            .iter()
+            .enumerate()
+            .map(|(idx, (_, record))| {
+                let mut buf = Vec::new();
+                let (value, expire_at) = match record {
+                    WriteBatchRecord::Put(_, value) => (value, None),
+                    WriteBatchRecord::PutWithTtl(_, value, ttl) => {
+                        (value, Some(now + ttl.as_millis() as u64))
+                    }
+                    // an empty value marks a tombstone
+                    WriteBatchRecord::Del(_) => return buf,
+                    WriteBatchRecord::DelRange(_, end) => {
+                        return RangeTombstone::encode_wal_value(end.as_ref());
+                    }
+                };
+                if min_separated_size
+                    .is_some_and(|min| value.as_ref().len() >= min)
+                {
+                    // encoded once the value has a pointer
+                    separated.push((idx, value.as_ref(), expire_at));
+                    return buf;
+                }
+                StoredValue {
+                    expire_at,
+                    value: value.as_ref(),
+                    separated: false,
+                }
+                .encode(&mut buf);
+                buf
+            })
+            .collect::<Vec<_>>();
+        // lengths are stored as u16 in the WAL and the blocks
+        if batch.iter().zip(&values).any(|((_, record), value)| {
+            record.key().len() > u16::MAX as usize
+                || value.len() > u16::MAX as usize
+        }) {
+            bail!("key or value too large");
+        }
+        let mut write_lock = self.mvcc().write_lock.lock();
+        // families are dropped and their memtables frozen under the write
+        // lock, so every family of the batch logs to the same WAL
+        for (family, _) in batch {
+            family.check_not_dropped()?;
+        }
+        if let Some((read_ts, read_set)) = reads {
+            self.mvcc().validate_reads(read_ts, read_set)?;
+        }
+        *write_lock += 1;
+        let ts = *write_lock;
+        if !separated.is_empty() {
+            // the value log records the version of each value, for garbage
+            // collection to tell whether it is still live
+            let entries = separated
+                .iter()
+                .map(|(idx, value, _)| {
+                    let (family, record) = &batch[*idx];
+                    (family.id, KeySlice::from_slice(record.key(), ts), *value)
+                })
+                .collect::<Vec<_>>();
+            let pointers = self.value_log.append(&entries)?;
+            for ((idx, _, expire_at), pointer) in separated.iter().zip(pointers)
+            {
+                StoredValue {
+                    expire_at: *expire_at,
+                    value: &pointer.encode(),
+                    separated: true,
+                }
+                .encode(&mut values[*idx]);
+            }
+        }
+        let entries = batch
+            .iter()
+            .zip(&values)
+            .map(|((family, record), value)| {
+                (
+                    *family,
+                    KeySlice::from_slice(record.key(), ts),
+                    value.as_slice(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let wal_memtable = self.log_and_apply(&entries)?;
+        if self.options.serializable {
+            let mut txn_data = CommittedTxnData::default();
+            for (family, record) in batch {
+                if !family.is_default() {
+                    continue;
+                }
+                if matches!(record, WriteBatchRecord::DelRange(..)) {
+                    txn_data.any_key = true;
+                } else {
+                    txn_data.key_hashes.insert(farmhash::hash32(record.key()));
+                }
+            }
+            self.mvcc().record_write(ts, txn_data);
+        }
+        // the batch only becomes visible once the commit ts moves past it
+        if self.options.sync_mode == SyncMode::EveryWrite {
+            // let other writers append while waiting for the fsync, so that
+            // one fsync covers all of them
+            drop(write_lock);
+            if !separated.is_empty() {
+                self.value_log.sync()?;
+            }
+            wal_memtable.sync_wal()?;
+            self.mvcc().update_commit_ts(ts);
+        } else {
+            self.mvcc().update_commit_ts(ts);
+            drop(write_lock);
+        }
+        let mut families = Vec::<&ColumnFamily>::new();
+        for (family, _) in batch {
+            if !families.iter().any(|f| f.id == family.id) {
+                families.push(family);
+            }
+        }
+        self.try_freeze(&families)?;
+        Ok(ts)
+    }
+
+    /// Log the entries to the WAL as one record, then apply them to the
+    /// memtables of their families, returning the memtable logging to the
+    /// WAL. Call with the write lock held and none of the families dropped.
+    pub(crate) fn log_and_apply(
+        &self,
+        entries: &[(&ColumnFamily, KeySlice, &[u8])],
+    ) -> Result<Arc<MemTable>> {
+        let mut memtables =
+            Vec::<(&ColumnFamily, Arc<MemTable>, Vec<_>)>::new();
+        for (family, key, value) in entries {
+            let entry = (*key, *value);
+            match memtables.iter_mut().find(|(f, ..)| f.id == family.id) {
+                Some((_, _, data)) => data.push(entry),
+                None => {
+                    let memtable = family.state.read().memtable.clone();
+                    memtables.push((family, memtable, vec![entry]));
+                }
+            }
+        }
+        let wal_entries = memtables
+            .iter()
+            .flat_map(|(family, _, data)| {
+                data.iter().map(|(key, value)| (family.id, *key, *value))
+            })
+            .collect::<Vec<_>>();
+        let wal_memtable = memtables[0].1.clone();
+        wal_memtable.log_batch(&wal_entries)?;
+        for (_, memtable, data) in &memtables {
+            memtable.apply_batch(data);
+        }
+        Ok(wal_memtable)
+    }
+
+    pub(crate) fn new_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
+        Ok(self.mvcc().new_txn(self.clone(), self.options.serializable))
+    }
+
+    /// Freeze the memtables once the memtable of one of `families` reaches
+    /// the SST size of its family.
+    fn try_freeze(&self, families: &[&ColumnFamily]) -> Result<()> {
+        let memtable_full = || {
+            families.iter().any(|family| {
+                family.state.read().memtable.approximate_size()
+                    >= family.options.target_sst_size
+            })
+        };
+        if memtable_full() {
+            let state_lock = self.state_lock.lock();
+            // another writer may have frozen the memtable in the meantime
+            if memtable_full() {
+                self.force_freeze_memtable(&state_lock)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Freeze the current memtables of all column families into immutable
+    /// memtables and start new ones, logging to a new WAL.
+    pub(crate) fn force_freeze_memtable(
+        &self,
+        state_lock_observer: &MutexGuard<'_, ()>,
+    ) -> Result<()> {
+        let memtable_id = self.next_sst_id();
+        let memtable = if self.options.enable_wal {
+            MemTable::create_with_wal(
+                memtable_id,
+                self.path_of_wal(memtable_id),
+            )?
+        } else {
+            MemTable::create(memtable_id)
+        };
+        // record the WAL before anything is written to it, so that no
+        // acknowledged write lives in a WAL recovery does not know about
+        self.manifest().add_record(
+            state_lock_observer,
+            ManifestRecord::NewMemtable(memtable_id),
+        )?;
+        self.sync_dir()?;
+        self.freeze_memtable_with_memtable(memtable)?;
+        self.flush_notifier.try_send(()).ok();
+        Ok(())
+    }
+
+    fn freeze_memtable_with_memtable(&self, memtable: MemTable) -> Result<()> {
+        let families = self.column_families();
+        // wait for the writers that already picked their memtables, so that
+        // the new ones all log to the new WAL
+        let write_lock = self.mvcc().write_lock.lock();
+        for family in &families {
+            let mut guard = family.state.write();
+            // Swap the current memtable with a new one.
+            // care about this clone
+            // different snapshot not a same one
+            let mut snapshot = guard.as_ref().clone();
+            let old_memtable = std::mem::replace(
+                &mut snapshot.memtable,
+                Arc::new(MemTable::create_sharing_wal(&memtable)),
+            );
+            // The default family always keeps its memtable until flushed,
+            // so that the WAL outlives it; the others only when written to.
+            if family.is_default() || !old_memtable.is_empty() {
+                snapshot.imm_memtables.insert(0, old_memtable);
+            }
+            // Update the snapshot. Origin may be dropped
+            *guard = Arc::new(snapshot);
+        }
+        drop(write_lock);
+        // every family logged to the same WAL
+        self.value_log.sync()?;
+        self.default_family.state.read().imm_memtables[0].sync_wal()?;
+
+        Ok(())
+    }
+
+    /// Flush the earliest immutable memtable of `family` to an SST in L0, or
+    /// in a new tier with tiered compaction.
+    pub(crate) fn force_flush_next_imm_memtable(
+        &self,
+        family: &ColumnFamily,
+    ) -> Result<()> {
+        let state_lock = self.state_lock.lock();
+        self.flush_next_imm_memtable(family, &state_lock)
     }
 
-    fn freeze_memtable_with_memtable(
+    fn flush_next_imm_memtable(
         &self,
-        memtable: Arc<MemTable>,
+        family: &ColumnFamily,
+        state_lock: &MutexGuard<'_, ()>,
     ) -> Result<()> {
-        let mut guard = self.state.write();
-        // Swap the current memtable with a new one.
-        // care about this clone
-        // different snapshot not a same one
-        let mut snapshot = guard.as_ref().clone();
-        let old_memtable = std::mem::replace(&mut snapshot.memtable, memtable);
-        // Add the memtable to the immutable memtables.
-        snapshot.imm_memtables.insert(0, old_memtable.clone());
-        // Update the snapshot. Origin may be dropped
-        *guard = Arc::new(snapshot);
-
-        drop(guard);
-        // old_memtable.sync_wal()?;
+        if family.is_dropped() {
+            return Ok(());
+        }
+        let flush_memtable = {
+            let guard = family.state.read();
+            match guard.imm_memtables.last() {
+                Some(memtable) => memtable.clone(),
+                None => return Ok(()),
+            }
+        };
+
+        let memtable_id = flush_memtable.id();
+        // the default family freezes a memtable along with the others even
+        // if it was not written to
+        if flush_memtable.is_empty() {
+            {
+                let mut guard = family.state.write();
+                let mut snapshot = guard.as_ref().clone();
+                let memtable = snapshot.imm_memtables.pop().unwrap();
+                assert_eq!(memtable.id(), memtable_id);
+                *guard = Arc::new(snapshot);
+            }
+            let record = family.manifest_record(ManifestRecord::FlushMemtable(
+                memtable_id,
+                None,
+            ));
+            return self.finish_flush(memtable_id, record, state_lock);
+        }
+
+        let mut builder = family.options.sst_builder(0);
+        flush_memtable.flush(&mut builder)?;
+        // the memtables of the other families share the id of their WAL
+        let sst_id = if family.is_default() {
+            memtable_id
+        } else {
+            self.next_sst_id()
+        };
+        let sst = Arc::new(builder.build(
+            sst_id,
+            Some(self.block_cache.clone()),
+            self.path_of_sst(sst_id),
+        )?);
+        self.metrics.flushes.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .flushed_bytes
+            .fetch_add(sst.table_size(), Ordering::Relaxed);
+
+        {
+            let mut guard = family.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            let memtable = snapshot.imm_memtables.pop().unwrap();
+            assert_eq!(memtable.id(), memtable_id);
+            snapshot.add_flushed_sst(sst_id, &family.compaction_controller);
+            snapshot.sstables.insert(sst_id, sst);
+            *guard = Arc::new(snapshot);
+        }
+
+        let record = if family.is_default() {
+            ManifestRecord::Flush(sst_id)
+        } else {
+            family.manifest_record(ManifestRecord::FlushMemtable(
+                memtable_id,
+                Some(sst_id),
+            ))
+        };
+        self.finish_flush(memtable_id, record, state_lock)
+    }
+
+    /// Record the flush of the memtable `memtable_id`, then remove its WAL
+    /// unless it still holds writes of other families.
+    fn finish_flush(
+        &self,
+        memtable_id: usize,
+        record: ManifestRecord,
+        state_lock: &MutexGuard<'_, ()>,
+    ) -> Result<()> {
+        // the SSTs must not point to values that are not durable, and the
+        // WAL is only removed once the manifest no longer needs it
+        self.value_log.sync()?;
+        self.manifest().add_record(state_lock, record)?;
+        self.notify_stalled_writers();
+        if self.options.enable_wal && !self.wal_in_use(memtable_id) {
+            std::fs::remove_file(self.path_of_wal(memtable_id))?;
+        }
+        self.sync_dir()?;
 
         Ok(())
     }
 }
 
+/// A version of a key with its ts, as stored.
+type StoredVersion = (u64, Bytes);
+
+/// The user value held by a stored value, `None` if it is deleted or
+/// expired. Separated values are read from `value_log`.
+fn user_value(
+    raw: Bytes,
+    now: u64,
+    value_log: &ValueLogSnapshot,
+) -> Result<Option<Bytes>> {
+    let Some(value) =
+        StoredValue::decode(&raw)?.filter(|value| !value.is_expired(now))
+    else {
+        return Ok(None);
+    };
+    if value.separated {
+        return value_log
+            .read(&ValuePointer::decode(value.value)?)
+            .map(Some);
+    }
+    let header_len = raw.len() - value.value.len();
+    Ok(Some(raw.slice(header_len..)))
+}
+
+/// The records of `batch` not deleted by a later range tombstone of the
+/// batch. A range tombstone only covers versions older than its own, so
+/// it cannot delete the records written with it.
+fn drop_range_deleted<'a, T: AsRef<[u8]>>(
+    batch: &[(&'a ColumnFamily, &'a WriteBatchRecord<T>)],
+) -> Vec<(&'a ColumnFamily, &'a WriteBatchRecord<T>)> {
+    let mut ranges = Vec::new();
+    let mut kept = Vec::with_capacity(batch.len());
+    for &(family, record) in batch.iter().rev() {
+        if let WriteBatchRecord::DelRange(start, end) = record {
+            ranges.push((family.id, start.as_ref(), end.as_ref()));
+        } else if ranges.iter().any(|&(id, start, end)| {
+            id == family.id && start <= record.key() && record.key() < end
+        }) {
+            continue;
+        }
+        kept.push((family, record));
+    }
+    kept.reverse();
+    kept
+}
+
+fn key_within(key: &[u8], table: &SsTable) -> bool {
+    table.first_key().key_ref() <= key && key <= table.last_key().key_ref()
+}
+
+/// Whether the key range of `table` overlaps with `[lower, upper]`.
+fn range_overlap(
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    table: &SsTable,
+) -> bool {
+    let (first, last) =
+        (table.first_key().key_ref(), table.last_key().key_ref());
+    match upper {
+        Bound::Excluded(key) if key <= first => return false,
+        Bound::Included(key) if key < first => return false,
+        _ => {}
+    }
+    match lower {
+        Bound::Excluded(key) if key >= last => return false,
+        Bound::Included(key) if key > last => return false,
+        _ => {}
+    }
+    true
+}
+
+/// Position a table iterator at the first version of the first key within
+/// `lower`.
+fn seek_table(
+    table: Arc<SsTable>,
+    lower: Bound<&[u8]>,
+) -> Result<SsTableIterator> {
+    match lower {
+        Bound::Included(key) => SsTableIterator::create_and_seek_to_key(
+            table,
+            KeySlice::from_slice(key, TS_RANGE_BEGIN),
+        ),
+        Bound::Excluded(key) => {
+            let mut iter = SsTableIterator::create_and_seek_to_key(
+                table,
+                KeySlice::from_slice(key, TS_RANGE_BEGIN),
+            )?;
+            skip_key(&mut iter, key)?;
+            Ok(iter)
+        }
+        Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table),
+    }
+}
+
+/// Position an iterator over the sorted SSTs of a level at the first
+/// version of the first key within `lower`.
+fn seek_level(
+    tables: Vec<Arc<SsTable>>,
+    lower: Bound<&[u8]>,
+) -> Result<SstConcatIterator> {
+    match lower {
+        Bound::Included(key) => SstConcatIterator::create_and_seek_to_key(
+            tables,
+            KeySlice::from_slice(key, TS_RANGE_BEGIN),
+        ),
+        Bound::Excluded(key) => {
+            let mut iter = SstConcatIterator::create_and_seek_to_key(
+                tables,
+                KeySlice::from_slice(key, TS_RANGE_BEGIN),
+            )?;
+            skip_key(&mut iter, key)?;
+            Ok(iter)
+        }
+        Bound::Unbounded => SstConcatIterator::create_and_seek_to_first(tables),
+    }
+}
+
+/// Move `iter` past every version of `key`.
+fn skip_key<I>(iter: &mut I, key: &[u8]) -> Result<()>
+where
+    I: for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
+{
+    while iter.is_valid() && iter.key().key_ref() == key {
+        iter.next()?;
+    }
+    Ok(())
+}
+
+/// Map a user key lower bound to a bound covering every version of it.
+fn map_lower_bound(bound: Bound<&[u8]>) -> Bound<KeySlice<'_>> {
+    match bound {
+        Bound::Included(x) => {
+            Bound::Included(KeySlice::from_slice(x, TS_RANGE_BEGIN))
+        }
+        Bound::Excluded(x) => {
+            Bound::Excluded(KeySlice::from_slice(x, TS_RANGE_END))
+        }
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Map a user key upper bound to a bound covering every version of it.
+fn map_upper_bound(bound: Bound<&[u8]>) -> Bound<KeySlice<'_>> {
+    match bound {
+        Bound::Included(x) => {
+            Bound::Included(KeySlice::from_slice(x, TS_RANGE_END))
+        }
+        Bound::Excluded(x) => {
+            Bound::Excluded(KeySlice::from_slice(x, TS_RANGE_BEGIN))
+        }
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
 type MemTableRef = Arc<MemTable>;
 
 #[derive(Clone)]
@@ -127,7 +1561,108 @@ pub struct LsmStorageState {
 }
 
 impl LsmStorageState {
-    fn create(options: &LsmStorageOptions) -> Self {
-        todo!()
+    pub fn create(options: &LsmStorageOptions) -> Self {
+        let levels = (1..=options.compaction_options.num_levels())
+            .map(|level| (level, Vec::new()))
+            .collect();
+        Self {
+            memtable: Arc::new(MemTable::create(0)),
+            imm_memtables: Vec::new(),
+            l0_sstable: Vec::new(),
+            levels,
+            sstables: HashMap::new(),
+        }
+    }
+
+    /// Replay a manifest record on the SST ids of the levels, tracking the
+    /// memtables whose WAL is still needed in `memtables`. Returns the
+    /// largest id the record mentions.
+    pub fn apply_manifest_record(
+        &mut self,
+        record: ManifestRecord,
+        compaction_controller: &CompactionController,
+        memtables: &mut BTreeSet<usize>,
+    ) -> usize {
+        match record {
+            ManifestRecord::Flush(sst_id) => {
+                let removed = memtables.remove(&sst_id);
+                assert!(removed, "memtable {sst_id} not exist");
+                self.add_flushed_sst(sst_id, compaction_controller);
+                sst_id
+            }
+            ManifestRecord::FlushMemtable(memtable_id, sst_id) => {
+                // the memtables of a family are flushed in order, and those
+                // it was not written to are never flushed
+                memtables.retain(|id| *id > memtable_id);
+                if let Some(sst_id) = sst_id {
+                    self.add_flushed_sst(sst_id, compaction_controller);
+                }
+                sst_id.unwrap_or(memtable_id)
+            }
+            ManifestRecord::Compaction(task, output) => {
+                let (new_state, _) = compaction_controller
+                    .apply_compaction_result(self, &task, &output, true);
+                *self = new_state;
+                output.iter().max().copied().unwrap_or_default()
+            }
+            ManifestRecord::NewMemtable(id) => {
+                memtables.insert(id);
+                id
+            }
+            ManifestRecord::Snapshot(l0_sstables, levels) => {
+                let max_id = l0_sstables
+                    .iter()
+                    .chain(levels.iter().flat_map(|(_, ssts)| ssts))
+                    .max()
+                    .copied()
+                    .unwrap_or_default();
+                self.l0_sstable = l0_sstables;
+                self.levels = levels;
+                max_id
+            }
+            ManifestRecord::Ingest(placements) => {
+                for &(sst_id, level) in &placements {
+                    self.add_ingested_sst(sst_id, level);
+                }
+                placements.iter().map(|(sst_id, _)| *sst_id).max().unwrap()
+            }
+            ManifestRecord::CreateColumnFamily(..)
+            | ManifestRecord::DropColumnFamily(_)
+            | ManifestRecord::ColumnFamily(..) => {
+                unreachable!("{record:?} is about the column families")
+            }
+        }
+    }
+
+    /// Add a flushed SST to L0, or as a new tier with tiered compaction.
+    pub(crate) fn add_flushed_sst(
+        &mut self,
+        sst_id: usize,
+        compaction_controller: &CompactionController,
+    ) {
+        if compaction_controller.flush_to_l0() {
+            self.l0_sstable.insert(0, sst_id);
+        } else {
+            // a new tier holding the flushed SST only
+            self.levels.insert(0, (sst_id, vec![sst_id]));
+        }
+    }
+
+    /// Add an ingested SST to the level or tier with the id `level`,
+    /// creating the tier on top if there is none, or to L0 if `None`. The
+    /// level is left for the caller to sort.
+    pub(crate) fn add_ingested_sst(
+        &mut self,
+        sst_id: usize,
+        level: Option<usize>,
+    ) {
+        let Some(level) = level else {
+            self.l0_sstable.insert(0, sst_id);
+            return;
+        };
+        match self.levels.iter_mut().find(|(id, _)| *id == level) {
+            Some((_, ssts)) => ssts.push(sst_id),
+            None => self.levels.insert(0, (level, vec![sst_id])),
+        }
     }
 }
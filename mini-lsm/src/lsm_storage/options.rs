@@ -1,2 +1,260 @@
-pub struct LsmStorageOptions {}
-impl LsmStorageOptions {}
\ No newline at end of file
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compact::{CompactionOptions, LeveledCompactionOptions},
+    table::{
+        CompressionType, FileBackend, FilterPolicy, PrefixExtractor,
+        SsTableBuilder,
+    },
+    value::MAX_INLINE_VALUE_LEN,
+};
+
+/// When WAL writes are made durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Only on `sync`, `close` and memtable freezes. A crash of the machine
+    /// may lose acknowledged writes.
+    None,
+    /// Before every write returns. Concurrent writers share one fsync.
+    EveryWrite,
+    /// In the background at this interval, losing at most about that much
+    /// of the latest writes on a crash.
+    Interval(Duration),
+}
+
+/// When writes are slowed down or stopped, so that flushes and compaction
+/// keep up instead of memtables and L0 SSTs piling up.
+#[derive(Debug, Clone)]
+pub struct WriteStallOptions {
+    /// Writes stop while this many memtables wait to be flushed. Without
+    /// `background_flush`, only `force_flush` lets them resume.
+    pub max_imm_memtables: usize,
+    /// Each write is delayed by `slowdown_delay` while L0 holds at least
+    /// this many SSTs
+    pub l0_slowdown_trigger: usize,
+    /// Writes stop while L0 holds at least this many SSTs. Only applies to
+    /// compactions that empty L0, not to tiered compaction or none at all.
+    pub l0_stop_trigger: usize,
+    pub slowdown_delay: Duration,
+    /// How long a stopped write waits before failing with
+    /// `LsmError::WriteStall`. `None` waits until writes resume.
+    pub stop_timeout: Option<Duration>,
+}
+
+impl Default for WriteStallOptions {
+    fn default() -> Self {
+        Self {
+            max_imm_memtables: 4,
+            l0_slowdown_trigger: 20,
+            l0_stop_trigger: 36,
+            slowdown_delay: Duration::from_millis(1),
+            stop_timeout: None,
+        }
+    }
+}
+
+/// Which values are kept apart from the LSM tree, in the value log. Only
+/// pointers to them go through the WAL, flushes and compaction.
+#[derive(Debug, Clone)]
+pub struct ValueLogOptions {
+    /// Values of at least this many bytes go to the value log. Smaller ones
+    /// are kept inline, so it can be at most 65,527 bytes.
+    pub min_value_size: usize,
+    /// Segments are sealed at this size, and only sealed segments are
+    /// garbage-collected by `MiniLsm::gc_value_log`
+    pub segment_size: usize,
+}
+
+impl Default for ValueLogOptions {
+    fn default() -> Self {
+        Self {
+            min_value_size: 4 << 10,
+            segment_size: 64 << 20,
+        }
+    }
+}
+
+/// Options of the engine. The block size, SST size, compaction,
+/// compression and filters are those of the default column family, see
+/// [`ColumnFamilyOptions`].
+#[derive(Debug, Clone)]
+pub struct LsmStorageOptions {
+    /// Block size in bytes
+    pub block_size: usize,
+    /// SST size in bytes, also the approximate memtable capacity limit
+    pub target_sst_size: usize,
+    /// Maximum number of blocks held in the block cache
+    pub block_cache_capacity: u64,
+    /// How SST files are read
+    pub file_backend: FileBackend,
+    pub compaction_options: CompactionOptions,
+    /// Whether writes go through a WAL before they are applied to the memtable
+    pub enable_wal: bool,
+    /// When WAL writes are made durable
+    pub sync_mode: SyncMode,
+    /// Whether transactions validate their read set on commit
+    pub serializable: bool,
+    /// The block compression of each level, starting at L0. Deeper levels
+    /// use the last entry, and no entry means no compression.
+    pub compression_per_level: Vec<CompressionType>,
+    /// The filter of the keys of each SST
+    pub filter_policy: FilterPolicy,
+    /// Also keep a filter of key prefixes in each SST, for prefix scans
+    pub prefix_extractor: Option<PrefixExtractor>,
+    /// Whether a background thread flushes the immutable memtables, instead
+    /// of only `force_flush` and `close`
+    pub background_flush: bool,
+    /// Backpressure on writers, `None` to never stall writes
+    pub write_stall: Option<WriteStallOptions>,
+    /// Keep large values in a value log, `None` to store every value in the
+    /// LSM tree
+    pub value_log: Option<ValueLogOptions>,
+    /// Compaction keeps the versions of the last this many commit
+    /// timestamps, so that `get_at` and `scan_at` can read them. Each write
+    /// batch and transaction commits at the next ts. This counts commits,
+    /// not time: to read the data as of a point in time later, record
+    /// `MiniLsm::latest_commit_ts` at that time.
+    pub retention_commits: u64,
+}
+
+/// The options a column family does not share with the rest of the engine.
+/// They are kept in the manifest, so a family always reopens with the
+/// options it was created with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnFamilyOptions {
+    pub block_size: usize,
+    pub target_sst_size: usize,
+    pub compaction_options: CompactionOptions,
+    pub compression_per_level: Vec<CompressionType>,
+    #[serde(default)]
+    pub filter_policy: FilterPolicy,
+    #[serde(default)]
+    pub prefix_extractor: Option<PrefixExtractor>,
+}
+
+impl LsmStorageOptions {
+    /// The options of the default column family, as a base for others.
+    pub fn column_family_options(&self) -> ColumnFamilyOptions {
+        ColumnFamilyOptions {
+            block_size: self.block_size,
+            target_sst_size: self.target_sst_size,
+            compaction_options: self.compaction_options.clone(),
+            compression_per_level: self.compression_per_level.clone(),
+            filter_policy: self.filter_policy,
+            prefix_extractor: self.prefix_extractor,
+        }
+    }
+
+    /// These options with those of a column family in place of the ones
+    /// of the default family.
+    pub(crate) fn with_column_family(
+        &self,
+        family: &ColumnFamilyOptions,
+    ) -> Self {
+        Self {
+            block_size: family.block_size,
+            target_sst_size: family.target_sst_size,
+            compaction_options: family.compaction_options.clone(),
+            compression_per_level: family.compression_per_level.clone(),
+            filter_policy: family.filter_policy,
+            prefix_extractor: family.prefix_extractor,
+            ..self.clone()
+        }
+    }
+
+    /// Check that the blocks and the values kept inline fit the u16
+    /// offsets and lengths they are stored with.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.block_size > u16::MAX as usize + 1 {
+            bail!("block size cannot exceed 64 KiB");
+        }
+        if let Some(value_log) = &self.value_log
+            && value_log.min_value_size > MAX_INLINE_VALUE_LEN + 1
+        {
+            bail!(
+                "min value size of the value log cannot exceed {}",
+                MAX_INLINE_VALUE_LEN + 1
+            );
+        }
+        Ok(())
+    }
+
+    /// options used by the tests
+    pub fn default_for_test() -> Self {
+        Self {
+            block_size: 4096,
+            target_sst_size: 2 << 20,
+            block_cache_capacity: 1 << 10,
+            file_backend: FileBackend::Buffered,
+            compaction_options: CompactionOptions::NoCompaction,
+            enable_wal: true,
+            sync_mode: SyncMode::None,
+            serializable: true,
+            compression_per_level: Vec::new(),
+            filter_policy: FilterPolicy::Bloom,
+            prefix_extractor: None,
+            background_flush: false,
+            write_stall: None,
+            value_log: None,
+            retention_commits: 0,
+        }
+    }
+
+    pub(crate) fn compression_for_level(
+        &self,
+        level: usize,
+    ) -> CompressionType {
+        self.compression_per_level
+            .get(level)
+            .or(self.compression_per_level.last())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// A builder for the SSTs of `level`.
+    pub(crate) fn sst_builder(&self, level: usize) -> SsTableBuilder {
+        SsTableBuilder::new(self.block_size)
+            .with_compression(self.compression_for_level(level))
+            .with_filter_policy(self.filter_policy)
+            .with_prefix_extractor(self.prefix_extractor)
+            .with_file_backend(self.file_backend)
+    }
+}
+
+impl Default for LsmStorageOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            target_sst_size: 64 << 20,
+            block_cache_capacity: 1 << 16,
+            file_backend: FileBackend::Buffered,
+            compaction_options: CompactionOptions::Leveled(
+                LeveledCompactionOptions {
+                    level_size_multiplier: 10,
+                    level0_file_num_compaction_trigger: 4,
+                    max_levels: 6,
+                    base_level_size_mb: 256,
+                },
+            ),
+            enable_wal: true,
+            sync_mode: SyncMode::EveryWrite,
+            serializable: true,
+            // recent data is rewritten soon, so only compress it cheaply
+            compression_per_level: vec![
+                CompressionType::None,
+                CompressionType::Lz4,
+                CompressionType::Lz4,
+                CompressionType::Zstd,
+            ],
+            filter_policy: FilterPolicy::Bloom,
+            prefix_extractor: None,
+            background_flush: true,
+            write_stall: Some(WriteStallOptions::default()),
+            value_log: Some(ValueLogOptions::default()),
+            retention_commits: 0,
+        }
+    }
+}
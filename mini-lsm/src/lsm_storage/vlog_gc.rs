@@ -0,0 +1,186 @@
+use anyhow::Result;
+
+use super::{ColumnFamily, LsmStorageInner};
+use crate::{
+    value::{StoredValue, now_ms},
+    vlog::{ValueLog, ValueLogRecord, ValuePointer},
+};
+
+/// What garbage collection does with a value of the segment it collects.
+enum Liveness {
+    /// The newest version of its key: copied to the active segment
+    Live(Option<u64>),
+    /// No reader can see it anymore
+    Dead,
+    /// A newer version shadows it, but not for every reader yet
+    Visible,
+}
+
+/// A live value to copy, with the expiry of its version.
+type LiveValue = (ValueLogRecord, Option<u64>);
+
+impl LsmStorageInner {
+    /// Collect the oldest sealed segment of the value log holding garbage.
+    /// Its live values are appended to the active segment and written back
+    /// to the LSM tree with the versions they belong to, then the segment
+    /// is removed. Returns whether a segment was collected: segments holding
+    /// only live values are skipped, since collecting them would only move
+    /// the values, as are those holding versions some reader may still see.
+    pub(crate) fn gc_value_log(&self) -> Result<bool> {
+        let _gc_lock = self.value_log.gc_lock.lock();
+        for segment in self.value_log.sealed_segments() {
+            let Some(live) = self.collectable_values(segment)? else {
+                continue;
+            };
+            if !live.is_empty() {
+                self.rewrite_values(&live)?;
+            }
+            // the copies must be durable before the originals are gone
+            self.sync()?;
+            self.value_log.remove_segment(segment)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// The live values of `segment`, if it holds garbage and no version a
+    /// reader may still see.
+    fn collectable_values(
+        &self,
+        segment: u32,
+    ) -> Result<Option<Vec<LiveValue>>> {
+        let records =
+            ValueLog::read_segment(self.value_log.path_of_segment(segment))?;
+        let num_records = records.len();
+        let mut live = Vec::new();
+        for record in records {
+            match self.value_liveness(&record)? {
+                Liveness::Live(expire_at) => live.push((record, expire_at)),
+                Liveness::Dead => {}
+                Liveness::Visible => return Ok(None),
+            }
+        }
+        Ok((live.len() < num_records).then_some(live))
+    }
+
+    fn value_liveness(&self, record: &ValueLogRecord) -> Result<Liveness> {
+        let Some(family) =
+            self.column_families.read().get(&record.family).cloned()
+        else {
+            // the family was dropped
+            return Ok(Liveness::Dead);
+        };
+        let snapshot = family.state.read().clone();
+        let latest = self.mvcc().latest_commit_ts();
+        let (version, range_deleted_at) =
+            self.get_stored(&snapshot, record.key.key_ref(), latest)?;
+        let ts = record.key.ts();
+        // the earliest ts at which a newer version or a range tombstone may
+        // hide the value
+        let mut shadowed_at =
+            range_deleted_at.filter(|deleted_at| *deleted_at > ts);
+        match version {
+            Some((version_ts, raw)) if version_ts == ts => {
+                // otherwise already copied by an interrupted collection
+                let Some(value) = separated_at(&raw, &record.pointer)? else {
+                    return Ok(Liveness::Dead);
+                };
+                if value.is_expired(now_ms()) {
+                    return Ok(Liveness::Dead);
+                }
+                if shadowed_at.is_none() {
+                    return Ok(Liveness::Live(value.expire_at));
+                }
+            }
+            Some((version_ts, _)) if version_ts > ts => {
+                shadowed_at = Some(
+                    shadowed_at.map_or(version_ts, |at| at.min(version_ts)),
+                );
+            }
+            // compaction removed the version
+            _ => {}
+        }
+        Ok(match shadowed_at {
+            Some(ts) if ts > self.mvcc().gc_watermark() => Liveness::Visible,
+            _ => Liveness::Dead,
+        })
+    }
+
+    /// Copy the values to the active segment, and point their versions at
+    /// the copies. The copies keep the ts of the originals, so that they
+    /// shadow them in every merge.
+    fn rewrite_values(&self, live: &[LiveValue]) -> Result<()> {
+        let entries = live
+            .iter()
+            .map(|(record, _)| {
+                (record.family, record.key.as_key_slice(), &record.value[..])
+            })
+            .collect::<Vec<_>>();
+        let pointers = self.value_log.append(&entries)?;
+        let values = live
+            .iter()
+            .zip(&pointers)
+            .map(|((_, expire_at), pointer)| {
+                let mut buf = Vec::new();
+                StoredValue {
+                    expire_at: *expire_at,
+                    value: &pointer.encode(),
+                    separated: true,
+                }
+                .encode(&mut buf);
+                buf
+            })
+            .collect::<Vec<_>>();
+
+        let write_lock = self.mvcc().write_lock.lock();
+        let latest = self.mvcc().latest_commit_ts();
+        let mut rewrites = Vec::new();
+        for ((record, _), value) in live.iter().zip(&values) {
+            let Some(family) =
+                self.column_families.read().get(&record.family).cloned()
+            else {
+                continue;
+            };
+            // a write may have shadowed the value since it was checked
+            let snapshot = family.state.read().clone();
+            let (version, _) =
+                self.get_stored(&snapshot, record.key.key_ref(), latest)?;
+            if let Some((ts, raw)) = version
+                && ts == record.key.ts()
+                && separated_at(&raw, &record.pointer)?.is_some()
+            {
+                rewrites.push((family, record.key.as_key_slice(), value));
+            }
+        }
+        if rewrites.is_empty() {
+            return Ok(());
+        }
+        let entries = rewrites
+            .iter()
+            .map(|(family, key, value)| (family.as_ref(), *key, &value[..]))
+            .collect::<Vec<_>>();
+        self.log_and_apply(&entries)?;
+        drop(write_lock);
+        let mut families = Vec::<&ColumnFamily>::new();
+        for (family, ..) in &entries {
+            if !families.iter().any(|f| f.id == family.id) {
+                families.push(family);
+            }
+        }
+        self.try_freeze(&families)
+    }
+}
+
+/// The stored value `raw` if it is the separated value at `pointer`.
+fn separated_at<'a>(
+    raw: &'a [u8],
+    pointer: &ValuePointer,
+) -> Result<Option<StoredValue<'a>>> {
+    let Some(value) = StoredValue::decode(raw)? else {
+        return Ok(None);
+    };
+    if !value.separated || ValuePointer::decode(value.value)? != *pointer {
+        return Ok(None);
+    }
+    Ok(Some(value))
+}
@@ -0,0 +1,136 @@
+use std::{
+    sync::{Arc, atomic::Ordering},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use super::LsmStorageInner;
+use crate::{compact::CompactionController, error::LsmError};
+
+impl LsmStorageInner {
+    /// Delay the calling writer while L0 is past the slowdown trigger, and
+    /// block it while too many memtables or L0 SSTs wait for the background
+    /// threads.
+    pub(crate) fn wait_for_write_stall(&self) -> Result<()> {
+        let Some(options) = &self.options.write_stall else {
+            return Ok(());
+        };
+        let start = Instant::now();
+        let deadline = options.stop_timeout.map(|timeout| start + timeout);
+        let mut stalled = false;
+        let mut stall_lock = self.stall_lock.lock();
+        let l0_sstables = loop {
+            let (imm_memtables, l0_sstables) = self.stall_counts();
+            if imm_memtables < options.max_imm_memtables
+                && l0_sstables < options.l0_stop_trigger
+            {
+                break l0_sstables;
+            }
+            stalled = true;
+            self.flush_notifier.try_send(()).ok();
+            match deadline {
+                Some(deadline) if Instant::now() >= deadline => {
+                    self.record_write_stall(start.elapsed());
+                    return Err(LsmError::WriteStall {
+                        imm_memtables,
+                        l0_sstables,
+                    }
+                    .into());
+                }
+                Some(deadline) => {
+                    self.stall_cond.wait_until(&mut stall_lock, deadline);
+                }
+                None => self.stall_cond.wait(&mut stall_lock),
+            }
+        };
+        drop(stall_lock);
+        if l0_sstables >= options.l0_slowdown_trigger {
+            std::thread::sleep(options.slowdown_delay);
+            stalled = true;
+        }
+        if stalled {
+            self.record_write_stall(start.elapsed());
+        }
+        Ok(())
+    }
+
+    /// The memtables waiting to be flushed and the SSTs in L0 that count
+    /// towards the stall thresholds, the largest of any column family.
+    fn stall_counts(&self) -> (usize, usize) {
+        let mut counts = (0, 0);
+        for family in self.column_families() {
+            let snapshot = family.state.read();
+            // nothing empties L0 of tiered compaction or without compaction
+            let controller = &family.compaction_controller;
+            let l0_compacted = controller.flush_to_l0()
+                && !matches!(controller, CompactionController::NoCompaction);
+            let l0_sstables = if l0_compacted {
+                snapshot.l0_sstable.len()
+            } else {
+                0
+            };
+            counts.0 = counts.0.max(snapshot.imm_memtables.len());
+            counts.1 = counts.1.max(l0_sstables);
+        }
+        counts
+    }
+
+    fn record_write_stall(&self, stalled: Duration) {
+        self.metrics.stalled_writes.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .write_stall_micros
+            .fetch_add(stalled.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Wake up the writers stalled in `wait_for_write_stall` after a flush
+    /// or a compaction, to check the thresholds again.
+    pub(crate) fn notify_stalled_writers(&self) {
+        let _stall_lock = self.stall_lock.lock();
+        self.stall_cond.notify_all();
+    }
+
+    /// With `background_flush`, flush the immutable memtables in the
+    /// background until `rx` is notified. The thread wakes up periodically
+    /// and whenever a memtable is frozen.
+    pub(crate) fn spawn_flush_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<JoinHandle<()>>> {
+        if !self.options.background_flush {
+            return Ok(None);
+        }
+        let this = self.clone();
+        let flush_requests = self.flush_requests.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => {}
+                    recv(flush_requests) -> _ => {}
+                    recv(rx) -> _ => return,
+                }
+                if let Err(e) = this.trigger_flush() {
+                    tracing::error!("flush failed: {}", e);
+                }
+            }
+        });
+        Ok(Some(handle))
+    }
+
+    /// Freeze the memtables if one reached the SST size of its family, then
+    /// flush every immutable memtable.
+    fn trigger_flush(&self) -> Result<()> {
+        let families = self.column_families();
+        self.try_freeze(&families.iter().map(Arc::as_ref).collect::<Vec<_>>())?;
+        for family in &families {
+            while !family.is_dropped()
+                && !family.state.read().imm_memtables.is_empty()
+            {
+                self.force_flush_next_imm_memtable(family)?;
+            }
+        }
+        Ok(())
+    }
+}
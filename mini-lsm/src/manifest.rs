@@ -2,22 +2,47 @@ use std::{
     fs::{File, OpenOptions},
     io::{Read, Write},
     path::Path,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{Arc, Mutex},
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use bytes::Buf;
+use parking_lot::MutexGuard;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    compact::CompactionTask, error::LsmError, lsm_storage::ColumnFamilyOptions,
+};
+
 pub struct Manifest {
     file: Arc<Mutex<File>>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// A change to the SSTs or memtables. Records about the SSTs apply to the
+/// default column family unless wrapped in `ColumnFamily`.
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ManifestRecord {
     Flush(usize),
+    /// A new memtable in every column family, all logging to one WAL
     NewMemtable(usize),
-    // Compaction(CompactionTask, Vec<usize>),
+    Compaction(CompactionTask, Vec<usize>),
+    /// The whole SST layout, replacing what earlier records built: the L0
+    /// SSTs, then each level or tier as `(id, ssts)`. Written first in the
+    /// manifest of a checkpoint.
+    Snapshot(Vec<usize>, Vec<(usize, Vec<usize>)>),
+    /// A column family `(id, name, options)`, created empty
+    CreateColumnFamily(u32, String, ColumnFamilyOptions),
+    DropColumnFamily(u32),
+    /// A record about the SSTs of the column family with this id
+    ColumnFamily(u32, Box<ManifestRecord>),
+    /// The memtable `.0` flushed to the SST `.1`, or dropped if empty. The
+    /// memtables of all column families share the id of their WAL, so the
+    /// SSTs of families other than the default one cannot.
+    FlushMemtable(usize, Option<usize>),
+    /// SSTs ingested from external files, as `(sst_id, level)`: the id of
+    /// the level or tier the SST was added to, `None` for L0. A tier id not
+    /// in use yet is a new tier on top.
+    Ingest(Vec<(usize, Option<usize>)>),
 }
 
 impl Manifest {
@@ -38,7 +63,7 @@ impl Manifest {
     /// add a record in manifest
     pub fn add_record(
         &self,
-        _state_lock_observer: &MutexGuard<()>,
+        _state_lock_observer: &MutexGuard<'_, ()>,
         record: ManifestRecord,
     ) -> Result<()> {
         self.add_record_when_init(record)
@@ -51,14 +76,18 @@ impl Manifest {
         let hash = crc32fast::hash(&buf);
         file.write_all(&(buf.len() as u64).to_be_bytes())?;
         file.write_all(&buf)?;
+        file.write_all(&hash.to_be_bytes())?;
         file.sync_all()?;
         Ok(())
     }
 
-    /// recover a manifest from disk
+    /// Recover a manifest from disk. A torn record at the end of the file,
+    /// left by a crash during a write, is truncated away; a damaged record
+    /// anywhere else is reported as [`LsmError::Corruption`].
     pub fn recover(
         path: impl AsRef<Path>,
     ) -> Result<(Self, Vec<ManifestRecord>)> {
+        let path = path.as_ref();
         let mut file = OpenOptions::new()
             .read(true)
             .append(true)
@@ -66,18 +95,15 @@ impl Manifest {
             .context("failed to recover menifest")?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
-        let mut buf_ptr = buf.as_slice();
-        let mut records = Vec::new();
-        while buf_ptr.has_remaining() {
-            let len = buf_ptr.get_u64();
-            let slice = &buf_ptr[..len as usize];
-            let json = serde_json::from_slice::<ManifestRecord>(slice)?;
-            buf_ptr.advance(len as usize);
-            let checksum = buf_ptr.get_u32();
-            if checksum != crc32fast::hash(slice) {
-                bail!("manifest checksum mismatched");
-            }
-            records.push(json);
+        let (records, valid_len) = decode_records(path, &buf)?;
+        if valid_len < buf.len() as u64 {
+            tracing::warn!(
+                "truncating torn manifest tail of {} at offset {}",
+                path.display(),
+                valid_len
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
         }
         Ok((
             Self {
@@ -86,4 +112,51 @@ impl Manifest {
             records,
         ))
     }
+
+    /// Decode the records of the manifest at `path` without modifying it,
+    /// ignoring a torn tail.
+    pub fn read_records(path: impl AsRef<Path>) -> Result<Vec<ManifestRecord>> {
+        let path = path.as_ref();
+        let buf = std::fs::read(path).context("failed to read manifest")?;
+        Ok(decode_records(path, &buf)?.0)
+    }
+}
+
+/// Decode the records of `buf`, read from `path`, stopping at a torn tail.
+/// Returns them with the length of `buf` before the torn tail.
+fn decode_records(
+    path: &Path,
+    buf: &[u8],
+) -> Result<(Vec<ManifestRecord>, u64)> {
+    let mut buf_ptr = buf;
+    let mut records = Vec::new();
+    while buf_ptr.has_remaining() {
+        let offset = (buf.len() - buf_ptr.remaining()) as u64;
+        let corruption = |reason: &str| LsmError::Corruption {
+            path: path.to_path_buf(),
+            offset,
+            reason: reason.to_string(),
+        };
+        if buf_ptr.remaining() < 8 {
+            return Ok((records, offset));
+        }
+        let len = buf_ptr.get_u64();
+        if (buf_ptr.remaining() as u64) < len.saturating_add(4) {
+            return Ok((records, offset));
+        }
+        let slice = &buf_ptr[..len as usize];
+        buf_ptr.advance(len as usize);
+        let checksum = buf_ptr.get_u32();
+        if checksum != crc32fast::hash(slice) {
+            // a partially persisted last record looks the same
+            if !buf_ptr.has_remaining() {
+                return Ok((records, offset));
+            }
+            return Err(corruption("checksum mismatch").into());
+        }
+        let record = serde_json::from_slice::<ManifestRecord>(slice)
+            .map_err(|e| corruption(&e.to_string()))?;
+        records.push(record);
+    }
+    Ok((records, buf.len() as u64))
 }
@@ -1,47 +1,67 @@
 #![allow(dead_code)]
 
-use std::{ops::Bound, path::Path, sync::atomic::AtomicUsize};
+use std::{
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::{Arc, atomic::AtomicUsize},
+};
 
 use crate::{
-    iterators::StorageIterator,
-    key::{KeyBytes, KeySlice},
+    iterators::{SeekIterator, StorageIterator},
+    key::{KeyBytes, KeySlice, TS_RANGE_END},
+    range_tombstone::{RangeTombstone, max_covering_ts},
+    table::SsTableBuilder,
     wal::Wal,
 };
 use anyhow::Result;
 use bytes::Bytes;
-use crossbeam_skiplist::{
-    SkipMap,
-    map::{Entry, Range},
-};
+use crossbeam_skiplist::{SkipMap, map::Entry};
+use ouroboros::self_referencing;
+use parking_lot::RwLock;
 
 /// thread safe
 pub struct MemTable {
     // more format
-    pub(crate) map: SkipMap<KeyBytes, Bytes>,
-    wal: Option<Wal>,
+    pub(crate) map: Arc<SkipMap<KeyBytes, Bytes>>,
+    /// Kept apart from the point entries, as they cover many keys
+    range_tombstones: RwLock<Vec<RangeTombstone>>,
+    /// Shared by the memtables of all column families frozen together
+    wal: Option<Arc<Wal>>,
     id: usize,
     approximate_size: AtomicUsize,
 }
 
 impl MemTable {
-    /// create a memtable by id
-    pub fn create(id: usize) -> Self {
+    fn new(id: usize, wal: Option<Arc<Wal>>) -> Self {
         Self {
             id,
-            map: SkipMap::new(),
-            wal: None,
+            map: Arc::new(SkipMap::new()),
+            range_tombstones: RwLock::new(Vec::new()),
+            wal,
             approximate_size: AtomicUsize::new(0),
         }
     }
 
+    /// create a memtable by id
+    pub fn create(id: usize) -> Self {
+        Self::new(id, None)
+    }
+
     /// create a mem table by id and wal
-    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Self {
-        todo!()
+    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(id, Some(Arc::new(Wal::create(path)?))))
+    }
+
+    /// create an empty mem table of another column family, with the id and
+    /// the WAL of `other`
+    pub fn create_sharing_wal(other: &MemTable) -> Self {
+        Self::new(other.id, other.wal.clone())
     }
 
-    /// recover a mem table by id and wal
-    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Self {
-        todo!()
+    /// create an empty mem table by id that logs to `wal`, recovered from
+    /// disk, and whose entries are replayed with `apply_batch`
+    pub fn create_with_recovered_wal(id: usize, wal: Arc<Wal>) -> Self {
+        Self::new(id, Some(wal))
     }
 
     /// get val by key
@@ -56,24 +76,78 @@ impl MemTable {
         self.map.get(&key_bytes).map(|e| e.value().clone())
     }
 
-    /// put val into memtable
+    /// get the latest version of `key` whose ts is not greater than
+    /// `read_ts`, with its ts
+    pub fn get_visible(
+        &self,
+        key: &[u8],
+        read_ts: u64,
+    ) -> Option<(u64, Bytes)> {
+        let range = (
+            map_key_bound(Bound::Included(KeySlice::from_slice(key, read_ts))),
+            map_key_bound(Bound::Included(KeySlice::from_slice(
+                key,
+                TS_RANGE_END,
+            ))),
+        );
+        self.map
+            .range(range)
+            .next()
+            .map(|e| (e.key().ts(), e.value().clone()))
+    }
+
+    /// The ts of the newest range tombstone covering `key` that is visible
+    /// at `read_ts`.
+    pub fn range_tombstone_ts(&self, key: &[u8], read_ts: u64) -> Option<u64> {
+        max_covering_ts(&self.range_tombstones.read(), key, read_ts)
+    }
+
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().clone()
+    }
+
+    /// put val into memtable, without logging it to the WAL
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-        self.put_batch(&[(key, value)])
+        self.apply_batch(&[(key, value)]);
+        Ok(())
     }
 
-    /// put batch style api
-    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+    /// Log a write batch to the WAL of this mem table, if any, as one
+    /// record. It may hold the entries of several column families.
+    pub fn log_batch(&self, data: &[(u32, KeySlice, &[u8])]) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.put_batch(data)?;
+        }
+        Ok(())
+    }
+
+    /// Insert entries already logged to the WAL. Range tombstones are
+    /// encoded as by `RangeTombstone::encode_wal_value`.
+    pub fn apply_batch(&self, data: &[(KeySlice, &[u8])]) {
         let mut estimated = 0;
         for (k, v) in data {
             estimated += k.raw_len() + v.len();
-            self.map.insert(
-                k.to_key_vec().into_key_bytes(),
-                Bytes::copy_from_slice(&v),
-            );
+            match RangeTombstone::from_wal_entry(k.key_ref(), k.ts(), v) {
+                Some(tombstone) => {
+                    self.range_tombstones.write().push(tombstone)
+                }
+                None => {
+                    self.map.insert(
+                        k.to_key_vec().into_key_bytes(),
+                        Bytes::copy_from_slice(v),
+                    );
+                }
+            }
         }
         self.approximate_size
             .fetch_add(estimated, std::sync::atomic::Ordering::Relaxed);
-        // todo wal
+    }
+
+    /// sync the wal of this mem table, if any
+    pub fn sync_wal(&self) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.sync()?;
+        }
         Ok(())
     }
 
@@ -83,17 +157,26 @@ impl MemTable {
         lower: Bound<KeySlice>,
         upper: Bound<KeySlice>,
     ) -> MemTableIter {
-        let (low, up) = (map_key_bound(lower), map_key_bound(upper));
-        let inner = self.map.range((low, up));
-        let mut ret = MemTableIter {
-            inner_iter: inner,
-            item: (KeyBytes::new(), Bytes::new()),
-        };
-        ret.next().unwrap();
+        let mut ret = MemTableIterBuilder {
+            map: self.map.clone(),
+            bounds: (map_key_bound(lower), map_key_bound(upper)),
+            entry_builder: |_| None,
+        }
+        .build();
+        ret.seek_to_first().unwrap();
         ret
     }
 
-    // pub fn flush(&self,builder:&mut )
+    /// Flush the mem table to an SST builder
+    pub fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
+        for entry in self.map.iter() {
+            builder.add(entry.key().as_key_slice(), &entry.value()[..]);
+        }
+        for tombstone in self.range_tombstones.read().iter() {
+            builder.add_range_tombstone(tombstone.clone());
+        }
+        Ok(())
+    }
 
     /// return id of this mem table
     pub fn id(&self) -> usize {
@@ -108,7 +191,7 @@ impl MemTable {
 
     /// is this mem table empty
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.map.is_empty() && self.range_tombstones.read().is_empty()
     }
 }
 
@@ -129,7 +212,7 @@ impl MemTable {
         &self,
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
-    ) -> MemTableIter<'_> {
+    ) -> MemTableIter {
         self.scan(
             lower.map(|x| KeySlice::from_slice(x, crate::key::TS_DEFAULT)),
             upper.map(|x| KeySlice::from_slice(x, crate::key::TS_DEFAULT)),
@@ -139,59 +222,109 @@ impl MemTable {
 
 /// Create a bound of `KeyBytes` from a bound of `KeySlice`.
 fn map_key_bound(bound: Bound<KeySlice>) -> Bound<KeyBytes> {
-    match bound {
-        Bound::Included(x) => Bound::Included(KeyBytes::from_bytes_with_ts(
-            Bytes::copy_from_slice(x.key_ref()),
-            x.ts(),
-        )),
-        Bound::Excluded(x) => Bound::Excluded(KeyBytes::from_bytes_with_ts(
-            Bytes::copy_from_slice(x.key_ref()),
-            x.ts(),
-        )),
-        Bound::Unbounded => Bound::Unbounded,
-    }
+    bound.map(to_key_bytes)
 }
 
-// key range k v
-type SkipMapRangeIter<'a> =
-    Range<'a, KeyBytes, (Bound<KeyBytes>, Bound<KeyBytes>), KeyBytes, Bytes>;
+/// Create a `KeyBytes` to look up a `KeySlice` with.
+fn to_key_bytes(key: KeySlice) -> KeyBytes {
+    KeyBytes::from_bytes_with_ts(
+        Bytes::copy_from_slice(key.key_ref()),
+        key.ts(),
+    )
+}
 
-pub struct MemTableIter<'map> {
-    inner_iter: SkipMapRangeIter<'map>,
-    item: (KeyBytes, Bytes),
+/// An iterator over a range of a mem table. It keeps the underlying skip map
+/// alive, so it can outlive the `MemTable` it was created from.
+#[self_referencing]
+pub struct MemTableIter {
+    map: Arc<SkipMap<KeyBytes, Bytes>>,
+    bounds: (Bound<KeyBytes>, Bound<KeyBytes>),
+    /// The current entry, `None` once out of `bounds`
+    #[borrows(map)]
+    #[not_covariant]
+    entry: Option<Entry<'this, KeyBytes, Bytes>>,
 }
 
-impl<'map> MemTableIter<'map> {
-    fn entry_to_item(
-        entry: Option<Entry<'_, KeyBytes, Bytes>>,
-    ) -> (KeyBytes, Bytes) {
-        entry
-            .map(|x| (x.key().clone(), x.value().clone()))
-            .unwrap_or_else(|| (KeyBytes::new(), Bytes::new()))
+impl MemTableIter {
+    /// Move to the entry `f` finds, unless it is out of `bounds`.
+    fn move_to(
+        &mut self,
+        f: impl for<'a> FnOnce(
+            &'a SkipMap<KeyBytes, Bytes>,
+            Option<&Entry<'a, KeyBytes, Bytes>>,
+            &(Bound<KeyBytes>, Bound<KeyBytes>),
+        ) -> Option<Entry<'a, KeyBytes, Bytes>>,
+    ) {
+        self.with_mut(|fields| {
+            let entry = f(fields.map, fields.entry.as_ref(), fields.bounds);
+            *fields.entry =
+                entry.filter(|entry| fields.bounds.contains(entry.key()));
+        });
     }
 }
 
-impl<'map> StorageIterator for MemTableIter<'map> {
+impl StorageIterator for MemTableIter {
     type KeyType<'a>
         = KeySlice<'a>
     where
         Self: 'a;
 
     fn key(&self) -> Self::KeyType<'_> {
-        self.item.0.as_key_slice()
+        self.with_entry(|entry| entry.as_ref().unwrap().key().as_key_slice())
     }
 
     fn value(&self) -> &[u8] {
-        &self.item.1[..]
+        self.with_entry(|entry| &entry.as_ref().unwrap().value()[..])
     }
 
     fn is_valid(&self) -> bool {
-        !self.item.0.is_empty()
+        self.with_entry(|entry| entry.is_some())
     }
 
     fn next(&mut self) -> Result<()> {
-        let item = MemTableIter::entry_to_item(self.inner_iter.next());
-        self.item = item;
+        self.move_to(|_, entry, _| entry.and_then(|entry| entry.next()));
+        Ok(())
+    }
+}
+
+impl SeekIterator for MemTableIter {
+    fn prev(&mut self) -> Result<()> {
+        self.move_to(|_, entry, _| entry.and_then(|entry| entry.prev()));
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.move_to(|map, _, bounds| map.lower_bound(bounds.0.as_ref()));
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.move_to(|map, _, bounds| map.upper_bound(bounds.1.as_ref()));
+        Ok(())
+    }
+
+    fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        let key = to_key_bytes(key);
+        self.move_to(|map, _, bounds| match &bounds.0 {
+            // the lower bound is past `key`
+            Bound::Included(start) | Bound::Excluded(start)
+                if *start >= key =>
+            {
+                map.lower_bound(bounds.0.as_ref())
+            }
+            _ => map.lower_bound(Bound::Included(&key)),
+        });
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        let key = to_key_bytes(key);
+        self.move_to(|map, _, bounds| match &bounds.1 {
+            Bound::Included(end) | Bound::Excluded(end) if *end <= key => {
+                map.upper_bound(bounds.1.as_ref())
+            }
+            _ => map.upper_bound(Bound::Included(&key)),
+        });
         Ok(())
     }
 }
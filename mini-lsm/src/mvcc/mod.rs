@@ -5,4 +5,174 @@
 mod txn;
 mod watermark;
 
-pub(crate) struct LsmMvccInner {}
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, atomic::AtomicBool},
+};
+
+use anyhow::{Result, bail};
+use crossbeam_skiplist::SkipMap;
+use parking_lot::Mutex;
+pub use txn::{Transaction, TxnIterator, TxnLocalIterator};
+pub use watermark::Watermark;
+
+use crate::{error::LsmError, lsm_storage::LsmStorageInner};
+
+/// The keys of the default column family a write batch or transaction
+/// wrote, kept until no active transaction started before its commit.
+#[derive(Default)]
+pub(crate) struct CommittedTxnData {
+    pub(crate) key_hashes: HashSet<u32>,
+    /// Whether it may have written any key, as a range delete or an
+    /// ingestion does
+    pub(crate) any_key: bool,
+}
+
+pub(crate) struct LsmMvccInner {
+    /// Serializes writers and holds the last commit ts handed out, which
+    /// may not be visible yet
+    pub(crate) write_lock: Mutex<u64>,
+    /// The latest commit timestamp, the read timestamps in use, and the
+    /// oldest ts a time-travel read may use: the highest watermark versions
+    /// were collapsed at
+    pub(crate) ts: Arc<Mutex<(u64, Watermark, u64)>>,
+    /// How many of the latest commit timestamps keep their versions
+    retention_commits: u64,
+    /// Commit ts -> write set of recent writes, recorded under the write
+    /// lock when transactions are serializable
+    pub(crate) committed_txns: Arc<Mutex<BTreeMap<u64, CommittedTxnData>>>,
+}
+
+impl LsmMvccInner {
+    pub fn new(initial_ts: u64, retention_commits: u64) -> Self {
+        // versions before the retained commits were collapsed before a restart
+        let oldest_ts = initial_ts.saturating_sub(retention_commits);
+        Self {
+            write_lock: Mutex::new(initial_ts),
+            ts: Arc::new(Mutex::new((initial_ts, Watermark::new(), oldest_ts))),
+            retention_commits,
+            committed_txns: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn latest_commit_ts(&self) -> u64 {
+        self.ts.lock().0
+    }
+
+    /// Make every version up to `ts` visible. Writers may publish out of
+    /// order, so the latest commit ts never goes backwards.
+    pub fn update_commit_ts(&self, ts: u64) {
+        let mut guard = self.ts.lock();
+        guard.0 = guard.0.max(ts);
+    }
+
+    /// Versions at or below this ts that are shadowed by a newer version at
+    /// or below it can never be read by a transaction again: it is the
+    /// lowest read ts of the active transactions, or the latest commit ts if
+    /// there is none. Plain reads are not tracked, as they hold their own
+    /// snapshot of the SSTs.
+    pub fn watermark(&self) -> u64 {
+        let ts = self.ts.lock();
+        ts.1.watermark().unwrap_or(ts.0)
+    }
+
+    /// The watermark compaction and value log GC collapse versions at,
+    /// held back by the retained commits. Time-travel reads below it are
+    /// refused from then on.
+    pub fn gc_watermark(&self) -> u64 {
+        let mut ts = self.ts.lock();
+        let watermark =
+            ts.1.watermark()
+                .unwrap_or(ts.0)
+                .min(ts.0.saturating_sub(self.retention_commits));
+        ts.2 = ts.2.max(watermark);
+        watermark
+    }
+
+    /// Fail with [`LsmError::TxnConflict`] if a write committed after
+    /// `read_ts` wrote a key of `read_set`. Call with the write lock held, so
+    /// that every write before the one validated is recorded.
+    pub(crate) fn validate_reads(
+        &self,
+        read_ts: u64,
+        read_set: &HashSet<u32>,
+    ) -> Result<()> {
+        let committed_txns = self.committed_txns.lock();
+        for (commit_ts, txn_data) in committed_txns.range(read_ts + 1..) {
+            if txn_data.any_key || !txn_data.key_hashes.is_disjoint(read_set) {
+                return Err(LsmError::TxnConflict {
+                    read_ts,
+                    conflict_commit_ts: *commit_ts,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the keys written at `commit_ts`, for the transactions active
+    /// before it to validate their reads against. Call with the write lock
+    /// held, before `commit_ts` becomes visible.
+    pub(crate) fn record_write(
+        &self,
+        commit_ts: u64,
+        txn_data: CommittedTxnData,
+    ) {
+        if !txn_data.any_key && txn_data.key_hashes.is_empty() {
+            return;
+        }
+        let mut committed_txns = self.committed_txns.lock();
+        committed_txns.insert(commit_ts, txn_data);
+        // no active transaction can conflict with these any more
+        let watermark = self.watermark();
+        while let Some(entry) = committed_txns.first_entry() {
+            if *entry.key() > watermark {
+                break;
+            }
+            entry.remove();
+        }
+    }
+
+    /// Run `f` with `ts` registered as a read ts, so that versions it reads
+    /// are not collapsed before it takes its snapshot.
+    pub fn with_read_ts<T>(
+        &self,
+        ts: u64,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        {
+            let mut guard = self.ts.lock();
+            if ts > guard.0 {
+                bail!("ts {ts} is after the latest commit ts {}", guard.0);
+            }
+            if ts < guard.2 {
+                return Err(LsmError::HistoryUnavailable {
+                    ts,
+                    oldest_ts: guard.2,
+                }
+                .into());
+            }
+            guard.1.add_reader(ts);
+        }
+        let result = f();
+        self.ts.lock().1.remove_reader(ts);
+        result
+    }
+
+    pub fn new_txn(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        serializable: bool,
+    ) -> Arc<Transaction> {
+        let mut ts = self.ts.lock();
+        let read_ts = ts.0;
+        ts.1.add_reader(read_ts);
+        Arc::new(Transaction {
+            read_ts,
+            inner,
+            local_storage: Arc::new(SkipMap::new()),
+            committed: Arc::new(AtomicBool::new(false)),
+            read_set: serializable.then(|| Mutex::new(HashSet::new())),
+        })
+    }
+}
@@ -4,23 +4,25 @@
 
 use std::{
     collections::HashSet,
-    ops::Bound,
+    ops::{Bound, RangeBounds},
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
 };
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 use bytes::Bytes;
-use crossbeam_skiplist::SkipMap;
+use crossbeam_skiplist::{SkipMap, map::Entry};
 use ouroboros::self_referencing;
 use parking_lot::Mutex;
 
 use crate::{
-    iterators::{StorageIterator, two_merge_iterator::TwoMergeIterator},
+    iterators::{
+        SeekIterator, StorageIterator, two_merge_iterator::TwoMergeIterator,
+    },
     lsm_iterator::{FusedIterator, LsmIterator},
-    lsm_storage::LsmStorageInner,
+    lsm_storage::{LsmStorageInner, WriteBatchRecord},
 };
 
 pub struct Transaction {
@@ -28,41 +30,229 @@ pub struct Transaction {
     pub(crate) inner: Arc<LsmStorageInner>,
     pub(crate) local_storage: Arc<SkipMap<Bytes, Bytes>>,
     pub(crate) committed: Arc<AtomicBool>,
-    /// Write set and read set
-    pub(crate) key_hashes: Option<Mutex<(HashSet<u32>, HashSet<u32>)>>,
+    /// Hashes of the keys read, when serializable
+    pub(crate) read_set: Option<Mutex<HashSet<u32>>>,
 }
 
 impl Transaction {
+    fn check_not_committed(&self) -> Result<()> {
+        if self.committed.load(Ordering::SeqCst) {
+            bail!("cannot operate on committed txn");
+        }
+        Ok(())
+    }
+
+    fn record_read(&self, key: &[u8]) {
+        if let Some(read_set) = &self.read_set {
+            read_set.lock().insert(farmhash::hash32(key));
+        }
+    }
+
+    /// Read `key` as of the transaction's snapshot, seeing its own writes.
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        todo!()
+        self.check_not_committed()?;
+        self.record_read(key);
+        if let Some(entry) = self.local_storage.get(key) {
+            let value = entry.value();
+            return Ok((!value.is_empty()).then(|| value.clone()));
+        }
+        self.inner
+            .get_with_ts(&self.inner.default_family, key, self.read_ts)
     }
+
     pub fn scan(
         self: &Arc<Self>,
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
     ) -> Result<TxnIterator> {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
+        self.check_not_committed()?;
+        let mut local_iter = TxnLocalIteratorBuilder {
+            map: self.local_storage.clone(),
+            bounds: (
+                lower.map(Bytes::copy_from_slice),
+                upper.map(Bytes::copy_from_slice),
+            ),
+            entry_builder: |_| None,
+        }
+        .build();
+        local_iter.seek_to_first()?;
+        TxnIterator::create(
+            self.clone(),
+            TwoMergeIterator::create(
+                local_iter,
+                self.inner.scan_with_ts(
+                    &self.inner.default_family,
+                    lower,
+                    upper,
+                    self.read_ts,
+                )?,
+            )?,
+        )
+    }
+
+    /// Buffer a write; it becomes visible to others on commit.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_not_committed()?;
+        if key.is_empty() {
+            bail!("key cannot be empty");
+        }
+        if value.is_empty() {
+            bail!("value cannot be empty");
         }
-        todo!()
+        self.local_storage
+            .insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.check_not_committed()?;
+        if key.is_empty() {
+            bail!("key cannot be empty");
+        }
+        self.local_storage
+            .insert(Bytes::copy_from_slice(key), Bytes::new());
+        Ok(())
+    }
+
+    /// Write the buffered writes with one commit ts. A serializable
+    /// transaction that wrote something fails with
+    /// [`LsmError::TxnConflict`](crate::error::LsmError::TxnConflict) if a
+    /// transaction or plain write that committed after it started wrote a
+    /// key it read.
+    pub fn commit(&self) -> Result<()> {
+        if self
+            .committed
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            bail!("cannot operate on committed txn");
+        }
+        let batch = self
+            .local_storage
+            .iter()
+            .map(|entry| {
+                if entry.value().is_empty() {
+                    WriteBatchRecord::Del(entry.key().clone())
+                } else {
+                    WriteBatchRecord::Put(
+                        entry.key().clone(),
+                        entry.value().clone(),
+                    )
+                }
+            })
+            .collect::<Vec<_>>();
+        // read-only transactions always see a consistent snapshot
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let family = self.inner.default_family.as_ref();
+        let batch = batch
+            .iter()
+            .map(|record| (family, record))
+            .collect::<Vec<_>>();
+        let read_set = self.read_set.as_ref().map(|read_set| read_set.lock());
+        self.inner.write_batch_inner(
+            &batch,
+            read_set.as_deref().map(|read_set| (self.read_ts, read_set)),
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.inner.mvcc().ts.lock().1.remove_reader(self.read_ts);
     }
 }
 
+/// Iterates over the merge of a transaction's own writes and its snapshot,
+/// hiding deleted keys.
 pub struct TxnIterator {
     txn: Arc<Transaction>,
     iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
 }
 
+impl TxnIterator {
+    pub fn create(
+        txn: Arc<Transaction>,
+        iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
+    ) -> Result<Self> {
+        let mut iter = Self { txn, iter };
+        iter.skip_deletes()?;
+        if iter.is_valid() {
+            iter.txn.record_read(iter.key());
+        }
+        Ok(iter)
+    }
+
+    fn skip_deletes(&mut self) -> Result<()> {
+        while self.iter.is_valid() && self.iter.value().is_empty() {
+            self.iter.next()?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageIterator for TxnIterator {
+    type KeyType<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn key(&self) -> Self::KeyType<'_> {
+        self.iter.key()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.skip_deletes()?;
+        if self.is_valid() {
+            self.txn.record_read(self.key());
+        }
+        Ok(())
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iter.num_active_iterators()
+    }
+}
+
 #[self_referencing]
 pub struct TxnLocalIterator {
     /// Stores a reference to the skipmap.
     map: Arc<SkipMap<Bytes, Bytes>>,
-    /// Stores a skipmap iterator that refers to the lifetime of `TxnLocalIterator` itself.
+    bounds: (Bound<Bytes>, Bound<Bytes>),
+    /// Stores the current entry of the skipmap, `None` once out of
+    /// `bounds`.
     #[borrows(map)]
     #[not_covariant]
-    iter: SkipMapRangeIter<'this>,
-    /// Stores the current key-value pair.
-    item: (Bytes, Bytes),
+    entry: Option<Entry<'this, Bytes, Bytes>>,
+}
+
+impl TxnLocalIterator {
+    /// Move to the entry `f` finds, unless it is out of `bounds`.
+    fn move_to(
+        &mut self,
+        f: impl for<'a> FnOnce(
+            &'a SkipMap<Bytes, Bytes>,
+            Option<&Entry<'a, Bytes, Bytes>>,
+            &(Bound<Bytes>, Bound<Bytes>),
+        ) -> Option<Entry<'a, Bytes, Bytes>>,
+    ) {
+        self.with_mut(|fields| {
+            let entry = f(fields.map, fields.entry.as_ref(), fields.bounds);
+            *fields.entry =
+                entry.filter(|entry| fields.bounds.contains(entry.key()));
+        });
+    }
 }
 
 impl StorageIterator for TxnLocalIterator {
@@ -72,26 +262,61 @@ impl StorageIterator for TxnLocalIterator {
         Self: 'a;
 
     fn value(&self) -> &[u8] {
-        todo!()
+        self.with_entry(|entry| &entry.as_ref().unwrap().value()[..])
     }
 
     fn key(&self) -> Self::KeyType<'_> {
-        todo!()
+        self.with_entry(|entry| &entry.as_ref().unwrap().key()[..])
     }
 
     fn is_valid(&self) -> bool {
-        todo!()
+        self.with_entry(|entry| entry.is_some())
     }
 
     fn next(&mut self) -> anyhow::Result<()> {
-        todo!()
+        self.move_to(|_, entry, _| entry.and_then(|entry| entry.next()));
+        Ok(())
     }
 }
 
-type SkipMapRangeIter<'a> = crossbeam_skiplist::map::Range<
-    'a,
-    Bytes,
-    (Bound<Bytes>, Bound<Bytes>),
-    Bytes,
-    Bytes,
->;
+impl SeekIterator for TxnLocalIterator {
+    fn prev(&mut self) -> anyhow::Result<()> {
+        self.move_to(|_, entry, _| entry.and_then(|entry| entry.prev()));
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> anyhow::Result<()> {
+        self.move_to(|map, _, bounds| map.lower_bound(bounds.0.as_ref()));
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> anyhow::Result<()> {
+        self.move_to(|map, _, bounds| map.upper_bound(bounds.1.as_ref()));
+        Ok(())
+    }
+
+    fn seek_to_key(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.move_to(|map, _, bounds| match &bounds.0 {
+            // the lower bound is past `key`
+            Bound::Included(start) | Bound::Excluded(start)
+                if start.as_ref() >= key =>
+            {
+                map.lower_bound(bounds.0.as_ref())
+            }
+            _ => map.lower_bound(Bound::Included(key)),
+        });
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.move_to(|map, _, bounds| match &bounds.1 {
+            Bound::Included(end) | Bound::Excluded(end)
+                if end.as_ref() <= key =>
+            {
+                map.upper_bound(bounds.1.as_ref())
+            }
+            _ => map.upper_bound(Bound::Included(key)),
+        });
+        Ok(())
+    }
+}
@@ -1,6 +1,39 @@
 // SPDX-FileCopyrightText: LakeSoul Contributors
-// 
+//
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::BTreeMap;
+
 /// Track the lowest `read_ts` in the system
-pub struct Watermark {}
\ No newline at end of file
+#[derive(Default)]
+pub struct Watermark {
+    /// read ts -> number of readers at it
+    readers: BTreeMap<u64, usize>,
+}
+
+impl Watermark {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_reader(&mut self, ts: u64) {
+        *self.readers.entry(ts).or_default() += 1;
+    }
+
+    pub fn remove_reader(&mut self, ts: u64) {
+        let count = self.readers.get_mut(&ts).expect("reader not registered");
+        *count -= 1;
+        if *count == 0 {
+            self.readers.remove(&ts);
+        }
+    }
+
+    /// The lowest read ts of the active readers, if any.
+    pub fn watermark(&self) -> Option<u64> {
+        self.readers.first_key_value().map(|(ts, _)| *ts)
+    }
+
+    pub fn num_retained_snapshots(&self) -> usize {
+        self.readers.len()
+    }
+}
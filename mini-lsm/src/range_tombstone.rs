@@ -0,0 +1,203 @@
+//! A range tombstone deletes the versions of the keys in `[start, end)`
+//! older than its ts. Versions written with the same or a later ts are not
+//! affected, so a write batch drops the records that a later range
+//! tombstone of the batch deletes.
+
+use std::collections::{BTreeMap, btree_map::Entry};
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::value::TAG_RANGE_TOMBSTONE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Bytes,
+    pub end: Bytes,
+    pub ts: u64,
+}
+
+impl RangeTombstone {
+    /// Whether the version of `key` written at `ts` is deleted.
+    pub fn covers(&self, key: &[u8], ts: u64) -> bool {
+        self.contains(key) && ts < self.ts
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.start.as_ref() <= key && key < self.end.as_ref()
+    }
+
+    /// The part of the tombstone within `[lower, upper)`, `None` meaning
+    /// unbounded.
+    pub(crate) fn clip(
+        &self,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> Option<Self> {
+        let start = match lower {
+            Some(lower) if lower > self.start.as_ref() => {
+                Bytes::copy_from_slice(lower)
+            }
+            _ => self.start.clone(),
+        };
+        let end = match upper {
+            Some(upper) if upper < self.end.as_ref() => {
+                Bytes::copy_from_slice(upper)
+            }
+            _ => self.end.clone(),
+        };
+        (start < end).then_some(Self {
+            start,
+            end,
+            ts: self.ts,
+        })
+    }
+
+    /// In the WAL, a range tombstone is an entry keyed by its start whose
+    /// value holds its end.
+    pub(crate) fn encode_wal_value(end: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(end.len() + 1);
+        buf.put_u8(TAG_RANGE_TOMBSTONE);
+        buf.put_slice(end);
+        buf
+    }
+
+    /// The range tombstone a WAL entry holds, `None` for a point entry.
+    pub(crate) fn from_wal_entry(
+        key: &[u8],
+        ts: u64,
+        value: &[u8],
+    ) -> Option<Self> {
+        match value.split_first() {
+            Some((&TAG_RANGE_TOMBSTONE, end)) => Some(Self {
+                start: Bytes::copy_from_slice(key),
+                end: Bytes::copy_from_slice(end),
+                ts,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encode the range tombstones of an SST, followed by a checksum.
+    pub(crate) fn encode_all(tombstones: &[Self], buf: &mut Vec<u8>) {
+        let original_len = buf.len();
+        buf.put_u32(tombstones.len() as u32);
+        for tombstone in tombstones {
+            buf.put_u16(tombstone.start.len() as u16);
+            buf.put_slice(&tombstone.start);
+            buf.put_u16(tombstone.end.len() as u16);
+            buf.put_slice(&tombstone.end);
+            buf.put_u64(tombstone.ts);
+        }
+        buf.put_u32(crc32fast::hash(&buf[original_len..]));
+    }
+
+    pub(crate) fn decode_all(mut buf: &[u8]) -> Result<Vec<Self>> {
+        if buf.len() < 8 {
+            bail!("range tombstones too short");
+        }
+        let checksum = crc32fast::hash(&buf[..buf.len() - 4]);
+        if (&buf[buf.len() - 4..]).get_u32() != checksum {
+            bail!("range tombstones checksum mismatched");
+        }
+        buf = &buf[..buf.len() - 4];
+        let num = buf.get_u32() as usize;
+        let mut tombstones = Vec::new();
+        for _ in 0..num {
+            let start = get_len_prefixed(&mut buf)?;
+            let end = get_len_prefixed(&mut buf)?;
+            let ts = buf.try_get_u64()?;
+            tombstones.push(Self { start, end, ts });
+        }
+        if buf.has_remaining() {
+            bail!("malformed range tombstones");
+        }
+        Ok(tombstones)
+    }
+}
+
+fn get_len_prefixed(buf: &mut &[u8]) -> Result<Bytes> {
+    let len = buf.try_get_u16()? as usize;
+    if buf.remaining() < len {
+        bail!("malformed range tombstones");
+    }
+    Ok(buf.copy_to_bytes(len))
+}
+
+/// The ts of the newest tombstone covering `key` that is visible at
+/// `read_ts`.
+pub(crate) fn max_covering_ts(
+    tombstones: &[RangeTombstone],
+    key: &[u8],
+    read_ts: u64,
+) -> Option<u64> {
+    tombstones
+        .iter()
+        .filter(|tombstone| tombstone.ts <= read_ts && tombstone.contains(key))
+        .map(|tombstone| tombstone.ts)
+        .max()
+}
+
+/// Overlapping range tombstones split into sorted, disjoint fragments, each
+/// with the ts of the newest tombstone covering it, so that a lookup is a
+/// binary search.
+#[derive(Debug, Default)]
+pub(crate) struct RangeTombstoneFragments {
+    fragments: Vec<RangeTombstone>,
+}
+
+impl RangeTombstoneFragments {
+    pub(crate) fn new<'a>(
+        tombstones: impl IntoIterator<Item = &'a RangeTombstone>,
+    ) -> Self {
+        // one sweep over the bounds, tracking the ts of the tombstones
+        // covering the keys from each bound to the next
+        let mut events = tombstones
+            .into_iter()
+            .flat_map(|tombstone| {
+                [
+                    (&tombstone.start, true, tombstone.ts),
+                    (&tombstone.end, false, tombstone.ts),
+                ]
+            })
+            .collect::<Vec<_>>();
+        events.sort_by(|x, y| x.0.cmp(y.0));
+        let mut active = BTreeMap::<u64, usize>::new();
+        let mut fragments = Vec::new();
+        let mut idx = 0;
+        while idx < events.len() {
+            let bound = events[idx].0;
+            while let Some(&(key, is_start, ts)) = events.get(idx)
+                && key == bound
+            {
+                if is_start {
+                    *active.entry(ts).or_default() += 1;
+                } else if let Entry::Occupied(mut count) = active.entry(ts) {
+                    *count.get_mut() -= 1;
+                    if *count.get() == 0 {
+                        count.remove();
+                    }
+                }
+                idx += 1;
+            }
+            if let (Some((&ts, _)), Some((end, ..))) =
+                (active.last_key_value(), events.get(idx))
+            {
+                fragments.push(RangeTombstone {
+                    start: bound.clone(),
+                    end: (*end).clone(),
+                    ts,
+                });
+            }
+        }
+        Self { fragments }
+    }
+
+    /// Whether the version of `key` written at `ts` is deleted.
+    pub(crate) fn covers(&self, key: &[u8], ts: u64) -> bool {
+        let idx = self
+            .fragments
+            .partition_point(|fragment| fragment.start.as_ref() <= key);
+        idx > 0 && self.fragments[idx - 1].covers(key, ts)
+    }
+}
@@ -0,0 +1,483 @@
+//! Counters and latency histograms of the engine, read as an [`LsmStats`]
+//! snapshot or scraped in the Prometheus text format.
+
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{
+        Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    },
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::lsm_storage::{LsmStorageInner, MiniLsm};
+
+/// Number of latency buckets; bucket `i` counts latencies of at most
+/// `2^i` us, and the last one everything slower.
+const NUM_BUCKETS: usize = 24;
+
+/// A latency histogram with power-of-two microsecond buckets.
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; NUM_BUCKETS],
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        // the smallest `i` with `us <= 2^i`
+        let bucket =
+            (u64::BITS - us.saturating_sub(1).leading_zeros()) as usize;
+        self.buckets[bucket.min(NUM_BUCKETS - 1)]
+            .fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counts of a latency histogram at some point in time.
+#[derive(Debug, Clone, Default)]
+pub struct HistogramSnapshot {
+    /// Bucket `i` counts the latencies of at most `2^i` us, the last one
+    /// also all slower ones
+    pub buckets: Vec<u64>,
+    pub sum_us: u64,
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// An upper bound of the `q`-quantile, `q` in `[0, 1]`.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let target = (q * self.count() as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (idx, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Duration::from_micros(1 << idx);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// Engine counters updated as it runs.
+pub(crate) struct Metrics {
+    pub(crate) flushes: AtomicU64,
+    pub(crate) flushed_bytes: AtomicU64,
+    pub(crate) compactions: AtomicU64,
+    pub(crate) compaction_read_bytes: AtomicU64,
+    pub(crate) compaction_written_bytes: AtomicU64,
+    /// SST lookups skipped because the bloom filter ruled the key out
+    pub(crate) bloom_useful: AtomicU64,
+    /// SST lookups the bloom filter let through that found nothing
+    pub(crate) bloom_false_positives: AtomicU64,
+    /// SSTs a scan skipped because the prefix filter ruled the prefix out
+    pub(crate) prefix_filter_useful: AtomicU64,
+    /// Writes delayed or stopped by `WriteStallOptions`
+    pub(crate) stalled_writes: AtomicU64,
+    pub(crate) write_stall_micros: AtomicU64,
+    pub(crate) get_latency: Histogram,
+    /// Latency of puts, deletes and write batches
+    pub(crate) put_latency: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Self {
+            flushes: AtomicU64::new(0),
+            flushed_bytes: AtomicU64::new(0),
+            compactions: AtomicU64::new(0),
+            compaction_read_bytes: AtomicU64::new(0),
+            compaction_written_bytes: AtomicU64::new(0),
+            bloom_useful: AtomicU64::new(0),
+            bloom_false_positives: AtomicU64::new(0),
+            prefix_filter_useful: AtomicU64::new(0),
+            stalled_writes: AtomicU64::new(0),
+            write_stall_micros: AtomicU64::new(0),
+            get_latency: Histogram::new(),
+            put_latency: Histogram::new(),
+        }
+    }
+}
+
+/// The SSTs of one level. Level 0 is L0, and the levels or tiers below it
+/// are numbered from 1 in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelStats {
+    pub level: usize,
+    pub num_ssts: usize,
+    pub bytes: u64,
+}
+
+/// A snapshot of the engine statistics.
+#[derive(Debug, Clone)]
+pub struct LsmStats {
+    pub memtable_bytes: usize,
+    pub imm_memtables: usize,
+    pub imm_memtable_bytes: usize,
+    pub flushes: u64,
+    pub flushed_bytes: u64,
+    pub levels: Vec<LevelStats>,
+    pub compactions: u64,
+    pub compaction_read_bytes: u64,
+    pub compaction_written_bytes: u64,
+    pub bloom_useful: u64,
+    pub bloom_false_positives: u64,
+    pub prefix_filter_useful: u64,
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    /// Writes delayed or stopped by `WriteStallOptions`
+    pub stalled_writes: u64,
+    /// Time the stalled writes spent waiting, in total
+    pub write_stall_time: Duration,
+    pub get_latency: HistogramSnapshot,
+    /// Latency of puts, deletes and write batches
+    pub put_latency: HistogramSnapshot,
+}
+
+impl LsmStats {
+    /// Render the statistics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP mini_lsm_{name} {help}").unwrap();
+            writeln!(out, "# TYPE mini_lsm_{name} {kind}").unwrap();
+            writeln!(out, "mini_lsm_{name} {value}").unwrap();
+        };
+        metric(
+            "memtable_bytes",
+            "gauge",
+            "Approximate size of the mutable memtable.",
+            self.memtable_bytes as u64,
+        );
+        metric(
+            "imm_memtables",
+            "gauge",
+            "Number of memtables waiting to be flushed.",
+            self.imm_memtables as u64,
+        );
+        metric(
+            "imm_memtable_bytes",
+            "gauge",
+            "Approximate size of the memtables waiting to be flushed.",
+            self.imm_memtable_bytes as u64,
+        );
+        metric(
+            "flushes_total",
+            "counter",
+            "Memtables flushed to SSTs.",
+            self.flushes,
+        );
+        metric(
+            "flushed_bytes_total",
+            "counter",
+            "Bytes of SSTs written by flushes.",
+            self.flushed_bytes,
+        );
+        metric(
+            "compactions_total",
+            "counter",
+            "Compaction tasks run.",
+            self.compactions,
+        );
+        metric(
+            "compaction_read_bytes_total",
+            "counter",
+            "Bytes of SSTs read by compaction.",
+            self.compaction_read_bytes,
+        );
+        metric(
+            "compaction_written_bytes_total",
+            "counter",
+            "Bytes of SSTs written by compaction.",
+            self.compaction_written_bytes,
+        );
+        metric(
+            "bloom_useful_total",
+            "counter",
+            "SST lookups skipped thanks to the bloom filter.",
+            self.bloom_useful,
+        );
+        metric(
+            "bloom_false_positives_total",
+            "counter",
+            "SST lookups let through by the bloom filter that found nothing.",
+            self.bloom_false_positives,
+        );
+        metric(
+            "prefix_filter_useful_total",
+            "counter",
+            "SSTs skipped by scans thanks to the prefix filter.",
+            self.prefix_filter_useful,
+        );
+        metric(
+            "block_cache_hits_total",
+            "counter",
+            "Block reads served by the block cache.",
+            self.block_cache_hits,
+        );
+        metric(
+            "block_cache_misses_total",
+            "counter",
+            "Block reads that went to disk.",
+            self.block_cache_misses,
+        );
+        metric(
+            "stalled_writes_total",
+            "counter",
+            "Writes delayed or stopped until flushes and compaction caught up.",
+            self.stalled_writes,
+        );
+        metric(
+            "write_stall_microseconds_total",
+            "counter",
+            "Time stalled writes spent waiting.",
+            self.write_stall_time.as_micros() as u64,
+        );
+
+        let mut level_metric =
+            |name: &str, help: &str, value: fn(&LevelStats) -> u64| {
+                writeln!(out, "# HELP mini_lsm_{name} {help}").unwrap();
+                writeln!(out, "# TYPE mini_lsm_{name} gauge").unwrap();
+                for level in &self.levels {
+                    writeln!(
+                        out,
+                        "mini_lsm_{name}{{level=\"{}\"}} {}",
+                        level.level,
+                        value(level)
+                    )
+                    .unwrap();
+                }
+            };
+        level_metric("level_ssts", "Number of SSTs in a level.", |level| {
+            level.num_ssts as u64
+        });
+        level_metric("level_bytes", "Bytes of the SSTs in a level.", |level| {
+            level.bytes
+        });
+
+        for (name, help, histogram) in [
+            ("get_latency_seconds", "Latency of gets.", &self.get_latency),
+            (
+                "put_latency_seconds",
+                "Latency of puts, deletes and write batches.",
+                &self.put_latency,
+            ),
+        ] {
+            writeln!(out, "# HELP mini_lsm_{name} {help}").unwrap();
+            writeln!(out, "# TYPE mini_lsm_{name} histogram").unwrap();
+            let mut cumulative = 0;
+            for (idx, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = if idx + 1 == histogram.buckets.len() {
+                    "+Inf".to_string()
+                } else {
+                    ((1u64 << idx) as f64 / 1e6).to_string()
+                };
+                writeln!(
+                    out,
+                    "mini_lsm_{name}_bucket{{le=\"{le}\"}} {cumulative}"
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "mini_lsm_{name}_sum {}",
+                histogram.sum_us as f64 / 1e6
+            )
+            .unwrap();
+            writeln!(out, "mini_lsm_{name}_count {cumulative}").unwrap();
+        }
+        out
+    }
+}
+
+impl LsmStorageInner {
+    /// The statistics of the engine, with the levels of the default column
+    /// family.
+    pub fn stats(&self) -> LsmStats {
+        let snapshot = self.default_family.state.read().clone();
+        let level_stats = |level: usize, sst_ids: &[usize]| LevelStats {
+            level,
+            num_ssts: sst_ids.len(),
+            bytes: sst_ids
+                .iter()
+                .map(|id| snapshot.sstables[id].table_size())
+                .sum(),
+        };
+        let levels =
+            std::iter::once(level_stats(0, &snapshot.l0_sstable))
+                .chain(
+                    snapshot.levels.iter().enumerate().map(
+                        |(idx, (_, sst_ids))| level_stats(idx + 1, sst_ids),
+                    ),
+                )
+                .collect();
+        let metrics = &self.metrics;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        LsmStats {
+            memtable_bytes: snapshot.memtable.approximate_size(),
+            imm_memtables: snapshot.imm_memtables.len(),
+            imm_memtable_bytes: snapshot
+                .imm_memtables
+                .iter()
+                .map(|memtable| memtable.approximate_size())
+                .sum(),
+            flushes: load(&metrics.flushes),
+            flushed_bytes: load(&metrics.flushed_bytes),
+            levels,
+            compactions: load(&metrics.compactions),
+            compaction_read_bytes: load(&metrics.compaction_read_bytes),
+            compaction_written_bytes: load(&metrics.compaction_written_bytes),
+            bloom_useful: load(&metrics.bloom_useful),
+            bloom_false_positives: load(&metrics.bloom_false_positives),
+            prefix_filter_useful: load(&metrics.prefix_filter_useful),
+            block_cache_hits: self.block_cache.hits(),
+            block_cache_misses: self.block_cache.misses(),
+            stalled_writes: load(&metrics.stalled_writes),
+            write_stall_time: Duration::from_micros(load(
+                &metrics.write_stall_micros,
+            )),
+            get_latency: metrics.get_latency.snapshot(),
+            put_latency: metrics.put_latency.snapshot(),
+        }
+    }
+}
+
+impl MiniLsm {
+    /// A snapshot of the engine statistics.
+    pub fn stats(&self) -> LsmStats {
+        self.inner.stats()
+    }
+
+    /// Serve the statistics in the Prometheus text format at
+    /// `http://{addr}/metrics` from a background thread, returning the
+    /// address it listens on. The server stops, and releases the address,
+    /// when the engine is closed or dropped.
+    pub fn serve_metrics(
+        &self,
+        addr: impl ToSocketAddrs,
+    ) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let inner = Arc::downgrade(&self.inner);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || serve(listener, inner, &stop))
+        };
+        self.metrics_servers.lock().push(MetricsServer {
+            addr: local_addr,
+            stop,
+            thread,
+        });
+        Ok(local_addr)
+    }
+}
+
+/// How long the metrics server waits on a client before dropping it, so
+/// that a stalled client does not hold up the next.
+const METRICS_IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A thread started by `serve_metrics`.
+pub(crate) struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    /// Stop accepting connections, and wait for the listener to close. The
+    /// thread is woken up from `accept` by connecting to it.
+    pub(crate) fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        // never wait on a thread that cannot be woken up
+        if TcpStream::connect_timeout(&addr, METRICS_IO_TIMEOUT).is_ok() {
+            self.thread.join().ok();
+        }
+    }
+}
+
+fn serve(
+    listener: TcpListener,
+    inner: Weak<LsmStorageInner>,
+    stop: &AtomicBool,
+) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::Acquire) {
+            return;
+        }
+        let result = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| respond(stream, &inner));
+        if let Err(e) = result {
+            tracing::warn!("failed to serve metrics: {}", e);
+        }
+    }
+}
+
+/// Answer one HTTP request, ignoring its headers. The engine is only held
+/// while the statistics are read, not while waiting on the client.
+fn respond(stream: TcpStream, inner: &Weak<LsmStorageInner>) -> Result<()> {
+    stream.set_read_timeout(Some(METRICS_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(METRICS_IO_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // read the headers, so that closing the socket does not reset it
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match inner.upgrade() {
+        Some(inner) if path == "/metrics" => {
+            ("200 OK", inner.stats().to_prometheus())
+        }
+        Some(_) => ("404 Not Found", String::new()),
+        None => ("503 Service Unavailable", String::new()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
@@ -8,9 +8,14 @@ use crate::{
     block::BlockBuilder,
     key::{KeySlice, KeyVec},
     lsm_storage::BlockCache,
+    range_tombstone::RangeTombstone,
 };
 
-use super::{BlockMeta, FileObject, SsTable, bloom::Bloom};
+use super::{
+    BlockMeta, CompressionType, FileBackend, FileObject, FilterPolicy,
+    PrefixExtractor, SsTable,
+    filter::{Filter, PrefixFilter},
+};
 use anyhow::Result;
 use bytes::BufMut;
 
@@ -22,7 +27,15 @@ pub struct SsTableBuilder {
     pub(crate) meta: Vec<BlockMeta>,
     block_size: usize,
     key_hashes: Vec<u32>,
+    /// hashes of the prefixes of the keys, each once
+    prefix_hashes: Vec<u32>,
+    last_prefix: Option<Vec<u8>>,
     max_ts: u64,
+    compression: CompressionType,
+    filter_policy: FilterPolicy,
+    prefix_extractor: Option<PrefixExtractor>,
+    file_backend: FileBackend,
+    range_tombstones: Vec<RangeTombstone>,
 }
 
 impl SsTableBuilder {
@@ -36,10 +49,44 @@ impl SsTableBuilder {
             block_size,
             builder: BlockBuilder::new(block_size),
             key_hashes: Vec::new(),
+            prefix_hashes: Vec::new(),
+            last_prefix: None,
             max_ts: 0,
+            compression: CompressionType::None,
+            filter_policy: FilterPolicy::default(),
+            prefix_extractor: None,
+            file_backend: FileBackend::default(),
+            range_tombstones: Vec::new(),
         }
     }
 
+    /// Compress the data blocks with `compression`.
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Build the filters with `filter_policy`.
+    pub fn with_filter_policy(mut self, filter_policy: FilterPolicy) -> Self {
+        self.filter_policy = filter_policy;
+        self
+    }
+
+    /// Also build a filter of the key prefixes `prefix_extractor` maps to.
+    pub fn with_prefix_extractor(
+        mut self,
+        prefix_extractor: Option<PrefixExtractor>,
+    ) -> Self {
+        self.prefix_extractor = prefix_extractor;
+        self
+    }
+
+    /// Read the SST once built with `file_backend`.
+    pub fn with_file_backend(mut self, file_backend: FileBackend) -> Self {
+        self.file_backend = file_backend;
+        self
+    }
+
     /// Adds a k-v pair to SSTable
     pub fn add(&mut self, key: KeySlice, value: &[u8]) {
         if self.first_key.is_empty() {
@@ -49,6 +96,15 @@ impl SsTableBuilder {
         self.max_ts = self.max_ts.max(key.ts());
 
         self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
+        // keys come in order, so the keys of a prefix are added in a row
+        if let Some(prefix) = self
+            .prefix_extractor
+            .and_then(|extractor| extractor.prefix(key.key_ref()))
+            && self.last_prefix.as_deref() != Some(prefix)
+        {
+            self.prefix_hashes.push(farmhash::fingerprint32(prefix));
+            self.last_prefix = Some(prefix.to_vec());
+        }
 
         if self.builder.add(key, value) {
             self.last_key.set_from_slice(key);
@@ -65,6 +121,12 @@ impl SsTableBuilder {
         self.last_key.set_from_slice(key);
     }
 
+    /// Adds a range tombstone, which may lie outside the keys added.
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.max_ts = self.max_ts.max(tombstone.ts);
+        self.range_tombstones.push(tombstone);
+    }
+
     /// Get the estimated size of the SSTable.
     pub fn estimated_size(&self) -> usize {
         self.data.len()
@@ -76,29 +138,53 @@ impl SsTableBuilder {
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
     ) -> Result<SsTable> {
-        self.finish_block();
+        // an SST may hold range tombstones only
+        if !self.builder.is_empty() {
+            self.finish_block();
+        }
         let mut buf = self.data;
         let meta_offset = buf.len();
         BlockMeta::encode_block_meta(&self.meta, self.max_ts, &mut buf);
         buf.put_u32(meta_offset as u32);
-        let bloom = Bloom::build_from_key_hashes(
-            &self.key_hashes,
-            Bloom::bloom_bits_per_key(self.key_hashes.len(), 0.01),
-        );
-        let bloom_offset = buf.len();
-        bloom.encode(&mut buf);
-        buf.put_u32(bloom_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
+        let range_tombstones_offset = buf.len();
+        RangeTombstone::encode_all(&self.range_tombstones, &mut buf);
+        buf.put_u32(range_tombstones_offset as u32);
+        let prefix_filter =
+            self.prefix_extractor.map(|extractor| PrefixFilter {
+                extractor,
+                filter: Filter::build_from_key_hashes(
+                    self.filter_policy,
+                    &self.prefix_hashes,
+                ),
+            });
+        let prefix_filter_offset = buf.len();
+        if let Some(prefix_filter) = &prefix_filter {
+            prefix_filter.encode(&mut buf);
+        }
+        buf.put_u32(prefix_filter_offset as u32);
+        let filter =
+            Filter::build_from_key_hashes(self.filter_policy, &self.key_hashes);
+        let filter_offset = buf.len();
+        filter.encode(&mut buf);
+        buf.put_u32(filter_offset as u32);
+        // no global ts, set once ingested
+        buf.put_u64(0);
+        let file = FileObject::create(path.as_ref(), buf, self.file_backend)?;
+        let (first_key, last_key) =
+            SsTable::key_range(&self.meta, &self.range_tombstones);
         Ok(SsTable {
             id,
             file,
-            first_key: self.meta.first().unwrap().first_key.clone(),
-            last_key: self.meta.last().unwrap().last_key.clone(),
+            first_key,
+            last_key,
             block_meta: self.meta,
+            range_tombstones: self.range_tombstones,
             block_meta_offset: meta_offset,
             block_cache,
-            bloom: Some(bloom),
+            filter: Some(filter),
+            prefix_filter,
             max_ts: self.max_ts,
+            global_ts: None,
         })
     }
 
@@ -108,13 +194,23 @@ impl SsTableBuilder {
             BlockBuilder::new(self.block_size),
         );
         let encode_block = builder.build().encode();
+        // blocks that do not shrink, or fail to compress, are stored as is
+        let (compression, block_data) =
+            match self.compression.compress(&encode_block) {
+                Ok(compressed) if compressed.len() < encode_block.len() => {
+                    (self.compression, compressed)
+                }
+                _ => (CompressionType::None, encode_block.to_vec()),
+            };
         self.meta.push(BlockMeta {
             offset: self.data.len(),
             first_key: std::mem::take(&mut self.first_key).into_key_bytes(),
             last_key: std::mem::take(&mut self.last_key).into_key_bytes(),
+            compression,
+            uncompressed_len: encode_block.len(),
         });
-        let check_sum = crc32fast::hash(&encode_block);
-        self.data.extend(encode_block);
+        let check_sum = crc32fast::hash(&block_data);
+        self.data.extend(block_data);
         self.data.put_u32(check_sum);
     }
 }
@@ -0,0 +1,78 @@
+// SPDX-FileCopyrightText: LakeSoul Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// The codec a data block is stored with, recorded in its `BlockMeta`.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+pub enum CompressionType {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
+}
+
+impl CompressionType {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Lz4 => 1,
+            CompressionType::Zstd => 2,
+            CompressionType::Snappy => 3,
+        }
+    }
+
+    pub(crate) fn from_u8(codec: u8) -> Result<Self> {
+        Ok(match codec {
+            0 => CompressionType::None,
+            1 => CompressionType::Lz4,
+            2 => CompressionType::Zstd,
+            3 => CompressionType::Snappy,
+            _ => bail!("unknown compression type {codec}"),
+        })
+    }
+
+    pub(crate) fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            CompressionType::None => data.to_vec(),
+            CompressionType::Lz4 => lz4_flex::compress(data),
+            CompressionType::Zstd => zstd::bulk::compress(data, 0)?,
+            CompressionType::Snappy => {
+                snap::raw::Encoder::new().compress_vec(data)?
+            }
+        })
+    }
+
+    pub(crate) fn decompress(
+        self,
+        data: Bytes,
+        uncompressed_len: usize,
+    ) -> Result<Bytes> {
+        let decompressed = match self {
+            // zero-copy from an mmap-ed file
+            CompressionType::None => data,
+            CompressionType::Lz4 => {
+                lz4_flex::decompress(&data, uncompressed_len)?.into()
+            }
+            CompressionType::Zstd => {
+                zstd::bulk::decompress(&data, uncompressed_len)?.into()
+            }
+            CompressionType::Snappy => {
+                snap::raw::Decoder::new().decompress_vec(&data)?.into()
+            }
+        };
+        if decompressed.len() != uncompressed_len {
+            bail!(
+                "block decompressed to {} bytes, expected {uncompressed_len}",
+                decompressed.len()
+            );
+        }
+        Ok(decompressed)
+    }
+}
@@ -0,0 +1,245 @@
+// SPDX-FileCopyrightText: LakeSoul Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    alloc::{self, Layout},
+    fs::File,
+    io,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    os::unix::io::AsRawFd,
+    path::Path,
+    ptr::NonNull,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+/// How SST files are read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileBackend {
+    /// A `pread` into a new buffer for each read
+    #[default]
+    Buffered,
+    /// The whole file is mapped, and reads are zero-copy slices of the
+    /// mapping
+    Mmap,
+    /// `O_DIRECT` reads into aligned buffers, bypassing the page cache.
+    /// Only worth it when the block cache is large, as nothing else caches
+    /// the blocks.
+    Direct,
+}
+
+/// Reads of an `O_DIRECT` file must be aligned to the logical block size of
+/// its device, which this is a multiple of.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+enum FileReader {
+    Buffered(File),
+    Mmap(Bytes),
+    Direct(File),
+}
+
+/// A file object
+pub struct FileObject(Option<FileReader>, u64);
+
+impl FileObject {
+    /// Create a new file object and write the file to the disk.
+    pub fn create(
+        path: &Path,
+        data: Vec<u8>,
+        backend: FileBackend,
+    ) -> Result<Self> {
+        std::fs::write(path, &data)?;
+        File::open(path)?.sync_all()?;
+        Self::open_with_backend(path, backend)
+    }
+
+    /// A file object of `size` bytes that cannot be read.
+    pub(crate) fn without_file(size: u64) -> Self {
+        FileObject(None, size)
+    }
+
+    /// open file object
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_backend(path, FileBackend::Buffered)
+    }
+
+    /// open file object, read with `backend`
+    pub fn open_with_backend(
+        path: impl AsRef<Path>,
+        backend: FileBackend,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let file = match backend {
+            FileBackend::Direct => File::options()
+                .read(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(path)?,
+            _ => File::options().read(true).write(false).open(path)?,
+        };
+        let size = file.metadata()?.len();
+        let reader = match backend {
+            FileBackend::Buffered => FileReader::Buffered(file),
+            FileBackend::Mmap => FileReader::Mmap(Mmap::map(&file, size)?),
+            FileBackend::Direct => FileReader::Direct(file),
+        };
+        Ok(FileObject(Some(reader), size))
+    }
+
+    /// read `len`` bytes from `offset` in file
+    pub fn read(&self, offset: u64, len: u64) -> Result<Bytes> {
+        match self.0.as_ref().unwrap() {
+            FileReader::Buffered(file) => {
+                let mut data = vec![0; len as usize];
+                file.read_exact_at(&mut data[..], offset)?;
+                Ok(data.into())
+            }
+            FileReader::Mmap(map) => {
+                if offset + len > map.len() as u64 {
+                    return Err(
+                        io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                    );
+                }
+                Ok(map.slice(offset as usize..(offset + len) as usize))
+            }
+            FileReader::Direct(file) => read_direct(file, offset, len),
+        }
+    }
+
+    /// size of file
+    pub fn size(&self) -> u64 {
+        self.1
+    }
+}
+
+/// Read the aligned range around `len` bytes at `offset`, returning the
+/// bytes asked for as a slice of it.
+fn read_direct(file: &File, offset: u64, len: u64) -> Result<Bytes> {
+    if len == 0 {
+        return Ok(Bytes::new());
+    }
+    let start = offset as usize % DIRECT_IO_ALIGNMENT;
+    let aligned_offset = offset - start as u64;
+    let end = start + len as usize;
+    let mut buf = AlignedBuf::new(end.next_multiple_of(DIRECT_IO_ALIGNMENT));
+    let mut filled = 0;
+    // only the last read of the file comes back short
+    while filled < end {
+        match file.read_at(
+            &mut buf.as_mut()[filled..],
+            aligned_offset + filled as u64,
+        ) {
+            Ok(0) => {
+                return Err(
+                    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                );
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Bytes::from_owner(buf).slice(start..end))
+}
+
+/// A zeroed heap buffer aligned for `O_DIRECT` reads.
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the buffer is owned, and only shared immutably
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let layout = Self::layout(len);
+        // SAFETY: the layout is not zero-sized, as `len` is a positive
+        // multiple of the alignment
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr)
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, DIRECT_IO_ALIGNMENT).unwrap()
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuf {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` initialized bytes owned by `self`
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl AsMut<[u8]> for AlignedBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in `as_ref`, and `self` is borrowed mutably
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with the same layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+/// A read-only shared mapping of a whole file.
+struct Mmap {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map the `len` bytes of `file`. The mapping outlives the file
+    /// descriptor, and the file once removed.
+    fn map(file: &File, len: u64) -> Result<Bytes> {
+        if len == 0 {
+            return Ok(Bytes::new());
+        }
+        let len = len as usize;
+        // SAFETY: a fresh mapping of a valid descriptor. SSTs are never
+        // modified once written, so the mapped bytes do not change.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let ptr = NonNull::new(ptr.cast()).unwrap();
+        Ok(Bytes::from_owner(Self { ptr, len }))
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: the mapping covers `len` readable bytes until dropped
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `map` with the same length
+        unsafe {
+            libc::munmap(self.ptr.as_ptr().cast(), self.len);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// SPDX-FileCopyrightText: LakeSoul Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ops::Bound;
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut};
+use serde::{Deserialize, Serialize};
+
+use super::{bloom::Bloom, ribbon::Ribbon};
+
+/// The false positive rate the filters of an SST are built for.
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// The kind of filter SSTs are built with.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum FilterPolicy {
+    /// About 10 bits per key
+    #[default]
+    Bloom,
+    /// About 7.7 bits per key, at a slightly lower false positive rate.
+    /// Slower to build and to query.
+    Ribbon,
+}
+
+/// Maps a key to its prefix. SSTs built with one keep a filter of the
+/// prefixes of their keys, so that a scan within one prefix skips the SSTs
+/// that do not hold it. A scan is within one prefix when both bounds have
+/// the same prefix, or when its upper bound excludes the first key past the
+/// prefix of the lower bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrefixExtractor {
+    /// The first bytes of the key. Shorter keys have no prefix.
+    FixedLength(usize),
+    /// The key up to and including the first occurrence of the byte. Keys
+    /// without it have no prefix.
+    Delimiter(u8),
+}
+
+impl PrefixExtractor {
+    /// The prefix of `key`, `None` if it has none.
+    pub fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            Self::FixedLength(len) => key.get(..len),
+            Self::Delimiter(delimiter) => key
+                .iter()
+                .position(|byte| *byte == delimiter)
+                .map(|pos| &key[..=pos]),
+        }
+    }
+
+    /// The prefix shared by every key within the bounds, if there is one.
+    pub fn prefix_of_range<'a>(
+        &self,
+        lower: Bound<&'a [u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Option<&'a [u8]> {
+        let (Bound::Included(lower) | Bound::Excluded(lower)) = lower else {
+            return None;
+        };
+        let prefix = self.prefix(lower)?;
+        match upper {
+            Bound::Included(upper) | Bound::Excluded(upper)
+                if self.prefix(upper) == Some(prefix) =>
+            {
+                Some(prefix)
+            }
+            Bound::Excluded(upper) if next_prefix(prefix) == upper => {
+                Some(prefix)
+            }
+            _ => None,
+        }
+    }
+
+    const ENCODED_LEN: usize = 9;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let offset = buf.len();
+        match *self {
+            Self::FixedLength(len) => {
+                buf.put_u8(0);
+                buf.put_u32(len as u32);
+            }
+            Self::Delimiter(delimiter) => {
+                buf.put_u8(1);
+                buf.put_u32(delimiter as u32);
+            }
+        }
+        let checksum = crc32fast::hash(&buf[offset..]);
+        buf.put_u32(checksum);
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        if crc32fast::hash(&buf[..5]) != (&buf[5..]).get_u32() {
+            bail!("checksum mismatched for prefix extractor");
+        }
+        let tag = buf.get_u8();
+        let param = buf.get_u32();
+        match tag {
+            0 => Ok(Self::FixedLength(param as usize)),
+            1 => Ok(Self::Delimiter(param as u8)),
+            _ => bail!("unknown prefix extractor {tag}"),
+        }
+    }
+}
+
+/// The smallest key greater than every key starting with `prefix`, empty
+/// if there is none.
+fn next_prefix(prefix: &[u8]) -> Vec<u8> {
+    let mut next = prefix.to_vec();
+    while let Some(last) = next.pop() {
+        if last != u8::MAX {
+            next.push(last + 1);
+            break;
+        }
+    }
+    next
+}
+
+/// A filter of key hashes, built with a `FilterPolicy`.
+pub enum Filter {
+    Bloom(Bloom),
+    Ribbon(Ribbon),
+}
+
+impl Filter {
+    pub fn build_from_key_hashes(policy: FilterPolicy, keys: &[u32]) -> Self {
+        match policy {
+            FilterPolicy::Bloom => Self::Bloom(Bloom::build_from_key_hashes(
+                keys,
+                Bloom::bloom_bits_per_key(keys.len(), FALSE_POSITIVE_RATE),
+            )),
+            FilterPolicy::Ribbon => {
+                Self::Ribbon(Ribbon::build_from_key_hashes(
+                    keys,
+                    Ribbon::ribbon_bits_per_key(FALSE_POSITIVE_RATE),
+                ))
+            }
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Bloom(bloom) => bloom.encode(buf),
+            Self::Ribbon(ribbon) => ribbon.encode(buf),
+        }
+    }
+
+    /// Decode a filter. Both end with a byte before the checksum: the
+    /// number of hash functions of a bloom filter, which is at most 30, or
+    /// `Ribbon::MARKER`.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 5 {
+            bail!("filter too short");
+        }
+        if buf[buf.len() - 5] == Ribbon::MARKER {
+            Ok(Self::Ribbon(Ribbon::decode(buf)?))
+        } else {
+            Ok(Self::Bloom(Bloom::decode(buf)?))
+        }
+    }
+
+    /// Check if the filter may contain the key hash `h`
+    pub fn may_contain(&self, h: u32) -> bool {
+        match self {
+            Self::Bloom(bloom) => bloom.may_contain(h),
+            Self::Ribbon(ribbon) => ribbon.may_contain(h),
+        }
+    }
+}
+
+/// The filter of the prefixes of the keys of an SST, with the extractor it
+/// was built with.
+pub struct PrefixFilter {
+    pub extractor: PrefixExtractor,
+    pub filter: Filter,
+}
+
+impl PrefixFilter {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        self.extractor.encode(buf);
+        self.filter.encode(buf);
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < PrefixExtractor::ENCODED_LEN {
+            bail!("prefix filter too short");
+        }
+        let (extractor, filter) = buf.split_at(PrefixExtractor::ENCODED_LEN);
+        Ok(Self {
+            extractor: PrefixExtractor::decode(extractor)?,
+            filter: Filter::decode(filter)?,
+        })
+    }
+
+    /// Check the filter for a scan, `false` means no key within the bounds
+    /// is in the SST.
+    pub fn may_contain_range(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> bool {
+        self.extractor
+            .prefix_of_range(lower, upper)
+            .is_none_or(|prefix| {
+                self.filter.may_contain(farmhash::fingerprint32(prefix))
+            })
+    }
+}
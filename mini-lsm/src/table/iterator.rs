@@ -1,3 +1,248 @@
 // SPDX-FileCopyrightText: LakeSoul Contributors
 //
 // SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::{
+    block::{Block, BlockIterator},
+    iterators::{SeekIterator, StorageIterator},
+    key::{KeySlice, TS_RANGE_END},
+};
+
+use super::SsTable;
+
+/// An iterator over the contents of an SSTable.
+pub struct SsTableIterator {
+    table: Arc<SsTable>,
+    blk_iter: BlockIterator,
+    blk_idx: usize,
+}
+
+impl SsTableIterator {
+    /// An exhausted iterator over an SST holding range tombstones only.
+    fn empty_inner() -> (usize, BlockIterator) {
+        let block = Block {
+            data: Bytes::new(),
+            restarts: Vec::new(),
+        };
+        (0, BlockIterator::create_and_seek_to_first(Arc::new(block)))
+    }
+
+    fn seek_to_first_inner(
+        table: &Arc<SsTable>,
+    ) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok(Self::empty_inner());
+        }
+        Ok((
+            0,
+            BlockIterator::create_and_seek_to_first(
+                table.read_block_cached(0)?,
+            ),
+        ))
+    }
+
+    /// Create a new iterator and seek to the first key-value pair in the first data block.
+    pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&table)?;
+        Ok(Self {
+            table,
+            blk_iter,
+            blk_idx,
+        })
+    }
+
+    /// Create a new iterator and seek to the last key-value pair.
+    pub fn create_and_seek_to_last(table: Arc<SsTable>) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::empty_inner();
+        let mut iter = Self {
+            table,
+            blk_iter,
+            blk_idx,
+        };
+        SeekIterator::seek_to_last(&mut iter)?;
+        Ok(iter)
+    }
+
+    /// Create a new iterator and seek to the last key-value pair which <=
+    /// `key`.
+    pub fn create_and_seek_for_prev(
+        table: Arc<SsTable>,
+        key: KeySlice,
+    ) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::empty_inner();
+        let mut iter = Self {
+            table,
+            blk_iter,
+            blk_idx,
+        };
+        iter.seek_for_prev(key)?;
+        Ok(iter)
+    }
+
+    /// Seek to the first key-value pair in the first data block.
+    pub fn seek_to_first(&mut self) -> Result<()> {
+        let (blk_idx, blk_iter) = Self::seek_to_first_inner(&self.table)?;
+        self.blk_idx = blk_idx;
+        self.blk_iter = blk_iter;
+        Ok(())
+    }
+
+    /// The key to look up in the blocks for `key`. The keys of an ingested
+    /// SST are stored at ts 0, each user key once, so its seeks find the
+    /// user key first, then check its global ts.
+    fn stored_key<'a>(table: &SsTable, key: KeySlice<'a>) -> KeySlice<'a> {
+        match table.global_ts {
+            Some(_) => KeySlice::from_slice(key.key_ref(), TS_RANGE_END),
+            None => key,
+        }
+    }
+
+    /// Skip the entry a seek to `key` found if it is before `key`, which
+    /// only an ingested SST read at its global ts can hold.
+    fn skip_before(&mut self, key: KeySlice) -> Result<()> {
+        if self.table.global_ts.is_some() && self.is_valid() && self.key() < key
+        {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    fn seek_to_key_inner(
+        table: &Arc<SsTable>,
+        key: KeySlice,
+    ) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok(Self::empty_inner());
+        }
+        let key = Self::stored_key(table, key);
+        let mut blk_idx = table.find_block_idx(key);
+        let mut blk_iter = BlockIterator::create_and_seek_to_key(
+            table.read_block_cached(blk_idx)?,
+            key,
+        );
+        if !blk_iter.is_valid() {
+            blk_idx += 1;
+            if blk_idx < table.num_of_blocks() {
+                blk_iter = BlockIterator::create_and_seek_to_first(
+                    table.read_block_cached(blk_idx)?,
+                );
+            }
+        }
+        Ok((blk_idx, blk_iter))
+    }
+
+    /// Create a new iterator and seek to the first key-value pair which >= `key`.
+    pub fn create_and_seek_to_key(
+        table: Arc<SsTable>,
+        key: KeySlice,
+    ) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key)?;
+        let mut iter = Self {
+            table,
+            blk_iter,
+            blk_idx,
+        };
+        iter.skip_before(key)?;
+        Ok(iter)
+    }
+
+    /// Seek to the first key-value pair which >= `key`.
+    pub fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key)?;
+        self.blk_iter = blk_iter;
+        self.blk_idx = blk_idx;
+        self.skip_before(key)
+    }
+}
+
+impl StorageIterator for SsTableIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn value(&self) -> &[u8] {
+        self.blk_iter.value()
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        let key = self.blk_iter.key();
+        match self.table.global_ts {
+            Some(ts) => KeySlice::from_slice(key.key_ref(), ts),
+            None => key,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.blk_iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.blk_iter.next();
+        if !self.blk_iter.is_valid() {
+            self.blk_idx += 1;
+            if self.blk_idx < self.table.num_of_blocks() {
+                self.blk_iter = BlockIterator::create_and_seek_to_first(
+                    self.table.read_block_cached(self.blk_idx)?,
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SeekIterator for SsTableIterator {
+    fn prev(&mut self) -> Result<()> {
+        self.blk_iter.prev();
+        if !self.blk_iter.is_valid() && self.blk_idx > 0 {
+            self.blk_idx -= 1;
+            self.blk_iter = BlockIterator::create_and_seek_to_last(
+                self.table.read_block_cached(self.blk_idx)?,
+            );
+        }
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        SsTableIterator::seek_to_first(self)
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        let Some(blk_idx) = self.table.num_of_blocks().checked_sub(1) else {
+            (self.blk_idx, self.blk_iter) = Self::empty_inner();
+            return Ok(());
+        };
+        self.blk_idx = blk_idx;
+        self.blk_iter = BlockIterator::create_and_seek_to_last(
+            self.table.read_block_cached(blk_idx)?,
+        );
+        Ok(())
+    }
+
+    fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
+        SsTableIterator::seek_to_key(self, key)
+    }
+
+    fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        if self.table.num_of_blocks() == 0 {
+            (self.blk_idx, self.blk_iter) = Self::empty_inner();
+            return Ok(());
+        }
+        // the last entry <= `key` is in the last block starting at or
+        // before it, unless `key` is before the first entry
+        let stored_key = Self::stored_key(&self.table, key);
+        self.blk_idx = self.table.find_block_idx(stored_key);
+        self.blk_iter = BlockIterator::create_and_seek_for_prev(
+            self.table.read_block_cached(self.blk_idx)?,
+            stored_key,
+        );
+        // an ingested SST may hold the user key at a ts after `key`
+        if self.table.global_ts.is_some() && self.is_valid() && self.key() > key
+        {
+            self.prev()?;
+        }
+        Ok(())
+    }
+}
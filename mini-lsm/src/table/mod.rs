@@ -2,19 +2,32 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
-use bloom::Bloom;
-use std::{fs::File, path::Path, sync::Arc};
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut, Bytes};
+use std::{fs::File, ops::Bound, os::unix::fs::FileExt, path::Path, sync::Arc};
 
 use crate::{
     block::Block,
-    key::{KeyBytes, KeySlice},
+    key::{KeyBytes, KeySlice, TS_RANGE_BEGIN, TS_RANGE_END},
     lsm_storage::BlockCache,
+    range_tombstone::RangeTombstone,
 };
 
 pub(crate) mod bloom;
 mod builder;
+mod compression;
+mod file;
+pub(crate) mod filter;
 mod iterator;
+pub(crate) mod ribbon;
+mod writer;
+pub use builder::SsTableBuilder;
+pub use compression::CompressionType;
+pub use file::{FileBackend, FileObject};
+use filter::{Filter, PrefixFilter};
+pub use filter::{FilterPolicy, PrefixExtractor};
+pub use iterator::SsTableIterator;
+pub use writer::SstFileWriter;
 
 pub struct SsTable {
     pub(crate) file: FileObject,
@@ -24,8 +37,13 @@ pub struct SsTable {
     block_cache: Option<Arc<BlockCache>>,
     first_key: KeyBytes,
     last_key: KeyBytes,
-    pub(crate) bloom: Option<Bloom>,
+    pub(crate) filter: Option<Filter>,
+    pub(crate) prefix_filter: Option<PrefixFilter>,
     max_ts: u64,
+    pub(crate) range_tombstones: Vec<RangeTombstone>,
+    /// The ts every key is read at, in place of the ts 0 they are stored
+    /// with, for an SST ingested from an external file
+    pub(crate) global_ts: Option<u64>,
 }
 impl SsTable {
     /// Open SSTable from a file.
@@ -34,28 +52,100 @@ impl SsTable {
         block_cache: Option<Arc<BlockCache>>,
         file: FileObject,
     ) -> Result<Self> {
-        todo!()
-        // let len = file.size();
-        // let raw_bloom_offset = file.read(len - 4, 4)?;
-        // let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
-        // let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
-        // let bloom_filter = Bloom::decode(&raw_bloom)?;
-        // let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
-        // let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
-        // let raw_meta =
-        //     file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
-        // let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
-        // Ok(Self {
-        //     file,
-        //     first_key: block_meta.first().unwrap().first_key.clone(),
-        //     last_key: block_meta.last().unwrap().last_key.clone(),
-        //     block_meta,
-        //     block_meta_offset: block_meta_offset as usize,
-        //     id,
-        //     block_cache,
-        //     bloom: Some(bloom_filter),
-        //     max_ts,
-        // })
+        // 0 unless ingested
+        let size = file.size();
+        if size < 8 {
+            bail!("SST too short for the global ts");
+        }
+        let global_ts = file.read(size - 8, 8)?.get_u64();
+        // the sections are read from the end, each followed by its offset
+        let mut end = size - 8;
+        let mut read_section = |name: &str| -> Result<Bytes> {
+            if end < 4 {
+                bail!("SST too short for the {name} offset");
+            }
+            let offset = file.read(end - 4, 4)?.get_u32() as u64;
+            if offset > end - 4 {
+                bail!("invalid {name} offset {offset}");
+            }
+            let raw = file.read(offset, end - 4 - offset)?;
+            end = offset;
+            Ok(raw)
+        };
+        let filter = Filter::decode(&read_section("filter")?)?;
+        // empty without a prefix extractor
+        let raw_prefix_filter = read_section("prefix filter")?;
+        let prefix_filter = if raw_prefix_filter.is_empty() {
+            None
+        } else {
+            Some(PrefixFilter::decode(&raw_prefix_filter)?)
+        };
+        let range_tombstones =
+            RangeTombstone::decode_all(&read_section("range tombstones")?)?;
+        let raw_meta = read_section("block meta")?;
+        let block_meta_offset = end;
+        let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        if block_meta.is_empty() && range_tombstones.is_empty() {
+            bail!("SST holds no data");
+        }
+        // each block ends with its checksum, before the next one
+        let block_ends = block_meta
+            .iter()
+            .skip(1)
+            .map(|meta| meta.offset as u64)
+            .chain([block_meta_offset]);
+        if block_meta
+            .iter()
+            .zip(block_ends)
+            .any(|(meta, end)| meta.offset as u64 + 4 > end)
+        {
+            bail!("invalid block offsets");
+        }
+        let (first_key, last_key) =
+            Self::key_range(&block_meta, &range_tombstones);
+        let table = Self {
+            file,
+            first_key,
+            last_key,
+            block_meta,
+            range_tombstones,
+            block_meta_offset: block_meta_offset as usize,
+            id,
+            block_cache,
+            filter: Some(filter),
+            prefix_filter,
+            max_ts,
+            global_ts: None,
+        };
+        Ok(match global_ts {
+            0 => table,
+            ts => table.with_global_ts(ts),
+        })
+    }
+
+    /// Read the keys of the SST at `path`, all stored at ts 0, at `ts` from
+    /// then on. Ingestion stages an SST first, and sets its ts on commit.
+    pub(crate) fn set_global_ts(self, path: &Path, ts: u64) -> Result<Self> {
+        let file = File::options().write(true).open(path)?;
+        file.write_all_at(&ts.to_be_bytes(), self.file.size() - 8)?;
+        file.sync_data()?;
+        Ok(self.with_global_ts(ts))
+    }
+
+    fn with_global_ts(self, ts: u64) -> Self {
+        Self {
+            first_key: KeyBytes::from_bytes_with_ts(
+                self.first_key.into_inner(),
+                ts,
+            ),
+            last_key: KeyBytes::from_bytes_with_ts(
+                self.last_key.into_inner(),
+                ts,
+            ),
+            max_ts: ts,
+            global_ts: Some(ts),
+            ..self
+        }
     }
     /// Create a mock SST with only first key + last key metadata
     pub fn create_meta_only(
@@ -64,28 +154,117 @@ impl SsTable {
         first_key: KeyBytes,
         last_key: KeyBytes,
     ) -> Self {
-        todo!()
+        Self {
+            file: FileObject::without_file(file_size),
+            block_meta: vec![],
+            block_meta_offset: 0,
+            id,
+            block_cache: None,
+            first_key,
+            last_key,
+            filter: None,
+            prefix_filter: None,
+            max_ts: 0,
+            range_tombstones: Vec::new(),
+            global_ts: None,
+        }
+    }
+
+    /// The smallest and largest keys covered by the data blocks and the
+    /// range tombstones.
+    fn key_range(
+        block_meta: &[BlockMeta],
+        range_tombstones: &[RangeTombstone],
+    ) -> (KeyBytes, KeyBytes) {
+        let starts = range_tombstones.iter().map(|tombstone| {
+            KeyBytes::from_bytes_with_ts(
+                tombstone.start.clone(),
+                TS_RANGE_BEGIN,
+            )
+        });
+        let ends = range_tombstones.iter().map(|tombstone| {
+            KeyBytes::from_bytes_with_ts(tombstone.end.clone(), TS_RANGE_END)
+        });
+        let first_key = block_meta
+            .first()
+            .map(|meta| meta.first_key.clone())
+            .into_iter()
+            .chain(starts)
+            .min()
+            .expect("SST holds no data");
+        let last_key = block_meta
+            .last()
+            .map(|meta| meta.last_key.clone())
+            .into_iter()
+            .chain(ends)
+            .max()
+            .expect("SST holds no data");
+        (first_key, last_key)
     }
 
     /// Read a block from the disk.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        todo!()
+        let offset = self.block_meta[block_idx].offset;
+        let offset_end = self
+            .block_meta
+            .get(block_idx + 1)
+            .map_or(self.block_meta_offset, |x| x.offset);
+        let block_len = offset_end - offset - 4;
+        let block_data_with_chksum = self
+            .file
+            .read(offset as u64, (offset_end - offset) as u64)?;
+        let block_data = block_data_with_chksum.slice(..block_len);
+        let checksum = (&block_data_with_chksum[block_len..]).get_u32();
+        if checksum != crc32fast::hash(&block_data) {
+            bail!("block checksum mismatched");
+        }
+        let meta = &self.block_meta[block_idx];
+        let block_data = meta
+            .compression
+            .decompress(block_data, meta.uncompressed_len)?;
+        Ok(Arc::new(Block::decode(&block_data)))
     }
     /// Read a block from disk, with block cache.
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
-        todo!()
-        // if let Some(ref block_cache) = self.block_cache {
-        //     let blk = block_cache
-        //         .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-        //         .map_err(|e| anyhow!("{}", e))?;
-        //     Ok(blk)
-        // } else {
-        //     self.read_block(block_idx)
-        // }
+        if let Some(ref block_cache) = self.block_cache {
+            block_cache.try_get_with((self.id, block_idx), || {
+                self.read_block(block_idx)
+            })
+        } else {
+            self.read_block(block_idx)
+        }
     }
+    /// Find the block that may contain `key`.
     pub fn find_block_idx(&self, key: KeySlice) -> usize {
-        todo!()
+        self.block_meta
+            .partition_point(|meta| meta.first_key.as_key_slice() <= key)
+            .saturating_sub(1)
+    }
+    /// The range tombstones of this sstable, which may extend past its keys.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Check the filter, `false` means `key` is definitely absent.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter.as_ref().is_none_or(|filter| {
+            filter.may_contain(farmhash::fingerprint32(key))
+        })
+    }
+
+    /// Check the prefix filter for a scan, `false` means no key within the
+    /// bounds is in this SST. Only scans within one prefix of the extractor
+    /// the SST was built with can be ruled out.
+    pub fn may_contain_range(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> bool {
+        self.prefix_filter
+            .as_ref()
+            .is_none_or(|filter| filter.may_contain_range(lower, upper))
     }
+
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
         self.block_meta.len()
@@ -103,7 +282,7 @@ impl SsTable {
 
     /// size of this sstable
     pub fn table_size(&self) -> u64 {
-        self.file.1
+        self.file.size()
     }
 
     /// id of this sstable
@@ -115,6 +294,19 @@ impl SsTable {
     pub fn max_ts(&self) -> u64 {
         self.max_ts
     }
+
+    /// size of the data blocks once decompressed
+    pub fn uncompressed_size(&self) -> u64 {
+        self.block_meta
+            .iter()
+            .map(|meta| meta.uncompressed_len as u64)
+            .sum()
+    }
+
+    /// size of the data blocks on disk, without their checksums
+    pub fn compressed_size(&self) -> u64 {
+        (self.block_meta_offset - self.block_meta.len() * 4) as u64
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -125,6 +317,10 @@ pub struct BlockMeta {
     pub first_key: KeyBytes,
     /// The last key of the data block.
     pub last_key: KeyBytes,
+    /// The codec the data block is stored with.
+    pub compression: CompressionType,
+    /// The size of the encoded block before compression.
+    pub uncompressed_len: usize,
 }
 impl BlockMeta {
     /// encode block meta to a buffer
@@ -133,48 +329,62 @@ impl BlockMeta {
         max_ts: u64,
         buf: &mut Vec<u8>,
     ) {
-        todo!()
+        let original_len = buf.len();
+        buf.put_u32(block_meta.len() as u32);
+        for meta in block_meta {
+            buf.put_u32(meta.offset as u32);
+            buf.put_u8(meta.compression.to_u8());
+            buf.put_u32(meta.uncompressed_len as u32);
+            buf.put_u16(meta.first_key.key_len() as u16);
+            buf.put_slice(meta.first_key.key_ref());
+            buf.put_u64(meta.first_key.ts());
+            buf.put_u16(meta.last_key.key_len() as u16);
+            buf.put_slice(meta.last_key.key_ref());
+            buf.put_u64(meta.last_key.ts());
+        }
+        buf.put_u64(max_ts);
+        buf.put_u32(crc32fast::hash(&buf[original_len..]));
     }
     /// decode block meta from a buffer
-    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
-        todo!()
+    pub fn decode_block_meta(buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
+        // the number of blocks, max ts and checksum
+        if buf.len() < 16 {
+            bail!("block meta too short");
+        }
+        let (mut buf, mut checksum) = buf.split_at(buf.len() - 4);
+        if crc32fast::hash(buf) != checksum.get_u32() {
+            bail!("meta checksum mismatched");
+        }
+        let num = buf.get_u32() as usize;
+        let mut block_meta = Vec::new();
+        for _ in 0..num {
+            let offset = buf.try_get_u32()? as usize;
+            let compression = CompressionType::from_u8(buf.try_get_u8()?)?;
+            let uncompressed_len = buf.try_get_u32()? as usize;
+            let first_key = decode_key(&mut buf)?;
+            let last_key = decode_key(&mut buf)?;
+            block_meta.push(BlockMeta {
+                offset,
+                first_key,
+                last_key,
+                compression,
+                uncompressed_len,
+            });
+        }
+        let max_ts = buf.try_get_u64()?;
+        if buf.has_remaining() {
+            bail!("malformed block meta");
+        }
+        Ok((block_meta, max_ts))
     }
 }
 
-/// A file object
-pub struct FileObject(Option<File>, u64);
-
-impl FileObject {
-    /// Create a new file object and write the file to the disk.
-    pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
-        File::open(path)?.sync_all()?;
-        Ok(FileObject(
-            Some(File::options().read(true).write(false).open(path)?),
-            data.len() as u64,
-        ))
-    }
-
-    /// open file object
-    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let file = File::options().read(true).write(false).open(path)?;
-        let size = file.metadata()?.len();
-        Ok(FileObject(Some(file), size))
-    }
-
-    /// read `len`` bytes from `offset` in file
-    pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
-        use std::os::unix::fs::FileExt;
-        let mut data = vec![0; len as usize];
-        self.0
-            .as_ref()
-            .unwrap()
-            .read_exact_at(&mut data[..], offset)?;
-        Ok(data)
-    }
-
-    /// size of file
-    pub fn size(&self) -> u64 {
-        self.1
+/// Decode a key stored with its length in front and its ts after it.
+fn decode_key(buf: &mut &[u8]) -> Result<KeyBytes> {
+    let len = buf.try_get_u16()? as usize;
+    if buf.remaining() < len {
+        bail!("malformed block meta");
     }
+    let key = buf.copy_to_bytes(len);
+    Ok(KeyBytes::from_bytes_with_ts(key, buf.try_get_u64()?))
 }
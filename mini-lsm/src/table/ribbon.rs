@@ -0,0 +1,198 @@
+// SPDX-FileCopyrightText: LakeSoul Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut};
+
+/// Implements a standard ribbon filter with 64-bit coefficient rows. Each
+/// key maps to a start slot, a 64-bit coefficient and an `r`-bit
+/// fingerprint; the filter stores an `r`-bit solution per slot such that
+/// the coefficient dotted with the 64 solutions from the start slot yields
+/// the fingerprint of every key. Other keys match with a probability of
+/// 2^-r, at about `r * 1.1` bits per key instead of the `r * 1.44` of a
+/// bloom filter.
+pub struct Ribbon {
+    /// number of slots, at least 64
+    pub(crate) num_slots: usize,
+    /// seed of the hashes the filter was built with
+    pub(crate) seed: u32,
+    /// fingerprint bits per key
+    pub(crate) r: u8,
+    /// solution bits, column by column: bit `i` of column `j` is bit `j` of
+    /// the solution of slot `i`. Each column has a trailing padding word.
+    pub(crate) columns: Vec<u64>,
+}
+
+const COEFF_BITS: usize = 64;
+
+/// Builds with a fresh seed after a failure, and more slots after this many
+const SEEDS_PER_SIZE: u32 = 4;
+
+impl Ribbon {
+    /// Marks a ribbon filter where a bloom filter keeps its hash count.
+    pub(crate) const MARKER: u8 = 0x80;
+
+    /// Fingerprint bits per key for a false positive rate
+    pub fn ribbon_bits_per_key(false_positive_rate: f64) -> u8 {
+        (1.0 / false_positive_rate).log2().ceil().clamp(1.0, 32.0) as u8
+    }
+
+    /// Build ribbon filter from key hashes
+    pub fn build_from_key_hashes(keys: &[u32], r: u8) -> Self {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        let mut num_slots = (keys.len() + keys.len() / 10).max(COEFF_BITS);
+        let mut seed = 0;
+        loop {
+            if let Some(ribbon) = Self::try_build(&keys, num_slots, seed, r) {
+                return ribbon;
+            }
+            seed += 1;
+            if seed % SEEDS_PER_SIZE == 0 {
+                num_slots += num_slots / 10;
+            }
+        }
+    }
+
+    fn try_build(
+        keys: &[u32],
+        num_slots: usize,
+        seed: u32,
+        r: u8,
+    ) -> Option<Self> {
+        let mut coeffs = vec![0u64; num_slots];
+        let mut results = vec![0u32; num_slots];
+        for h in keys {
+            let (mut slot, mut coeff, mut result) =
+                Self::hash(*h, seed, num_slots, r);
+            // Gaussian elimination, keeping the lowest bit of each row set
+            loop {
+                if coeffs[slot] == 0 {
+                    coeffs[slot] = coeff;
+                    results[slot] = result;
+                    break;
+                }
+                coeff ^= coeffs[slot];
+                result ^= results[slot];
+                if coeff == 0 {
+                    if result == 0 {
+                        // implied by the keys before
+                        break;
+                    }
+                    return None;
+                }
+                let shift = coeff.trailing_zeros();
+                slot += shift as usize;
+                coeff >>= shift;
+            }
+        }
+
+        let words_per_column = num_slots.div_ceil(64) + 1;
+        let mut ribbon = Self {
+            num_slots,
+            seed,
+            r,
+            columns: vec![0; words_per_column * r as usize],
+        };
+        // back substitution, from the last slot up
+        for slot in (0..num_slots).rev() {
+            for column in 0..r as usize {
+                let parity = (coeffs[slot] & ribbon.window(column, slot))
+                    .count_ones()
+                    & 1;
+                let bit = parity ^ ((results[slot] >> column) & 1);
+                if bit != 0 {
+                    let word = column * words_per_column + slot / 64;
+                    ribbon.columns[word] |= 1 << (slot % 64);
+                }
+            }
+        }
+        Some(ribbon)
+    }
+
+    /// The start slot, coefficient row and fingerprint of a key hash.
+    fn hash(h: u32, seed: u32, num_slots: usize, r: u8) -> (usize, u64, u32) {
+        let h1 = mix64(((seed as u64) << 32) | h as u64);
+        let h2 = mix64(h1);
+        let num_starts = (num_slots - COEFF_BITS + 1) as u64;
+        let start = ((h1 >> 32) * num_starts) >> 32;
+        let fingerprint = (h1 as u32) & (u32::MAX >> (32 - r as u32));
+        (start as usize, h2 | 1, fingerprint)
+    }
+
+    /// The 64 solution bits of `column` from `slot` on.
+    fn window(&self, column: usize, slot: usize) -> u64 {
+        let words_per_column = self.num_slots.div_ceil(64) + 1;
+        let word = column * words_per_column + slot / 64;
+        let shift = slot % 64;
+        let low = self.columns[word] >> shift;
+        if shift == 0 {
+            low
+        } else {
+            low | (self.columns[word + 1] << (64 - shift))
+        }
+    }
+
+    /// Encode a ribbon filter
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let offset = buf.len();
+        buf.put_u32(self.num_slots as u32);
+        buf.put_u32(self.seed);
+        buf.put_u8(self.r);
+        for word in &self.columns {
+            buf.put_u64(*word);
+        }
+        buf.put_u8(Self::MARKER);
+        let checksum = crc32fast::hash(&buf[offset..]);
+        buf.put_u32(checksum);
+    }
+
+    /// Decode a ribbon filter
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 14 {
+            bail!("ribbon filter too short");
+        }
+        let checksum = (&buf[buf.len() - 4..]).get_u32();
+        if checksum != crc32fast::hash(&buf[..buf.len() - 4]) {
+            bail!("checksum mismatched for ribbon filters");
+        }
+        let mut raw = &buf[..buf.len() - 5];
+        let num_slots = raw.get_u32() as usize;
+        let seed = raw.get_u32();
+        let r = raw.get_u8();
+        let num_words = (num_slots.div_ceil(64) + 1) * r as usize;
+        if num_slots < COEFF_BITS
+            || !(1..=32).contains(&r)
+            || raw.remaining() != num_words * 8
+        {
+            bail!("invalid ribbon filter");
+        }
+        let columns = (0..num_words).map(|_| raw.get_u64()).collect();
+        Ok(Self {
+            num_slots,
+            seed,
+            r,
+            columns,
+        })
+    }
+
+    /// Check if a ribbon filter may contain some data
+    pub fn may_contain(&self, h: u32) -> bool {
+        let (slot, coeff, fingerprint) =
+            Self::hash(h, self.seed, self.num_slots, self.r);
+        (0..self.r as usize).all(|column| {
+            let parity = (coeff & self.window(column, slot)).count_ones() & 1;
+            parity == (fingerprint >> column) & 1
+        })
+    }
+}
+
+/// The finalizer of SplitMix64, spreading the bits of the key hash and seed.
+fn mix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,74 @@
+// SPDX-FileCopyrightText: LakeSoul Contributors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+
+use anyhow::{Result, bail};
+
+use crate::{key::KeySlice, value::StoredValue};
+
+use super::SsTableBuilder;
+
+/// Writes an SST outside the engine, to be bulk-loaded with
+/// `MiniLsm::ingest_external_files`. Keys must be added in strictly
+/// increasing order, each with a single version.
+pub struct SstFileWriter {
+    builder: SsTableBuilder,
+    last_key: Option<Vec<u8>>,
+}
+
+impl SstFileWriter {
+    /// Create a writer building blocks of `block_size`.
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            builder: SsTableBuilder::new(block_size),
+            last_key: None,
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if value.is_empty() {
+            bail!("value cannot be empty");
+        }
+        let mut buf = Vec::with_capacity(value.len() + 1);
+        StoredValue {
+            expire_at: None,
+            value,
+            separated: false,
+        }
+        .encode(&mut buf);
+        self.add(key, &buf)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        // an empty value marks a tombstone
+        self.add(key, &[])
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            bail!("key cannot be empty");
+        }
+        // lengths are stored as u16 in the blocks
+        if key.len() > u16::MAX as usize || value.len() > u16::MAX as usize {
+            bail!("key or value too large");
+        }
+        if self.last_key.as_deref().is_some_and(|last| last >= key) {
+            bail!("keys must be added in strictly increasing order");
+        }
+        // versioned once ingested
+        self.builder.add(KeySlice::from_slice(key, 0), value);
+        self.last_key = Some(key.to_vec());
+        Ok(())
+    }
+
+    /// Write the SST to `path`.
+    pub fn finish(self, path: impl AsRef<Path>) -> Result<()> {
+        if self.last_key.is_none() {
+            bail!("cannot write an SST without keys");
+        }
+        self.builder.build(0, None, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use crate::{
+    block::{Block, BlockBuilder, BlockIterator, RESTART_INTERVAL},
+    key::KeySlice,
+};
+
+use super::harness::{key_of, value_of};
+
+const NUM_KEYS: usize = 100;
+
+fn generate_block() -> Block {
+    let mut builder = BlockBuilder::new(65536);
+    for idx in 0..NUM_KEYS {
+        // two versions of each key, newest first
+        for ts in [2, 1] {
+            assert!(builder.add(
+                KeySlice::for_testing_from_slice_with_ts(&key_of(idx), ts),
+                &value_of(idx, ts as usize),
+            ));
+        }
+    }
+    builder.build()
+}
+
+#[test]
+fn test_block_encode_decode() {
+    let block = generate_block();
+    assert_eq!(
+        block.restarts.len(),
+        (2 * NUM_KEYS).div_ceil(RESTART_INTERVAL)
+    );
+    let encoded = block.encode();
+    let decoded = Block::decode(&encoded);
+    assert_eq!(decoded.data, block.data);
+    assert_eq!(decoded.restarts, block.restarts);
+
+    let mut iter = BlockIterator::create_and_seek_to_first(Arc::new(decoded));
+    for idx in 0..NUM_KEYS {
+        for ts in [2, 1] {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key().key_ref(), key_of(idx));
+            assert_eq!(iter.key().ts(), ts);
+            assert_eq!(iter.value(), value_of(idx, ts as usize));
+            iter.next();
+        }
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_prefix_compression() {
+    let block = generate_block();
+    let raw_size = (0..NUM_KEYS)
+        .map(|idx| 2 * (key_of(idx).len() + 8 + value_of(idx, 1).len() + 6))
+        .sum::<usize>();
+    // keys share a long common prefix with the previous one
+    assert!(block.encode().len() < raw_size * 4 / 5);
+}
+
+#[test]
+fn test_block_seek_to_key() {
+    let block = Arc::new(generate_block());
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for idx in 0..NUM_KEYS {
+        let key = key_of(idx);
+        for (seek_ts, expected_ts) in [(3, 2), (2, 2), (1, 1)] {
+            iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(
+                &key, seek_ts,
+            ));
+            assert!(iter.is_valid());
+            assert_eq!(iter.key().key_ref(), key);
+            assert_eq!(iter.key().ts(), expected_ts);
+            assert_eq!(iter.value(), value_of(idx, expected_ts as usize));
+        }
+        // past the oldest version lands on the next key
+        iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(&key, 0));
+        if idx + 1 < NUM_KEYS {
+            assert_eq!(iter.key().key_ref(), key_of(idx + 1));
+            assert_eq!(iter.key().ts(), 2);
+        } else {
+            assert!(!iter.is_valid());
+        }
+    }
+    iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(b"", 0));
+    assert_eq!(iter.key().key_ref(), key_of(0));
+    iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(b"zzz", 0));
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_reverse() {
+    let block = Arc::new(generate_block());
+    let mut iter = BlockIterator::create_and_seek_to_last(block.clone());
+    for idx in (0..NUM_KEYS).rev() {
+        for ts in [1, 2] {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key().key_ref(), key_of(idx));
+            assert_eq!(iter.key().ts(), ts);
+            assert_eq!(iter.value(), value_of(idx, ts as usize));
+            iter.prev();
+        }
+    }
+    assert!(!iter.is_valid());
+
+    for idx in 0..NUM_KEYS {
+        let key = key_of(idx);
+        for (seek_ts, expected_ts) in [(0, 1), (1, 1), (2, 2)] {
+            iter.seek_for_prev(KeySlice::for_testing_from_slice_with_ts(
+                &key, seek_ts,
+            ));
+            assert_eq!(iter.key().key_ref(), key);
+            assert_eq!(iter.key().ts(), expected_ts);
+            assert_eq!(iter.value(), value_of(idx, expected_ts as usize));
+        }
+        // before the newest version lands on the previous key
+        iter.seek_for_prev(KeySlice::for_testing_from_slice_with_ts(&key, 3));
+        if idx > 0 {
+            assert_eq!(iter.key().key_ref(), key_of(idx - 1));
+            assert_eq!(iter.key().ts(), 1);
+        } else {
+            assert!(!iter.is_valid());
+        }
+    }
+    iter.seek_for_prev(KeySlice::for_testing_from_slice_with_ts(b"zzz", 0));
+    assert_eq!(iter.key().key_ref(), key_of(NUM_KEYS - 1));
+
+    // next and prev can be mixed
+    iter.seek_to_key(KeySlice::for_testing_from_slice_with_ts(&key_of(50), 2));
+    iter.prev();
+    assert_eq!(iter.key().key_ref(), key_of(49));
+    iter.next();
+    assert_eq!(iter.key().key_ref(), key_of(50));
+    assert_eq!(iter.key().ts(), 2);
+}
@@ -0,0 +1,114 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+use super::{
+    compaction::leveled_options,
+    harness::{check_get, key_of, value_of},
+};
+
+const NUM_THREADS: usize = 4;
+const KEYS_PER_THREAD: usize = 1_000_000;
+
+/// The number of leading keys of `thread` present in `storage`, asserting
+/// that none comes after.
+fn written_prefix(storage: &MiniLsm, thread: usize, limit: usize) -> usize {
+    let key = |idx| key_of(thread * KEYS_PER_THREAD + idx);
+    let present = (0..limit)
+        .take_while(|idx| storage.get(&key(*idx)).unwrap().is_some())
+        .count();
+    for idx in present..limit {
+        check_get(storage, &key(idx), None);
+    }
+    present
+}
+
+#[test]
+fn test_checkpoint_during_writes() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 16 << 10,
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(dir.path().join("db"), &options).unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let acked = Arc::new(
+        (0..NUM_THREADS)
+            .map(|_| AtomicUsize::new(0))
+            .collect::<Vec<_>>(),
+    );
+    let writers = (0..NUM_THREADS)
+        .map(|thread| {
+            let storage = Arc::clone(&storage);
+            let stop = Arc::clone(&stop);
+            let acked = Arc::clone(&acked);
+            std::thread::spawn(move || {
+                let mut idx = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let key = thread * KEYS_PER_THREAD + idx;
+                    storage.put(&key_of(key), &value_of(key, 0)).unwrap();
+                    idx += 1;
+                    acked[thread].store(idx, Ordering::SeqCst);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    std::thread::sleep(Duration::from_millis(200));
+    let acked_before = acked
+        .iter()
+        .map(|count| count.load(Ordering::SeqCst))
+        .collect::<Vec<_>>();
+    let checkpoint_dir = dir.path().join("checkpoint");
+    storage.checkpoint(&checkpoint_dir).unwrap();
+    let acked_after = acked
+        .iter()
+        .map(|count| count.load(Ordering::SeqCst))
+        .collect::<Vec<_>>();
+    std::thread::sleep(Duration::from_millis(50));
+    stop.store(true, Ordering::SeqCst);
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let acked_total = acked
+        .iter()
+        .map(|count| count.load(Ordering::SeqCst))
+        .collect::<Vec<_>>();
+    assert!(storage.checkpoint(&checkpoint_dir).is_err());
+
+    let checkpoint = MiniLsm::open(&checkpoint_dir, &options).unwrap();
+    for thread in 0..NUM_THREADS {
+        // writes racing with the checkpoint may land on either side, and a
+        // put may have returned without being counted yet
+        let prefix = written_prefix(&checkpoint, thread, acked_total[thread]);
+        assert!(prefix >= acked_before[thread]);
+        assert!(prefix <= acked_after[thread] + 1);
+    }
+
+    // the two engines no longer share anything
+    checkpoint.put(b"checkpoint", b"only").unwrap();
+    storage.put(b"source", b"only").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_compaction().unwrap();
+    check_get(&storage, b"checkpoint", None);
+    check_get(&checkpoint, b"source", None);
+    checkpoint.close().unwrap();
+    drop(checkpoint);
+    let checkpoint = MiniLsm::open(&checkpoint_dir, &options).unwrap();
+    check_get(&checkpoint, b"checkpoint", Some(b"only"));
+    for thread in 0..NUM_THREADS {
+        assert_eq!(written_prefix(&checkpoint, thread, 1), 1);
+    }
+}
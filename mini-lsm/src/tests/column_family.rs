@@ -0,0 +1,180 @@
+use std::{ops::Bound, path::Path};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{
+        ColumnFamilyHandle, ColumnFamilyOptions, LsmStorageOptions, MiniLsm,
+        WriteBatchRecord,
+    },
+};
+
+use super::{
+    compaction::leveled_options,
+    harness::{check_get, check_iter_result_by_key, key_of, value_of},
+};
+
+fn family_options() -> ColumnFamilyOptions {
+    ColumnFamilyOptions {
+        target_sst_size: 1 << 12,
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test().column_family_options()
+    }
+}
+
+fn check_get_cf(
+    storage: &MiniLsm,
+    cf: &ColumnFamilyHandle,
+    key: &[u8],
+    expected: Option<&[u8]>,
+) {
+    assert_eq!(
+        storage.get_cf(cf, key).unwrap(),
+        expected.map(Bytes::copy_from_slice),
+        "unexpected value for key {:?} in {}",
+        Bytes::copy_from_slice(key),
+        cf.name(),
+    );
+}
+
+fn count_files(dir: &Path, ext: &str) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|e| e == ext)
+        })
+        .count()
+}
+
+#[test]
+fn test_column_family_write_batch() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    let users = storage
+        .create_column_family("users", &family_options())
+        .unwrap();
+    assert!(
+        storage
+            .create_column_family("users", &family_options())
+            .is_err()
+    );
+    let default = storage.column_family("default").unwrap();
+    assert_eq!(storage.list_column_families(), ["default", "users"]);
+
+    // the same key lives apart in each family
+    storage
+        .write_batch_cf(&[
+            (&default, WriteBatchRecord::Put(&b"a"[..], &b"1"[..])),
+            (&users, WriteBatchRecord::Put(b"a", b"2")),
+            (&users, WriteBatchRecord::Put(b"b", b"3")),
+        ])
+        .unwrap();
+    check_get(&storage, b"a", Some(b"1"));
+    check_get(&storage, b"b", None);
+    check_get_cf(&storage, &users, b"a", Some(b"2"));
+    check_get_cf(&storage, &users, b"b", Some(b"3"));
+
+    storage.force_flush().unwrap();
+    storage.delete_cf(&users, b"a").unwrap();
+    check_get_cf(&storage, &users, b"a", None);
+    check_get(&storage, b"a", Some(b"1"));
+    let mut iter = storage
+        .scan_cf(&users, Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    check_iter_result_by_key(&mut iter, vec![(b"b", b"3")]);
+}
+
+#[test]
+fn test_column_family_persistence() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    let users = storage
+        .create_column_family("users", &family_options())
+        .unwrap();
+    // flushed to SSTs, and left in the memtables for WAL recovery
+    for idx in 0..100 {
+        storage
+            .put_cf(&users, &key_of(idx), &value_of(idx, 0))
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    for idx in 100..200 {
+        storage
+            .put_cf(&users, &key_of(idx), &value_of(idx, 0))
+            .unwrap();
+    }
+    storage.put(b"a", b"1").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    assert_eq!(storage.list_column_families(), ["default", "users"]);
+    let users = storage.column_family("users").unwrap();
+    // the options of the family outlive the engine options
+    assert_eq!(users.0.options.target_sst_size, 1 << 12);
+    assert!(matches!(
+        users.0.options.compaction_options,
+        CompactionOptions::Leveled(_)
+    ));
+    for idx in 0..200 {
+        check_get_cf(&storage, &users, &key_of(idx), Some(&value_of(idx, 0)));
+        check_get(&storage, &key_of(idx), None);
+    }
+    check_get(&storage, b"a", Some(b"1"));
+    check_get_cf(&storage, &users, b"a", None);
+}
+
+#[test]
+fn test_drop_column_family() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    // no compaction, so that the SSTs can be counted
+    let options = ColumnFamilyOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        ..family_options()
+    };
+    let users = storage.create_column_family("users", &options).unwrap();
+    storage.put_cf(&users, b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put_cf(&users, b"b", b"1").unwrap();
+    storage.put(b"a", b"2").unwrap();
+    storage.force_flush().unwrap();
+    assert_eq!(count_files(dir.path(), "sst"), 3);
+    // not flushed yet, so the WAL stays for the default family
+    storage.put_cf(&users, b"c", b"1").unwrap();
+    storage.put(b"b", b"2").unwrap();
+
+    assert!(storage.drop_column_family("default").is_err());
+    storage.drop_column_family("users").unwrap();
+    assert!(storage.drop_column_family("users").is_err());
+    assert!(storage.column_family("users").is_none());
+    assert!(storage.get_cf(&users, b"a").is_err());
+    assert!(storage.put_cf(&users, b"d", b"1").is_err());
+    assert_eq!(count_files(dir.path(), "sst"), 1);
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    assert_eq!(storage.list_column_families(), ["default"]);
+    check_get(&storage, b"a", Some(b"2"));
+    check_get(&storage, b"b", Some(b"2"));
+    check_get(&storage, b"c", None);
+    // a new family with the same name starts empty
+    let users = storage
+        .create_column_family("users", &family_options())
+        .unwrap();
+    check_get_cf(&storage, &users, b"a", None);
+    check_get_cf(&storage, &users, b"c", None);
+}
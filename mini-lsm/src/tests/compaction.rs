@@ -0,0 +1,444 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionFilter, CompactionOptions, LeveledCompactionController,
+        LeveledCompactionOptions, SimpleLeveledCompactionController,
+        SimpleLeveledCompactionOptions, TieredCompactionController,
+        TieredCompactionOptions,
+    },
+    iterators::StorageIterator,
+    key::KeyBytes,
+    lsm_storage::{LsmStorageOptions, LsmStorageState, MiniLsm},
+    table::{CompressionType, SsTable, SsTableIterator},
+};
+
+use super::harness::{check_get, key_of, value_of};
+
+pub(super) fn leveled_options() -> LeveledCompactionOptions {
+    LeveledCompactionOptions {
+        level_size_multiplier: 2,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+        base_level_size_mb: 1,
+    }
+}
+
+fn tiered_options() -> TieredCompactionOptions {
+    TieredCompactionOptions {
+        num_tiers: 3,
+        max_size_amplification_percent: 200,
+        size_ratio: 1,
+        min_merge_width: 2,
+        max_merge_width: None,
+    }
+}
+
+fn compaction_test_options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        target_sst_size: 64 << 10,
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    }
+}
+
+fn add_meta_only_sst(
+    state: &mut LsmStorageState,
+    id: usize,
+    size_mb: u64,
+    first: &[u8],
+    last: &[u8],
+) {
+    let key = |k: &[u8]| {
+        KeyBytes::for_testing_from_bytes_no_ts(Bytes::copy_from_slice(k))
+    };
+    state.sstables.insert(
+        id,
+        Arc::new(SsTable::create_meta_only(
+            id,
+            size_mb << 20,
+            key(first),
+            key(last),
+        )),
+    );
+}
+
+#[test]
+fn test_leveled_task_generation() {
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let controller = LeveledCompactionController::new(leveled_options());
+    let mut state = LsmStorageState::create(&options);
+    assert_eq!(state.levels.len(), 3);
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    add_meta_only_sst(&mut state, 1, 1, b"a", b"c");
+    add_meta_only_sst(&mut state, 2, 1, b"b", b"d");
+    add_meta_only_sst(&mut state, 3, 1, b"c", b"e");
+    add_meta_only_sst(&mut state, 4, 0, b"x", b"z");
+    state.l0_sstable = vec![2, 1];
+    state.levels[2].1 = vec![3, 4];
+
+    // L0 goes to the bottom level while it is within the base level size
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, None);
+    assert_eq!(task.upper_level_sst_ids, vec![2, 1]);
+    assert_eq!(task.lower_level, 3);
+    assert_eq!(task.lower_level_sst_ids, vec![3]);
+    assert!(task.is_lower_level_bottom_level);
+
+    let (state, removed) =
+        controller.apply_compaction_result(&state, &task, &[5], true);
+    assert!(state.l0_sstable.is_empty());
+    assert_eq!(state.levels[2].1, vec![4, 5]);
+    assert_eq!(removed, vec![2, 1, 3]);
+}
+
+#[test]
+fn test_leveled_task_by_level_priority() {
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let controller = LeveledCompactionController::new(leveled_options());
+    let mut state = LsmStorageState::create(&options);
+    // 4 MB at the bottom makes L2 a 2 MB level holding 3 MB
+    add_meta_only_sst(&mut state, 1, 1, b"a", b"b");
+    add_meta_only_sst(&mut state, 2, 2, b"c", b"d");
+    add_meta_only_sst(&mut state, 3, 4, b"a", b"z");
+    state.levels[1].1 = vec![1, 2];
+    state.levels[2].1 = vec![3];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(2));
+    assert_eq!(task.upper_level_sst_ids, vec![1]);
+    assert_eq!(task.lower_level, 3);
+    assert_eq!(task.lower_level_sst_ids, vec![3]);
+}
+
+#[test]
+fn test_simple_leveled_task_generation() {
+    let simple_options = SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 2,
+    };
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Simple(simple_options.clone()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let controller = SimpleLeveledCompactionController::new(simple_options);
+    let mut state = LsmStorageState::create(&options);
+    for id in 1..=4 {
+        add_meta_only_sst(&mut state, id, 1, b"a", b"z");
+    }
+    state.l0_sstable = vec![1];
+    state.levels[0].1 = vec![2, 3];
+    state.levels[1].1 = vec![4];
+    // L0 is below its trigger, L1 is twice the size of L2
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.upper_level_sst_ids, vec![2, 3]);
+    assert_eq!(task.lower_level, 2);
+    assert_eq!(task.lower_level_sst_ids, vec![4]);
+    assert!(task.is_lower_level_bottom_level);
+
+    let (state, removed) =
+        controller.apply_compaction_result(&state, &task, &[5, 6]);
+    assert_eq!(state.l0_sstable, vec![1]);
+    assert!(state.levels[0].1.is_empty());
+    assert_eq!(state.levels[1].1, vec![5, 6]);
+    assert_eq!(removed, vec![2, 3, 4]);
+}
+
+#[test]
+fn test_tiered_task_generation() {
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Tiered(tiered_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let controller = TieredCompactionController::new(tiered_options());
+    let mut state = LsmStorageState::create(&options);
+    assert!(state.levels.is_empty());
+    for id in 1..=8 {
+        add_meta_only_sst(&mut state, id, 1, b"a", b"z");
+    }
+    state.levels = vec![(1, vec![1]), (2, vec![2, 3, 4])];
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // the top two tiers together are half the size of the last one
+    state.levels = vec![(1, vec![1]), (2, vec![2]), (5, vec![5, 6, 7, 8])];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.tiers, vec![(1, vec![1]), (2, vec![2])]);
+    assert!(!task.bottom_tier_included);
+    let (new_state, removed) =
+        controller.apply_compaction_result(&state, &task, &[9]);
+    assert_eq!(new_state.levels, vec![(9, vec![9]), (5, vec![5, 6, 7, 8])]);
+    assert_eq!(removed, vec![1, 2]);
+
+    // 4 SSTs above a tier of 2 exceeds the space amplification limit
+    state.levels = vec![(1, vec![1, 2]), (3, vec![3, 4]), (5, vec![5, 6])];
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task.tiers.len(), 3);
+    assert!(task.bottom_tier_included);
+    let (new_state, _) = controller.apply_compaction_result(&state, &task, &[]);
+    assert!(new_state.levels.is_empty());
+}
+
+#[test]
+fn test_tiered_compaction_keeps_latest_data() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 64 << 10,
+        compaction_options: CompactionOptions::Tiered(tiered_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    storage.close().unwrap();
+    let mut expected = HashMap::new();
+    for round in 0..8 {
+        for idx in 0..500 {
+            if (idx + round) % 7 == 0 {
+                storage.delete(&key_of(idx)).unwrap();
+                expected.insert(idx, None);
+            } else {
+                let value = value_of(idx, round);
+                storage.put(&key_of(idx), &value).unwrap();
+                expected.insert(idx, Some(value));
+            }
+        }
+        storage.force_flush().unwrap();
+        while !storage
+            .inner
+            .default_family
+            .state
+            .read()
+            .imm_memtables
+            .is_empty()
+        {
+            storage
+                .inner
+                .force_flush_next_imm_memtable(&storage.inner.default_family)
+                .unwrap();
+        }
+        storage.force_compaction().unwrap();
+        let state = storage.inner.default_family.state.read();
+        assert!(state.l0_sstable.is_empty());
+        assert!(state.levels.len() < 3);
+    }
+    for (idx, value) in &expected {
+        check_get(&storage, &key_of(*idx), Option::as_deref(value));
+    }
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    for (idx, value) in &expected {
+        check_get(&storage, &key_of(*idx), Option::as_deref(value));
+    }
+    storage.close().unwrap();
+}
+
+#[test]
+fn test_leveled_compaction_keeps_latest_data() {
+    let dir = tempdir().unwrap();
+    let options = compaction_test_options();
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    // the background thread must not race with the assertions below
+    storage.close().unwrap();
+    let mut expected = HashMap::new();
+    for round in 0..6 {
+        for idx in 0..2000 {
+            if (idx + round) % 5 == 0 {
+                storage.delete(&key_of(idx)).unwrap();
+                expected.insert(idx, None);
+            } else {
+                let value = value_of(idx, round);
+                storage.put(&key_of(idx), &value).unwrap();
+                expected.insert(idx, Some(value));
+            }
+        }
+        storage.force_flush().unwrap();
+        while !storage
+            .inner
+            .default_family
+            .state
+            .read()
+            .imm_memtables
+            .is_empty()
+        {
+            storage
+                .inner
+                .force_flush_next_imm_memtable(&storage.inner.default_family)
+                .unwrap();
+        }
+        storage.force_compaction().unwrap();
+        assert!(storage.inner.default_family.state.read().l0_sstable.len() < 2);
+    }
+    {
+        let state = storage.inner.default_family.state.read();
+        let bottom = &state.levels[2].1;
+        assert!(!bottom.is_empty());
+        // the bottom level stays sorted and non-overlapping
+        for pair in bottom.windows(2) {
+            assert!(
+                state.sstables[&pair[0]].last_key()
+                    < state.sstables[&pair[1]].first_key()
+            );
+        }
+    }
+    for (idx, value) in &expected {
+        check_get(&storage, &key_of(*idx), Option::as_deref(value));
+    }
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    for (idx, value) in &expected {
+        check_get(&storage, &key_of(*idx), Option::as_deref(value));
+    }
+    storage.close().unwrap();
+}
+
+#[test]
+fn test_background_compaction() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, &compaction_test_options()).unwrap();
+    for round in 0..4 {
+        for idx in 0..1000 {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    let mut retries = 0;
+    while storage.inner.default_family.state.read().l0_sstable.len() >= 2 {
+        assert!(retries < 100, "background compaction did not run");
+        std::thread::sleep(Duration::from_millis(50));
+        retries += 1;
+    }
+    storage.close().unwrap();
+    for idx in 0..1000 {
+        check_get(&storage, &key_of(idx), Some(&value_of(idx, 3)));
+    }
+}
+
+#[test]
+fn test_compression_per_level() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compression_per_level: vec![
+            CompressionType::None,
+            CompressionType::Zstd,
+        ],
+        ..compaction_test_options()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    storage.close().unwrap();
+    for round in 0..2 {
+        for idx in 0..1000 {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    let codecs = |sst_ids: &[usize]| {
+        let state = storage.inner.default_family.state.read();
+        sst_ids
+            .iter()
+            .flat_map(|id| state.sstables[id].block_meta.clone())
+            .map(|meta| meta.compression)
+            .collect::<HashSet<_>>()
+    };
+    let l0 = storage.inner.default_family.state.read().l0_sstable.clone();
+    assert_eq!(codecs(&l0), HashSet::from([CompressionType::None]));
+
+    // deeper levels than configured use the last entry
+    storage.force_compaction().unwrap();
+    let state = storage.inner.default_family.state.read().clone();
+    assert!(state.l0_sstable.is_empty());
+    let compacted = state
+        .levels
+        .iter()
+        .flat_map(|(_, ids)| ids.clone())
+        .collect::<Vec<_>>();
+    assert!(!compacted.is_empty());
+    assert_eq!(codecs(&compacted), HashSet::from([CompressionType::Zstd]));
+    assert!(
+        compacted
+            .iter()
+            .all(|id| state.sstables[id].compression_ratio() > 1.0)
+    );
+    drop(state);
+
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    for idx in 0..1000 {
+        check_get(&storage, &key_of(idx), Some(&value_of(idx, 1)));
+    }
+}
+
+#[test]
+fn test_compaction_removes_expired_and_filtered() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, &compaction_test_options()).unwrap();
+    storage.close().unwrap();
+    storage.add_compaction_filter(CompactionFilter::Prefix(
+        Bytes::from_static(b"tenant1_"),
+    ));
+    storage.add_compaction_filter(CompactionFilter::custom(|_, value| {
+        value.starts_with(b"drop")
+    }));
+    for idx in 0..100 {
+        let suffix = format!("{idx:03}");
+        storage
+            .put(format!("tenant1_{suffix}").as_bytes(), b"value")
+            .unwrap();
+        storage
+            .put(format!("tenant2_{suffix}").as_bytes(), b"value")
+            .unwrap();
+        storage
+            .put(format!("dropped_{suffix}").as_bytes(), b"drop me")
+            .unwrap();
+        storage
+            .put_with_ttl(
+                format!("session_{suffix}").as_bytes(),
+                b"value",
+                Duration::ZERO,
+            )
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    // filters only take effect during compaction
+    check_get(&storage, b"tenant1_000", Some(b"value"));
+    storage.put(b"tenant2_000", b"new").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_compaction().unwrap();
+
+    let state = storage.inner.default_family.state.read().clone();
+    assert!(state.l0_sstable.is_empty());
+    let mut keys = Vec::new();
+    for (_, sst_ids) in &state.levels {
+        for id in sst_ids {
+            let mut iter = SsTableIterator::create_and_seek_to_first(
+                state.sstables[id].clone(),
+            )
+            .unwrap();
+            while iter.is_valid() {
+                keys.push(Bytes::copy_from_slice(iter.key().key_ref()));
+                iter.next().unwrap();
+            }
+        }
+    }
+    let expected = (0..100)
+        .map(|idx| Bytes::from(format!("tenant2_{idx:03}")))
+        .collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+    check_get(&storage, b"tenant1_000", None);
+    check_get(&storage, b"tenant2_000", Some(b"new"));
+    check_get(&storage, b"tenant2_001", Some(b"value"));
+}
@@ -0,0 +1,47 @@
+use bytes::Bytes;
+
+use crate::{iterators::StorageIterator, lsm_storage::MiniLsm};
+
+pub(crate) fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{idx:010}").into_bytes()
+}
+
+pub(crate) fn value_of(idx: usize, version: usize) -> Vec<u8> {
+    format!("value_{idx:010}@{version}").into_bytes()
+}
+
+/// Assert that `key` reads back as `expected`, `None` meaning absent.
+pub(crate) fn check_get(
+    storage: &MiniLsm,
+    key: &[u8],
+    expected: Option<&[u8]>,
+) {
+    assert_eq!(
+        storage.get(key).unwrap(),
+        expected.map(Bytes::copy_from_slice),
+        "unexpected value for key {:?}",
+        Bytes::copy_from_slice(key),
+    );
+}
+
+/// Drain `iter` and assert that it yields exactly `expected`, in order.
+pub(crate) fn check_iter_result_by_key<I>(
+    iter: &mut I,
+    expected: Vec<(&[u8], &[u8])>,
+) where
+    I: 'static + for<'a> StorageIterator<KeyType<'a> = &'a [u8]>,
+{
+    let mut actual = Vec::new();
+    while iter.is_valid() {
+        actual.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    let expected = expected
+        .into_iter()
+        .map(|(k, v)| (Bytes::copy_from_slice(k), Bytes::copy_from_slice(v)))
+        .collect::<Vec<_>>();
+    assert_eq!(actual, expected);
+}
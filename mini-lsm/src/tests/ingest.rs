@@ -0,0 +1,301 @@
+use std::{ops::Bound, path::Path};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    },
+    iterators::{SeekIterator, StorageIterator},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::SstFileWriter,
+};
+
+use super::harness::{check_get, check_iter_result_by_key, key_of, value_of};
+
+/// Write the keys of `range` as `version` to an external file, deleting
+/// those in `deleted`.
+fn write_external_file(
+    path: &Path,
+    range: std::ops::Range<usize>,
+    version: usize,
+    deleted: &[usize],
+) {
+    let mut writer = SstFileWriter::new(4096);
+    for idx in range {
+        if deleted.contains(&idx) {
+            writer.delete(&key_of(idx)).unwrap();
+        } else {
+            writer.put(&key_of(idx), &value_of(idx, version)).unwrap();
+        }
+    }
+    writer.finish(path).unwrap();
+}
+
+/// Leveled compaction that never runs, so that SSTs stay where they are
+/// ingested.
+fn leveled_options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        compaction_options: CompactionOptions::Simple(
+            SimpleLeveledCompactionOptions {
+                size_ratio_percent: 0,
+                level0_file_num_compaction_trigger: 100,
+                max_levels: 3,
+            },
+        ),
+        ..LsmStorageOptions::default_for_test()
+    }
+}
+
+/// The SSTs of L0, then of each level or tier.
+fn layout(storage: &MiniLsm) -> (Vec<usize>, Vec<(usize, Vec<usize>)>) {
+    let state = storage.inner.default_family.state.read();
+    (state.l0_sstable.clone(), state.levels.clone())
+}
+
+#[test]
+fn test_ingest_external_files() {
+    let dir = tempdir().unwrap();
+    let files = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, &leveled_options()).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    storage.force_flush().unwrap();
+    // still in the memtable when ingested
+    storage.put(&key_of(150), &value_of(150, 0)).unwrap();
+    let txn = storage.new_txn().unwrap();
+
+    let first = files.path().join("1.sst");
+    let second = files.path().join("2.sst");
+    write_external_file(&first, 50..80, 1, &[60]);
+    write_external_file(&second, 140..160, 1, &[]);
+    storage.ingest_external_files(&[&second, &first]).unwrap();
+
+    let check = |storage: &MiniLsm| {
+        check_get(storage, &key_of(0), Some(&value_of(0, 0)));
+        check_get(storage, &key_of(50), Some(&value_of(50, 1)));
+        check_get(storage, &key_of(60), None);
+        check_get(storage, &key_of(80), Some(&value_of(80, 0)));
+        check_get(storage, &key_of(150), Some(&value_of(150, 1)));
+        let mut iter = storage
+            .scan(Bound::Included(&key_of(59)), Bound::Included(&key_of(61)))
+            .unwrap();
+        check_iter_result_by_key(
+            &mut iter,
+            vec![
+                (&key_of(59), &value_of(59, 1)),
+                (&key_of(61), &value_of(61, 1)),
+            ],
+        );
+    };
+    check(&storage);
+    // the keys become visible at once, after the snapshot
+    assert_eq!(
+        txn.get(&key_of(50)).unwrap(),
+        Some(Bytes::from(value_of(50, 0)))
+    );
+    assert_eq!(txn.get(&key_of(140)).unwrap(), None);
+    // the memtable holding an older version was flushed before
+    assert_eq!(storage.stats().memtable_bytes, 0);
+
+    // the files are left in place, and recorded in the manifest
+    assert!(first.exists() && second.exists());
+    let before = layout(&storage);
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &leveled_options()).unwrap();
+    check(&storage);
+    assert_eq!(layout(&storage), before);
+    // newer writes win
+    storage.put(&key_of(150), &value_of(150, 2)).unwrap();
+    check_get(&storage, &key_of(150), Some(&value_of(150, 2)));
+}
+
+#[test]
+fn test_ingest_placement() {
+    let dir = tempdir().unwrap();
+    let files = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, &leveled_options()).unwrap();
+    let file = |name: &str| files.path().join(name);
+    let ingest = |name: &str| {
+        storage.ingest_external_files(&[file(name)]).unwrap();
+        let state = storage.inner.default_family.state.read();
+        *state.sstables.keys().max().unwrap()
+    };
+
+    // an empty tree takes them at the bottom level
+    write_external_file(&file("a"), 0..100, 0, &[]);
+    write_external_file(&file("b"), 200..300, 0, &[]);
+    let a = ingest("a");
+    let b = ingest("b");
+    assert_eq!(
+        layout(&storage),
+        (vec![], vec![(1, vec![]), (2, vec![]), (3, vec![a, b])])
+    );
+
+    // above the level holding an overlapping SST
+    write_external_file(&file("c"), 50..60, 1, &[]);
+    let c = ingest("c");
+    // in L0 when it holds an overlapping SST
+    storage.put(&key_of(250), &value_of(250, 1)).unwrap();
+    storage.force_flush().unwrap();
+    let flushed = layout(&storage).0[0];
+    write_external_file(&file("d"), 240..260, 2, &[]);
+    let d = ingest("d");
+    // past the last SST of the bottom level
+    write_external_file(&file("e"), 300..310, 0, &[]);
+    let e = ingest("e");
+    assert_eq!(
+        layout(&storage),
+        (
+            vec![d, flushed],
+            vec![(1, vec![]), (2, vec![c]), (3, vec![a, b, e])]
+        )
+    );
+    check_get(&storage, &key_of(55), Some(&value_of(55, 1)));
+    check_get(&storage, &key_of(250), Some(&value_of(250, 2)));
+    check_get(&storage, &key_of(305), Some(&value_of(305, 0)));
+
+    // the files must be sorted and must not overlap
+    let mut writer = SstFileWriter::new(4096);
+    writer.put(&key_of(2), &value_of(2, 0)).unwrap();
+    assert!(writer.put(&key_of(1), &value_of(1, 0)).is_err());
+    assert!(writer.put(&key_of(2), &value_of(2, 0)).is_err());
+    write_external_file(&file("f"), 400..410, 0, &[]);
+    write_external_file(&file("g"), 405..420, 0, &[]);
+    assert!(
+        storage
+            .ingest_external_files(&[file("f"), file("g")])
+            .is_err()
+    );
+    check_get(&storage, &key_of(400), None);
+}
+
+#[test]
+fn test_ingest_tiered() {
+    let dir = tempdir().unwrap();
+    let files = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Tiered(
+            TieredCompactionOptions {
+                num_tiers: 100,
+                max_size_amplification_percent: 200,
+                size_ratio: 1,
+                min_merge_width: 2,
+                max_merge_width: None,
+            },
+        ),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    let file = |name: &str| files.path().join(name);
+
+    // a new tier, then the top tier it does not overlap
+    write_external_file(&file("a"), 0..10, 0, &[]);
+    write_external_file(&file("b"), 10..20, 0, &[]);
+    storage.ingest_external_files(&[file("a")]).unwrap();
+    storage.ingest_external_files(&[file("b")]).unwrap();
+    assert_eq!(layout(&storage).1.len(), 1);
+    assert_eq!(layout(&storage).1[0].1.len(), 2);
+    // a new tier above the tier it overlaps
+    storage.put(&key_of(5), &value_of(5, 1)).unwrap();
+    storage.force_flush().unwrap();
+    write_external_file(&file("c"), 5..6, 2, &[]);
+    write_external_file(&file("d"), 15..16, 2, &[]);
+    storage
+        .ingest_external_files(&[file("c"), file("d")])
+        .unwrap();
+    let (l0, tiers) = layout(&storage);
+    assert!(l0.is_empty());
+    // the second file does not overlap the flushed tier
+    assert_eq!(tiers.len(), 3);
+    assert_eq!(tiers[0].1.len(), 1);
+    assert_eq!(tiers[1].1.len(), 2);
+    check_get(&storage, &key_of(5), Some(&value_of(5, 2)));
+    check_get(&storage, &key_of(15), Some(&value_of(15, 2)));
+    check_get(&storage, &key_of(16), Some(&value_of(16, 0)));
+
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    assert_eq!(layout(&storage), (l0, tiers));
+    check_get(&storage, &key_of(5), Some(&value_of(5, 2)));
+}
+
+#[test]
+fn test_ingest_versions() {
+    let dir = tempdir().unwrap();
+    let files = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, &leveled_options()).unwrap();
+    for idx in 0..20 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    storage.force_flush().unwrap();
+    let txn = storage.new_txn().unwrap();
+    let file = files.path().join("a.sst");
+    write_external_file(&file, 5..15, 1, &[10]);
+    storage.ingest_external_files(&[&file]).unwrap();
+    let ingested = storage.latest_commit_ts();
+    assert_eq!(ingested, txn.read_ts + 1);
+    storage.put(&key_of(12), &value_of(12, 2)).unwrap();
+    // the ingested keys all hold the commit ts of the ingestion
+    for (ts, version) in [(txn.read_ts, 0), (ingested, 1)] {
+        assert_eq!(
+            storage.get_at(&key_of(5), ts).unwrap(),
+            Some(Bytes::from(value_of(5, version)))
+        );
+    }
+    assert_eq!(
+        storage.get_at(&key_of(10), txn.read_ts).unwrap(),
+        Some(Bytes::from(value_of(10, 0)))
+    );
+    drop(txn);
+
+    let check = |storage: &MiniLsm| {
+        check_get(storage, &key_of(10), None);
+        check_get(storage, &key_of(12), Some(&value_of(12, 2)));
+
+        // seeking backward lands on the ingested versions
+        let mut iter = storage
+            .scan(Bound::Included(&key_of(4)), Bound::Included(&key_of(11)))
+            .unwrap();
+        iter.seek_for_prev(&key_of(10)).unwrap();
+        assert_eq!(
+            (iter.key(), iter.value()),
+            (&key_of(9)[..], &value_of(9, 1)[..])
+        );
+        iter.seek_to_last().unwrap();
+        assert_eq!(iter.key(), &key_of(11)[..]);
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), &key_of(9)[..]);
+        iter.seek_to_key(&key_of(6)).unwrap();
+        assert_eq!(
+            (iter.key(), iter.value()),
+            (&key_of(6)[..], &value_of(6, 1)[..])
+        );
+    };
+    check(&storage);
+    storage.close().unwrap();
+    drop(storage);
+    // compaction rewrites the keys at the ts they were ingested at
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Simple(
+            SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 1,
+                max_levels: 3,
+            },
+        ),
+        ..leveled_options()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    check(&storage);
+    assert!(storage.latest_commit_ts() > ingested);
+    let sst = layout(&storage).0[0];
+    storage.force_compaction().unwrap();
+    assert!(!layout(&storage).0.contains(&sst));
+    check(&storage);
+}
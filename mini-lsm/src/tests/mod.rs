@@ -1 +1,16 @@
+mod block;
+mod checkpoint;
+mod column_family;
+mod compaction;
 mod harness;
+mod ingest;
+mod range_tombstone;
+mod recovery;
+mod scan;
+mod stats;
+mod storage;
+mod table;
+mod time_travel;
+mod txn;
+mod value_log;
+mod write_stall;
@@ -0,0 +1,289 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    range_tombstone::{RangeTombstone, RangeTombstoneFragments},
+    table::SsTableIterator,
+};
+
+use super::{
+    compaction::leveled_options,
+    harness::{check_get, key_of, value_of},
+};
+
+const NUM_KEYS: usize = 100;
+
+fn tombstone(start: &[u8], end: &[u8], ts: u64) -> RangeTombstone {
+    RangeTombstone {
+        start: Bytes::copy_from_slice(start),
+        end: Bytes::copy_from_slice(end),
+        ts,
+    }
+}
+
+/// The live keys a full scan returns.
+fn scan_keys(storage: &MiniLsm) -> Vec<usize> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut keys = Vec::new();
+    while iter.is_valid() {
+        let key = std::str::from_utf8(iter.key()).unwrap();
+        keys.push(key["key_".len()..].parse().unwrap());
+        iter.next().unwrap();
+    }
+    keys
+}
+
+fn flush_all(storage: &MiniLsm) {
+    storage.force_flush().unwrap();
+    while !storage
+        .inner
+        .default_family
+        .state
+        .read()
+        .imm_memtables
+        .is_empty()
+    {
+        storage
+            .inner
+            .force_flush_next_imm_memtable(&storage.inner.default_family)
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_range_tombstone_fragments() {
+    let fragments = RangeTombstoneFragments::new(&[
+        tombstone(b"b", b"f", 5),
+        tombstone(b"d", b"h", 3),
+        tombstone(b"x", b"z", 7),
+    ]);
+    assert!(!fragments.covers(b"a", 0));
+    assert!(fragments.covers(b"b", 4));
+    assert!(!fragments.covers(b"b", 5));
+    // the newer tombstone wins where they overlap
+    assert!(fragments.covers(b"e", 4));
+    assert!(fragments.covers(b"f", 2));
+    assert!(!fragments.covers(b"f", 4));
+    assert!(!fragments.covers(b"h", 0));
+    assert!(fragments.covers(b"y", 6));
+    assert!(!fragments.covers(b"z", 0));
+
+    // the same answers as the tombstones themselves
+    let mut rng = StdRng::seed_from_u64(0);
+    let tombstones = (0..200)
+        .map(|_| {
+            let start = rng.gen_range(0..100);
+            let end = rng.gen_range(start + 1..=100);
+            tombstone(&key_of(start), &key_of(end), rng.gen_range(1..50))
+        })
+        .collect::<Vec<_>>();
+    let fragments = RangeTombstoneFragments::new(&tombstones);
+    for idx in 0..=100 {
+        for ts in 0..50 {
+            assert_eq!(
+                fragments.covers(&key_of(idx), ts),
+                tombstones.iter().any(|t| t.covers(&key_of(idx), ts)),
+                "key {idx} at ts {ts}"
+            );
+        }
+    }
+
+    let clipped = tombstone(b"b", b"f", 5).clip(Some(b"c"), None).unwrap();
+    assert_eq!(clipped, tombstone(b"c", b"f", 5));
+    assert!(tombstone(b"b", b"f", 5).clip(None, Some(b"b")).is_none());
+}
+
+#[test]
+fn test_delete_range_overlapping() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_test();
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    for idx in 0..NUM_KEYS {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    let snapshot = storage.new_txn().unwrap();
+    storage.delete_range(&key_of(20), &key_of(40)).unwrap();
+    storage.delete_range(&key_of(30), &key_of(60)).unwrap();
+    // puts after a range delete are visible again
+    storage.put(&key_of(35), &value_of(35, 1)).unwrap();
+    storage.put(&key_of(70), &value_of(70, 1)).unwrap();
+    storage
+        .write_batch(&[
+            WriteBatchRecord::DelRange(key_of(80), key_of(90)),
+            WriteBatchRecord::Put(key_of(85), value_of(85, 1)),
+        ])
+        .unwrap();
+    // puts before a range delete in the same batch are deleted
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(key_of(75), value_of(75, 1)),
+            WriteBatchRecord::DelRange(key_of(74), key_of(78)),
+            WriteBatchRecord::Put(key_of(76), value_of(76, 1)),
+        ])
+        .unwrap();
+
+    let expected = (0..20)
+        .chain([35])
+        .chain(60..74)
+        .chain([76])
+        .chain(78..80)
+        .chain([85])
+        .chain(90..NUM_KEYS)
+        .collect::<Vec<_>>();
+    let check = |storage: &MiniLsm| {
+        assert_eq!(scan_keys(storage), expected);
+        check_get(storage, &key_of(19), Some(&value_of(19, 0)));
+        check_get(storage, &key_of(20), None);
+        check_get(storage, &key_of(35), Some(&value_of(35, 1)));
+        check_get(storage, &key_of(59), None);
+        check_get(storage, &key_of(60), Some(&value_of(60, 0)));
+        check_get(storage, &key_of(70), Some(&value_of(70, 1)));
+        check_get(storage, &key_of(75), None);
+        check_get(storage, &key_of(76), Some(&value_of(76, 1)));
+        check_get(storage, &key_of(84), None);
+        check_get(storage, &key_of(85), Some(&value_of(85, 1)));
+    };
+    check(&storage);
+    // a transaction that started before still sees the keys
+    assert_eq!(
+        snapshot.get(&key_of(30)).unwrap(),
+        Some(Bytes::from(value_of(30, 0)))
+    );
+    drop(snapshot);
+
+    // from the WAL
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    check(&storage);
+
+    // from the SSTs, with a range tombstone only SST
+    storage.force_flush().unwrap();
+    storage.delete_range(&key_of(95), &key_of(98)).unwrap();
+    storage.force_flush().unwrap();
+    let state = storage.inner.default_family.state.read().clone();
+    assert_eq!(state.sstables[&state.l0_sstable[0]].num_of_blocks(), 0);
+    drop(state);
+    let expected = expected
+        .into_iter()
+        .filter(|idx| !(95..98).contains(idx))
+        .collect::<Vec<_>>();
+    assert_eq!(scan_keys(&storage), expected);
+    check_get(&storage, &key_of(96), None);
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    assert_eq!(scan_keys(&storage), expected);
+    check_get(&storage, &key_of(35), Some(&value_of(35, 1)));
+    check_get(&storage, &key_of(36), None);
+}
+
+#[test]
+fn test_compaction_drops_range_deleted_keys() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 1 << 10,
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    storage.close().unwrap();
+    for idx in 0..NUM_KEYS {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    flush_all(&storage);
+    storage.delete_range(&key_of(10), &key_of(90)).unwrap();
+    storage.put(&key_of(50), &value_of(50, 1)).unwrap();
+    flush_all(&storage);
+    assert!(storage.inner.default_family.state.read().l0_sstable.len() > 2);
+    storage.force_compaction().unwrap();
+
+    let state = storage.inner.default_family.state.read().clone();
+    assert!(state.l0_sstable.is_empty());
+    let mut keys = Vec::new();
+    for (_, sst_ids) in &state.levels {
+        for id in sst_ids {
+            let table = state.sstables[id].clone();
+            // compacted into the bottom level with no reader left
+            assert!(table.range_tombstones().is_empty());
+            let mut iter =
+                SsTableIterator::create_and_seek_to_first(table).unwrap();
+            while iter.is_valid() {
+                keys.push(Bytes::copy_from_slice(iter.key().key_ref()));
+                iter.next().unwrap();
+            }
+        }
+    }
+    let expected = (0..10).chain([50]).chain(90..NUM_KEYS).collect::<Vec<_>>();
+    assert_eq!(
+        keys,
+        expected
+            .iter()
+            .map(|idx| Bytes::from(key_of(*idx)))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(scan_keys(&storage), expected);
+    check_get(&storage, &key_of(50), Some(&value_of(50, 1)));
+}
+
+#[test]
+fn test_compaction_keeps_range_tombstones_for_readers() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 1 << 10,
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    storage.close().unwrap();
+    for idx in 0..NUM_KEYS {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    flush_all(&storage);
+    let txn = storage.new_txn().unwrap();
+    storage.delete_range(&key_of(10), &key_of(90)).unwrap();
+    flush_all(&storage);
+    storage.force_compaction().unwrap();
+
+    // the tombstone is split between the output SSTs without overlap
+    let state = storage.inner.default_family.state.read().clone();
+    assert!(state.l0_sstable.is_empty());
+    let mut tables = state
+        .levels
+        .iter()
+        .flat_map(|(_, sst_ids)| sst_ids.iter().map(|id| &state.sstables[id]))
+        .collect::<Vec<_>>();
+    tables.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+    assert!(tables.len() > 1);
+    for pair in tables.windows(2) {
+        assert!(pair[0].last_key().key_ref() <= pair[1].first_key().key_ref());
+    }
+    let mut fragments = tables
+        .iter()
+        .flat_map(|table| table.range_tombstones().to_vec())
+        .collect::<Vec<_>>();
+    fragments.sort_by(|a, b| a.start.cmp(&b.start));
+    assert_eq!(fragments.first().unwrap().start, key_of(10));
+    assert_eq!(fragments.last().unwrap().end, key_of(90));
+    for pair in fragments.windows(2) {
+        assert_eq!(pair[0].end, pair[1].start);
+    }
+
+    assert_eq!(
+        txn.get(&key_of(50)).unwrap(),
+        Some(Bytes::from(value_of(50, 0)))
+    );
+    assert_eq!(
+        scan_keys(&storage),
+        (0..10).chain(90..NUM_KEYS).collect::<Vec<_>>()
+    );
+    check_get(&storage, &key_of(50), None);
+}
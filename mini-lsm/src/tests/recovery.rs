@@ -0,0 +1,228 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use tempfile::{TempDir, tempdir};
+
+use crate::{
+    error::LsmError,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    manifest::Manifest,
+    wal::Wal,
+};
+
+use super::harness::{check_get, key_of, value_of};
+
+const NUM_KEYS: usize = 50;
+
+/// Write `NUM_KEYS` keys, one WAL record each, and close the engine.
+fn pristine_dir(flush_half: bool) -> TempDir {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    for idx in 0..NUM_KEYS {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+        if flush_half && idx == NUM_KEYS / 2 {
+            storage.force_flush().unwrap();
+        }
+    }
+    storage.close().unwrap();
+    dir
+}
+
+fn copy_dir(src: &Path) -> TempDir {
+    let dst = tempdir().unwrap();
+    for entry in std::fs::read_dir(src).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), dst.path().join(entry.file_name()))
+            .unwrap();
+    }
+    dst
+}
+
+/// The WAL files of `dir`, from earliest to latest.
+fn wal_files(dir: &Path) -> Vec<PathBuf> {
+    let mut wals = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+        .collect::<Vec<_>>();
+    wals.sort();
+    wals
+}
+
+fn flip_byte(path: &Path, offset: u64) {
+    let mut data = std::fs::read(path).unwrap();
+    data[offset as usize] ^= 0xff;
+    std::fs::write(path, data).unwrap();
+}
+
+fn append(path: &Path, data: &[u8]) {
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .unwrap()
+        .write_all(data)
+        .unwrap();
+}
+
+/// The number of leading keys present, asserting that none comes after.
+fn recovered_prefix(storage: &MiniLsm) -> usize {
+    let present = (0..NUM_KEYS)
+        .take_while(|idx| storage.get(&key_of(*idx)).unwrap().is_some())
+        .count();
+    for idx in present..NUM_KEYS {
+        check_get(storage, &key_of(idx), None);
+    }
+    present
+}
+
+fn assert_corruption(err: anyhow::Error) {
+    assert!(
+        matches!(
+            err.downcast_ref::<LsmError>(),
+            Some(LsmError::Corruption { .. })
+        ),
+        "unexpected error: {err:?}"
+    );
+}
+
+#[test]
+fn test_wal_truncated_at_any_offset() {
+    let pristine = pristine_dir(false);
+    let wal = wal_files(pristine.path()).pop().unwrap();
+    let wal_len = std::fs::metadata(&wal).unwrap().len();
+    let mut cuts = (0..=wal_len).step_by(5).collect::<Vec<_>>();
+    cuts.extend([wal_len - 1, wal_len]);
+    cuts.sort();
+    cuts.dedup();
+    let mut last_prefix = 0;
+    for cut in cuts {
+        let dir = copy_dir(pristine.path());
+        let wal = dir.path().join(wal.file_name().unwrap());
+        OpenOptions::new()
+            .write(true)
+            .open(&wal)
+            .unwrap()
+            .set_len(cut)
+            .unwrap();
+        let options = LsmStorageOptions::default_for_test();
+        let storage = MiniLsm::open(&dir, &options).unwrap();
+        let prefix = recovered_prefix(&storage);
+        assert!(prefix >= last_prefix);
+        assert_eq!(prefix == NUM_KEYS, cut == wal_len);
+        last_prefix = prefix;
+
+        // the torn tail is gone, so later records are readable again
+        storage.put(b"after", b"crash").unwrap();
+        storage.close().unwrap();
+        drop(storage);
+        let storage = MiniLsm::open(&dir, &options).unwrap();
+        assert_eq!(recovered_prefix(&storage), prefix);
+        check_get(&storage, b"after", Some(b"crash"));
+    }
+}
+
+#[test]
+fn test_wal_garbage_tail() {
+    let dir = pristine_dir(false);
+    let wal = wal_files(dir.path()).pop().unwrap();
+    // a record header promising more bytes than were written
+    append(&wal, &[0, 0, 1, 0, 0xde, 0xad]);
+    let options = LsmStorageOptions::default_for_test();
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    assert_eq!(recovered_prefix(&storage), NUM_KEYS);
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    assert_eq!(recovered_prefix(&storage), NUM_KEYS);
+}
+
+#[test]
+fn test_wal_checksum_mismatch() {
+    let pristine = pristine_dir(false);
+    let options = LsmStorageOptions::default_for_test();
+
+    // a damaged last record may be a torn write
+    let dir = copy_dir(pristine.path());
+    let wal = wal_files(dir.path()).pop().unwrap();
+    let wal_len = std::fs::metadata(&wal).unwrap().len();
+    flip_byte(&wal, wal_len - 1);
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    assert_eq!(recovered_prefix(&storage), NUM_KEYS - 1);
+    drop(storage);
+
+    // anywhere else it is corruption
+    let dir = copy_dir(pristine.path());
+    let wal = wal_files(dir.path()).pop().unwrap();
+    flip_byte(&wal, 8);
+    assert_corruption(MiniLsm::open(&dir, &options).err().unwrap());
+}
+
+#[test]
+fn test_manifest_torn_tail() {
+    let dir = pristine_dir(true);
+    let manifest = dir.path().join("MANIFEST");
+    // the start of a record that was being written during a crash
+    append(&manifest, &[0, 0, 0, 0, 0, 0, 0, 20, b'{', b'"']);
+    let options = LsmStorageOptions::default_for_test();
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    assert_eq!(recovered_prefix(&storage), NUM_KEYS);
+    assert_eq!(
+        storage.inner.default_family.state.read().l0_sstable.len(),
+        1
+    );
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    assert_eq!(recovered_prefix(&storage), NUM_KEYS);
+
+    let len = std::fs::metadata(&manifest).unwrap().len();
+    for cut in 1..8 {
+        let dir = copy_dir(dir.path());
+        // a record whose checksum was not persisted
+        let manifest = dir.path().join("MANIFEST");
+        OpenOptions::new()
+            .write(true)
+            .open(&manifest)
+            .unwrap()
+            .set_len(len - cut)
+            .unwrap();
+        let storage = MiniLsm::open(&dir, &options).unwrap();
+        assert_eq!(recovered_prefix(&storage), NUM_KEYS);
+    }
+}
+
+#[test]
+fn test_manifest_corruption() {
+    let options = LsmStorageOptions::default_for_test();
+    // a damaged length field is indistinguishable from a torn tail, so
+    // only damage the rest of the first record
+    for offset in [8, 12, 20, 26] {
+        let dir = pristine_dir(true);
+        flip_byte(&dir.path().join("MANIFEST"), offset);
+        assert_corruption(MiniLsm::open(&dir, &options).err().unwrap());
+    }
+}
+
+#[test]
+fn test_read_records_leaves_torn_tails() {
+    let dir = pristine_dir(true);
+    let wal = wal_files(dir.path()).pop().unwrap();
+    let manifest = dir.path().join("MANIFEST");
+    append(&wal, &[0, 0, 1, 0, 0xde, 0xad]);
+    append(&manifest, &[0, 0, 0, 0, 0, 0, 0, 20, b'{', b'"']);
+    let wal_len = std::fs::metadata(&wal).unwrap().len();
+    let manifest_len = std::fs::metadata(&manifest).unwrap().len();
+
+    let (records, valid_len) = Wal::read_records(&wal).unwrap();
+    assert_eq!(valid_len, wal_len - 6);
+    let num_entries = records.iter().map(|r| r.entries.len()).sum::<usize>();
+    assert_eq!(num_entries, NUM_KEYS / 2 - 1);
+    // the first memtable, the one replacing it and the flush of the first
+    assert_eq!(Manifest::read_records(&manifest).unwrap().len(), 3);
+    assert_eq!(std::fs::metadata(&wal).unwrap().len(), wal_len);
+    assert_eq!(std::fs::metadata(&manifest).unwrap().len(), manifest_len);
+}
@@ -0,0 +1,519 @@
+use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+
+use bytes::Bytes;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::{
+        SeekIterator, StorageIterator, concat_iterator::SstConcatIterator,
+    },
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    range_tombstone::RangeTombstone,
+    table::{FilterPolicy, PrefixExtractor, SsTableBuilder},
+};
+
+use super::{
+    compaction::leveled_options,
+    harness::{check_iter_result_by_key, key_of, value_of},
+};
+
+/// The index a key of `key_of` was made from.
+fn index_of(key: &[u8]) -> usize {
+    let key = std::str::from_utf8(key).unwrap();
+    key["key_".len()..].parse().unwrap()
+}
+
+/// The key indices an iterator yields until it is exhausted.
+fn drain_indices(iter: &mut SstConcatIterator) -> Vec<usize> {
+    let mut indices = Vec::new();
+    while iter.is_valid() {
+        indices.push(index_of(iter.key().key_ref()));
+        iter.next().unwrap();
+    }
+    indices
+}
+
+#[test]
+fn test_scan_across_memtables_and_l0() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"00", b"2333").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.delete(b"1").unwrap();
+    storage.force_freeze_memtable().unwrap();
+    storage.put(b"1", b"233333").unwrap();
+    storage.delete(b"00").unwrap();
+    storage.put(b"4", b"2333333").unwrap();
+
+    check_iter_result_by_key(
+        &mut storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (b"1", b"233333"),
+            (b"2", b"2333"),
+            (b"3", b"23333"),
+            (b"4", b"2333333"),
+        ],
+    );
+    check_iter_result_by_key(
+        &mut storage
+            .scan(Bound::Included(b"2"), Bound::Included(b"3"))
+            .unwrap(),
+        vec![(b"2", b"2333"), (b"3", b"23333")],
+    );
+    check_iter_result_by_key(
+        &mut storage
+            .scan(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .unwrap(),
+        vec![(b"2", b"2333")],
+    );
+    check_iter_result_by_key(
+        &mut storage
+            .scan(Bound::Included(b"5"), Bound::Unbounded)
+            .unwrap(),
+        vec![],
+    );
+}
+
+#[test]
+fn test_scan_upper_bound_in_sst() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    for key in [b"a", b"b", b"c", b"d", b"e"] {
+        storage.put(key, b"v").unwrap();
+    }
+    storage.force_flush().unwrap();
+    check_iter_result_by_key(
+        &mut storage
+            .scan(Bound::Excluded(b"a"), Bound::Excluded(b"d"))
+            .unwrap(),
+        vec![(b"b", b"v"), (b"c", b"v")],
+    );
+    check_iter_result_by_key(
+        &mut storage
+            .scan(Bound::Unbounded, Bound::Included(b"b"))
+            .unwrap(),
+        vec![(b"a", b"v"), (b"b", b"v")],
+    );
+}
+
+#[test]
+fn test_scan_is_snapshot_consistent() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"c", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"1").unwrap();
+
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    storage.put(b"a", b"2").unwrap();
+    storage.delete(b"b").unwrap();
+    storage.put(b"bb", b"2").unwrap();
+    storage.force_flush().unwrap();
+    storage.delete(b"c").unwrap();
+    storage.put(b"d", b"2").unwrap();
+
+    check_iter_result_by_key(
+        &mut iter,
+        vec![(b"a", b"1"), (b"b", b"1"), (b"c", b"1")],
+    );
+    check_iter_result_by_key(
+        &mut storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![(b"a", b"2"), (b"bb", b"2"), (b"d", b"2")],
+    );
+}
+
+#[test]
+fn test_concat_iterator() {
+    let dir = tempdir().unwrap();
+    let mut tables = Vec::new();
+    for (id, range) in [(0..10), (10..20), (20..20), (20..30)]
+        .into_iter()
+        .enumerate()
+    {
+        let mut builder = SsTableBuilder::new(128);
+        if range.is_empty() {
+            // an SST left with range tombstones only by compaction
+            builder.add_range_tombstone(RangeTombstone {
+                start: Bytes::from(key_of(19)),
+                end: Bytes::from(key_of(20)),
+                ts: 1,
+            });
+        }
+        for idx in range {
+            builder.add(
+                KeySlice::for_testing_from_slice_with_ts(&key_of(idx), 1),
+                &value_of(idx, 0),
+            );
+        }
+        let path = dir.path().join(format!("{id}.sst"));
+        tables.push(Arc::new(builder.build_for_test(path).unwrap()));
+    }
+    let all = (0..30).collect::<Vec<_>>();
+
+    let mut iter =
+        SstConcatIterator::create_and_seek_to_first(tables.clone()).unwrap();
+    assert_eq!(drain_indices(&mut iter), all);
+    for (key, first) in [
+        (key_of(0), 0),
+        (key_of(15), 15),
+        // between two SSTs
+        ([key_of(9), b"x".to_vec()].concat(), 10),
+        (key_of(20), 20),
+    ] {
+        let mut iter = SstConcatIterator::create_and_seek_to_key(
+            tables.clone(),
+            KeySlice::from_slice(&key, 1),
+        )
+        .unwrap();
+        assert_eq!(drain_indices(&mut iter), all[first..]);
+    }
+    let mut iter = SstConcatIterator::create_and_seek_to_key(
+        tables,
+        KeySlice::from_slice(&key_of(30), 1),
+    )
+    .unwrap();
+    assert!(!iter.is_valid());
+
+    // backward, across the SST holding range tombstones only
+    iter.seek_for_prev(KeySlice::from_slice(&key_of(25), 1))
+        .unwrap();
+    let mut indices = Vec::new();
+    while iter.is_valid() {
+        indices.push(index_of(iter.key().key_ref()));
+        iter.prev().unwrap();
+    }
+    assert_eq!(
+        indices,
+        all[..=25].iter().rev().copied().collect::<Vec<_>>()
+    );
+    iter.seek_to_last().unwrap();
+    assert_eq!(index_of(iter.key().key_ref()), 29);
+    iter.seek_for_prev(KeySlice::from_slice(b"key", 1)).unwrap();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_scan_reads_only_overlapping_ssts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 4 << 10,
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    // compaction only runs when forced below
+    storage.close().unwrap();
+    for idx in 0..1000 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    storage.force_flush().unwrap();
+    while !storage
+        .inner
+        .default_family
+        .state
+        .read()
+        .imm_memtables
+        .is_empty()
+    {
+        storage.force_flush().unwrap();
+    }
+    storage.force_compaction().unwrap();
+    let stats = storage.stats();
+    assert_eq!(stats.levels[0].num_ssts, 0);
+    let num_ssts = stats
+        .levels
+        .iter()
+        .map(|level| level.num_ssts)
+        .sum::<usize>();
+    assert!(num_ssts >= 8, "only {num_ssts} SSTs");
+
+    // the SSTs past the first keys of an open-ended scan are not read
+    let misses = storage.stats().block_cache_misses;
+    let mut iter = storage
+        .scan(Bound::Included(&key_of(500)), Bound::Unbounded)
+        .unwrap();
+    for idx in 500..502 {
+        assert_eq!(iter.key(), key_of(idx));
+        iter.next().unwrap();
+    }
+    // one block, or two if the keys straddle a block or an SST boundary
+    assert!(storage.stats().block_cache_misses - misses <= 2);
+}
+
+#[test]
+fn test_prefix_scan_skips_ssts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        filter_policy: FilterPolicy::Ribbon,
+        prefix_extractor: Some(PrefixExtractor::FixedLength(4)),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    // one L0 SST per prefix
+    for prefix in ["aaaa", "bbbb", "cccc", "dddd"] {
+        for idx in 0..10 {
+            storage
+                .put(format!("{prefix}{idx}").as_bytes(), b"1")
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    // the SST of this tombstone holds no key with the prefix, but it still
+    // deletes some
+    storage.delete_range(b"aaaa2", b"aaaa8").unwrap();
+    storage.put(b"eeee0", b"1").unwrap();
+    storage.force_flush().unwrap();
+
+    let useful = storage.stats().prefix_filter_useful;
+    let mut iter = storage
+        .scan(Bound::Included(b"aaaa"), Bound::Excluded(b"aaab"))
+        .unwrap();
+    check_iter_result_by_key(
+        &mut iter,
+        vec![
+            (b"aaaa0", b"1"),
+            (b"aaaa1", b"1"),
+            (b"aaaa8", b"1"),
+            (b"aaaa9", b"1"),
+        ],
+    );
+    // the tombstone SST overlaps the range, but has no key of the prefix
+    assert_eq!(storage.stats().prefix_filter_useful - useful, 1);
+
+    // the same after reopening, and with both bounds in the prefix
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    let mut iter = storage
+        .scan(Bound::Included(b"cccc3"), Bound::Included(b"cccc5"))
+        .unwrap();
+    check_iter_result_by_key(
+        &mut iter,
+        vec![(b"cccc3", b"1"), (b"cccc4", b"1"), (b"cccc5", b"1")],
+    );
+    // the tombstone SST spans this prefix too
+    assert_eq!(storage.stats().prefix_filter_useful, 1);
+}
+
+/// Walk `iter` backward from where it is, collecting what it yields.
+fn drain_backward<I>(iter: &mut I) -> Vec<(Bytes, Bytes)>
+where
+    I: 'static + for<'a> SeekIterator<KeyType<'a> = &'a [u8]>,
+{
+    let mut entries = Vec::new();
+    while iter.is_valid() {
+        entries.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.prev().unwrap();
+    }
+    entries
+}
+
+fn entries(expected: &[(&[u8], &[u8])]) -> Vec<(Bytes, Bytes)> {
+    expected
+        .iter()
+        .map(|(k, v)| (Bytes::copy_from_slice(k), Bytes::copy_from_slice(v)))
+        .collect()
+}
+
+#[test]
+fn test_scan_reverse() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"b", b"1").unwrap();
+    storage.put(b"c", b"1").unwrap();
+    storage.force_flush().unwrap();
+    let txn = storage.new_txn().unwrap();
+    storage.put(b"b", b"2").unwrap();
+    storage.delete(b"c").unwrap();
+    storage.put(b"d", b"2").unwrap();
+    storage.force_freeze_memtable().unwrap();
+    storage.put(b"e", b"3").unwrap();
+    storage.put(b"a", b"3").unwrap();
+
+    // the latest entries first
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    iter.seek_to_last().unwrap();
+    assert_eq!(
+        drain_backward(&mut iter),
+        entries(&[(b"e", b"3"), (b"d", b"2"), (b"b", b"2"), (b"a", b"3")])
+    );
+    // the deleted key is skipped
+    iter.seek_for_prev(b"c").unwrap();
+    assert_eq!(iter.key(), b"b");
+    iter.seek_for_prev(b"0").unwrap();
+    assert!(!iter.is_valid());
+
+    // within the bounds
+    let mut iter = storage
+        .scan(Bound::Excluded(b"a"), Bound::Excluded(b"e"))
+        .unwrap();
+    iter.seek_to_last().unwrap();
+    assert_eq!(
+        drain_backward(&mut iter),
+        entries(&[(b"d", b"2"), (b"b", b"2")])
+    );
+    iter.seek_for_prev(b"z").unwrap();
+    assert_eq!(iter.key(), b"d");
+    iter.seek_to_key(b"0").unwrap();
+    assert_eq!(iter.key(), b"b");
+
+    // next and prev can be mixed
+    iter.next().unwrap();
+    assert_eq!(iter.key(), b"d");
+    iter.prev().unwrap();
+    assert_eq!((iter.key(), iter.value()), (&b"b"[..], &b"2"[..]));
+    iter.next().unwrap();
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+
+    // the versions of a snapshot
+    let mut iter = storage
+        .inner
+        .scan_with_ts(
+            &storage.inner.default_family,
+            Bound::Unbounded,
+            Bound::Unbounded,
+            txn.read_ts,
+        )
+        .unwrap();
+    iter.seek_to_last().unwrap();
+    assert_eq!(
+        drain_backward(&mut iter),
+        entries(&[(b"c", b"1"), (b"b", b"1"), (b"a", b"1")])
+    );
+}
+
+#[test]
+fn test_scan_reverse_matches_model() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 2 << 10,
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    // compaction only runs when forced below
+    storage.close().unwrap();
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut model = BTreeMap::new();
+    // each snapshot is kept by an open transaction
+    let mut snapshots = Vec::new();
+    for round in 0..8 {
+        for op in 0..200 {
+            let idx = rng.gen_range(0..100);
+            match rng.gen_range(0..10) {
+                0..7 => {
+                    let value = value_of(idx, round * 1000 + op);
+                    storage.put(&key_of(idx), &value).unwrap();
+                    model.insert(key_of(idx), value);
+                }
+                7..9 => {
+                    storage.delete(&key_of(idx)).unwrap();
+                    model.remove(&key_of(idx));
+                }
+                _ => {
+                    storage
+                        .delete_range(&key_of(idx), &key_of(idx + 3))
+                        .unwrap();
+                    for idx in idx..idx + 3 {
+                        model.remove(&key_of(idx));
+                    }
+                }
+            }
+        }
+        snapshots.push((storage.new_txn().unwrap(), model.clone()));
+        match round % 3 {
+            0 => storage.force_freeze_memtable().unwrap(),
+            1 => storage.force_flush().unwrap(),
+            _ => {
+                storage.force_flush().unwrap();
+                storage.force_compaction().unwrap();
+            }
+        }
+    }
+
+    for (txn, model) in &snapshots {
+        for (lower, upper) in [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(key_of(20)), Bound::Excluded(key_of(80))),
+            (Bound::Excluded(key_of(20)), Bound::Included(key_of(80))),
+        ] {
+            let expected = model
+                .range::<Vec<u8>, _>((lower.as_ref(), upper.as_ref()))
+                .map(|(k, v)| (Bytes::from(k.clone()), Bytes::from(v.clone())))
+                .collect::<Vec<_>>();
+            let mut iter = storage
+                .inner
+                .scan_with_ts(
+                    &storage.inner.default_family,
+                    lower.as_ref().map(Vec::as_slice),
+                    upper.as_ref().map(Vec::as_slice),
+                    txn.read_ts,
+                )
+                .unwrap();
+            iter.seek_to_last().unwrap();
+            let mut backward = drain_backward(&mut iter);
+            backward.reverse();
+            assert_eq!(backward, expected);
+
+            // a random walk, moving as the model does
+            let mut pos = Some(0).filter(|_| !expected.is_empty());
+            iter.seek_to_first().unwrap();
+            for _ in 0..200 {
+                let key = key_of(rng.gen_range(0..100));
+                let after = |key: &[u8]| {
+                    Some(expected.partition_point(|(k, _)| k < key))
+                        .filter(|&idx| idx < expected.len())
+                };
+                let before = |key: &[u8]| {
+                    expected.partition_point(|(k, _)| k <= key).checked_sub(1)
+                };
+                match rng.gen_range(0..6) {
+                    0..2 => {
+                        iter.next().unwrap();
+                        pos = pos
+                            .map(|pos| pos + 1)
+                            .filter(|&pos| pos < expected.len());
+                    }
+                    2..4 => {
+                        iter.prev().unwrap();
+                        pos = pos.and_then(|pos| pos.checked_sub(1));
+                    }
+                    4 => {
+                        iter.seek_to_key(&key).unwrap();
+                        pos = after(&key);
+                    }
+                    _ => {
+                        iter.seek_for_prev(&key).unwrap();
+                        pos = before(&key);
+                    }
+                }
+                match pos {
+                    Some(pos) => {
+                        assert!(iter.is_valid());
+                        assert_eq!(iter.key(), expected[pos].0);
+                        assert_eq!(iter.value(), expected[pos].1);
+                    }
+                    None => assert!(!iter.is_valid()),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+use super::{
+    compaction::leveled_options,
+    harness::{check_get, key_of, value_of},
+};
+
+const NUM_KEYS: usize = 100;
+
+#[test]
+fn test_stats_counters() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    // compaction only runs when forced below
+    storage.close().unwrap();
+    for round in 0..2 {
+        for idx in 0..NUM_KEYS {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    let stats = storage.stats();
+    assert_eq!(stats.flushes, 2);
+    assert!(stats.flushed_bytes > 0);
+    assert_eq!(stats.levels[0].num_ssts, 2);
+    assert_eq!(stats.put_latency.count(), 2 * NUM_KEYS as u64);
+    assert_eq!(stats.compactions, 0);
+
+    storage.force_compaction().unwrap();
+    let stats = storage.stats();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.levels[0].num_ssts, 0);
+    assert_eq!(
+        stats.compaction_read_bytes, stats.flushed_bytes,
+        "compaction reads every flushed SST"
+    );
+    let level_bytes = stats.levels.iter().map(|level| level.bytes).sum::<u64>();
+    assert_eq!(stats.compaction_written_bytes, level_bytes);
+
+    for idx in 0..NUM_KEYS {
+        check_get(&storage, &key_of(idx), Some(&value_of(idx, 1)));
+    }
+    // keys within the range of the SST, absent from it
+    for idx in 0..NUM_KEYS - 1 {
+        check_get(&storage, format!("key_{idx:010}_").as_bytes(), None);
+    }
+    let stats = storage.stats();
+    assert_eq!(stats.get_latency.count(), 2 * NUM_KEYS as u64 - 1);
+    assert_eq!(
+        stats.bloom_useful + stats.bloom_false_positives,
+        NUM_KEYS as u64 - 1
+    );
+    assert!(stats.bloom_useful > stats.bloom_false_positives);
+    assert!(stats.block_cache_hits + stats.block_cache_misses > 0);
+    assert!(
+        stats.get_latency.quantile(0.5) <= stats.get_latency.quantile(0.99)
+    );
+}
+
+#[test]
+fn test_stats_prometheus_endpoint() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    check_get(&storage, b"a", Some(b"1"));
+
+    let text = storage.stats().to_prometheus();
+    assert!(text.contains("# TYPE mini_lsm_flushes_total counter\n"));
+    assert!(text.contains("\nmini_lsm_flushes_total 1\n"));
+    assert!(text.contains("\nmini_lsm_level_ssts{level=\"0\"} 1\n"));
+    assert!(text.contains("\nmini_lsm_get_latency_seconds_count 1\n"));
+    assert!(
+        text.contains("mini_lsm_put_latency_seconds_bucket{le=\"+Inf\"} 1")
+    );
+
+    let addr = storage.serve_metrics("127.0.0.1:0").unwrap();
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\nmini_lsm_flushes_total 1\n"));
+    assert!(get("/").starts_with("HTTP/1.1 404"));
+
+    // a client sending nothing is dropped, and does not keep the engine
+    let mut idle = TcpStream::connect(addr).unwrap();
+    let start = Instant::now();
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(start.elapsed() < Duration::from_secs(10));
+    let mut response = String::new();
+    idle.read_to_string(&mut response).unwrap();
+    assert!(response.is_empty());
+
+    // closing the engine stops the server, which only waited on the idle
+    // client with a weak reference to the engine
+    let mut idle = TcpStream::connect(addr).unwrap();
+    let inner = Arc::downgrade(&storage.inner);
+    storage.close().unwrap();
+    drop(storage);
+    assert_eq!(inner.strong_count(), 0);
+    let mut response = String::new();
+    idle.read_to_string(&mut response).unwrap();
+    assert!(response.is_empty());
+    // the address is free again, and released on drop too
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    assert_eq!(storage.serve_metrics(addr).unwrap(), addr);
+    assert!(get("/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    drop(storage);
+    assert!(TcpStream::connect(addr).is_err());
+}
@@ -0,0 +1,394 @@
+use std::{collections::HashMap, ops::Bound, time::Duration};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    iterators::StorageIterator,
+    lsm_storage::{
+        LsmStorageOptions, MiniLsm, SyncMode, ValueLogOptions, WriteBatchRecord,
+    },
+    table::FileBackend,
+};
+
+use super::harness::{check_get, check_iter_result_by_key, key_of, value_of};
+
+#[test]
+fn test_put_get_delete() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    check_get(&storage, b"1", None);
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    check_get(&storage, b"1", Some(b"233"));
+    check_get(&storage, b"2", Some(b"2333"));
+    check_get(&storage, b"3", Some(b"23333"));
+    storage.delete(b"2").unwrap();
+    check_get(&storage, b"2", None);
+    // deleting a missing key is fine
+    storage.delete(b"0").unwrap();
+    storage.put(b"2", b"23").unwrap();
+    check_get(&storage, b"2", Some(b"23"));
+}
+
+#[test]
+fn test_get_across_memtables_and_l0() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"2", b"23").unwrap();
+    storage.delete(b"1").unwrap();
+    storage.force_freeze_memtable().unwrap();
+    storage.put(b"3", b"233333").unwrap();
+    {
+        let state = storage.inner.default_family.state.read();
+        assert_eq!(state.l0_sstable.len(), 1);
+        assert_eq!(state.imm_memtables.len(), 1);
+    }
+    check_get(&storage, b"1", None);
+    check_get(&storage, b"2", Some(b"23"));
+    check_get(&storage, b"3", Some(b"233333"));
+    storage.force_flush().unwrap();
+    storage.force_flush().unwrap();
+    assert_eq!(
+        storage.inner.default_family.state.read().l0_sstable.len(),
+        3
+    );
+    check_get(&storage, b"1", None);
+    check_get(&storage, b"2", Some(b"23"));
+    check_get(&storage, b"3", Some(b"233333"));
+}
+
+#[test]
+fn test_recover_after_crash() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 4096,
+        ..LsmStorageOptions::default_for_test()
+    };
+    let mut acknowledged = HashMap::new();
+    for round in 0..4 {
+        let storage = MiniLsm::open(&dir, &options).unwrap();
+        for (idx, value) in &acknowledged {
+            check_get(&storage, &key_of(*idx), Option::as_deref(value));
+        }
+        for idx in 0..200 {
+            if idx % 7 == round {
+                storage.delete(&key_of(idx)).unwrap();
+                acknowledged.insert(idx, None);
+            } else {
+                let value = value_of(idx, round);
+                storage.put(&key_of(idx), &value).unwrap();
+                acknowledged.insert(idx, Some(value));
+            }
+            if idx == 100 {
+                storage.force_flush().unwrap();
+            }
+        }
+        // drop without closing the engine
+    }
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    for (idx, value) in &acknowledged {
+        check_get(&storage, &key_of(*idx), Option::as_deref(value));
+    }
+}
+
+#[test]
+fn test_file_backends() {
+    for file_backend in [
+        FileBackend::Buffered,
+        FileBackend::Mmap,
+        FileBackend::Direct,
+    ] {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            file_backend,
+            block_cache_capacity: 4,
+            ..LsmStorageOptions::default_for_test()
+        };
+        let storage = MiniLsm::open(&dir, &options).unwrap();
+        for idx in 0..1000 {
+            storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+        }
+        storage.force_flush().unwrap();
+        for idx in (0..1000).step_by(7) {
+            check_get(&storage, &key_of(idx), Some(&value_of(idx, 0)));
+        }
+        storage.close().unwrap();
+        drop(storage);
+        // the SSTs read back with the same backend
+        let storage = MiniLsm::open(&dir, &options).unwrap();
+        for idx in (0..1000).step_by(3) {
+            check_get(&storage, &key_of(idx), Some(&value_of(idx, 0)));
+        }
+    }
+}
+
+#[test]
+fn test_close_without_wal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: false,
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    storage.force_freeze_memtable().unwrap();
+    storage.delete(&key_of(7)).unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    assert!(
+        storage
+            .inner
+            .default_family
+            .state
+            .read()
+            .imm_memtables
+            .is_empty()
+    );
+    for idx in 0..100 {
+        let expected = (idx != 7).then(|| value_of(idx, 0));
+        check_get(&storage, &key_of(idx), expected.as_deref());
+    }
+}
+
+#[test]
+fn test_write_batch() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_test();
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    storage.put(b"1", b"old").unwrap();
+    storage.put(b"2", b"old").unwrap();
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(&b"1"[..], &b"new"[..]),
+            WriteBatchRecord::Del(b"2"),
+            WriteBatchRecord::Put(b"3", b"first"),
+            WriteBatchRecord::Put(b"3", b"second"),
+        ])
+        .unwrap();
+    storage.write_batch::<&[u8]>(&[]).unwrap();
+    let check = |storage: &MiniLsm| {
+        check_get(storage, b"1", Some(b"new"));
+        check_get(storage, b"2", None);
+        check_get(storage, b"3", Some(b"second"));
+    };
+    check(&storage);
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    check(&storage);
+    storage.force_flush().unwrap();
+    check(&storage);
+}
+
+#[test]
+fn test_invalid_writes_fail() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    let check_err = |result: anyhow::Result<()>, message: &str| {
+        assert_eq!(result.unwrap_err().to_string(), message);
+    };
+    check_err(storage.put(b"", b"1"), "key cannot be empty");
+    check_err(storage.put(b"1", b""), "value cannot be empty");
+    check_err(storage.delete(b""), "key cannot be empty");
+    check_err(storage.delete_range(b"2", b"1"), "range cannot be empty");
+    check_err(storage.delete_range(b"1", b"1"), "range cannot be empty");
+    // nothing of a batch is written when one record is invalid
+    check_err(
+        storage.write_batch(&[
+            WriteBatchRecord::Put(&b"1"[..], &b"1"[..]),
+            WriteBatchRecord::Del(b""),
+        ]),
+        "key cannot be empty",
+    );
+    check_get(&storage, b"1", None);
+    storage.put(b"1", b"1").unwrap();
+    check_get(&storage, b"1", Some(b"1"));
+}
+
+#[test]
+fn test_oversized_writes_fail() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_test();
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    // the largest key and value fitting the u16 lengths, with the tag
+    let key = vec![b'k'; u16::MAX as usize];
+    let value = vec![b'v'; u16::MAX as usize - 1];
+    storage.put(&key, &value).unwrap();
+    for (key, value) in [
+        (&key_of(0), &vec![0; 70_000]),
+        (&key_of(0), &vec![0; u16::MAX as usize]),
+        (&vec![b'k'; u16::MAX as usize + 1], &value_of(0, 0)),
+    ] {
+        let err = storage.put(key, value).unwrap_err();
+        assert_eq!(err.to_string(), "key or value too large");
+    }
+    // the expiry time is stored with the value
+    assert!(
+        storage
+            .put_with_ttl(&key_of(1), &value, Duration::from_secs(60))
+            .is_err()
+    );
+    check_get(&storage, &key_of(0), None);
+    check_get(&storage, &key, Some(&value));
+    storage.force_flush().unwrap();
+    check_get(&storage, &key, Some(&value));
+    storage.put(&key_of(2), &value).unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    check_get(&storage, &key, Some(&value));
+    check_get(&storage, &key_of(2), Some(&value));
+    check_get(&storage, &key_of(0), None);
+    drop(storage);
+
+    // values that would be kept inline must fit as well
+    for options in [
+        LsmStorageOptions {
+            value_log: Some(ValueLogOptions {
+                min_value_size: u16::MAX as usize,
+                ..ValueLogOptions::default()
+            }),
+            ..options.clone()
+        },
+        LsmStorageOptions {
+            block_size: 1 << 20,
+            ..options.clone()
+        },
+    ] {
+        assert!(MiniLsm::open(tempdir().unwrap(), &options).is_err());
+    }
+}
+
+#[test]
+fn test_write_batch_is_atomic_to_readers() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        &LsmStorageOptions {
+            target_sst_size: 4096,
+            ..LsmStorageOptions::default_for_test()
+        },
+    )
+    .unwrap();
+    let keys = (0..20).map(key_of).collect::<Vec<_>>();
+    let reader = {
+        let storage = storage.clone();
+        let keys = keys.clone();
+        std::thread::spawn(move || {
+            for _ in 0..200 {
+                // every key of a batch carries the same round
+                let mut iter =
+                    storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+                let mut values = Vec::new();
+                while iter.is_valid() {
+                    values.push(Bytes::copy_from_slice(iter.value()));
+                    iter.next().unwrap();
+                }
+                assert!(values.len() == keys.len() || values.is_empty());
+                assert!(values.windows(2).all(|pair| pair[0] == pair[1]));
+            }
+        })
+    };
+    for round in 0..200 {
+        let value = format!("round_{round}").into_bytes();
+        let batch = keys
+            .iter()
+            .map(|key| WriteBatchRecord::Put(key.as_slice(), value.as_slice()))
+            .collect::<Vec<_>>();
+        storage.write_batch(&batch).unwrap();
+    }
+    reader.join().unwrap();
+}
+
+#[test]
+fn test_sync_modes_persist_concurrent_writes() {
+    let modes = [
+        SyncMode::None,
+        SyncMode::EveryWrite,
+        SyncMode::Interval(Duration::from_millis(5)),
+    ];
+    for sync_mode in modes {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            target_sst_size: 16 << 10,
+            sync_mode,
+            ..LsmStorageOptions::default_for_test()
+        };
+        let storage = MiniLsm::open(&dir, &options).unwrap();
+        let writers = (0..4)
+            .map(|thread| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for idx in (thread..400).step_by(4) {
+                        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for idx in 0..400 {
+            check_get(&storage, &key_of(idx), Some(&value_of(idx, 0)));
+        }
+        storage.close().unwrap();
+        drop(storage);
+
+        let storage = MiniLsm::open(&dir, &options).unwrap();
+        for idx in 0..400 {
+            check_get(&storage, &key_of(idx), Some(&value_of(idx, 0)));
+        }
+        storage.close().unwrap();
+    }
+}
+
+#[test]
+fn test_ttl_hides_expired_values() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    storage.put(b"a", b"old").unwrap();
+    storage.put_with_ttl(b"a", b"gone", Duration::ZERO).unwrap();
+    storage
+        .put_with_ttl(b"b", b"short", Duration::from_millis(100))
+        .unwrap();
+    storage
+        .put_with_ttl(b"c", b"long", Duration::from_secs(3600))
+        .unwrap();
+    storage.put(b"d", b"forever").unwrap();
+
+    // an expired value hides the older versions too
+    check_get(&storage, b"a", None);
+    check_get(&storage, b"b", Some(b"short"));
+    std::thread::sleep(Duration::from_millis(150));
+    for flushed in [false, true] {
+        check_get(&storage, b"a", None);
+        check_get(&storage, b"b", None);
+        check_get(&storage, b"c", Some(b"long"));
+        check_get(&storage, b"d", Some(b"forever"));
+        check_iter_result_by_key(
+            &mut storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            vec![(b"c", b"long"), (b"d", b"forever")],
+        );
+        if !flushed {
+            storage.force_flush().unwrap();
+        }
+    }
+
+    // a later put without a TTL brings the key back
+    storage.put(b"b", b"again").unwrap();
+    check_get(&storage, b"b", Some(b"again"));
+}
@@ -0,0 +1,428 @@
+use std::{ops::Bound, sync::Arc};
+
+use tempfile::tempdir;
+
+use crate::{
+    iterators::{SeekIterator, StorageIterator},
+    key::KeySlice,
+    lsm_storage::BlockCache,
+    table::{
+        CompressionType, FileBackend, FileObject, FilterPolicy,
+        PrefixExtractor, SsTable, SsTableBuilder, SsTableIterator,
+        filter::Filter,
+    },
+};
+
+use super::harness::{key_of, value_of};
+
+const NUM_KEYS: usize = 500;
+
+fn generate_sst(path: &std::path::Path) -> SsTable {
+    generate_sst_with_compression(path, CompressionType::None)
+}
+
+fn generate_sst_with_compression(
+    path: &std::path::Path,
+    compression: CompressionType,
+) -> SsTable {
+    let mut builder = SsTableBuilder::new(128).with_compression(compression);
+    for idx in 0..NUM_KEYS {
+        builder.add(
+            KeySlice::for_testing_from_slice_with_ts(&key_of(idx), idx as u64),
+            &value_of(idx, 0),
+        );
+    }
+    builder.build_for_test(path).unwrap()
+}
+
+#[test]
+fn test_sst_build_and_open() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let built = generate_sst(&path);
+    assert!(built.num_of_blocks() > 1);
+    let opened =
+        SsTable::open(0, None, FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(built.block_meta, opened.block_meta);
+    assert_eq!(opened.first_key(), built.first_key());
+    assert_eq!(opened.last_key(), built.last_key());
+    assert_eq!(opened.max_ts(), (NUM_KEYS - 1) as u64);
+
+    let mut iter =
+        SsTableIterator::create_and_seek_to_first(Arc::new(opened)).unwrap();
+    for idx in 0..NUM_KEYS {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().key_ref(), key_of(idx));
+        assert_eq!(iter.key().ts(), idx as u64);
+        assert_eq!(iter.value(), value_of(idx, 0));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_seek_to_key() {
+    let dir = tempdir().unwrap();
+    let table = Arc::new(generate_sst(&dir.path().join("1.sst")));
+    for idx in (0..NUM_KEYS).step_by(7) {
+        let key = key_of(idx);
+        let ts = idx as u64;
+        let block_idx = table
+            .find_block_idx(KeySlice::for_testing_from_slice_with_ts(&key, ts));
+        let meta = &table.block_meta[block_idx];
+        assert!(meta.first_key.key_ref() <= &key[..]);
+        assert!(&key[..] <= meta.last_key.key_ref());
+        let iter = SsTableIterator::create_and_seek_to_key(
+            table.clone(),
+            KeySlice::for_testing_from_slice_with_ts(&key, ts),
+        )
+        .unwrap();
+        assert_eq!(iter.key().key_ref(), key);
+        assert_eq!(iter.value(), value_of(idx, 0));
+    }
+    let iter = SsTableIterator::create_and_seek_to_key(
+        table,
+        KeySlice::for_testing_from_slice_with_ts(b"zzz", 0),
+    )
+    .unwrap();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_reverse() {
+    let dir = tempdir().unwrap();
+    let table = Arc::new(generate_sst(&dir.path().join("1.sst")));
+    assert!(table.num_of_blocks() > 1);
+    let mut iter =
+        SsTableIterator::create_and_seek_to_last(table.clone()).unwrap();
+    for idx in (0..NUM_KEYS).rev() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx, 0));
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    for idx in (0..NUM_KEYS).step_by(7) {
+        let key = key_of(idx);
+        let ts = idx as u64;
+        iter.seek_for_prev(KeySlice::for_testing_from_slice_with_ts(&key, ts))
+            .unwrap();
+        assert_eq!(iter.key().key_ref(), key);
+        // the previous key, which may be in the previous block
+        iter.seek_for_prev(KeySlice::for_testing_from_slice_with_ts(
+            &key,
+            ts + 1,
+        ))
+        .unwrap();
+        if idx > 0 {
+            assert_eq!(iter.key().key_ref(), key_of(idx - 1));
+            iter.next().unwrap();
+            assert_eq!(iter.key().key_ref(), key);
+        } else {
+            assert!(!iter.is_valid());
+        }
+    }
+    iter.seek_for_prev(KeySlice::for_testing_from_slice_with_ts(b"zzz", 0))
+        .unwrap();
+    assert_eq!(iter.key().key_ref(), key_of(NUM_KEYS - 1));
+}
+
+#[test]
+fn test_sst_bloom_filter() {
+    let dir = tempdir().unwrap();
+    let table = generate_sst(&dir.path().join("1.sst"));
+    for idx in 0..NUM_KEYS {
+        assert!(table.may_contain(&key_of(idx)));
+    }
+    let false_positives = (NUM_KEYS..NUM_KEYS * 11)
+        .filter(|idx| table.may_contain(&key_of(*idx)))
+        .count();
+    // built for a 1% false-positive rate
+    assert!(false_positives < NUM_KEYS * 10 / 20, "{false_positives}");
+}
+
+#[test]
+fn test_sst_filter_policies() {
+    let dir = tempdir().unwrap();
+    let num_keys = 10_000;
+    let mut bits_per_key = Vec::new();
+    for policy in [FilterPolicy::Bloom, FilterPolicy::Ribbon] {
+        let path = dir.path().join(format!("{policy:?}.sst"));
+        let mut builder = SsTableBuilder::new(4096).with_filter_policy(policy);
+        for idx in 0..num_keys {
+            builder.add(
+                KeySlice::for_testing_from_slice_with_ts(&key_of(idx), 1),
+                b"",
+            );
+        }
+        builder.build_for_test(&path).unwrap();
+        let table =
+            SsTable::open(0, None, FileObject::open(&path).unwrap()).unwrap();
+        for idx in 0..num_keys {
+            assert!(table.may_contain(&key_of(idx)));
+        }
+        let false_positives = (num_keys..num_keys * 11)
+            .filter(|idx| table.may_contain(&key_of(*idx)))
+            .count();
+        let false_positive_rate =
+            false_positives as f64 / (num_keys * 10) as f64;
+        assert!(
+            false_positive_rate < 0.015,
+            "{policy:?}: {false_positive_rate}"
+        );
+        let mut buf = Vec::new();
+        table.filter.as_ref().unwrap().encode(&mut buf);
+        bits_per_key.push(buf.len() as f64 * 8.0 / num_keys as f64);
+    }
+    // ribbon needs about 7.7 bits per key where bloom needs 10
+    assert!(bits_per_key[1] < bits_per_key[0] * 0.85, "{bits_per_key:?}");
+}
+
+#[test]
+fn test_ribbon_filter_small() {
+    for num_keys in [0, 1, 10, 100] {
+        let hashes = (0..num_keys)
+            .map(|idx| farmhash::fingerprint32(&key_of(idx)))
+            .collect::<Vec<_>>();
+        let filter =
+            Filter::build_from_key_hashes(FilterPolicy::Ribbon, &hashes);
+        let mut buf = Vec::new();
+        filter.encode(&mut buf);
+        let filter = Filter::decode(&buf).unwrap();
+        assert!(matches!(filter, Filter::Ribbon(_)));
+        assert!(hashes.iter().all(|hash| filter.may_contain(*hash)));
+    }
+}
+
+#[test]
+fn test_sst_prefix_filter() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new(128)
+        .with_prefix_extractor(Some(PrefixExtractor::Delimiter(b':')));
+    // only the even users
+    for user in (0..100).step_by(2) {
+        for item in 0..5 {
+            let key = format!("user{user:03}:{item}");
+            builder.add(
+                KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), 1),
+                b"",
+            );
+        }
+    }
+    builder.build_for_test(&path).unwrap();
+    let table =
+        SsTable::open(0, None, FileObject::open(&path).unwrap()).unwrap();
+
+    let may_contain_user = |user: usize| {
+        let lower = format!("user{user:03}:");
+        let upper = format!("user{user:03};");
+        table.may_contain_range(
+            Bound::Included(lower.as_bytes()),
+            Bound::Excluded(upper.as_bytes()),
+        )
+    };
+    assert!((0..100).step_by(2).all(may_contain_user));
+    let false_positives = (1..100)
+        .step_by(2)
+        .filter(|user| may_contain_user(*user))
+        .count();
+    assert!(false_positives < 5, "{false_positives}");
+
+    // bounds with the same prefix
+    assert!(!table.may_contain_range(
+        Bound::Included(b"user001:a"),
+        Bound::Included(b"user001:b")
+    ));
+    // bounds that may hold several prefixes
+    assert!(table.may_contain_range(
+        Bound::Included(b"user001:"),
+        Bound::Excluded(b"user003:")
+    ));
+    assert!(
+        table.may_contain_range(Bound::Included(b"user001:"), Bound::Unbounded)
+    );
+    assert!(
+        table.may_contain_range(Bound::Unbounded, Bound::Excluded(b"user001;"))
+    );
+}
+
+#[test]
+fn test_sst_file_backends() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    generate_sst_with_compression(&path, CompressionType::Lz4);
+    let data = std::fs::read(&path).unwrap();
+    for backend in [
+        FileBackend::Buffered,
+        FileBackend::Mmap,
+        FileBackend::Direct,
+    ] {
+        let file = FileObject::open_with_backend(&path, backend).unwrap();
+        assert_eq!(file.size(), data.len() as u64);
+        // unaligned reads, across the alignment of direct I/O, and to the
+        // end of the file
+        for (offset, len) in
+            [(0, 1), (5, 4090), (4000, 200), (1, data.len() - 1), (0, 0)]
+        {
+            assert_eq!(
+                file.read(offset as u64, len as u64).unwrap(),
+                data[offset..offset + len],
+                "{backend:?} at {offset}"
+            );
+        }
+        assert!(file.read(data.len() as u64 - 1, 2).is_err());
+
+        let table = Arc::new(SsTable::open(0, None, file).unwrap());
+        let mut iter =
+            SsTableIterator::create_and_seek_to_first(table.clone()).unwrap();
+        for idx in 0..NUM_KEYS {
+            assert_eq!(iter.key().key_ref(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx, 0));
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+        // the mapping outlives the file
+        if backend == FileBackend::Mmap {
+            let copy = dir.path().join("2.sst");
+            std::fs::copy(&path, &copy).unwrap();
+            let file = FileObject::open_with_backend(&copy, backend).unwrap();
+            let table = SsTable::open(0, None, file).unwrap();
+            std::fs::remove_file(&copy).unwrap();
+            assert_eq!(
+                table.read_block(1).unwrap().data,
+                SsTable::open(0, None, FileObject::open(&path).unwrap())
+                    .unwrap()
+                    .read_block(1)
+                    .unwrap()
+                    .data
+            );
+        }
+    }
+}
+
+#[test]
+fn test_sst_block_cache() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    generate_sst(&path);
+    let cache = Arc::new(BlockCache::new(1024));
+    let table = Arc::new(
+        SsTable::open(1, Some(cache.clone()), FileObject::open(&path).unwrap())
+            .unwrap(),
+    );
+    let num_of_blocks = table.num_of_blocks();
+    for _ in 0..3 {
+        let mut iter =
+            SsTableIterator::create_and_seek_to_first(table.clone()).unwrap();
+        while iter.is_valid() {
+            iter.next().unwrap();
+        }
+    }
+    assert_eq!(cache.misses(), num_of_blocks as u64);
+    assert_eq!(cache.hits(), 2 * num_of_blocks as u64);
+}
+
+#[test]
+fn test_sst_detects_corruption() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let table = generate_sst(&path);
+    let mut data = std::fs::read(&path).unwrap();
+
+    // a flipped bit inside the first data block
+    data[3] ^= 1;
+    std::fs::write(&path, &data).unwrap();
+    let opened =
+        SsTable::open(0, None, FileObject::open(&path).unwrap()).unwrap();
+    assert!(opened.read_block(0).is_err());
+    assert!(opened.read_block(1).is_ok());
+    data[3] ^= 1;
+
+    // a flipped bit inside the block meta
+    data[table.block_meta_offset + 5] ^= 1;
+    std::fs::write(&path, &data).unwrap();
+    assert!(SsTable::open(0, None, FileObject::open(&path).unwrap()).is_err());
+}
+
+#[test]
+fn test_sst_truncated_or_garbled_footer() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let table = generate_sst(&path);
+    let data = std::fs::read(&path).unwrap();
+    let open = |data: &[u8]| {
+        std::fs::write(&path, data).unwrap();
+        SsTable::open(0, None, FileObject::open(&path).unwrap())
+    };
+
+    // every cut through the metadata, and some through the blocks
+    for len in (0..table.block_meta_offset)
+        .step_by(61)
+        .chain(table.block_meta_offset..data.len())
+    {
+        assert!(open(&data[..len]).is_err(), "truncated to {len} bytes");
+    }
+    // offsets past the end of their section, before the global ts
+    for garbage in [u32::MAX, data.len() as u32, 0] {
+        let mut data = data.clone();
+        let len = data.len();
+        data[len - 12..len - 8].copy_from_slice(&garbage.to_be_bytes());
+        assert!(open(&data).is_err());
+    }
+    assert!(open(&data).is_ok());
+}
+
+#[test]
+fn test_sst_compression() {
+    let dir = tempdir().unwrap();
+    for compression in [
+        CompressionType::None,
+        CompressionType::Lz4,
+        CompressionType::Zstd,
+        CompressionType::Snappy,
+    ] {
+        let path = dir.path().join(format!("{compression:?}.sst"));
+        let built = generate_sst_with_compression(&path, compression);
+        let opened =
+            SsTable::open(0, None, FileObject::open(&path).unwrap()).unwrap();
+        assert_eq!(built.block_meta, opened.block_meta);
+        assert!(
+            opened
+                .block_meta
+                .iter()
+                .all(|meta| meta.compression == compression)
+        );
+        if compression == CompressionType::None {
+            assert_eq!(opened.compression_ratio(), 1.0);
+        } else {
+            assert!(opened.compression_ratio() > 1.0);
+        }
+
+        let mut iter =
+            SsTableIterator::create_and_seek_to_first(Arc::new(opened))
+                .unwrap();
+        for idx in 0..NUM_KEYS {
+            assert_eq!(iter.key().key_ref(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx, 0));
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+    }
+}
+
+#[test]
+fn test_sst_incompressible_block_stored_raw() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder =
+        SsTableBuilder::new(4096).with_compression(CompressionType::Lz4);
+    let value = (0..1024).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+    builder.add(KeySlice::for_testing_from_slice_with_ts(b"a", 1), &value);
+    let table = builder.build_for_test(&path).unwrap();
+    assert_eq!(table.block_meta[0].compression, CompressionType::None);
+    let block = table.read_block(0).unwrap();
+    let iter = crate::block::BlockIterator::create_and_seek_to_first(block);
+    assert_eq!(iter.value(), value);
+}
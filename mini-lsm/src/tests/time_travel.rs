@@ -0,0 +1,137 @@
+use std::ops::Bound;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::LsmError,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+use super::{
+    compaction::leveled_options,
+    harness::{check_iter_result_by_key, key_of, value_of},
+};
+
+fn get_at(storage: &MiniLsm, idx: usize, ts: u64) -> Option<Vec<u8>> {
+    storage
+        .get_at(&key_of(idx), ts)
+        .unwrap()
+        .map(|value| value.to_vec())
+}
+
+/// Compact until no task is left.
+fn compact(storage: &MiniLsm) {
+    storage.force_flush().unwrap();
+    loop {
+        let compactions = storage.stats().compactions;
+        storage.force_compaction().unwrap();
+        if storage.stats().compactions == compactions {
+            break;
+        }
+    }
+}
+
+fn assert_history_unavailable(err: anyhow::Error, ts: u64, oldest_ts: u64) {
+    assert_eq!(
+        err.downcast_ref::<LsmError>(),
+        Some(&LsmError::HistoryUnavailable { ts, oldest_ts }),
+        "unexpected error: {err:?}"
+    );
+}
+
+#[test]
+fn test_get_at_and_scan_at() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    let mut versions = Vec::new();
+    for version in 0..3 {
+        for idx in 0..10 {
+            storage.put(&key_of(idx), &value_of(idx, version)).unwrap();
+        }
+        if version == 1 {
+            storage.delete(&key_of(5)).unwrap();
+        }
+        versions.push(storage.latest_commit_ts());
+        // the last version stays in the memtable
+        if version < 2 {
+            storage.force_flush().unwrap();
+        }
+    }
+
+    for (version, &ts) in versions.iter().enumerate() {
+        assert_eq!(get_at(&storage, 1, ts), Some(value_of(1, version)));
+    }
+    assert_eq!(get_at(&storage, 5, versions[0]), Some(value_of(5, 0)));
+    assert_eq!(get_at(&storage, 5, versions[1]), None);
+    // in between two writes of the same version
+    assert_eq!(get_at(&storage, 1, versions[0] + 5), Some(value_of(1, 1)));
+    assert_eq!(get_at(&storage, 9, versions[0] + 5), Some(value_of(9, 0)));
+
+    let mut iter = storage
+        .scan_at(
+            Bound::Included(&key_of(4)),
+            Bound::Included(&key_of(6)),
+            versions[1],
+        )
+        .unwrap();
+    // not affected by later writes
+    storage.put(&key_of(4), &value_of(4, 3)).unwrap();
+    check_iter_result_by_key(
+        &mut iter,
+        vec![(&key_of(4), &value_of(4, 1)), (&key_of(6), &value_of(6, 1))],
+    );
+    // versions not committed yet cannot be read
+    let ts = storage.latest_commit_ts() + 1;
+    assert!(storage.get_at(&key_of(1), ts).is_err());
+}
+
+#[test]
+fn test_retention_commits() {
+    let dir = tempdir().unwrap();
+    let options = |retention_commits| LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        retention_commits,
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(dir.path().join("1"), &options(20)).unwrap();
+    let unretained = MiniLsm::open(dir.path().join("2"), &options(0)).unwrap();
+    let mut versions = Vec::new();
+    for storage in [&storage, &unretained] {
+        // compaction only runs when forced below
+        storage.close().unwrap();
+        versions.clear();
+        for version in 0..30 {
+            storage.put(&key_of(0), &value_of(0, version)).unwrap();
+            versions.push(storage.latest_commit_ts());
+            if version % 5 == 4 {
+                storage.force_flush().unwrap();
+            }
+        }
+        compact(storage);
+        assert!(storage.stats().compactions > 0);
+    }
+
+    // the versions of the last 20 timestamps are kept
+    let oldest_ts = versions[29] - 20;
+    assert_eq!(oldest_ts, versions[9]);
+    for (version, &ts) in versions.iter().enumerate().skip(9) {
+        assert_eq!(get_at(&storage, 0, ts), Some(value_of(0, version)));
+    }
+    let err = storage.get_at(&key_of(0), versions[8]).unwrap_err();
+    assert_history_unavailable(err, versions[8], oldest_ts);
+    // without a window, only the latest state is kept
+    assert_eq!(get_at(&unretained, 0, versions[29]), Some(value_of(0, 29)));
+    let err = unretained
+        .scan_at(Bound::Unbounded, Bound::Unbounded, versions[28])
+        .err()
+        .unwrap();
+    assert_history_unavailable(err, versions[28], versions[29]);
+
+    // the window holds across restarts
+    drop(storage);
+    let storage = MiniLsm::open(dir.path().join("1"), &options(20)).unwrap();
+    assert_eq!(get_at(&storage, 0, versions[9]), Some(value_of(0, 9)));
+    assert!(storage.get_at(&key_of(0), versions[8]).is_err());
+}
@@ -0,0 +1,249 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, LeveledCompactionOptions},
+    error::LsmError,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mvcc::Watermark,
+};
+
+use super::harness::{check_get, check_iter_result_by_key};
+
+#[test]
+fn test_watermark() {
+    let mut watermark = Watermark::new();
+    assert_eq!(watermark.watermark(), None);
+    watermark.add_reader(3);
+    watermark.add_reader(5);
+    watermark.add_reader(3);
+    assert_eq!(watermark.watermark(), Some(3));
+    assert_eq!(watermark.num_retained_snapshots(), 2);
+    watermark.remove_reader(3);
+    assert_eq!(watermark.watermark(), Some(3));
+    watermark.remove_reader(3);
+    assert_eq!(watermark.watermark(), Some(5));
+    watermark.remove_reader(5);
+    assert_eq!(watermark.watermark(), None);
+}
+
+#[test]
+fn test_txn_snapshot_and_own_writes() {
+    let dir = tempdir().unwrap();
+    // the writes after the snapshot would conflict with the reads on commit
+    let options = LsmStorageOptions {
+        serializable: false,
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"b", b"1").unwrap();
+    storage.put(b"c", b"1").unwrap();
+    let txn = storage.new_txn().unwrap();
+    storage.put(b"a", b"2").unwrap();
+    storage.delete(b"b").unwrap();
+    storage.force_flush().unwrap();
+
+    // writes committed after the txn started are invisible
+    assert_eq!(txn.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+    assert_eq!(txn.get(b"b").unwrap(), Some(Bytes::from_static(b"1")));
+    txn.put(b"d", b"3").unwrap();
+    txn.delete(b"c").unwrap();
+    assert_eq!(txn.get(b"d").unwrap(), Some(Bytes::from_static(b"3")));
+    assert_eq!(txn.get(b"c").unwrap(), None);
+    check_iter_result_by_key(
+        &mut txn.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![(b"a", b"1"), (b"b", b"1"), (b"d", b"3")],
+    );
+    check_iter_result_by_key(
+        &mut txn
+            .scan(Bound::Excluded(b"a"), Bound::Excluded(b"d"))
+            .unwrap(),
+        vec![(b"b", b"1")],
+    );
+
+    // nothing leaks before the commit
+    check_get(&storage, b"c", Some(b"1"));
+    check_get(&storage, b"d", None);
+    txn.commit().unwrap();
+    check_get(&storage, b"a", Some(b"2"));
+    check_get(&storage, b"b", None);
+    check_get(&storage, b"c", None);
+    check_get(&storage, b"d", Some(b"3"));
+}
+
+#[test]
+fn test_txn_invalid_use_fails() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    let txn = storage.new_txn().unwrap();
+    let check_err = |result: anyhow::Result<()>, message: &str| {
+        assert_eq!(result.unwrap_err().to_string(), message);
+    };
+    check_err(txn.put(b"", b"1"), "key cannot be empty");
+    check_err(txn.put(b"a", b""), "value cannot be empty");
+    check_err(txn.delete(b""), "key cannot be empty");
+    txn.put(b"a", b"1").unwrap();
+    txn.commit().unwrap();
+    check_get(&storage, b"a", Some(b"1"));
+
+    let committed = "cannot operate on committed txn";
+    check_err(txn.commit(), committed);
+    check_err(txn.put(b"b", b"1"), committed);
+    check_err(txn.delete(b"a"), committed);
+    check_err(txn.get(b"a").map(|_| ()), committed);
+    check_err(
+        txn.scan(Bound::Unbounded, Bound::Unbounded).map(|_| ()),
+        committed,
+    );
+    check_get(&storage, b"a", Some(b"1"));
+}
+
+#[test]
+fn test_serializable_conflict() {
+    let dir = tempdir().unwrap();
+    let storage =
+        MiniLsm::open(&dir, &LsmStorageOptions::default_for_test()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"b", b"2").unwrap();
+
+    // write skew: each txn copies the key the other one writes
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn1.put(b"b", &txn1.get(b"a").unwrap().unwrap()).unwrap();
+    txn2.put(b"a", &txn2.get(b"b").unwrap().unwrap()).unwrap();
+    txn1.commit().unwrap();
+    let err = txn2.commit().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<LsmError>(),
+        Some(LsmError::TxnConflict { .. })
+    ));
+    check_get(&storage, b"a", Some(b"1"));
+    check_get(&storage, b"b", Some(b"1"));
+
+    // a read-only txn never conflicts
+    let txn3 = storage.new_txn().unwrap();
+    assert_eq!(txn3.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+    storage.put(b"a", b"3").unwrap();
+    txn3.commit().unwrap();
+
+    // neither does one whose reads were not written since it started
+    let txn4 = storage.new_txn().unwrap();
+    txn4.get(b"b").unwrap();
+    storage.put(b"a", b"4").unwrap();
+    txn4.put(b"c", b"5").unwrap();
+    txn4.commit().unwrap();
+    check_get(&storage, b"c", Some(b"5"));
+}
+
+#[test]
+fn test_serializable_conflict_with_plain_writes() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_test();
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    let cf = storage
+        .create_column_family("cf", &options.column_family_options())
+        .unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"c", b"1").unwrap();
+    let assert_conflict = |result: anyhow::Result<()>| {
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<LsmError>(),
+            Some(LsmError::TxnConflict { .. })
+        ));
+    };
+
+    // a put overwrites a key the txn read
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"b", &txn.get(b"a").unwrap().unwrap()).unwrap();
+    storage.put(b"a", b"2").unwrap();
+    assert_conflict(txn.commit());
+    check_get(&storage, b"b", None);
+
+    // so does a range delete covering it
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"b", &txn.get(b"c").unwrap().unwrap()).unwrap();
+    storage.delete_range(b"c", b"d").unwrap();
+    assert_conflict(txn.commit());
+    check_get(&storage, b"b", None);
+
+    // the same key in another family is another key
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"b", &txn.get(b"a").unwrap().unwrap()).unwrap();
+    storage.put_cf(&cf, b"a", b"3").unwrap();
+    txn.commit().unwrap();
+    check_get(&storage, b"b", Some(b"2"));
+
+    // without serializable transactions, nothing is validated
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        &LsmStorageOptions {
+            serializable: false,
+            ..options
+        },
+    )
+    .unwrap();
+    storage.put(b"a", b"1").unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"b", &txn.get(b"a").unwrap().unwrap()).unwrap();
+    storage.put(b"a", b"2").unwrap();
+    txn.commit().unwrap();
+    check_get(&storage, b"b", Some(b"1"));
+    assert!(storage.inner.mvcc().committed_txns.lock().is_empty());
+}
+
+#[test]
+fn test_compaction_keeps_versions_visible_to_txn() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(
+            LeveledCompactionOptions {
+                level_size_multiplier: 2,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 2,
+                base_level_size_mb: 1,
+            },
+        ),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    // compaction only runs when forced below
+    storage.close().unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    let txn = storage.new_txn().unwrap();
+    let read_ts = txn.read_ts;
+    storage.put(b"a", b"2").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_compaction().unwrap();
+    assert!(
+        storage
+            .inner
+            .default_family
+            .state
+            .read()
+            .l0_sstable
+            .is_empty()
+    );
+    assert_eq!(txn.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+    drop(txn);
+
+    // with no reader left, the old version is garbage-collected
+    storage.put(b"a", b"3").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"3").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_compaction().unwrap();
+    assert_eq!(
+        storage
+            .inner
+            .get_with_ts(&storage.inner.default_family, b"a", read_ts)
+            .unwrap(),
+        None
+    );
+    check_get(&storage, b"a", Some(b"3"));
+}
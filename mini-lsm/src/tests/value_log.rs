@@ -0,0 +1,178 @@
+use std::{ops::Bound, path::Path};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::lsm_storage::{LsmStorageOptions, MiniLsm, ValueLogOptions};
+
+use super::harness::{check_get, check_iter_result_by_key, key_of};
+
+const MIN_VALUE_SIZE: usize = 1024;
+
+fn value_log_options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        value_log: Some(ValueLogOptions {
+            min_value_size: MIN_VALUE_SIZE,
+            segment_size: 64 << 10,
+        }),
+        ..LsmStorageOptions::default_for_test()
+    }
+}
+
+/// A value of `len` bytes, distinct for each key and version.
+fn large_value(idx: usize, version: usize, len: usize) -> Vec<u8> {
+    let mut value = format!("value_{idx:010}@{version}_").into_bytes();
+    value.resize(len, b'a' + (idx % 26) as u8);
+    value
+}
+
+fn segments(dir: &Path) -> Vec<String> {
+    let mut segments = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "vlog"))
+        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    segments.sort();
+    segments
+}
+
+#[test]
+fn test_value_log_large_values() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, &value_log_options()).unwrap();
+    // past the 64 KiB limit of the WAL and the blocks
+    let huge = large_value(0, 0, 100 << 10);
+    storage.put(b"huge", &huge).unwrap();
+    storage.put(b"small", b"1").unwrap();
+    for idx in 0..20 {
+        storage
+            .put(&key_of(idx), &large_value(idx, 0, 4096))
+            .unwrap();
+    }
+    assert!(!segments(dir.path()).is_empty());
+    check_get(&storage, b"huge", Some(&huge));
+    check_get(&storage, b"small", Some(b"1"));
+
+    storage.force_flush().unwrap();
+    // the SSTs only hold pointers
+    let sst_bytes = storage.stats().levels[0].bytes;
+    assert!(sst_bytes < 20 * 4096, "{sst_bytes} bytes in L0");
+    let mut iter = storage
+        .scan(Bound::Unbounded, Bound::Included(b"huge"))
+        .unwrap();
+    check_iter_result_by_key(&mut iter, vec![(b"huge", &huge)]);
+
+    // recovered from the WAL and from the SSTs
+    storage.put(b"huge", &large_value(0, 1, 70 << 10)).unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &value_log_options()).unwrap();
+    check_get(&storage, b"huge", Some(&large_value(0, 1, 70 << 10)));
+    for idx in 0..20 {
+        check_get(&storage, &key_of(idx), Some(&large_value(idx, 0, 4096)));
+    }
+
+    // a checkpoint links the value log along with the SSTs
+    let checkpoint_dir = tempdir().unwrap();
+    let checkpoint_path = checkpoint_dir.path().join("checkpoint");
+    storage.checkpoint(&checkpoint_path).unwrap();
+    drop(storage);
+    let checkpoint =
+        MiniLsm::open(&checkpoint_path, &value_log_options()).unwrap();
+    check_get(&checkpoint, b"huge", Some(&large_value(0, 1, 70 << 10)));
+    check_get(&checkpoint, &key_of(19), Some(&large_value(19, 0, 4096)));
+}
+
+#[test]
+fn test_value_log_gc() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, &value_log_options()).unwrap();
+    for idx in 0..64 {
+        storage
+            .put(&key_of(idx), &large_value(idx, 0, 4096))
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    // segments holding only live values are left alone
+    let first = segments(dir.path());
+    assert!(first.len() > 2, "{first:?}");
+    assert!(!storage.gc_value_log().unwrap());
+    assert_eq!(segments(dir.path()), first);
+    // overwrite or delete most of the keys, leaving garbage behind
+    for idx in 0..48 {
+        if idx % 2 == 0 {
+            storage.delete(&key_of(idx)).unwrap();
+        } else {
+            storage
+                .put(&key_of(idx), &large_value(idx, 1, 4096))
+                .unwrap();
+        }
+    }
+    storage.force_flush().unwrap();
+    let before = segments(dir.path());
+    assert!(before.len() > 2, "{before:?}");
+
+    // the live values fill more than one segment, and the segments they
+    // are copied to are not collected again
+    let mut collected = 0;
+    while storage.gc_value_log().unwrap() {
+        collected += 1;
+        assert!(collected <= before.len(), "{collected} segments collected");
+    }
+    assert!(collected > 0);
+    // only live values were copied
+    let after = segments(dir.path());
+    assert!(after.len() < before.len(), "{before:?} -> {after:?}");
+    assert!(!storage.gc_value_log().unwrap());
+
+    let check = |storage: &MiniLsm| {
+        for idx in 0..64 {
+            let expected = match idx {
+                idx if idx >= 48 => Some(large_value(idx, 0, 4096)),
+                idx if idx % 2 == 0 => None,
+                idx => Some(large_value(idx, 1, 4096)),
+            };
+            check_get(storage, &key_of(idx), expected.as_deref());
+        }
+    };
+    check(&storage);
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, &value_log_options()).unwrap();
+    check(&storage);
+}
+
+#[test]
+fn test_value_log_gc_waits_for_readers() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        value_log: Some(ValueLogOptions {
+            min_value_size: MIN_VALUE_SIZE,
+            // every write seals its segment
+            segment_size: 1,
+        }),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    storage.put(b"a", &large_value(0, 0, 2048)).unwrap();
+    let txn = storage.new_txn().unwrap();
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    storage.put(b"a", &large_value(0, 1, 2048)).unwrap();
+
+    // the transaction may still read the first value
+    assert!(!storage.gc_value_log().unwrap());
+    assert_eq!(
+        txn.get(b"a").unwrap(),
+        Some(Bytes::from(large_value(0, 0, 2048)))
+    );
+    drop(txn);
+    assert!(storage.gc_value_log().unwrap());
+    // the scan opened before keeps reading the removed segment
+    check_iter_result_by_key(&mut iter, vec![(b"a", &large_value(0, 0, 2048))]);
+    check_get(&storage, b"a", Some(&large_value(0, 1, 2048)));
+    // the second value is live, so its segment is kept
+    assert!(!storage.gc_value_log().unwrap());
+    check_get(&storage, b"a", Some(&large_value(0, 1, 2048)));
+    assert_eq!(segments(dir.path()).len(), 1);
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::LsmError,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteStallOptions},
+};
+
+use super::{
+    compaction::leveled_options,
+    harness::{check_get, key_of, value_of},
+};
+
+const STOP_TIMEOUT: Duration = Duration::from_millis(50);
+
+fn stall_options() -> WriteStallOptions {
+    WriteStallOptions {
+        max_imm_memtables: 2,
+        l0_slowdown_trigger: 2,
+        l0_stop_trigger: 3,
+        slowdown_delay: Duration::from_millis(10),
+        stop_timeout: Some(STOP_TIMEOUT),
+    }
+}
+
+fn assert_write_stall(err: anyhow::Error, imm: usize, l0: usize) {
+    assert_eq!(
+        err.downcast_ref::<LsmError>(),
+        Some(&LsmError::WriteStall {
+            imm_memtables: imm,
+            l0_sstables: l0,
+        }),
+        "unexpected error: {err:?}"
+    );
+}
+
+#[test]
+fn test_write_stall_on_imm_memtables() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        write_stall: Some(stall_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_freeze_memtable().unwrap();
+    storage.put(b"b", b"1").unwrap();
+    storage.force_freeze_memtable().unwrap();
+    assert_eq!(storage.stats().stalled_writes, 0);
+
+    // nothing flushes in the background, so the write times out
+    let err = storage.put(b"c", b"1").unwrap_err();
+    assert_write_stall(err, 2, 0);
+    let stats = storage.stats();
+    assert_eq!(stats.stalled_writes, 1);
+    assert!(stats.write_stall_time >= STOP_TIMEOUT);
+    check_get(&storage, b"c", None);
+
+    storage
+        .inner
+        .force_flush_next_imm_memtable(&storage.inner.default_family)
+        .unwrap();
+    storage.put(b"c", b"1").unwrap();
+    check_get(&storage, b"c", Some(b"1"));
+    assert_eq!(storage.stats().stalled_writes, 1);
+}
+
+#[test]
+fn test_write_stall_on_l0() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(leveled_options()),
+        write_stall: Some(stall_options()),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    // compaction only runs when forced below
+    storage.close().unwrap();
+    for idx in 0..2 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+        storage.force_flush().unwrap();
+    }
+    assert_eq!(storage.stats().stalled_writes, 0);
+
+    // past the slowdown trigger, writes are delayed
+    storage.put(&key_of(2), &value_of(2, 0)).unwrap();
+    let stats = storage.stats();
+    assert_eq!(stats.stalled_writes, 1);
+    assert!(stats.write_stall_time >= Duration::from_millis(10));
+
+    // at the stop trigger, they wait for compaction
+    storage.force_flush().unwrap();
+    let err = storage.put(&key_of(3), &value_of(3, 0)).unwrap_err();
+    assert_write_stall(err, 0, 3);
+
+    storage.force_compaction().unwrap();
+    assert_eq!(storage.stats().levels[0].num_ssts, 0);
+    storage.put(&key_of(3), &value_of(3, 0)).unwrap();
+    assert_eq!(storage.stats().stalled_writes, 2);
+    for idx in 0..4 {
+        check_get(&storage, &key_of(idx), Some(&value_of(idx, 0)));
+    }
+}
+
+#[test]
+fn test_background_flush_bounds_imm_memtables() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 4096,
+        background_flush: true,
+        write_stall: Some(WriteStallOptions {
+            max_imm_memtables: 1,
+            stop_timeout: None,
+            ..WriteStallOptions::default()
+        }),
+        ..LsmStorageOptions::default_for_test()
+    };
+    let storage = MiniLsm::open(&dir, &options).unwrap();
+    for idx in 0..2000 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+        // writes wait for the flush thread instead of piling up memtables
+        assert!(storage.stats().imm_memtables <= 1);
+    }
+    let stats = storage.stats();
+    assert!(stats.flushes > 0);
+    assert!(stats.stalled_writes > 0);
+    storage.close().unwrap();
+    for idx in 0..2000 {
+        check_get(&storage, &key_of(idx), Some(&value_of(idx, 0)));
+    }
+}
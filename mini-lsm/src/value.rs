@@ -0,0 +1,84 @@
+//! Values are stored with a one-byte tag in front, and an empty value marks
+//! a tombstone.
+//! (TAG_PLAIN, value)
+//! (TAG_EXPIRING, expire_at, value), `expire_at` in ms since the Unix epoch
+//! (TAG_SEPARATED, value pointer)
+//! (TAG_EXPIRING_SEPARATED, expire_at, value pointer)
+//! Range tombstones use `TAG_RANGE_TOMBSTONE` in the WAL only.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut};
+
+const TAG_PLAIN: u8 = 0;
+const TAG_EXPIRING: u8 = 1;
+pub(crate) const TAG_RANGE_TOMBSTONE: u8 = 2;
+const TAG_SEPARATED: u8 = 3;
+const TAG_EXPIRING_SEPARATED: u8 = 4;
+
+/// The longest value stored inline, whose length with its tag and expiry
+/// time fits the u16 value lengths of the WAL and the blocks.
+pub(crate) const MAX_INLINE_VALUE_LEN: usize = u16::MAX as usize - 9;
+
+/// A live value as stored in the memtables and SSTs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredValue<'a> {
+    /// When the value stops being readable, in ms since the Unix epoch
+    pub expire_at: Option<u64>,
+    /// The user value, or the encoded `ValuePointer` to it if `separated`
+    pub value: &'a [u8],
+    /// Whether the user value is kept in the value log
+    pub separated: bool,
+}
+
+impl<'a> StoredValue<'a> {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match (self.expire_at, self.separated) {
+            (None, false) => buf.put_u8(TAG_PLAIN),
+            (None, true) => buf.put_u8(TAG_SEPARATED),
+            (Some(expire_at), separated) => {
+                buf.put_u8(if separated {
+                    TAG_EXPIRING_SEPARATED
+                } else {
+                    TAG_EXPIRING
+                });
+                buf.put_u64(expire_at);
+            }
+        }
+        buf.put_slice(self.value);
+    }
+
+    /// Decode a stored value, `None` meaning a tombstone.
+    pub fn decode(mut raw: &'a [u8]) -> Result<Option<Self>> {
+        if raw.is_empty() {
+            return Ok(None);
+        }
+        let (expire_at, separated) = match raw.get_u8() {
+            TAG_PLAIN => (None, false),
+            TAG_SEPARATED => (None, true),
+            TAG_EXPIRING if raw.len() >= 8 => (Some(raw.get_u64()), false),
+            TAG_EXPIRING_SEPARATED if raw.len() >= 8 => {
+                (Some(raw.get_u64()), true)
+            }
+            tag => bail!("invalid value with tag {tag}"),
+        };
+        Ok(Some(Self {
+            expire_at,
+            value: raw,
+            separated,
+        }))
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
+    }
+}
+
+/// The current time in ms since the Unix epoch, the clock of TTLs.
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the Unix epoch")
+        .as_millis() as u64
+}
@@ -0,0 +1,364 @@
+//! The value log keeps large values apart from the LSM tree, which only
+//! holds pointers to them, so that flushes and compaction move the pointers
+//! instead of the values (WiscKey). The log is split into append-only
+//! segments; garbage collection copies the live values of a sealed segment
+//! into the active one, then removes it.
+//!
+//! A segment is a sequence of records:
+//! | family (u32) | key len (u16) | key | ts (u64) | value len (u32) | value | checksum (u32) |
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::{Mutex, RwLock};
+
+use crate::{
+    error::LsmError,
+    key::{KeyBytes, KeySlice},
+};
+
+/// Where a value lives in the value log: the whole record holding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValuePointer {
+    pub segment: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl ValuePointer {
+    pub const ENCODED_LEN: usize = 16;
+
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut buf = [0; Self::ENCODED_LEN];
+        let mut slice = &mut buf[..];
+        slice.put_u32(self.segment);
+        slice.put_u64(self.offset);
+        slice.put_u32(self.len);
+        buf
+    }
+
+    pub fn decode(mut raw: &[u8]) -> Result<Self> {
+        anyhow::ensure!(
+            raw.len() == Self::ENCODED_LEN,
+            "invalid value pointer of {} bytes",
+            raw.len()
+        );
+        Ok(Self {
+            segment: raw.get_u32(),
+            offset: raw.get_u64(),
+            len: raw.get_u32(),
+        })
+    }
+}
+
+/// One value of a segment, with the version of the key it was written for.
+#[derive(Debug)]
+pub struct ValueLogRecord {
+    pub family: u32,
+    pub key: KeyBytes,
+    pub value: Bytes,
+    pub pointer: ValuePointer,
+}
+
+/// The segments a reader can resolve pointers with. Readers take one before
+/// reading the LSM tree, so that garbage collection cannot remove a segment
+/// under them: the files stay open until the snapshot is dropped.
+#[derive(Clone)]
+pub(crate) struct ValueLogSnapshot {
+    path: Arc<Path>,
+    segments: Arc<BTreeMap<u32, Arc<File>>>,
+}
+
+impl ValueLogSnapshot {
+    /// Read the value `pointer` refers to.
+    pub(crate) fn read(&self, pointer: &ValuePointer) -> Result<Bytes> {
+        let file = self.segments.get(&pointer.segment).with_context(|| {
+            format!("value log segment {} not found", pointer.segment)
+        })?;
+        let mut buf = vec![0; pointer.len as usize];
+        file.read_exact_at(&mut buf, pointer.offset)?;
+        match decode_record(&buf, pointer.segment, pointer.offset) {
+            Ok(Some(record)) => Ok(record.value),
+            result => Err(LsmError::Corruption {
+                path: ValueLog::path_of_segment_static(
+                    &self.path,
+                    pointer.segment,
+                ),
+                offset: pointer.offset,
+                reason: result.err().unwrap_or("checksum mismatch").to_string(),
+            }
+            .into()),
+        }
+    }
+
+    pub(crate) fn contains(&self, segment: u32) -> bool {
+        self.segments.contains_key(&segment)
+    }
+
+    fn with_segments(&self, segments: BTreeMap<u32, Arc<File>>) -> Self {
+        Self {
+            path: self.path.clone(),
+            segments: Arc::new(segments),
+        }
+    }
+}
+
+struct ActiveSegment {
+    id: u32,
+    file: Arc<File>,
+    len: u64,
+}
+
+pub(crate) struct ValueLog {
+    path: PathBuf,
+    segment_size: u64,
+    segments: RwLock<ValueLogSnapshot>,
+    /// Created on the first append after opening, as the last segment of a
+    /// previous run may end with a torn record
+    active: Mutex<Option<ActiveSegment>>,
+    next_segment_id: Mutex<u32>,
+    /// Held while collecting a segment, and while linking the segments into
+    /// a checkpoint
+    pub(crate) gc_lock: Mutex<()>,
+}
+
+impl ValueLog {
+    /// Open the segments in `path`, all of them sealed. New segments are
+    /// sealed once they reach `segment_size`.
+    pub(crate) fn open(path: &Path, segment_size: usize) -> Result<Self> {
+        let mut segments = BTreeMap::new();
+        for entry in std::fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            if entry_path.extension().is_none_or(|ext| ext != "vlog") {
+                continue;
+            }
+            let id = entry_path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse().ok())
+                .with_context(|| {
+                    format!("invalid segment name {}", entry_path.display())
+                })?;
+            segments.insert(id, Arc::new(File::open(&entry_path)?));
+        }
+        let next_segment_id = segments.keys().last().map_or(0, |id| id + 1);
+        Ok(Self {
+            path: path.to_path_buf(),
+            segment_size: segment_size as u64,
+            segments: RwLock::new(ValueLogSnapshot {
+                path: path.into(),
+                segments: Arc::new(segments),
+            }),
+            active: Mutex::new(None),
+            next_segment_id: Mutex::new(next_segment_id),
+            gc_lock: Mutex::new(()),
+        })
+    }
+
+    pub(crate) fn path_of_segment_static(
+        path: impl AsRef<Path>,
+        id: u32,
+    ) -> PathBuf {
+        path.as_ref().join(segment_file_name(id))
+    }
+
+    pub(crate) fn path_of_segment(&self, id: u32) -> PathBuf {
+        Self::path_of_segment_static(&self.path, id)
+    }
+
+    pub(crate) fn snapshot(&self) -> ValueLogSnapshot {
+        self.segments.read().clone()
+    }
+
+    /// The segments no longer appended to, oldest first.
+    pub(crate) fn sealed_segments(&self) -> Vec<u32> {
+        let active = self.active.lock().as_ref().map(|active| active.id);
+        self.segments
+            .read()
+            .segments
+            .keys()
+            .copied()
+            .filter(|id| Some(*id) != active)
+            .collect()
+    }
+
+    /// Append the values with the versions they belong to, returning their
+    /// pointers. They are durable once `sync` returns.
+    pub(crate) fn append(
+        &self,
+        entries: &[(u32, KeySlice, &[u8])],
+    ) -> Result<Vec<ValuePointer>> {
+        let mut active = self.active.lock();
+        let segment = match active.as_mut() {
+            Some(segment) => segment,
+            None => active.insert(self.create_segment()?),
+        };
+        let mut buf = Vec::new();
+        let mut pointers = Vec::with_capacity(entries.len());
+        for (family, key, value) in entries {
+            let start = buf.len();
+            buf.put_u32(*family);
+            buf.put_u16(key.key_len() as u16);
+            buf.put_slice(key.key_ref());
+            buf.put_u64(key.ts());
+            buf.put_u32(value.len() as u32);
+            buf.put_slice(value);
+            let checksum = crc32fast::hash(&buf[start..]);
+            buf.put_u32(checksum);
+            pointers.push(ValuePointer {
+                segment: segment.id,
+                offset: segment.len + start as u64,
+                len: (buf.len() - start) as u32,
+            });
+        }
+        segment.file.write_all_at(&buf, segment.len)?;
+        segment.len += buf.len() as u64;
+        if segment.len >= self.segment_size {
+            // the next append starts a new segment
+            segment.file.sync_all()?;
+            *active = None;
+        }
+        Ok(pointers)
+    }
+
+    fn create_segment(&self) -> Result<ActiveSegment> {
+        let mut next_segment_id = self.next_segment_id.lock();
+        let id = *next_segment_id;
+        *next_segment_id += 1;
+        let file = Arc::new(
+            File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(self.path_of_segment(id))
+                .context("failed to create value log segment")?,
+        );
+        File::open(&self.path)?.sync_all()?;
+        let mut segments = self.segments.write();
+        let mut map = segments.segments.as_ref().clone();
+        map.insert(id, file.clone());
+        *segments = segments.with_segments(map);
+        Ok(ActiveSegment { id, file, len: 0 })
+    }
+
+    /// Make every value appended so far durable.
+    pub(crate) fn sync(&self) -> Result<()> {
+        let file = self
+            .active
+            .lock()
+            .as_ref()
+            .map(|active| active.file.clone());
+        if let Some(file) = file {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Seal the active segment, so that it is never appended to again.
+    pub(crate) fn seal(&self) -> Result<()> {
+        let mut active = self.active.lock();
+        if let Some(segment) = active.take() {
+            segment.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Remove a sealed segment. Readers holding a snapshot keep reading it.
+    pub(crate) fn remove_segment(&self, id: u32) -> Result<()> {
+        {
+            let mut segments = self.segments.write();
+            let mut map = segments.segments.as_ref().clone();
+            map.remove(&id);
+            *segments = segments.with_segments(map);
+        }
+        std::fs::remove_file(self.path_of_segment(id))?;
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Decode the records of the segment at `path`, stopping at a torn
+    /// tail.
+    pub fn read_segment(path: impl AsRef<Path>) -> Result<Vec<ValueLogRecord>> {
+        let path = path.as_ref();
+        let segment = path
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.parse().ok())
+            .unwrap_or_default();
+        let buf = std::fs::read(path).context("failed to read value log")?;
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            match decode_record(&buf[offset..], segment, offset as u64) {
+                Ok(Some(record)) => {
+                    offset += record.pointer.len as usize;
+                    records.push(record);
+                }
+                Ok(None) => break,
+                Err(reason) => {
+                    return Err(LsmError::Corruption {
+                        path: path.to_path_buf(),
+                        offset: offset as u64,
+                        reason: reason.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn segment_file_name(id: u32) -> String {
+    format!("{id:05}.vlog")
+}
+
+/// Decode the record at the start of `buf`, or `None` if it is cut short.
+fn decode_record(
+    buf: &[u8],
+    segment: u32,
+    offset: u64,
+) -> std::result::Result<Option<ValueLogRecord>, &'static str> {
+    let mut rbuf = buf;
+    if rbuf.remaining() < 6 {
+        return Ok(None);
+    }
+    let family = rbuf.get_u32();
+    let key_len = rbuf.get_u16() as usize;
+    if rbuf.remaining() < key_len + 12 {
+        return Ok(None);
+    }
+    let key = Bytes::copy_from_slice(&rbuf[..key_len]);
+    rbuf.advance(key_len);
+    let ts = rbuf.get_u64();
+    let value_len = rbuf.get_u32() as usize;
+    if rbuf.remaining() < value_len + 4 {
+        return Ok(None);
+    }
+    let value = Bytes::copy_from_slice(&rbuf[..value_len]);
+    rbuf.advance(value_len);
+    let len = buf.len() - rbuf.remaining();
+    let checksum = rbuf.get_u32();
+    if crc32fast::hash(&buf[..len]) != checksum {
+        // a partially persisted last record looks the same
+        if !rbuf.has_remaining() {
+            return Ok(None);
+        }
+        return Err("checksum mismatch");
+    }
+    Ok(Some(ValueLogRecord {
+        family,
+        key: KeyBytes::from_bytes_with_ts(key, ts),
+        value,
+        pointer: ValuePointer {
+            segment,
+            offset,
+            len: (len + 4) as u32,
+        },
+    }))
+}
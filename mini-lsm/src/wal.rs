@@ -2,43 +2,70 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use bytes::{Buf, BufMut, Bytes};
-use crossbeam_skiplist::SkipMap;
 use std::{
     fs::{File, OpenOptions},
-    hash::Hasher,
     io::{BufWriter, Read, Write},
     path::Path,
-    sync::{Arc, Mutex},
+    sync::{Arc, Condvar, Mutex},
 };
 
-use crate::key::{KeyBytes, KeySlice};
+use crate::{
+    error::LsmError,
+    key::{KeyBytes, KeySlice},
+};
 
 pub struct Wal {
     file: Arc<Mutex<BufWriter<File>>>,
+    /// Another handle to the file, so that fsync does not block appends
+    sync_file: File,
+    sync_state: Mutex<SyncState>,
+    /// Notified when an fsync finishes
+    synced: Condvar,
+}
+
+/// Records are numbered in the order they are written, so that concurrent
+/// writers can share one fsync (group commit).
+#[derive(Default)]
+struct SyncState {
+    /// Number of records written
+    written: u64,
+    /// Number of records known to be durable
+    synced: u64,
+    /// Whether a writer is running an fsync for the others
+    syncing: bool,
 }
 
 impl Wal {
-    /// create WAL (write ahead log)
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    fn new(file: File) -> Result<Self> {
         Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create wal")?,
-            ))),
+            sync_file: file.try_clone()?,
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            sync_state: Mutex::new(SyncState::default()),
+            synced: Condvar::new(),
         })
     }
 
-    /// recover wal from disk file
-    pub fn recover(
-        path: impl AsRef<Path>,
-        skiplist: &SkipMap<KeyBytes, Bytes>,
-    ) -> Result<Self> {
+    /// create WAL (write ahead log). A file left at `path` by a crash before
+    /// the manifest recorded it holds no acknowledged writes and is replaced.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(
+            OpenOptions::new()
+                .read(true)
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(path)
+                .context("failed to create wal")?,
+        )
+    }
+
+    /// Recover the WAL from disk, returning it with its records. A torn
+    /// record at the end of the file, left by a crash during a write, is
+    /// truncated away; a damaged record anywhere else is reported as
+    /// [`LsmError::Corruption`].
+    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<WalRecord>)> {
         let path = path.as_ref();
         let mut file = OpenOptions::new()
             .read(true)
@@ -47,57 +74,36 @@ impl Wal {
             .context("failed to recover from WAL")?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
-        let mut rbuf: &[u8] = buf.as_slice();
-        while rbuf.has_remaining() {
-            let batch_size = rbuf.get_u32() as usize;
-            if rbuf.remaining() < batch_size {
-                bail!("incomplete WAL");
-            }
-            let mut batch_buf = &rbuf[..batch_size];
-            let mut kv_pairs = Vec::new();
-            let mut hasher = crc32fast::Hasher::new();
-            let single_checksum = crc32fast::hash(batch_buf);
-            while batch_buf.has_remaining() {
-                let key_len = batch_buf.get_u16() as usize;
-                hasher.write(&(key_len as u16).to_be_bytes());
-                let key = Bytes::copy_from_slice(&batch_buf[..key_len]);
-                hasher.write(&key);
-                batch_buf.advance(key_len);
-                let ts = batch_buf.get_u64();
-                hasher.write(&ts.to_be_bytes());
-                let val_len = batch_buf.get_u16() as usize;
-                hasher.write(&(val_len as u16).to_be_bytes());
-                let val = Bytes::copy_from_slice(&batch_buf[..val_len]);
-                hasher.write(&val);
-                kv_pairs.push((key, ts, val));
-                batch_buf.advance(val_len);
-            }
-            rbuf.advance(batch_size);
-            let expected_checksum = rbuf.get_u32();
-            let component_checksum = hasher.finalize();
-            assert_eq!(component_checksum, single_checksum);
-            if single_checksum != expected_checksum {
-                bail!("checksum mismatch");
-            }
-            for (k, t, v) in kv_pairs {
-                skiplist.insert(KeyBytes::from_bytes_with_ts(k, t), v);
-            }
+        let (records, valid_len) = decode_records(path, &buf)?;
+        if valid_len < buf.len() as u64 {
+            tracing::warn!(
+                "truncating torn WAL tail of {} at offset {}",
+                path.display(),
+                valid_len
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
         }
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
-        })
+        Ok((Self::new(file)?, records))
     }
 
-    /// put k-v pair
-    pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-        self.put_batch(&[(key, value)])
+    /// Decode the records of the WAL at `path` without modifying it,
+    /// returning them with the length of the file before any torn tail.
+    pub fn read_records(
+        path: impl AsRef<Path>,
+    ) -> Result<(Vec<WalRecord>, u64)> {
+        let path = path.as_ref();
+        let buf = std::fs::read(path).context("failed to read WAL")?;
+        decode_records(path, &buf)
     }
 
-    /// batch style api for put
-    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+    /// Append the entries of a write batch as one record. Each entry is
+    /// tagged with the id of its column family.
+    pub fn put_batch(&self, data: &[(u32, KeySlice, &[u8])]) -> Result<()> {
         let mut file = self.file.lock().unwrap();
         let mut buf = Vec::<u8>::new();
-        for (k, v) in data {
+        for (family, k, v) in data {
+            buf.put_u32(*family);
             buf.put_u16(k.key_len() as u16);
             buf.put_slice(k.key_ref());
             buf.put_u64(k.ts());
@@ -107,17 +113,140 @@ impl Wal {
         // batch size header
         file.write_all(&(buf.len() as u32).to_be_bytes())?;
         // k-v pairs
-        file.write(&buf)?;
+        file.write_all(&buf)?;
         // checksum(u32)
         file.write_all(&crc32fast::hash(&buf).to_be_bytes())?;
+        self.sync_state.lock().unwrap().written += 1;
         Ok(())
     }
 
-    /// sync memory to disk
+    /// Make every record written so far durable. Concurrent callers wait
+    /// for one fsync covering all their records instead of each running
+    /// their own.
     pub fn sync(&self) -> Result<()> {
-        let mut file = self.file.lock().unwrap();
-        file.flush()?;
-        file.get_mut().sync_all()?;
-        Ok(())
+        let mut state = self.sync_state.lock().unwrap();
+        let target = state.written;
+        loop {
+            if state.synced >= target {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // become the leader and sync the records of everyone waiting
+            state.syncing = true;
+            drop(state);
+            let result = self.flush_and_sync();
+            state = self.sync_state.lock().unwrap();
+            state.syncing = false;
+            self.synced.notify_all();
+            state.synced = state.synced.max(result?);
+        }
+    }
+
+    /// Flush the buffered records and fsync them, returning how many
+    /// records are durable.
+    fn flush_and_sync(&self) -> Result<u64> {
+        let written = {
+            let mut file = self.file.lock().unwrap();
+            file.flush()?;
+            self.sync_state.lock().unwrap().written
+        };
+        self.sync_file.sync_all()?;
+        Ok(written)
+    }
+}
+
+/// One write batch of a WAL.
+#[derive(Debug)]
+pub struct WalRecord {
+    /// Offset of the record in the file
+    pub offset: u64,
+    /// The entries with the id of their column family
+    pub entries: Vec<(u32, KeyBytes, Bytes)>,
+}
+
+/// Decode the records of `buf`, read from `path`, stopping at a torn tail.
+fn decode_records(path: &Path, buf: &[u8]) -> Result<(Vec<WalRecord>, u64)> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        match decode_record(&buf[offset..]) {
+            Ok(Some((entries, record_len))) => {
+                records.push(WalRecord {
+                    offset: offset as u64,
+                    entries,
+                });
+                offset += record_len;
+            }
+            Ok(None) => break,
+            Err(reason) => {
+                return Err(LsmError::Corruption {
+                    path: path.to_path_buf(),
+                    offset: offset as u64,
+                    reason: reason.to_string(),
+                }
+                .into());
+            }
+        }
+    }
+    Ok((records, offset as u64))
+}
+
+type DecodedRecord = (Vec<(u32, KeyBytes, Bytes)>, usize);
+
+/// Decode the record at the start of `buf`, returning its entries and its
+/// length, or `None` if it is cut short by the end of the file.
+fn decode_record(
+    buf: &[u8],
+) -> std::result::Result<Option<DecodedRecord>, &'static str> {
+    let mut rbuf = buf;
+    if rbuf.remaining() < 4 {
+        return Ok(None);
+    }
+    let batch_size = rbuf.get_u32() as usize;
+    if rbuf.remaining() < batch_size + 4 {
+        return Ok(None);
+    }
+    let mut batch_buf = &rbuf[..batch_size];
+    rbuf.advance(batch_size);
+    let expected_checksum = rbuf.get_u32();
+    if crc32fast::hash(batch_buf) != expected_checksum {
+        // a partially persisted last record looks the same
+        if !rbuf.has_remaining() {
+            return Ok(None);
+        }
+        return Err("checksum mismatch");
+    }
+    let mut kv_pairs = Vec::new();
+    while batch_buf.has_remaining() {
+        if batch_buf.remaining() < 4 {
+            return Err("malformed record");
+        }
+        let family = batch_buf.get_u32();
+        let key = get_len_prefixed(&mut batch_buf)?;
+        if batch_buf.remaining() < 8 {
+            return Err("malformed record");
+        }
+        let ts = batch_buf.get_u64();
+        let value = get_len_prefixed(&mut batch_buf)?;
+        kv_pairs.push((family, KeyBytes::from_bytes_with_ts(key, ts), value));
+    }
+    Ok(Some((kv_pairs, buf.len() - rbuf.remaining())))
+}
+
+fn get_len_prefixed(
+    buf: &mut &[u8],
+) -> std::result::Result<Bytes, &'static str> {
+    if buf.remaining() < 2 {
+        return Err("malformed record");
+    }
+    let len = buf.get_u16() as usize;
+    if buf.remaining() < len {
+        return Err("malformed record");
     }
+    let data = Bytes::copy_from_slice(&buf[..len]);
+    buf.advance(len);
+    Ok(data)
 }